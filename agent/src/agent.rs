@@ -0,0 +1,254 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing the agent loop.
+
+use ryst_error::InvalidStateError;
+use ryst_openai::{ChatCompletionRequest, KeySource, Message, OpenAIClient, OpenAIError, Tool};
+
+use crate::memory::{BufferMemory, Memory};
+use crate::tools::ToolSet;
+
+const DEFAULT_MAX_ITERATIONS: usize = 10;
+
+/// The final reply of an agent run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AgentOutput {
+    /// The text of the model's final reply
+    pub content: String,
+    /// The number of requests made during the run
+    pub iterations: usize,
+    /// The tokens used during the run
+    pub total_tokens: i32,
+}
+
+/// Runs a chat model in a loop, calling the tools it asks for until it replies without calling
+/// one.
+#[derive(Debug)]
+pub struct Agent<M = BufferMemory> {
+    model: String,
+    system_prompt: Option<String>,
+    tools: ToolSet,
+    memory: M,
+    max_iterations: usize,
+    token_budget: Option<i32>,
+    tokens_used: i32,
+    key_source: Option<KeySource>,
+    client: Option<OpenAIClient>,
+}
+
+impl Agent<BufferMemory> {
+    /// Create a new `Agent` using the given model, with no tools and a `BufferMemory`.
+    pub fn new(model: &str) -> Self {
+        Self {
+            model: model.to_string(),
+            system_prompt: None,
+            tools: ToolSet::new(),
+            memory: BufferMemory::new(),
+            max_iterations: DEFAULT_MAX_ITERATIONS,
+            token_budget: None,
+            tokens_used: 0,
+            key_source: None,
+            client: None,
+        }
+    }
+}
+
+impl<M: Memory> Agent<M> {
+    /// The system prompt sent at the start of every request.
+    pub fn with_system_prompt(mut self, system_prompt: &str) -> Self {
+        self.system_prompt = Some(system_prompt.to_string());
+        self
+    }
+
+    /// The tools the model may call.
+    pub fn with_tools(mut self, tools: ToolSet) -> Self {
+        self.tools = tools;
+        self
+    }
+
+    /// The memory the conversation is kept in.
+    pub fn with_memory<N: Memory>(self, memory: N) -> Agent<N> {
+        Agent {
+            model: self.model,
+            system_prompt: self.system_prompt,
+            tools: self.tools,
+            memory,
+            max_iterations: self.max_iterations,
+            token_budget: self.token_budget,
+            tokens_used: self.tokens_used,
+            key_source: self.key_source,
+            client: self.client,
+        }
+    }
+
+    /// The maximum number of requests made by a single call to `run`. Defaults to 10.
+    pub fn with_max_iterations(mut self, max_iterations: usize) -> Self {
+        self.max_iterations = max_iterations;
+        self
+    }
+
+    /// The maximum number of tokens the agent may use across all of its runs.
+    ///
+    /// The budget is checked before each request, so the request which crosses it completes and
+    /// the next one fails.
+    pub fn with_token_budget(mut self, token_budget: i32) -> Self {
+        self.token_budget = Some(token_budget);
+        self
+    }
+
+    /// Where to read the API key from, see `ChatCompletionRequest::with_key_source`.
+    pub fn with_key_source(mut self, key_source: KeySource) -> Self {
+        self.key_source = Some(key_source);
+        self
+    }
+
+    /// Send the requests through the client, see `ChatCompletionRequest::with_client`.
+    pub fn with_client(mut self, client: OpenAIClient) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+
+    /// Returns the tokens used across all runs.
+    pub fn tokens_used(&self) -> i32 {
+        self.tokens_used
+    }
+
+    /// Add the input to the conversation as a user message and run until the model replies
+    /// without calling a tool.
+    ///
+    /// Returns an `InvalidState` error if the model is still calling tools after the maximum
+    /// number of iterations, or if the token budget has been used up.
+    pub async fn run(&mut self, input: &str) -> Result<AgentOutput, OpenAIError> {
+        self.memory.push(Message::new("user", input));
+        let tokens_at_start = self.tokens_used;
+
+        for iteration in 1..=self.max_iterations {
+            if let Some(budget) = self.token_budget {
+                if self.tokens_used >= budget {
+                    return Err(OpenAIError::InvalidState(InvalidStateError::with_message(
+                        format!("Agent has used its token budget of {budget} tokens"),
+                    )));
+                }
+            }
+
+            self.memory.compact().await?;
+            let response = self.request(self.memory.messages(), &[]).submit().await?;
+            self.tokens_used += response.usage.total_tokens;
+
+            let message = response
+                .choices
+                .into_iter()
+                .next()
+                .map(|choice| choice.message)
+                .ok_or_else(|| {
+                    OpenAIError::InvalidState(InvalidStateError::with_message(
+                        "Response contained no choices".to_string(),
+                    ))
+                })?;
+            let calls = message.tool_calls().to_vec();
+            let content = message.content().as_text().unwrap_or_default().to_string();
+            self.memory.push(message);
+
+            if calls.is_empty() {
+                return Ok(AgentOutput {
+                    content,
+                    iterations: iteration,
+                    total_tokens: self.tokens_used - tokens_at_start,
+                });
+            }
+
+            for call in &calls {
+                let result = self.tools.call(call).await;
+                self.memory.push(Message::tool_result(&call.id, &result));
+            }
+        }
+
+        Err(OpenAIError::InvalidState(InvalidStateError::with_message(
+            format!(
+                "Agent did not reply within {} iterations",
+                self.max_iterations
+            ),
+        )))
+    }
+
+    pub(crate) fn tools(&self) -> &ToolSet {
+        &self.tools
+    }
+
+    /// Build a request for the next reply to the conversation, offering `extra_tools` alongside
+    /// the agent's own tools.
+    pub(crate) fn request(
+        &self,
+        history: Vec<Message>,
+        extra_tools: &[Tool],
+    ) -> ChatCompletionRequest {
+        let mut messages = Vec::new();
+        if let Some(system_prompt) = &self.system_prompt {
+            messages.push(Message::new("system", system_prompt));
+        }
+        messages.extend(history);
+
+        let mut tools = self.tools.definitions();
+        tools.extend_from_slice(extra_tools);
+
+        let mut request = ChatCompletionRequest::new(&self.model, &messages);
+        if !tools.is_empty() {
+            request = request.with_tools(&tools);
+        }
+        if let Some(key_source) = &self.key_source {
+            request = request.with_key_source(key_source.clone());
+        }
+        if let Some(client) = &self.client {
+            request = request.with_client(client.clone());
+        }
+        request
+    }
+}
+
+// The following tests require that OPENAI_API_KEY is set and will burn tokens.
+#[cfg(feature = "integration")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ryst_openai::Tool;
+    use serde_json::json;
+
+    #[tokio::test]
+    // Verify that the agent calls a tool and uses its result in the reply
+    async fn test_agent_run_with_tool() {
+        let tools = ToolSet::new().with_tool(
+            Tool::function(
+                "get_secret_word",
+                "Returns the secret word",
+                json!({"type": "object", "properties": {}}),
+            ),
+            |_| async { Ok("pineapple".to_string()) },
+        );
+
+        let mut agent = Agent::new("gpt-4o-mini")
+            .with_system_prompt("Use the tools available to answer.")
+            .with_tools(tools)
+            .with_max_iterations(4);
+
+        let output = agent.run("What is the secret word?").await.unwrap();
+        assert!(output.content.to_lowercase().contains("pineapple"));
+        assert!(output.iterations >= 2);
+    }
+}
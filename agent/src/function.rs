@@ -0,0 +1,240 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing the traits describing typed tool functions.
+
+use std::collections::HashMap;
+
+use ryst_openai::Tool;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+/// A tool whose arguments are deserialized into this type, usually implemented with
+/// `#[derive(ToolFunction)]` from the `derive` feature.
+///
+/// Add it to a `ToolSet` with `ToolSet::with_function`.
+pub trait ToolFunction: DeserializeOwned {
+    /// The name of the tool
+    const NAME: &'static str;
+    /// What the tool does, telling the model when to call it
+    const DESCRIPTION: &'static str;
+
+    /// Returns the JSON Schema of the arguments.
+    fn parameters() -> Value;
+
+    /// Returns the definition of the tool, to send with a request.
+    fn tool() -> Tool {
+        Tool::function(Self::NAME, Self::DESCRIPTION, Self::parameters())
+    }
+}
+
+/// A type which can be a field of a `ToolFunction`, describing its own JSON Schema.
+pub trait ToolParameter {
+    /// Whether a field of this type must be provided
+    const REQUIRED: bool = true;
+
+    /// Returns the JSON Schema of the type.
+    fn schema() -> Value;
+}
+
+macro_rules! impl_tool_parameter {
+    ($schema_type:literal: $($ty:ty),*) => {
+        $(
+            impl ToolParameter for $ty {
+                fn schema() -> Value {
+                    json!({ "type": $schema_type })
+                }
+            }
+        )*
+    };
+}
+
+impl_tool_parameter!("string": String, char);
+impl_tool_parameter!("boolean": bool);
+impl_tool_parameter!("integer": i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+impl_tool_parameter!("number": f32, f64);
+
+/// An optional field, which may be omitted or null.
+impl<T: ToolParameter> ToolParameter for Option<T> {
+    const REQUIRED: bool = false;
+
+    fn schema() -> Value {
+        json!({ "anyOf": [T::schema(), { "type": "null" }] })
+    }
+}
+
+impl<T: ToolParameter> ToolParameter for Vec<T> {
+    fn schema() -> Value {
+        json!({ "type": "array", "items": T::schema() })
+    }
+}
+
+impl<T: ToolParameter> ToolParameter for HashMap<String, T> {
+    fn schema() -> Value {
+        json!({ "type": "object", "additionalProperties": T::schema() })
+    }
+}
+
+/// Any JSON value.
+impl ToolParameter for Value {
+    fn schema() -> Value {
+        json!({})
+    }
+}
+
+#[cfg(feature = "derive")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde::Deserialize;
+
+    use crate::{ToolFunction, ToolParameter, ToolSet};
+    use ryst_openai::{FunctionCall, ToolCall};
+
+    #[allow(dead_code)]
+    #[derive(Deserialize, ToolParameter)]
+    enum Unit {
+        Celsius,
+        Fahrenheit,
+    }
+
+    /// Get the current weather
+    /// for a city.
+    #[allow(dead_code)]
+    #[derive(Deserialize, ToolFunction)]
+    struct GetWeather {
+        /// The name of the city
+        city: String,
+        unit: Option<Unit>,
+        days: Vec<u8>,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Deserialize, ToolFunction)]
+    #[tool(name = "lookup", description = "Look something up")]
+    struct Lookup {
+        r#type: String,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Deserialize, ToolParameter)]
+    #[serde(rename_all = "snake_case")]
+    enum SortOrder {
+        MostRecent,
+        #[serde(rename = "top")]
+        MostRelevant,
+        #[serde(skip)]
+        Internal,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Deserialize, ToolFunction)]
+    #[serde(rename_all = "camelCase")]
+    struct SearchDocs {
+        search_query: String,
+        #[serde(rename = "limit", default)]
+        max_results: u32,
+        #[serde(default = "default_order", alias = "sort")]
+        order: SortOrder,
+        #[serde(skip)]
+        cache_key: String,
+    }
+
+    fn default_order() -> SortOrder {
+        SortOrder::MostRelevant
+    }
+
+    #[test]
+    // Verify that the derived schema follows serde's renames, defaults and skipped fields
+    fn test_derive_serde_attributes() {
+        assert_eq!(
+            SearchDocs::parameters(),
+            json!({
+                "type": "object",
+                "properties": {
+                    "searchQuery": {"type": "string"},
+                    "limit": {"type": "integer"},
+                    "order": {"type": "string", "enum": ["most_recent", "top"]}
+                },
+                "required": ["searchQuery"],
+                "additionalProperties": false
+            })
+        );
+
+        let args: SearchDocs =
+            serde_json::from_value(json!({"searchQuery": "rust", "limit": 3, "order": "top"}))
+                .unwrap();
+        assert_eq!(args.max_results, 3);
+    }
+
+    #[test]
+    // Verify that the derived schema describes the fields of the struct
+    fn test_derive_tool_function() {
+        assert_eq!(GetWeather::NAME, "get_weather");
+        assert_eq!(
+            GetWeather::DESCRIPTION,
+            "Get the current weather for a city."
+        );
+        assert_eq!(
+            GetWeather::parameters(),
+            json!({
+                "type": "object",
+                "properties": {
+                    "city": {"type": "string", "description": "The name of the city"},
+                    "unit": {"anyOf": [
+                        {"type": "string", "enum": ["Celsius", "Fahrenheit"]},
+                        {"type": "null"}
+                    ]},
+                    "days": {"type": "array", "items": {"type": "integer"}}
+                },
+                "required": ["city", "days"],
+                "additionalProperties": false
+            })
+        );
+    }
+
+    #[test]
+    // Verify that the name and description can be set with the tool attribute
+    fn test_derive_tool_attribute() {
+        let tool = Lookup::tool();
+        assert_eq!(tool.function.name, "lookup");
+        assert_eq!(
+            tool.function.description.as_deref(),
+            Some("Look something up")
+        );
+        assert_eq!(
+            Lookup::parameters()["properties"]["type"],
+            json!({"type": "string"})
+        );
+    }
+
+    #[tokio::test]
+    // Verify that a tool accepts null for an optional field
+    async fn test_call_with_null_optional_field() {
+        let tools = ToolSet::new().with_function(|weather: GetWeather| async move {
+            Ok(format!("{} {}", weather.city, weather.unit.is_none()))
+        });
+        let call = ToolCall {
+            id: "call_1".to_string(),
+            kind: "function".to_string(),
+            function: FunctionCall {
+                name: "get_weather".to_string(),
+                arguments: r#"{"city": "Paris", "unit": null, "days": []}"#.to_string(),
+            },
+        };
+
+        assert_eq!(tools.call(&call).await, "Paris true");
+    }
+}
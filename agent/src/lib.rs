@@ -0,0 +1,37 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Primitives for building agents on top of ryst-openai chat completions: an `Agent` loop which
+//! calls the tools in a `ToolSet` until the model replies, the `Memory` it keeps the
+//! conversation in, and a `Router` which hands a conversation between several agents.
+
+// Allows the code generated by the derive macros to be used within this crate
+#[cfg(all(feature = "derive", test))]
+extern crate self as ryst_agent;
+
+mod agent;
+mod function;
+mod memory;
+mod routing;
+mod tools;
+
+pub use agent::{Agent, AgentOutput};
+pub use function::{ToolFunction, ToolParameter};
+pub use memory::{BufferMemory, Memory, SummaryMemory};
+pub use routing::{Router, RouterEvent, RouterOutput};
+pub use tools::ToolSet;
+
+#[cfg(feature = "derive")]
+pub use ryst_derive::{ToolFunction, ToolParameter};
+pub use serde_json;
@@ -0,0 +1,337 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing the memory an agent keeps its conversation in.
+
+use std::future::Future;
+
+use ryst_error::InvalidStateError;
+use ryst_openai::{ChatCompletionRequest, KeySource, Message, OpenAIClient, OpenAIError};
+
+/// Stores the conversation of an agent and decides which messages are sent with each request.
+pub trait Memory {
+    /// Add a message to the conversation.
+    fn push(&mut self, message: Message);
+
+    /// Returns the messages to send with the next request, not including the system prompt.
+    fn messages(&self) -> Vec<Message>;
+
+    /// Called before each request, giving the memory a chance to shrink the conversation.
+    fn compact(&mut self) -> impl Future<Output = Result<(), OpenAIError>> + Send {
+        async { Ok(()) }
+    }
+}
+
+/// Keeps the conversation as it is, optionally only the most recent messages.
+#[derive(Debug, Clone, Default)]
+pub struct BufferMemory {
+    messages: Vec<Message>,
+    max_messages: Option<usize>,
+}
+
+impl BufferMemory {
+    /// Create a new `BufferMemory` which keeps every message.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only keep the most recent messages, dropping the oldest once there are more than
+    /// `max_messages`.
+    pub fn with_max_messages(mut self, max_messages: usize) -> Self {
+        self.max_messages = Some(max_messages);
+        self
+    }
+}
+
+impl Memory for BufferMemory {
+    fn push(&mut self, message: Message) {
+        self.messages.push(message);
+
+        if let Some(max_messages) = self.max_messages {
+            let excess = self.messages.len().saturating_sub(max_messages);
+            self.messages.drain(..excess);
+            drop_orphaned_tool_results(&mut self.messages);
+        }
+    }
+
+    fn messages(&self) -> Vec<Message> {
+        self.messages.clone()
+    }
+}
+
+/// Keeps the most recent messages, replacing older messages with a summary written by a model.
+#[derive(Debug, Clone)]
+pub struct SummaryMemory {
+    model: String,
+    key_source: Option<KeySource>,
+    client: Option<OpenAIClient>,
+    messages: Vec<Message>,
+    summary: Option<String>,
+    max_messages: usize,
+    keep_messages: usize,
+}
+
+impl SummaryMemory {
+    /// Create a new `SummaryMemory` which summarizes with the given model.
+    ///
+    /// By default, once there are more than 20 messages all but the 6 most recent are summarized.
+    pub fn new(model: &str) -> Self {
+        Self {
+            model: model.to_string(),
+            key_source: None,
+            client: None,
+            messages: Vec::new(),
+            summary: None,
+            max_messages: 20,
+            keep_messages: 6,
+        }
+    }
+
+    /// The number of messages which triggers a summary.
+    pub fn with_max_messages(mut self, max_messages: usize) -> Self {
+        self.max_messages = max_messages;
+        self
+    }
+
+    /// The number of recent messages kept as they are when summarizing.
+    pub fn with_keep_messages(mut self, keep_messages: usize) -> Self {
+        self.keep_messages = keep_messages;
+        self
+    }
+
+    /// Where to read the API key from, see `ChatCompletionRequest::with_key_source`.
+    pub fn with_key_source(mut self, key_source: KeySource) -> Self {
+        self.key_source = Some(key_source);
+        self
+    }
+
+    /// Send the requests through the client, see `ChatCompletionRequest::with_client`.
+    pub fn with_client(mut self, client: OpenAIClient) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Returns the summary of the older messages, if they have been summarized.
+    pub fn summary(&self) -> Option<&str> {
+        self.summary.as_deref()
+    }
+
+    async fn summarize(&self, messages: &[Message]) -> Result<String, OpenAIError> {
+        let mut transcript = String::new();
+        if let Some(summary) = &self.summary {
+            transcript.push_str(&format!("Summary so far: {summary}\n"));
+        }
+        for message in messages {
+            transcript.push_str(&describe(message));
+            transcript.push('\n');
+        }
+
+        let mut request = ChatCompletionRequest::new(
+            &self.model,
+            &[
+                Message::new(
+                    "system",
+                    "Summarize the conversation below for an assistant that will continue it. \
+                     Keep facts, decisions, tool results and open questions; drop pleasantries. \
+                     Reply with the summary only.",
+                ),
+                Message::new("user", &transcript),
+            ],
+        );
+        if let Some(key_source) = &self.key_source {
+            request = request.with_key_source(key_source.clone());
+        }
+        if let Some(client) = &self.client {
+            request = request.with_client(client.clone());
+        }
+
+        let response = request.submit().await?;
+        response
+            .choices
+            .first()
+            .and_then(|choice| choice.message.content().as_text())
+            .map(|summary| summary.trim().to_string())
+            .ok_or_else(|| {
+                OpenAIError::InvalidState(InvalidStateError::with_message(
+                    "Summary response contained no text".to_string(),
+                ))
+            })
+    }
+}
+
+impl Memory for SummaryMemory {
+    fn push(&mut self, message: Message) {
+        self.messages.push(message);
+    }
+
+    fn messages(&self) -> Vec<Message> {
+        let mut messages = Vec::with_capacity(self.messages.len() + 1);
+        if let Some(summary) = &self.summary {
+            messages.push(Message::new(
+                "system",
+                &format!("Summary of the earlier conversation:\n{summary}"),
+            ));
+        }
+        messages.extend(self.messages.iter().cloned());
+        messages
+    }
+
+    /// Summarize the older messages if there are more than the maximum.
+    ///
+    /// The tokens used by the summary request are not counted towards the agent's budget.
+    async fn compact(&mut self) -> Result<(), OpenAIError> {
+        if self.messages.len() <= self.max_messages {
+            return Ok(());
+        }
+
+        // Tool results must stay with the assistant message which called the tool
+        let mut split = self.messages.len().saturating_sub(self.keep_messages);
+        while split > 0
+            && self
+                .messages
+                .get(split)
+                .is_some_and(|message| message.role() == "tool")
+        {
+            split -= 1;
+        }
+        if split == 0 {
+            return Ok(());
+        }
+
+        let summary = self.summarize(&self.messages[..split]).await?;
+        self.summary = Some(summary);
+        self.messages.drain(..split);
+        Ok(())
+    }
+}
+
+/// Describe a message as a line of a transcript.
+fn describe(message: &Message) -> String {
+    let mut parts = Vec::new();
+    match message.content().as_text() {
+        Some("") => (),
+        Some(text) => parts.push(text.to_string()),
+        None => parts.push("[non-text content]".to_string()),
+    }
+    parts.extend(message.tool_calls().iter().map(|call| {
+        format!(
+            "[called {}({})]",
+            call.function.name, call.function.arguments
+        )
+    }));
+
+    format!("{}: {}", message.role(), parts.join(" "))
+}
+
+/// Remove tool results from the start of the conversation whose tool calls were dropped, as the
+/// API rejects them.
+fn drop_orphaned_tool_results(messages: &mut Vec<Message>) {
+    let orphans = messages
+        .iter()
+        .take_while(|message| message.role() == "tool")
+        .count();
+    messages.drain(..orphans);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Verify that a buffer keeps the most recent messages without orphaned tool results
+    fn test_buffer_memory_max_messages() {
+        let mut memory = BufferMemory::new().with_max_messages(3);
+        memory.push(Message::new("user", "one"));
+        memory.push(Message::new("assistant", ""));
+        memory.push(Message::tool_result("call_1", "result"));
+        memory.push(Message::tool_result("call_2", "result"));
+        assert_eq!(memory.messages().len(), 3);
+
+        memory.push(Message::new("assistant", "two"));
+        let messages = memory.messages();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content(), "two");
+    }
+
+    #[test]
+    // Verify that an unbounded buffer keeps every message
+    fn test_buffer_memory_unbounded() {
+        let mut memory = BufferMemory::new();
+        for i in 0..50 {
+            memory.push(Message::new("user", &i.to_string()));
+        }
+        assert_eq!(memory.messages().len(), 50);
+    }
+
+    #[tokio::test]
+    // Verify that a summary memory below its limit sends the messages unchanged
+    async fn test_summary_memory_below_limit() {
+        let mut memory = SummaryMemory::new("gpt-4o-mini").with_max_messages(4);
+        memory.push(Message::new("user", "hello"));
+        memory.compact().await.unwrap();
+
+        assert_eq!(memory.messages(), vec![Message::new("user", "hello")]);
+        assert_eq!(memory.summary(), None);
+    }
+
+    #[tokio::test]
+    // Verify that keeping no messages summarizes them all instead of panicking
+    async fn test_summary_memory_keep_no_messages() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        use ryst_error::InternalError;
+
+        // The summary request fails before it is sent, once it has been built
+        let requested = Arc::new(AtomicBool::new(false));
+        let key_source = KeySource::provider({
+            let requested = requested.clone();
+            move || {
+                requested.store(true, Ordering::SeqCst);
+                Err(OpenAIError::Internal(InternalError::with_message(
+                    "offline",
+                )))
+            }
+        });
+        let mut memory = SummaryMemory::new("gpt-4o-mini")
+            .with_max_messages(2)
+            .with_keep_messages(0)
+            .with_key_source(key_source);
+        memory.push(Message::new("user", "one"));
+        memory.push(Message::new("assistant", "two"));
+        memory.push(Message::tool_result("call_1", "three"));
+
+        assert!(memory.compact().await.is_err());
+        assert!(requested.load(Ordering::SeqCst));
+        assert_eq!(memory.messages().len(), 3);
+    }
+
+    #[test]
+    // Verify that messages are described with their tool calls
+    fn test_describe() {
+        let message: Message = serde_json::from_value(serde_json::json!({
+            "role": "assistant",
+            "content": null,
+            "tool_calls": [{
+                "id": "call_1",
+                "type": "function",
+                "function": {"name": "add", "arguments": "{\"a\":1}"}
+            }]
+        }))
+        .unwrap();
+
+        assert_eq!(describe(&message), "assistant: [called add({\"a\":1})]");
+        assert_eq!(describe(&Message::new("user", "hi")), "user: hi");
+    }
+}
@@ -0,0 +1,478 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing the routing of a conversation between several agents.
+
+use std::fmt;
+use std::sync::Arc;
+
+use ryst_error::{InvalidArgumentError, InvalidStateError};
+use ryst_openai::{Message, OpenAIError, Tool, ToolCall};
+use serde_json::{json, Value};
+
+use crate::agent::Agent;
+use crate::memory::{BufferMemory, Memory};
+
+const HANDOFF_PREFIX: &str = "transfer_to_";
+const DEFAULT_MAX_ITERATIONS: usize = 10;
+
+type Selector = dyn Fn(&[Message]) -> Option<String> + Send + Sync;
+type EventHandler = dyn Fn(&RouterEvent) + Send + Sync;
+
+/// Something that happened while routing a conversation, for logging.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouterEvent {
+    /// The conversation was handed from one agent to another
+    Handoff {
+        from: String,
+        to: String,
+        /// The reason given by the model, or `None` if the handoff was made by the selector
+        reason: Option<String>,
+    },
+    /// An agent called one of its tools
+    ToolCalled { agent: String, tool: String },
+}
+
+/// The final reply of a routed run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouterOutput {
+    /// The text of the final reply
+    pub content: String,
+    /// The name of the agent which gave the final reply
+    pub agent: String,
+    /// The events during the run, in order
+    pub events: Vec<RouterEvent>,
+    /// The number of requests made during the run
+    pub iterations: usize,
+    /// The tokens used during the run
+    pub total_tokens: i32,
+}
+
+struct Route {
+    name: String,
+    description: String,
+    agent: Agent,
+}
+
+/// Routes a shared conversation between named agents, each with its own model, system prompt and
+/// tools.
+///
+/// Every agent is offered a `transfer_to_<name>` tool for each of the other agents, which the
+/// model calls to hand the conversation over. A selector may also choose the agent before each
+/// run. The agents' own memories are not used; the conversation is kept in the router's memory.
+pub struct Router<M = BufferMemory> {
+    routes: Vec<Route>,
+    active: usize,
+    memory: M,
+    selector: Option<Arc<Selector>>,
+    event_handler: Option<Arc<EventHandler>>,
+    max_iterations: usize,
+    token_budget: Option<i32>,
+    tokens_used: i32,
+}
+
+impl Router<BufferMemory> {
+    /// Create a new `Router` with no agents and a `BufferMemory`.
+    pub fn new() -> Self {
+        Self {
+            routes: Vec::new(),
+            active: 0,
+            memory: BufferMemory::new(),
+            selector: None,
+            event_handler: None,
+            max_iterations: DEFAULT_MAX_ITERATIONS,
+            token_budget: None,
+            tokens_used: 0,
+        }
+    }
+}
+
+impl Default for Router<BufferMemory> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: Memory> Router<M> {
+    /// Add an agent. The first agent added starts the conversation.
+    ///
+    /// The description tells the other agents when to hand over to this one. The name is used in
+    /// a tool name, so it may only contain ASCII letters, digits, `_` and `-`, and must be unique.
+    pub fn with_agent(
+        mut self,
+        name: &str,
+        description: &str,
+        agent: Agent,
+    ) -> Result<Self, OpenAIError> {
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid {
+            return Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
+                "name",
+                "Agent names may only contain ASCII letters, digits, '_' and '-'",
+            )));
+        }
+        if self.routes.iter().any(|route| route.name == name) {
+            return Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
+                "name",
+                format!("An agent named {name} has already been added"),
+            )));
+        }
+
+        self.routes.push(Route {
+            name: name.to_string(),
+            description: description.to_string(),
+            agent,
+        });
+        Ok(self)
+    }
+
+    /// The memory the shared conversation is kept in.
+    pub fn with_memory<N: Memory>(self, memory: N) -> Router<N> {
+        Router {
+            routes: self.routes,
+            active: self.active,
+            memory,
+            selector: self.selector,
+            event_handler: self.event_handler,
+            max_iterations: self.max_iterations,
+            token_budget: self.token_budget,
+            tokens_used: self.tokens_used,
+        }
+    }
+
+    /// A function called with the conversation at the start of each run, which may return the
+    /// name of the agent to hand over to.
+    pub fn with_selector<F>(mut self, selector: F) -> Self
+    where
+        F: Fn(&[Message]) -> Option<String> + Send + Sync + 'static,
+    {
+        self.selector = Some(Arc::new(selector));
+        self
+    }
+
+    /// A function called with each event as it happens, in addition to the events being returned
+    /// in the output.
+    pub fn with_event_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&RouterEvent) + Send + Sync + 'static,
+    {
+        self.event_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// The maximum number of requests made by a single call to `run`, across all agents.
+    /// Defaults to 10.
+    pub fn with_max_iterations(mut self, max_iterations: usize) -> Self {
+        self.max_iterations = max_iterations;
+        self
+    }
+
+    /// The maximum number of tokens the router may use across all of its runs.
+    pub fn with_token_budget(mut self, token_budget: i32) -> Self {
+        self.token_budget = Some(token_budget);
+        self
+    }
+
+    /// Returns the name of the agent currently handling the conversation.
+    pub fn active(&self) -> Option<&str> {
+        self.routes
+            .get(self.active)
+            .map(|route| route.name.as_str())
+    }
+
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+
+    /// Returns the tokens used across all runs.
+    pub fn tokens_used(&self) -> i32 {
+        self.tokens_used
+    }
+
+    /// Add the input to the conversation and run the active agent, following handoffs, until an
+    /// agent replies without calling a tool.
+    pub async fn run(&mut self, input: &str) -> Result<RouterOutput, OpenAIError> {
+        if self.routes.is_empty() {
+            return Err(OpenAIError::InvalidState(InvalidStateError::with_message(
+                "Router has no agents".to_string(),
+            )));
+        }
+
+        self.memory.push(Message::new("user", input));
+        let tokens_at_start = self.tokens_used;
+        let mut events = Vec::new();
+
+        if let Some(selector) = self.selector.clone() {
+            if let Some(name) = selector(&self.memory.messages()) {
+                self.hand_off(&name, None, &mut events)?;
+            }
+        }
+
+        for iteration in 1..=self.max_iterations {
+            if let Some(budget) = self.token_budget {
+                if self.tokens_used >= budget {
+                    return Err(OpenAIError::InvalidState(InvalidStateError::with_message(
+                        format!("Router has used its token budget of {budget} tokens"),
+                    )));
+                }
+            }
+
+            self.memory.compact().await?;
+            let route = &self.routes[self.active];
+            let response = route
+                .agent
+                .request(self.memory.messages(), &self.handoff_tools())
+                .submit()
+                .await?;
+            self.tokens_used += response.usage.total_tokens;
+
+            let message = response
+                .choices
+                .into_iter()
+                .next()
+                .map(|choice| choice.message)
+                .ok_or_else(|| {
+                    OpenAIError::InvalidState(InvalidStateError::with_message(
+                        "Response contained no choices".to_string(),
+                    ))
+                })?;
+            let calls = message.tool_calls().to_vec();
+            let content = message.content().as_text().unwrap_or_default().to_string();
+            self.memory.push(message);
+
+            if calls.is_empty() {
+                return Ok(RouterOutput {
+                    content,
+                    agent: self.routes[self.active].name.clone(),
+                    events,
+                    iterations: iteration,
+                    total_tokens: self.tokens_used - tokens_at_start,
+                });
+            }
+
+            let mut handoff = None;
+            for call in &calls {
+                let result = match call.function.name.strip_prefix(HANDOFF_PREFIX) {
+                    Some(target) if self.routes.iter().any(|route| route.name == target) => {
+                        handoff = Some((target.to_string(), handoff_reason(call)));
+                        format!("Transferred to {target}.")
+                    }
+                    _ => {
+                        self.emit(
+                            RouterEvent::ToolCalled {
+                                agent: self.routes[self.active].name.clone(),
+                                tool: call.function.name.clone(),
+                            },
+                            &mut events,
+                        );
+                        self.routes[self.active].agent.tools().call(call).await
+                    }
+                };
+                self.memory.push(Message::tool_result(&call.id, &result));
+            }
+
+            if let Some((target, reason)) = handoff {
+                self.hand_off(&target, reason, &mut events)?;
+            }
+        }
+
+        Err(OpenAIError::InvalidState(InvalidStateError::with_message(
+            format!(
+                "Router did not reply within {} iterations",
+                self.max_iterations
+            ),
+        )))
+    }
+
+    /// The handoff tools offered to the active agent, one for each other agent.
+    fn handoff_tools(&self) -> Vec<Tool> {
+        self.routes
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| *index != self.active)
+            .map(|(_, route)| {
+                Tool::function(
+                    &format!("{HANDOFF_PREFIX}{}", route.name),
+                    &format!(
+                        "Hand the conversation over to {}: {}",
+                        route.name, route.description
+                    ),
+                    json!({
+                        "type": "object",
+                        "properties": {
+                            "reason": {
+                                "type": "string",
+                                "description": "Why the conversation is being handed over"
+                            }
+                        }
+                    }),
+                )
+            })
+            .collect()
+    }
+
+    fn hand_off(
+        &mut self,
+        target: &str,
+        reason: Option<String>,
+        events: &mut Vec<RouterEvent>,
+    ) -> Result<(), OpenAIError> {
+        let index = self
+            .routes
+            .iter()
+            .position(|route| route.name == target)
+            .ok_or_else(|| {
+                OpenAIError::InvalidArgument(InvalidArgumentError::new(
+                    "name",
+                    format!("There is no agent named {target}"),
+                ))
+            })?;
+
+        if index != self.active {
+            let event = RouterEvent::Handoff {
+                from: self.routes[self.active].name.clone(),
+                to: target.to_string(),
+                reason,
+            };
+            self.active = index;
+            self.emit(event, events);
+        }
+        Ok(())
+    }
+
+    fn emit(&self, event: RouterEvent, events: &mut Vec<RouterEvent>) {
+        if let Some(handler) = &self.event_handler {
+            handler(&event);
+        }
+        events.push(event);
+    }
+}
+
+impl<M: fmt::Debug> fmt::Debug for Router<M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Router")
+            .field(
+                "agents",
+                &self
+                    .routes
+                    .iter()
+                    .map(|route| &route.name)
+                    .collect::<Vec<_>>(),
+            )
+            .field("active", &self.active)
+            .field("memory", &self.memory)
+            .field("max_iterations", &self.max_iterations)
+            .field("token_budget", &self.token_budget)
+            .field("tokens_used", &self.tokens_used)
+            .finish()
+    }
+}
+
+/// Returns the reason given in the arguments of a handoff call, if any.
+fn handoff_reason(call: &ToolCall) -> Option<String> {
+    call.parse_arguments::<Value>()
+        .ok()
+        .and_then(|args| args["reason"].as_str().map(str::to_string))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    fn router() -> Router {
+        Router::new()
+            .with_agent(
+                "triage",
+                "Works out what the user needs",
+                Agent::new("gpt-4o-mini"),
+            )
+            .unwrap()
+            .with_agent(
+                "billing",
+                "Answers billing questions",
+                Agent::new("gpt-4o-mini"),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    // Verify that invalid and duplicate agent names are rejected
+    fn test_with_agent_names() {
+        assert!(router()
+            .with_agent("billing", "Again", Agent::new("gpt-4o-mini"))
+            .is_err());
+        assert!(Router::new()
+            .with_agent("has space", "", Agent::new("gpt-4o-mini"))
+            .is_err());
+        assert!(Router::new()
+            .with_agent("", "", Agent::new("gpt-4o-mini"))
+            .is_err());
+    }
+
+    #[test]
+    // Verify that the active agent is offered handoff tools for the other agents only
+    fn test_handoff_tools() {
+        let router = router();
+        let tools = router.handoff_tools();
+
+        assert_eq!(tools.len(), 1);
+        assert_eq!(tools[0].function.name, "transfer_to_billing");
+        assert_eq!(
+            tools[0].function.description.as_deref(),
+            Some("Hand the conversation over to billing: Answers billing questions")
+        );
+    }
+
+    #[test]
+    // Verify that a handoff switches the active agent and emits an event
+    fn test_hand_off() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let handler_seen = seen.clone();
+        let mut router = router().with_event_handler(move |event| {
+            handler_seen.lock().unwrap().push(event.clone());
+        });
+
+        let mut events = Vec::new();
+        router
+            .hand_off("billing", Some("invoice question".to_string()), &mut events)
+            .unwrap();
+        assert_eq!(router.active(), Some("billing"));
+        assert_eq!(
+            events,
+            vec![RouterEvent::Handoff {
+                from: "triage".to_string(),
+                to: "billing".to_string(),
+                reason: Some("invoice question".to_string()),
+            }]
+        );
+        assert_eq!(*seen.lock().unwrap(), events);
+
+        // Handing off to the active agent does nothing
+        router.hand_off("billing", None, &mut events).unwrap();
+        assert_eq!(events.len(), 1);
+
+        assert!(router.hand_off("shipping", None, &mut events).is_err());
+    }
+
+    #[tokio::test]
+    // Verify that running a router without agents is an error
+    async fn test_run_without_agents() {
+        assert!(Router::new().run("hello").await.is_err());
+    }
+}
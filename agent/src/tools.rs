@@ -0,0 +1,248 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing the set of tools available to an agent.
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use ryst_error::InvalidArgumentError;
+use ryst_openai::{OpenAIError, Tool, ToolCall};
+use serde_json::Value;
+
+use crate::function::ToolFunction;
+
+type ToolFuture = Pin<Box<dyn Future<Output = Result<String, OpenAIError>> + Send>>;
+type Handler = dyn Fn(Value) -> ToolFuture + Send + Sync;
+
+/// The tools an agent may call, each with the handler that runs it.
+#[derive(Clone, Default)]
+pub struct ToolSet {
+    tools: Vec<(Tool, Arc<Handler>)>,
+}
+
+impl ToolSet {
+    /// Create an empty `ToolSet`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a tool, run by calling `handler` with the arguments the model provided.
+    ///
+    /// The handler returns the text sent back to the model. A tool with the same name as one
+    /// already in the set replaces it.
+    pub fn with_tool<F, Fut>(mut self, tool: Tool, handler: F) -> Self
+    where
+        F: Fn(Value) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<String, OpenAIError>> + Send + 'static,
+    {
+        let handler: Arc<Handler> = Arc::new(move |args| Box::pin(handler(args)));
+        self.tools
+            .retain(|(existing, _)| existing.function.name != tool.function.name);
+        self.tools.push((tool, handler));
+        self
+    }
+
+    /// Add a typed tool, run by calling `handler` with the arguments deserialized into `T`.
+    ///
+    /// Arguments which do not match `T` are reported back to the model without calling the
+    /// handler.
+    pub fn with_function<T, F, Fut>(self, handler: F) -> Self
+    where
+        T: ToolFunction,
+        F: Fn(T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<String, OpenAIError>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        self.with_tool(T::tool(), move |args| {
+            let handler = handler.clone();
+            async move {
+                let args = serde_json::from_value::<T>(args).map_err(|err| {
+                    OpenAIError::InvalidArgument(InvalidArgumentError::new(
+                        "arguments",
+                        format!("Invalid arguments for tool {}: {}", T::NAME, err),
+                    ))
+                })?;
+                handler(args).await
+            }
+        })
+    }
+
+    /// Returns the definitions of the tools, to send with a request.
+    pub fn definitions(&self) -> Vec<Tool> {
+        self.tools.iter().map(|(tool, _)| tool.clone()).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tools.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    /// Run the tool the model called, returning the text to send back to the model.
+    ///
+    /// Unknown tools, invalid arguments and errors returned by the handler are described in the
+    /// returned text, so that the model can correct itself rather than the agent failing.
+    /// Arguments are checked against the tool's schema before the handler is called.
+    pub async fn call(&self, call: &ToolCall) -> String {
+        let Some((tool, handler)) = self
+            .tools
+            .iter()
+            .find(|(tool, _)| tool.function.name == call.function.name)
+        else {
+            return format!("Error: there is no tool named {}", call.function.name);
+        };
+
+        let args = match call.validated_arguments(tool) {
+            Ok(args) => args,
+            Err(err) => return format!("Error: {err}"),
+        };
+
+        match handler(args).await {
+            Ok(output) => output,
+            Err(err) => format!("Error: {err}"),
+        }
+    }
+}
+
+impl fmt::Debug for ToolSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.tools.iter().map(|(tool, _)| &tool.function.name))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ryst_openai::FunctionCall;
+    use serde_json::json;
+
+    fn call(name: &str, arguments: &str) -> ToolCall {
+        ToolCall {
+            id: "call_1".to_string(),
+            kind: "function".to_string(),
+            function: FunctionCall {
+                name: name.to_string(),
+                arguments: arguments.to_string(),
+            },
+        }
+    }
+
+    fn tools() -> ToolSet {
+        ToolSet::new().with_tool(
+            Tool::function("add", "Add two numbers", json!({"type": "object"})),
+            |args| async move {
+                match (args["a"].as_i64(), args["b"].as_i64()) {
+                    (Some(a), Some(b)) => Ok((a + b).to_string()),
+                    _ => Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
+                        "a",
+                        "a and b must be integers",
+                    ))),
+                }
+            },
+        )
+    }
+
+    #[tokio::test]
+    // Verify that a tool call runs the matching handler
+    async fn test_call() {
+        assert_eq!(tools().call(&call("add", r#"{"a": 2, "b": 3}"#)).await, "5");
+    }
+
+    #[tokio::test]
+    // Verify that failures are reported as text for the model
+    async fn test_call_errors() {
+        let tools = tools();
+        assert!(tools
+            .call(&call("subtract", "{}"))
+            .await
+            .starts_with("Error: there is no tool named subtract"));
+        assert!(tools.call(&call("add", "{")).await.starts_with("Error:"));
+        assert!(tools
+            .call(&call("add", r#"{"a": "x"}"#))
+            .await
+            .contains("a and b must be integers"));
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Double {
+        value: i64,
+    }
+
+    impl ToolFunction for Double {
+        const NAME: &'static str = "double";
+        const DESCRIPTION: &'static str = "Double a number";
+
+        fn parameters() -> Value {
+            json!({"type": "object", "properties": {"value": {"type": "integer"}}})
+        }
+    }
+
+    #[tokio::test]
+    // Verify that a typed tool receives its deserialized arguments
+    async fn test_with_function() {
+        let tools = ToolSet::new()
+            .with_function(|args: Double| async move { Ok((args.value * 2).to_string()) });
+
+        assert_eq!(tools.definitions()[0].function.name, "double");
+        assert_eq!(tools.call(&call("double", r#"{"value": 21}"#)).await, "42");
+        assert!(tools
+            .call(&call("double", r#"{"value": "x"}"#))
+            .await
+            .contains("Invalid arguments for tool double"));
+    }
+
+    #[tokio::test]
+    // Verify that arguments which do not match the schema never reach the handler
+    async fn test_call_validates_arguments() {
+        let tools = ToolSet::new().with_tool(
+            Tool::function(
+                "greet",
+                "Greet someone",
+                json!({
+                    "type": "object",
+                    "properties": {"name": {"type": "string"}},
+                    "required": ["name"]
+                }),
+            ),
+            |_| async { panic!("handler called with invalid arguments") },
+        );
+
+        assert_eq!(
+            tools.call(&call("greet", r#"{"name": 1}"#)).await,
+            "Error: Invalid arguments for tool greet: name: expected string, found integer"
+        );
+    }
+
+    #[test]
+    // Verify that adding a tool with an existing name replaces it
+    fn test_with_tool_replaces() {
+        let tools = tools().with_tool(
+            Tool::function("add", "Add numbers", json!({"type": "object"})),
+            |_| async { Ok(String::new()) },
+        );
+        assert_eq!(tools.len(), 1);
+        assert_eq!(
+            tools.definitions()[0].function.description.as_deref(),
+            Some("Add numbers")
+        );
+    }
+}
@@ -0,0 +1,51 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The `completions` and `man` subcommands, which generate shell completions and manual pages
+//! from the command line definition so packages can install them.
+
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use clap::{Args, Command};
+use clap_complete::Shell;
+
+use crate::error::CliError;
+
+#[derive(Debug, Args)]
+pub struct CompletionsArgs {
+    /// The shell to generate completions for
+    shell: Shell,
+}
+
+#[derive(Debug, Args)]
+pub struct ManArgs {
+    /// The directory the pages are written to, one for `ryst` and one for each subcommand
+    #[arg(long, default_value = ".")]
+    out_dir: PathBuf,
+}
+
+/// Write the completion script for the shell to stdout.
+pub fn completions(args: CompletionsArgs, mut command: Command) -> Result<(), CliError> {
+    // Generated into a buffer first, since the generator panics on write errors such as a
+    // closed pipe
+    let mut script = Vec::new();
+    clap_complete::generate(args.shell, &mut command, "ryst", &mut script);
+    Ok(io::stdout().write_all(&script)?)
+}
+
+/// Write the manual pages to the output directory.
+pub fn man(args: ManArgs, command: Command) -> Result<(), CliError> {
+    Ok(clap_mangen::generate_to(command, &args.out_dir)?)
+}
@@ -0,0 +1,143 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing the errors the commands fail with and the exit codes they map to.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use ryst_openai::OpenAIError;
+
+/// Exit code for any failure without a more specific code.
+const EXIT_FAILURE: u8 = 1;
+/// Exit code for an invalid request, such as a header the API client rejects (`EX_USAGE`).
+const EXIT_USAGE: u8 = 64;
+/// Exit code for input which cannot be parsed (`EX_DATAERR`).
+const EXIT_DATA: u8 = 65;
+/// Exit code for a request the API did not answer (`EX_UNAVAILABLE`).
+const EXIT_UNAVAILABLE: u8 = 69;
+/// Exit code for a failure reading or writing a file or stream (`EX_IOERR`).
+const EXIT_IO: u8 = 74;
+/// Exit code for a failure which may succeed when run again, such as a rate limit
+/// (`EX_TEMPFAIL`).
+const EXIT_TEMPORARY: u8 = 75;
+
+/// An error a command fails with.
+#[derive(Debug)]
+pub enum CliError {
+    /// The input could not be parsed
+    InvalidInput(String),
+    /// Reading or writing a file or stream failed
+    Io(io::Error),
+    /// A request to the API failed
+    Api(OpenAIError),
+    /// A git command failed or found nothing to work on
+    Git(String),
+    /// Some records of a run failed and were left for the next run to retry
+    RecordsFailed(usize),
+}
+
+impl CliError {
+    /// The code the process exits with, following the conventions of `sysexits.h`.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            CliError::InvalidInput(_) => EXIT_DATA,
+            CliError::Io(_) => EXIT_IO,
+            CliError::Api(OpenAIError::InvalidArgument(_)) => EXIT_USAGE,
+            CliError::Api(OpenAIError::RateLimited { .. })
+            | CliError::Api(OpenAIError::StreamInterrupted { .. }) => EXIT_TEMPORARY,
+            CliError::Api(OpenAIError::Internal(_)) => EXIT_UNAVAILABLE,
+            CliError::Api(_) | CliError::Git(_) => EXIT_FAILURE,
+            CliError::RecordsFailed(_) => EXIT_TEMPORARY,
+        }
+    }
+
+    /// Whether the error is a closed pipe, such as after `ryst --raw | head -1`, which is not
+    /// worth reporting.
+    pub fn is_broken_pipe(&self) -> bool {
+        matches!(self, CliError::Io(err) if err.kind() == io::ErrorKind::BrokenPipe)
+    }
+
+    /// The ID OpenAI assigned the failed request, to quote when contacting support.
+    pub fn request_id(&self) -> Option<&str> {
+        match self {
+            CliError::Api(err) => err.request_id(),
+            _ => None,
+        }
+    }
+}
+
+impl Error for CliError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CliError::Io(err) => Some(err),
+            CliError::Api(err) => Some(err),
+            CliError::InvalidInput(_) | CliError::Git(_) | CliError::RecordsFailed(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::InvalidInput(message) => write!(f, "invalid input: {message}"),
+            CliError::Io(err) => err.fmt(f),
+            CliError::Api(err) => err.fmt(f),
+            CliError::Git(message) => write!(f, "git: {message}"),
+            CliError::RecordsFailed(count) => {
+                write!(f, "{count} records failed, run again to retry them")
+            }
+        }
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(err: io::Error) -> Self {
+        CliError::Io(err)
+    }
+}
+
+impl From<OpenAIError> for CliError {
+    fn from(err: OpenAIError) -> Self {
+        CliError::Api(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ryst_error::InvalidArgumentError;
+
+    #[test]
+    // Verify that errors map to distinct exit codes, with retryable failures as EX_TEMPFAIL
+    fn test_exit_code() {
+        let rate_limited = OpenAIError::RateLimited {
+            message: "slow down".to_string(),
+            retry_after: None,
+            request_id: None,
+        };
+        let invalid = OpenAIError::InvalidArgument(InvalidArgumentError::new("header", "bad"));
+
+        assert_eq!(CliError::from(rate_limited).exit_code(), 75);
+        assert_eq!(CliError::from(invalid).exit_code(), 64);
+        assert_eq!(CliError::InvalidInput("line 1".to_string()).exit_code(), 65);
+        assert_eq!(CliError::RecordsFailed(2).exit_code(), 75);
+
+        let broken_pipe = CliError::from(io::Error::from(io::ErrorKind::BrokenPipe));
+        assert_eq!(broken_pipe.exit_code(), 74);
+        assert!(broken_pipe.is_broken_pipe());
+    }
+}
@@ -0,0 +1,254 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The `git` subcommands, which write text about a repository's changes, such as commit
+//! messages for the staged diff.
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+use clap::{Args, Subcommand};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use ryst_openai::retry::RateLimitRetry;
+use ryst_openai::{tokens, ChatCompletionRequest, Message, OpenAIError};
+use serde_json::{Map, Value};
+
+use crate::error::CliError;
+use crate::profile;
+use crate::template::render;
+
+/// The template a commit message is written from, with the staged changes as `{{changes}}`.
+const COMMIT_TEMPLATE: &str = "Write a commit message in the Conventional Commits format for \
+the changes below. Start with a subject line of at most 72 characters, such as \
+`feat(parser): support nested lists`, then a blank line and a short body explaining what \
+changed and why. Reply with the commit message only.\n\n{{changes}}";
+
+/// The prompt each chunk of a diff too large for one request is summarized with.
+const SUMMARY_PROMPT: &str = "Summarize the changes in this part of a diff as short bullet \
+points, naming the files and what changed in each. Reply with the bullet points only.";
+
+/// The tokens of diff sent in one request when the model's context window is unknown.
+const DEFAULT_CHUNK_TOKENS: usize = 8_000;
+
+#[derive(Debug, Args)]
+pub struct GitArgs {
+    #[command(subcommand)]
+    command: GitCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum GitCommand {
+    /// Write a Conventional Commits message for the staged changes to stdout
+    CommitMsg(CommitMsgArgs),
+}
+
+#[derive(Debug, Args)]
+struct CommitMsgArgs {
+    /// A file with the template of the prompt, referring to the staged changes as `{{changes}}`
+    #[arg(long)]
+    template: Option<PathBuf>,
+    /// The model the message is written by, by default the profile's
+    #[arg(long)]
+    model: Option<String>,
+    /// The most tokens of diff sent in one request, by default half the model's context window.
+    /// Larger diffs are summarized in chunks first.
+    #[arg(long)]
+    chunk_tokens: Option<usize>,
+    /// The most chunks of a large diff summarized at once
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+    concurrency: u16,
+    /// How many times a rate limited request is retried
+    #[arg(long, default_value_t = 3)]
+    retries: u32,
+}
+
+pub async fn git(args: GitArgs) -> Result<(), CliError> {
+    match args.command {
+        GitCommand::CommitMsg(args) => commit_msg(args).await,
+    }
+}
+
+/// Write a commit message for the staged diff, summarizing the diff in chunks first if it does
+/// not fit in one request.
+async fn commit_msg(args: CommitMsgArgs) -> Result<(), CliError> {
+    let template = match &args.template {
+        Some(path) => fs::read_to_string(path)?,
+        None => COMMIT_TEMPLATE.to_string(),
+    };
+
+    let diff = staged_diff()?;
+    if diff.trim().is_empty() {
+        return Err(CliError::Git("no changes are staged".to_string()));
+    }
+
+    let model = profile::resolve_model(args.model.clone())?;
+    let budget = args.chunk_tokens.unwrap_or_else(|| {
+        tokens::context_window(&model).map_or(DEFAULT_CHUNK_TOKENS, |window| window / 2)
+    });
+    let count = |text: &str| tokens::count_tokens(&model, text).unwrap_or(text.len() / 4);
+    let chunks = chunk_diff(&diff, budget, count);
+
+    let changes = match chunks.as_slice() {
+        [diff] => diff.clone(),
+        chunks => {
+            // Summaries are kept in the order of their chunks, so the changes read as the diff does
+            let summaries: Vec<_> = stream::iter(chunks)
+                .map(|chunk| {
+                    let messages = [
+                        Message::new("system", SUMMARY_PROMPT),
+                        Message::new("user", chunk),
+                    ];
+                    reply(&model, args.retries, messages)
+                })
+                .buffered(usize::from(args.concurrency))
+                .try_collect()
+                .await?;
+            summaries.join("\n")
+        }
+    };
+
+    let values = Map::from_iter([("changes".to_string(), Value::String(changes))]);
+    let prompt = render(&template, &values)?;
+    let message = reply(&model, args.retries, [Message::new("user", &prompt)]).await?;
+    println!("{}", message.trim());
+    Ok(())
+}
+
+/// Returns the diff of the changes staged in the repository of the current directory.
+fn staged_diff() -> Result<String, CliError> {
+    // Outside a repository `git diff` falls back to comparing paths, so check for one first
+    run_git(&["rev-parse", "--git-dir"])?;
+    run_git(&["diff", "--cached", "--no-color", "--no-ext-diff"])
+}
+
+/// Run git with the arguments, returning its output or its error message if it fails.
+fn run_git(args: &[&str]) -> Result<String, CliError> {
+    let output = Command::new("git").args(args).output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(CliError::Git(stderr.trim().to_string()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+async fn reply<const N: usize>(
+    model: &str,
+    retries: u32,
+    messages: [Message; N],
+) -> Result<String, OpenAIError> {
+    let response = ChatCompletionRequest::new(model, &messages)
+        .with_rate_limit_retry(RateLimitRetry::new(retries))
+        .submit()
+        .await?;
+
+    Ok(response
+        .choices
+        .first()
+        .and_then(|choice| choice.message.content().as_text())
+        .unwrap_or_default()
+        .to_string())
+}
+
+/// Split a diff into chunks of at most `budget` tokens, keeping each file's diff whole when it
+/// fits and splitting it between lines otherwise.
+fn chunk_diff<F: Fn(&str) -> usize>(diff: &str, budget: usize, count: F) -> Vec<String> {
+    let pieces = file_diffs(diff).into_iter().flat_map(|file| {
+        if count(file) <= budget {
+            vec![file]
+        } else {
+            file.split_inclusive('\n').collect()
+        }
+    });
+
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+    let mut chunk_tokens = 0;
+    for piece in pieces {
+        let tokens = count(piece);
+        if !chunk.is_empty() && chunk_tokens + tokens > budget {
+            chunks.push(std::mem::take(&mut chunk));
+            chunk_tokens = 0;
+        }
+        chunk.push_str(piece);
+        chunk_tokens += tokens;
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+/// Split a diff into the diffs of each file, which start at a `diff --git` line.
+fn file_diffs(diff: &str) -> Vec<&str> {
+    let mut starts: Vec<usize> = diff
+        .match_indices("\ndiff --git ")
+        .map(|(index, _)| index + 1)
+        .collect();
+    starts.insert(0, 0);
+    starts.push(diff.len());
+    starts
+        .windows(2)
+        .map(|window| &diff[window[0]..window[1]])
+        .filter(|file| !file.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIFF: &str = "\
+diff --git a/a.rs b/a.rs
++one
++two
+diff --git a/b.rs b/b.rs
++three
+";
+
+    #[test]
+    // Verify that a diff is split at the start of each file's diff
+    fn test_file_diffs() {
+        assert_eq!(
+            file_diffs(DIFF),
+            vec![
+                "diff --git a/a.rs b/a.rs\n+one\n+two\n",
+                "diff --git a/b.rs b/b.rs\n+three\n"
+            ]
+        );
+    }
+
+    #[test]
+    // Verify that files are packed into chunks within the budget, splitting files too large
+    fn test_chunk_diff() {
+        let lines = |text: &str| text.lines().count();
+
+        assert_eq!(chunk_diff(DIFF, 5, lines), vec![DIFF.to_string()]);
+        assert_eq!(
+            chunk_diff(DIFF, 3, lines),
+            vec![
+                "diff --git a/a.rs b/a.rs\n+one\n+two\n",
+                "diff --git a/b.rs b/b.rs\n+three\n"
+            ]
+        );
+        assert_eq!(
+            chunk_diff(DIFF, 2, lines),
+            vec![
+                "diff --git a/a.rs b/a.rs\n+one\n",
+                "+two\n",
+                "diff --git a/b.rs b/b.rs\n+three\n"
+            ]
+        );
+    }
+}
@@ -1,3 +1,95 @@
-fn main() {
-    println!("Hello, world!");
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Command line interface to ryst.
+
+mod completions;
+mod error;
+mod git;
+mod models;
+mod profile;
+mod raw;
+mod run;
+mod template;
+
+use std::process::ExitCode;
+
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
+
+#[derive(Debug, Parser)]
+#[command(name = "ryst", version, about, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    raw: raw::RawArgs,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Send each record of a JSONL file through a chat template and write the replies as JSONL
+    Run(run::RunArgs),
+    /// List the available models and their context windows, or pick the profile's default
+    Models(models::ModelsArgs),
+    /// Write text about a git repository's changes, such as commit messages
+    Git(git::GitArgs),
+    /// Print the completion script for a shell
+    Completions(completions::CompletionsArgs),
+    /// Write the manual pages
+    Man(completions::ManArgs),
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Some(Command::Run(args)) => run::run(args).await,
+        Some(Command::Models(args)) => models::models(args).await,
+        Some(Command::Git(args)) => git::git(args).await,
+        Some(Command::Completions(args)) => completions::completions(args, Cli::command()),
+        Some(Command::Man(args)) => completions::man(args, Cli::command()),
+        None if cli.raw.raw => raw::raw(cli.raw).await,
+        None => Cli::command()
+            .error(
+                ErrorKind::MissingSubcommand,
+                "a subcommand or --raw is required",
+            )
+            .exit(),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            if !err.is_broken_pipe() {
+                eprintln!("error: {err}");
+                if let Some(request_id) = err.request_id() {
+                    eprintln!("request ID: {request_id}");
+                }
+            }
+            ExitCode::from(err.exit_code())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Verify that the command line definition is consistent, as completions and pages rely on it
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+    }
 }
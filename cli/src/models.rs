@@ -0,0 +1,102 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The `models` subcommand, which lists the models available to the API key and can save one
+//! as the profile's default.
+
+use std::fmt;
+
+use clap::Args;
+use inquire::{InquireError, Select};
+use ryst_openai::models::{ListModels, Model};
+
+use crate::error::CliError;
+use crate::profile::{self, Config};
+
+#[derive(Debug, Args)]
+pub struct ModelsArgs {
+    /// Choose a model interactively and save it as the profile's default model
+    #[arg(long)]
+    pick: bool,
+    /// The profile the chosen model is saved to, by default `RYST_PROFILE` or `default`
+    #[arg(long, requires = "pick")]
+    profile: Option<String>,
+}
+
+/// A model as shown in the picker, with its context window.
+struct Choice(Model);
+
+impl fmt::Display for Choice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0.context_window() {
+            Some(window) => write!(f, "{} ({window} tokens)", self.0.id),
+            None => f.write_str(&self.0.id),
+        }
+    }
+}
+
+/// List the models with their context windows, or pick one and save it to the profile.
+pub async fn models(args: ModelsArgs) -> Result<(), CliError> {
+    let models = ListModels::new().send().await?;
+    if !args.pick {
+        print_models(&models);
+        return Ok(());
+    }
+
+    let mut config = Config::load()?;
+    let profile = args.profile.unwrap_or_else(profile::profile_name);
+    let current = config.model(&profile).map(str::to_string);
+    let start = models
+        .iter()
+        .position(|model| Some(&model.id) == current.as_ref())
+        .unwrap_or(0);
+
+    let choices = models.into_iter().map(Choice).collect();
+    let chosen = match Select::new("Default model:", choices)
+        .with_starting_cursor(start)
+        .prompt()
+    {
+        Ok(Choice(model)) => model,
+        Err(InquireError::OperationCanceled | InquireError::OperationInterrupted) => {
+            eprintln!("No model chosen, the profile is unchanged");
+            return Ok(());
+        }
+        Err(InquireError::NotTTY) => {
+            return Err(CliError::InvalidInput(
+                "--pick needs an interactive terminal".to_string(),
+            ))
+        }
+        Err(InquireError::IO(err)) => return Err(err.into()),
+        Err(err) => return Err(CliError::InvalidInput(err.to_string())),
+    };
+
+    config.profiles.entry(profile.clone()).or_default().model = Some(chosen.id.clone());
+    let path = config.save()?;
+    eprintln!(
+        "Saved {} as the default model of profile {profile} in {}",
+        chosen.id,
+        path.display()
+    );
+    Ok(())
+}
+
+fn print_models(models: &[Model]) {
+    let width = models.iter().map(|model| model.id.len()).max().unwrap_or(0);
+    for model in models {
+        match model.context_window() {
+            Some(window) => println!("{:width$}  {window}", model.id),
+            None => println!("{:width$}  -", model.id),
+        }
+    }
+}
@@ -0,0 +1,150 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing the configuration file, which keeps named profiles of defaults such as the
+//! model commands use.
+//!
+//! The file is read from `RYST_CONFIG` if set, otherwise from `ryst/config.toml` in
+//! `XDG_CONFIG_HOME` or `~/.config`. Commands use the profile named by `RYST_PROFILE`, or the
+//! `default` profile.
+//!
+//! ```toml
+//! [profiles.default]
+//! model = "gpt-4o-mini"
+//!
+//! [profiles.review]
+//! model = "gpt-4o"
+//! ```
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::CliError;
+
+/// The model used when neither the command line nor the profile sets one.
+const DEFAULT_MODEL: &str = "gpt-4o-mini";
+
+/// The profile used when `RYST_PROFILE` is not set.
+const DEFAULT_PROFILE: &str = "default";
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// Defaults for commands, used when they are not given on the command line.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+}
+
+impl Config {
+    /// Read the configuration file, or an empty configuration if there is none.
+    pub fn load() -> Result<Self, CliError> {
+        let Some(path) = path() else {
+            return Ok(Self::default());
+        };
+        match fs::read_to_string(&path) {
+            Ok(text) => Self::parse(&text, &path),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Write the configuration file, creating its directory if needed, and return its path.
+    pub fn save(&self) -> Result<PathBuf, CliError> {
+        let path = path().ok_or_else(|| {
+            CliError::InvalidInput("set RYST_CONFIG or HOME to save the configuration".to_string())
+        })?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let text = toml::to_string(self)
+            .map_err(|err| CliError::InvalidInput(format!("{}: {err}", path.display())))?;
+        fs::write(&path, text)?;
+        Ok(path)
+    }
+
+    fn parse(text: &str, path: &Path) -> Result<Self, CliError> {
+        toml::from_str(text)
+            .map_err(|err| CliError::InvalidInput(format!("{}: {err}", path.display())))
+    }
+
+    /// Returns the model the profile sets, if any.
+    pub fn model(&self, profile: &str) -> Option<&str> {
+        self.profiles.get(profile)?.model.as_deref()
+    }
+}
+
+/// Returns the name of the profile commands use.
+pub fn profile_name() -> String {
+    env::var("RYST_PROFILE").unwrap_or_else(|_| DEFAULT_PROFILE.to_string())
+}
+
+/// Returns the model given on the command line, or else the profile's, or else the default.
+pub fn resolve_model(model: Option<String>) -> Result<String, CliError> {
+    if let Some(model) = model {
+        return Ok(model);
+    }
+    Ok(Config::load()?
+        .model(&profile_name())
+        .unwrap_or(DEFAULT_MODEL)
+        .to_string())
+}
+
+fn path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("RYST_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_dir.join("ryst").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Verify that profiles are read and written back, and unknown settings are rejected
+    fn test_config_round_trip() {
+        let path = Path::new("config.toml");
+        let config = Config::parse(
+            "[profiles.default]\nmodel = \"gpt-4o\"\n\n[profiles.empty]\n",
+            path,
+        )
+        .unwrap();
+
+        assert_eq!(config.model("default"), Some("gpt-4o"));
+        assert_eq!(config.model("empty"), None);
+        assert_eq!(config.model("missing"), None);
+        assert_eq!(
+            Config::parse(&toml::to_string(&config).unwrap(), path).unwrap(),
+            config
+        );
+
+        assert!(Config::parse("[profiles.default]\nmodle = \"gpt-4o\"\n", path).is_err());
+    }
+}
@@ -0,0 +1,94 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The `--raw` mode, which reads a prompt from stdin and writes only the reply to stdout so the
+//! command composes in shell pipelines.
+
+use std::io::{self, IsTerminal, Read, Write};
+
+use clap::Args;
+use ryst_openai::retry::RateLimitRetry;
+use ryst_openai::{ChatCompletionRequest, Message};
+
+use crate::error::CliError;
+use crate::profile;
+
+#[derive(Debug, Args)]
+pub struct RawArgs {
+    /// Read the prompt from stdin and write only the reply to stdout, reporting errors on stderr
+    #[arg(long)]
+    pub raw: bool,
+    /// The model the prompt is sent to, by default the profile's
+    #[arg(long, requires = "raw")]
+    model: Option<String>,
+    /// An optional system message
+    #[arg(long, requires = "raw")]
+    system: Option<String>,
+    /// The sampling temperature, from 0 to 2
+    #[arg(long, requires = "raw")]
+    temperature: Option<f32>,
+    /// The most tokens generated for the reply
+    #[arg(long, requires = "raw")]
+    max_tokens: Option<i32>,
+    /// How many times a rate limited request is retried
+    #[arg(long, requires = "raw", default_value_t = 3)]
+    retries: u32,
+}
+
+/// Send the prompt read from stdin and write the reply to stdout as it arrives.
+pub async fn raw(args: RawArgs) -> Result<(), CliError> {
+    let mut prompt = String::new();
+    io::stdin().read_to_string(&mut prompt)?;
+    let prompt = prompt.trim_end();
+    if prompt.is_empty() {
+        return Err(CliError::InvalidInput("no prompt on stdin".to_string()));
+    }
+
+    let mut messages = Vec::new();
+    if let Some(system) = &args.system {
+        messages.push(Message::new("system", system));
+    }
+    messages.push(Message::new("user", prompt));
+
+    let model = profile::resolve_model(args.model)?;
+    let mut request = ChatCompletionRequest::new(&model, &messages)
+        .with_rate_limit_retry(RateLimitRetry::new(args.retries));
+    if let Some(temperature) = args.temperature {
+        request = request.with_temperature(temperature);
+    }
+    if let Some(max_tokens) = args.max_tokens {
+        request = request.with_max_tokens(max_tokens);
+    }
+
+    let mut stream = request.stream().await?;
+    let mut stdout = io::stdout();
+    let mut ends_with_newline = true;
+    while let Some(response) = stream.next().await? {
+        let text = response
+            .choices
+            .first()
+            .and_then(|choice| choice.message.content().as_text());
+        if let Some(text) = text.filter(|text| !text.is_empty()) {
+            stdout.write_all(text.as_bytes())?;
+            stdout.flush()?;
+            ends_with_newline = text.ends_with('\n');
+        }
+    }
+
+    // Only a terminal gets a final newline, so piped output is exactly the reply
+    if !ends_with_newline && stdout.is_terminal() {
+        writeln!(stdout)?;
+    }
+    Ok(())
+}
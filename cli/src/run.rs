@@ -0,0 +1,275 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The `run` subcommand, which maps each record of a JSONL file through a chat template.
+//!
+//! Replies are appended to the output file as they complete, each tagged with the line of the
+//! input it answers. The output file doubles as the checkpoint: records already answered in it
+//! are skipped, so an interrupted or partly failed run is finished by running it again.
+
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::Args;
+use futures::stream::{self, StreamExt};
+use indicatif::{ProgressBar, ProgressStyle};
+use ryst_error::InternalError;
+use ryst_openai::rate_limit::RateLimiter;
+use ryst_openai::retry::RateLimitRetry;
+use ryst_openai::{ChatCompletionRequest, Message, OpenAIError};
+use serde_json::{json, Map, Value};
+
+use crate::error::CliError;
+use crate::profile;
+use crate::template::render;
+
+/// The wait before the first retry of a failed request, doubled for each further retry.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// A record of the input and the line it was read from, numbered from 1.
+type Record = (usize, Map<String, Value>);
+
+#[derive(Debug, Args)]
+pub struct RunArgs {
+    /// The JSONL file of records to send, one JSON object per line
+    #[arg(long)]
+    input: PathBuf,
+    /// The JSONL file replies are appended to, skipping records it already answers
+    #[arg(long)]
+    output: PathBuf,
+    /// The template of the user message, referring to the fields of a record as `{{name}}`
+    #[arg(long, default_value = "{{prompt}}")]
+    template: String,
+    /// The template of an optional system message
+    #[arg(long)]
+    system: Option<String>,
+    /// The model the records are sent to, by default the profile's
+    #[arg(long)]
+    model: Option<String>,
+    /// The sampling temperature, from 0 to 2
+    #[arg(long)]
+    temperature: Option<f32>,
+    /// The most tokens generated for each reply
+    #[arg(long)]
+    max_tokens: Option<i32>,
+    /// The most requests sent at once
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+    concurrency: u16,
+    /// How many times a failed request is retried
+    #[arg(long, default_value_t = 3)]
+    retries: u32,
+    /// The most requests sent per minute, to stay within the account's rate limit
+    #[arg(long)]
+    requests_per_minute: Option<u32>,
+    /// The most tokens sent per minute, estimated from the prompt and --max-tokens
+    #[arg(long)]
+    tokens_per_minute: Option<u32>,
+}
+
+/// Send the records of the input which the output does not answer yet, appending the replies.
+///
+/// Returns an error after the run if any record failed, so running again retries them.
+pub async fn run(args: RunArgs) -> Result<(), CliError> {
+    let model = profile::resolve_model(args.model.clone())?;
+    let records = parse_records(&fs::read_to_string(&args.input)?)?;
+    let existing = match fs::read_to_string(&args.output) {
+        Ok(output) => output,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err.into()),
+    };
+    let answered = answered_lines(&existing);
+    let pending: Vec<_> = records
+        .into_iter()
+        .filter(|(line, _)| !answered.contains(line))
+        .collect();
+
+    let mut output = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&args.output)?;
+    // A run killed mid-write may leave a partial last line, which the next reply must not join
+    if !existing.is_empty() && !existing.ends_with('\n') {
+        writeln!(output)?;
+    }
+
+    let progress = ProgressBar::new(pending.len() as u64).with_style(
+        ProgressStyle::with_template("{bar:40} {pos}/{len} [{elapsed_precise}<{eta_precise}]")
+            .expect("the progress template is valid"),
+    );
+
+    let limiter = rate_limiter(&args)?;
+    let mut replies = stream::iter(pending)
+        .map(|(line, record)| {
+            let (args, model, limiter) = (&args, &model, limiter.as_ref());
+            async move {
+                let reply = complete(args, model, limiter, &record)
+                    .await
+                    .map_err(|err| err.to_string());
+                (line, record, reply)
+            }
+        })
+        .buffer_unordered(usize::from(args.concurrency));
+
+    let mut failed = 0;
+    while let Some((line, record, reply)) = replies.next().await {
+        match reply {
+            Ok(reply) => {
+                let result = json!({"line": line, "input": record, "output": reply});
+                writeln!(output, "{result}")?;
+                output.flush()?;
+            }
+            Err(err) => {
+                failed += 1;
+                progress.println(format!("line {line}: {err}"));
+            }
+        }
+        progress.inc(1);
+    }
+    progress.finish();
+
+    if failed > 0 {
+        return Err(CliError::RecordsFailed(failed));
+    }
+    Ok(())
+}
+
+/// The limiter shared by every request of the run, if a limit is set.
+fn rate_limiter(args: &RunArgs) -> Result<Option<RateLimiter>, CliError> {
+    if args.requests_per_minute.is_none() && args.tokens_per_minute.is_none() {
+        return Ok(None);
+    }
+
+    let mut limiter = RateLimiter::new();
+    if let Some(requests) = args.requests_per_minute {
+        limiter = limiter.with_requests_per_minute(requests)?;
+    }
+    if let Some(tokens) = args.tokens_per_minute {
+        limiter = limiter.with_tokens_per_minute(tokens)?;
+    }
+    Ok(Some(limiter))
+}
+
+/// Send the record through the templates, retrying failures with exponential backoff.
+async fn complete(
+    args: &RunArgs,
+    model: &str,
+    limiter: Option<&RateLimiter>,
+    record: &Map<String, Value>,
+) -> Result<String, OpenAIError> {
+    let mut messages = Vec::new();
+    if let Some(system) = &args.system {
+        messages.push(Message::new("system", &render(system, record)?));
+    }
+    messages.push(Message::new("user", &render(&args.template, record)?));
+
+    let mut retries = 0;
+    loop {
+        let mut request = ChatCompletionRequest::new(model, &messages)
+            .with_rate_limit_retry(RateLimitRetry::new(args.retries));
+        if let Some(temperature) = args.temperature {
+            request = request.with_temperature(temperature);
+        }
+        if let Some(max_tokens) = args.max_tokens {
+            request = request.with_max_tokens(max_tokens);
+        }
+        if let Some(limiter) = limiter {
+            request = request.with_rate_limiter(limiter.clone());
+        }
+
+        let retry = match request.submit().await {
+            Ok(response) => {
+                return response
+                    .choices
+                    .first()
+                    .and_then(|choice| choice.message.content().as_text())
+                    .map(str::to_string)
+                    .ok_or_else(|| {
+                        OpenAIError::Internal(InternalError::with_message(
+                            "the response has no text reply",
+                        ))
+                    })
+            }
+            // Rate limits were already retried by the request's policy
+            Err(err @ (OpenAIError::InvalidArgument(_) | OpenAIError::RateLimited { .. })) => {
+                return Err(err)
+            }
+            Err(err) if retries >= args.retries => return Err(err),
+            Err(_) => RETRY_DELAY * 2u32.saturating_pow(retries),
+        };
+        tokio::time::sleep(retry).await;
+        retries += 1;
+    }
+}
+
+/// Parse the JSON object on each non-empty line, numbering lines from 1.
+fn parse_records(input: &str) -> Result<Vec<Record>, CliError> {
+    input
+        .lines()
+        .enumerate()
+        .filter(|(_, text)| !text.trim().is_empty())
+        .map(|(index, text)| {
+            let line = index + 1;
+            match serde_json::from_str(text) {
+                Ok(Value::Object(record)) => Ok((line, record)),
+                Ok(_) => Err(invalid_input(line, "expected a JSON object")),
+                Err(err) => Err(invalid_input(line, &err.to_string())),
+            }
+        })
+        .collect()
+}
+
+/// Returns the input lines answered in the output, skipping lines which cannot be parsed.
+fn answered_lines(output: &str) -> HashSet<usize> {
+    output
+        .lines()
+        .filter_map(|text| serde_json::from_str::<Value>(text).ok())
+        .filter(|result| result.get("output").is_some())
+        .filter_map(|result| result.get("line")?.as_u64())
+        .map(|line| line as usize)
+        .collect()
+}
+
+fn invalid_input(line: usize, message: &str) -> CliError {
+    CliError::InvalidInput(format!("line {line}: {message}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Verify that records are numbered by their line, skipping blank lines and rejecting others
+    fn test_parse_records() {
+        let records = parse_records("{\"prompt\": \"a\"}\n\n{\"prompt\": \"b\"}\n").unwrap();
+        let lines: Vec<_> = records.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![1, 3]);
+
+        assert!(parse_records("{\"prompt\": \"a\"}\n[1, 2]\n").is_err());
+        assert!(parse_records("{\"prompt\": \n").is_err());
+    }
+
+    #[test]
+    // Verify that only replies which were written in full count as answered
+    fn test_answered_lines() {
+        let output = concat!(
+            "{\"line\": 1, \"input\": {}, \"output\": \"a\"}\n",
+            "{\"line\": 3, \"input\": {}}\n",
+            "{\"line\": 2, \"input\": {}, \"outp",
+        );
+        assert_eq!(answered_lines(output), HashSet::from([1]));
+    }
+}
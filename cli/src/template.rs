@@ -0,0 +1,56 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing the `{{name}}` templates prompts are rendered from.
+
+use ryst_error::InvalidArgumentError;
+use ryst_openai::{template, OpenAIError};
+use serde_json::{Map, Value};
+
+/// Replace the `{{name}}` placeholders in a template with the fields of the record.
+///
+/// Text fields are inserted as they are and other values as JSON. Returns an error if the
+/// record has no field a placeholder refers to.
+pub fn render(template: &str, record: &Map<String, Value>) -> Result<String, OpenAIError> {
+    if let Some(name) = template::placeholders(template).find(|name| !record.contains_key(*name)) {
+        return Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
+            "template",
+            format!("the record has no field `{name}`"),
+        )));
+    }
+    Ok(template::render(template, |name| record.get(name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    // Verify that placeholders are filled from the record and missing fields are rejected
+    fn test_render() {
+        let record = json!({"name": "Ada", "tags": ["math"]});
+        let record = record.as_object().unwrap();
+
+        assert_eq!(
+            render("Hello {{ name }}, {{tags}}", record).unwrap(),
+            r#"Hello Ada, ["math"]"#
+        );
+        assert!(matches!(
+            render("{{missing}}", record),
+            Err(OpenAIError::InvalidArgument(_))
+        ));
+    }
+}
@@ -0,0 +1,549 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing the generation of Rust types from the schemas of an OpenAPI spec.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use ryst_error::InvalidArgumentError;
+use serde_json::Value;
+
+const REF_PREFIX: &str = "#/components/schemas/";
+
+const HEADER: &str =
+    "// Generated by ryst-codegen from the OpenAI OpenAPI spec. Do not edit by hand.
+
+use serde::{Deserialize, Serialize};
+";
+
+// Keywords which can't be used as raw identifiers, and are suffixed with an underscore instead
+const RESERVED: &[&str] = &["crate", "self", "Self", "super"];
+
+const KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "do", "dyn",
+    "else", "enum", "extern", "false", "final", "fn", "for", "if", "impl", "in", "let", "loop",
+    "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return", "static",
+    "struct", "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use", "virtual",
+    "where", "while", "yield",
+];
+
+/// The names of the generated types and the references between them which must be boxed.
+struct Context {
+    /// The type name of each schema, by schema name
+    type_names: BTreeMap<String, String>,
+    /// The schemas each schema must box, by schema name
+    boxed: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl Context {
+    fn new(schemas: &BTreeMap<String, &Value>) -> Self {
+        // Schemas are named in order, so the first of the schemas whose names convert to the same
+        // type name keeps it
+        let mut used = BTreeSet::new();
+        let type_names = schemas
+            .keys()
+            .map(|name| (name.clone(), unique(type_name(name), &mut used, "")))
+            .collect();
+
+        Self {
+            type_names,
+            boxed: boxed_refs(schemas),
+        }
+    }
+
+    fn type_name(&self, name: &str) -> String {
+        self.type_names
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| type_name(name))
+    }
+
+    fn is_boxed(&self, from: &str, to: &str) -> bool {
+        self.boxed.get(from).is_some_and(|boxed| boxed.contains(to))
+    }
+}
+
+/// Generate the Rust types for the named schemas of the spec, and for the schemas they refer to.
+///
+/// All of the schemas are generated when no names are given. Types are generated in order of
+/// name so the output is stable across runs.
+pub fn generate(spec: &Value, names: &[String]) -> Result<String, InvalidArgumentError> {
+    let schemas = spec
+        .pointer("/components/schemas")
+        .and_then(Value::as_object)
+        .ok_or_else(|| InvalidArgumentError::new("spec", "The spec has no components.schemas"))?;
+
+    let mut pending = if names.is_empty() {
+        schemas.keys().cloned().collect()
+    } else {
+        names.to_vec()
+    };
+    let mut selected = BTreeMap::new();
+    while let Some(name) = pending.pop() {
+        if selected.contains_key(&name) {
+            continue;
+        }
+        let schema = schemas.get(&name).ok_or_else(|| {
+            InvalidArgumentError::new("schema", format!("The spec has no schema named {name}"))
+        })?;
+        collect_refs(schema, &mut pending);
+        selected.insert(name, schema);
+    }
+
+    let context = Context::new(&selected);
+    let mut out = HEADER.to_string();
+    for (name, schema) in &selected {
+        out.push('\n');
+        generate_schema(&mut out, &context, name, schema);
+    }
+    Ok(out)
+}
+
+/// Collect the names of the schemas referred to anywhere within the schema.
+fn collect_refs(schema: &Value, refs: &mut Vec<String>) {
+    match schema {
+        Value::Object(object) => {
+            if let Some(name) = ref_name(schema) {
+                refs.push(name.to_string());
+            }
+            object.values().for_each(|value| collect_refs(value, refs));
+        }
+        Value::Array(values) => values.iter().for_each(|value| collect_refs(value, refs)),
+        _ => (),
+    }
+}
+
+/// Returns the name of the schema a schema refers to.
+fn ref_name(schema: &Value) -> Option<&str> {
+    schema.get("$ref")?.as_str()?.strip_prefix(REF_PREFIX)
+}
+
+/// Returns the schemas whose types the schema's type holds by value, rather than in a `Vec`.
+fn value_refs(schema: &Value) -> Vec<&str> {
+    if string_enum(schema).is_some() {
+        return Vec::new();
+    }
+    match schema.get("properties").and_then(Value::as_object) {
+        Some(properties) => properties.values().filter_map(ref_name).collect(),
+        None => ref_name(schema).into_iter().collect(),
+    }
+}
+
+/// Find the references which must be boxed for the generated types to have a known size.
+///
+/// Types which hold each other by value in a cycle, such as `A` holding a `B` which holds an `A`,
+/// are the strongly connected components of the references between the types. Every reference
+/// within a component is boxed.
+fn boxed_refs(schemas: &BTreeMap<String, &Value>) -> BTreeMap<String, BTreeSet<String>> {
+    let refs = schemas
+        .iter()
+        .map(|(name, schema)| (name.as_str(), value_refs(schema)))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut components = Components {
+        refs: &refs,
+        index: BTreeMap::new(),
+        low: BTreeMap::new(),
+        stack: Vec::new(),
+        component: BTreeMap::new(),
+    };
+    for name in refs.keys() {
+        if !components.index.contains_key(name) {
+            components.visit(name);
+        }
+    }
+
+    let mut boxed = BTreeMap::<String, BTreeSet<String>>::new();
+    for (from, targets) in &refs {
+        for to in targets {
+            if components.component.get(from) == components.component.get(to) {
+                boxed
+                    .entry(from.to_string())
+                    .or_default()
+                    .insert(to.to_string());
+            }
+        }
+    }
+    boxed
+}
+
+/// Tarjan's algorithm for the strongly connected components of the references between schemas.
+struct Components<'a> {
+    refs: &'a BTreeMap<&'a str, Vec<&'a str>>,
+    /// The order in which each schema was visited
+    index: BTreeMap<&'a str, usize>,
+    /// The earliest visited schema reachable from each schema
+    low: BTreeMap<&'a str, usize>,
+    /// The visited schemas not yet assigned to a component
+    stack: Vec<&'a str>,
+    /// The component of each schema, identified by the index of its first visited schema
+    component: BTreeMap<&'a str, usize>,
+}
+
+impl<'a> Components<'a> {
+    fn visit(&mut self, name: &'a str) {
+        let index = self.index.len();
+        self.index.insert(name, index);
+        self.low.insert(name, index);
+        self.stack.push(name);
+
+        for &next in self.refs.get(name).into_iter().flatten() {
+            let reachable = match self.index.get(next) {
+                None => {
+                    self.visit(next);
+                    self.low[next]
+                }
+                // A visited schema without a component is still on the stack
+                Some(&next_index) if !self.component.contains_key(next) => next_index,
+                Some(_) => continue,
+            };
+            let low = self.low[name].min(reachable);
+            self.low.insert(name, low);
+        }
+
+        if self.low[name] == index {
+            while let Some(member) = self.stack.pop() {
+                self.component.insert(member, index);
+                if member == name {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn generate_schema(out: &mut String, context: &Context, schema_name: &str, schema: &Value) {
+    let name = context.type_name(schema_name);
+    write_docs(out, schema, "");
+
+    if let Some(values) = string_enum(schema) {
+        out.push_str("#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]\n");
+        let _ = writeln!(out, "pub enum {name} {{");
+        let mut used = BTreeSet::new();
+        for value in values {
+            let _ = writeln!(out, "    #[serde(rename = {value:?})]");
+            let _ = writeln!(out, "    {},", unique(type_name(value), &mut used, ""));
+        }
+        out.push_str("}\n");
+    } else if let Some(properties) = schema.get("properties").and_then(Value::as_object) {
+        let required = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|required| {
+                required
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        out.push_str("#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]\n");
+        let _ = writeln!(out, "pub struct {name} {{");
+        let mut used = BTreeSet::new();
+        for (property, property_schema) in properties {
+            write_docs(out, property_schema, "    ");
+
+            let field = unique(field_name(property), &mut used, "_");
+            if field.trim_start_matches("r#") != property {
+                let _ = writeln!(out, "    #[serde(rename = {property:?})]");
+            }
+
+            let field_type = field_type(context, property_schema, schema_name);
+            if required.contains(&property.as_str()) && !is_nullable(property_schema) {
+                let _ = writeln!(out, "    pub {field}: {field_type},");
+            } else {
+                out.push_str("    #[serde(default, skip_serializing_if = \"Option::is_none\")]\n");
+                let _ = writeln!(out, "    pub {field}: Option<{field_type}>,");
+            }
+        }
+        out.push_str("}\n");
+    } else {
+        let _ = writeln!(
+            out,
+            "pub type {name} = {};",
+            field_type(context, schema, schema_name)
+        );
+    }
+}
+
+/// Returns the values of a schema which is an enumeration of strings.
+fn string_enum(schema: &Value) -> Option<Vec<&str>> {
+    if schema_type(schema) != Some("string") {
+        return None;
+    }
+    schema
+        .get("enum")?
+        .as_array()?
+        .iter()
+        .map(Value::as_str)
+        .collect()
+}
+
+/// Returns the Rust type for a schema within the current schema, boxing the references which
+/// would otherwise make the current type contain itself.
+fn field_type(context: &Context, schema: &Value, current: &str) -> String {
+    if let Some(name) = ref_name(schema) {
+        let type_name = context.type_name(name);
+        return if context.is_boxed(current, name) {
+            format!("Box<{type_name}>")
+        } else {
+            type_name
+        };
+    }
+
+    match schema_type(schema) {
+        Some("string") => "String".to_string(),
+        Some("integer") => "i64".to_string(),
+        Some("number") => "f64".to_string(),
+        Some("boolean") => "bool".to_string(),
+        Some("array") => {
+            let items = schema
+                .get("items")
+                .map(|items| field_type(context, items, ""))
+                .unwrap_or_else(|| "serde_json::Value".to_string());
+            format!("Vec<{items}>")
+        }
+        _ => "serde_json::Value".to_string(),
+    }
+}
+
+/// Returns the type of the schema, which OpenAPI 3.1 may give as a list including `null`.
+fn schema_type(schema: &Value) -> Option<&str> {
+    match schema.get("type")? {
+        Value::String(schema_type) => Some(schema_type),
+        Value::Array(types) => types
+            .iter()
+            .filter_map(Value::as_str)
+            .find(|schema_type| *schema_type != "null"),
+        _ => None,
+    }
+}
+
+fn is_nullable(schema: &Value) -> bool {
+    schema.get("nullable").and_then(Value::as_bool) == Some(true)
+        || schema
+            .get("type")
+            .and_then(Value::as_array)
+            .is_some_and(|types| types.iter().any(|schema_type| schema_type == "null"))
+}
+
+fn write_docs(out: &mut String, schema: &Value, indent: &str) {
+    if let Some(description) = schema.get("description").and_then(Value::as_str) {
+        for line in description.trim().lines() {
+            let _ = writeln!(out, "{indent}/// {}", line.trim_end());
+        }
+    }
+}
+
+/// Convert a schema name such as `CreateChatCompletionRequest` or `chat.completion` to a type name.
+fn type_name(name: &str) -> String {
+    let name: String = name
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .map(|part| {
+            let mut chars = part.chars();
+            chars
+                .next()
+                .map(|first| first.to_ascii_uppercase().to_string() + chars.as_str())
+                .unwrap_or_default()
+        })
+        .collect();
+
+    match name.chars().next() {
+        Some(first) if first.is_ascii_alphabetic() => name,
+        _ => format!("V{name}"),
+    }
+}
+
+/// Returns the identifier, numbered if it is already used, such as `user_id_2` after `user_id`.
+fn unique(identifier: String, used: &mut BTreeSet<String>, separator: &str) -> String {
+    let mut unique = identifier.clone();
+    let mut number = 1;
+    while used.contains(&unique) {
+        number += 1;
+        unique = format!("{identifier}{separator}{number}");
+    }
+    used.insert(unique.clone());
+    unique
+}
+
+/// Convert a property name to a snake case field name which is a valid identifier.
+fn field_name(property: &str) -> String {
+    let mut field = String::new();
+    let mut previous_lowercase = false;
+    for c in property.chars() {
+        if c.is_ascii_uppercase() {
+            if previous_lowercase {
+                field.push('_');
+            }
+            field.push(c.to_ascii_lowercase());
+        } else if c.is_ascii_alphanumeric() {
+            field.push(c);
+        } else {
+            field.push('_');
+        }
+        previous_lowercase = c.is_ascii_lowercase() || c.is_ascii_digit();
+    }
+
+    if field.is_empty() || field.starts_with(|c: char| c.is_ascii_digit()) {
+        field.insert(0, '_');
+    }
+    if RESERVED.contains(&field.as_str()) {
+        field.push('_');
+    } else if KEYWORDS.contains(&field.as_str()) {
+        field.insert_str(0, "r#");
+    }
+    field
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    // The snapshot is included so the generated code is compiled with the tests
+    mod snapshot {
+        #![allow(dead_code)]
+
+        include!("../snapshot/generated.rs");
+    }
+
+    fn spec() -> Value {
+        json!({
+            "components": {
+                "schemas": {
+                    "CreateEmbeddingRequest": {
+                        "type": "object",
+                        "description": "Creates an embedding vector.",
+                        "required": ["input", "model"],
+                        "properties": {
+                            "input": {"type": "array", "items": {"type": "string"}},
+                            "model": {"type": "string"},
+                            "encoding_format": {"$ref": "#/components/schemas/EncodingFormat"},
+                            "dimensions": {"type": "integer", "description": "Output size."},
+                            "type": {"type": ["string", "null"]},
+                            "userId": {"type": "string"}
+                        }
+                    },
+                    "EncodingFormat": {"type": "string", "enum": ["float", "base64"]},
+                    "Unrelated": {"type": "object", "properties": {}}
+                }
+            }
+        })
+    }
+
+    #[test]
+    // Verify that the named schema and the schemas it refers to are generated
+    fn test_generate() {
+        let code = generate(&spec(), &["CreateEmbeddingRequest".to_string()]).unwrap();
+
+        assert_eq!(
+            code,
+            r#"// Generated by ryst-codegen from the OpenAI OpenAPI spec. Do not edit by hand.
+
+use serde::{Deserialize, Serialize};
+
+/// Creates an embedding vector.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CreateEmbeddingRequest {
+    /// Output size.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dimensions: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding_format: Option<EncodingFormat>,
+    pub input: Vec<String>,
+    pub model: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub r#type: Option<String>,
+    #[serde(rename = "userId")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EncodingFormat {
+    #[serde(rename = "float")]
+    Float,
+    #[serde(rename = "base64")]
+    Base64,
+}
+"#
+        );
+    }
+
+    #[test]
+    // Verify that every schema is generated when none are named, and unknown names are an error
+    fn test_generate_all_and_unknown() {
+        let code = generate(&spec(), &[]).unwrap();
+        assert!(code.contains("pub struct Unrelated {"));
+
+        assert!(generate(&spec(), &["Missing".to_string()]).is_err());
+        assert!(generate(&json!({}), &[]).is_err());
+    }
+
+    #[test]
+    // Verify that names are converted to valid identifiers
+    fn test_names() {
+        assert_eq!(type_name("chat.completion.chunk"), "ChatCompletionChunk");
+        assert_eq!(type_name("1024x1024"), "V1024x1024");
+        assert_eq!(field_name("type"), "r#type");
+        assert_eq!(field_name("self"), "self_");
+        assert_eq!(field_name("logit-bias"), "logit_bias");
+        assert_eq!(field_name("2fa"), "_2fa");
+    }
+
+    #[test]
+    // Verify that a schema referring to itself is boxed, and other schemas become aliases
+    fn test_recursive_and_alias() {
+        let spec = json!({
+            "components": {
+                "schemas": {
+                    "Node": {
+                        "type": "object",
+                        "required": ["child", "children"],
+                        "properties": {
+                            "child": {"$ref": "#/components/schemas/Node"},
+                            "children": {"type": "array", "items": {"$ref": "#/components/schemas/Node"}}
+                        }
+                    },
+                    "Input": {"oneOf": [{"type": "string"}, {"type": "array"}]}
+                }
+            }
+        });
+
+        let code = generate(&spec, &[]).unwrap();
+        assert!(code.contains("pub child: Box<Node>,"));
+        assert!(code.contains("pub children: Vec<Node>,"));
+        assert!(code.contains("pub type Input = serde_json::Value;"));
+    }
+
+    #[test]
+    // Verify that the snapshot matches the code generated from its spec, and that the boxed cycle
+    // between its types deserializes. Regenerate it with
+    // `cargo run -p ryst-codegen -- codegen/snapshot/spec.json codegen/snapshot/generated.rs`
+    fn test_snapshot() {
+        let spec = serde_json::from_str(include_str!("../snapshot/spec.json")).unwrap();
+        assert_eq!(
+            generate(&spec, &[]).unwrap(),
+            include_str!("../snapshot/generated.rs")
+        );
+
+        let filter: snapshot::Filter = serde_json::from_value(json!({
+            "condition": {"key": "a", "filter": {"condition": {"key": "b"}}}
+        }))
+        .unwrap();
+        assert_eq!(filter.condition.filter.unwrap().condition.key, "b");
+    }
+}
@@ -0,0 +1,51 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Generates Rust types from the schemas of OpenAI's OpenAPI spec, as the base of the
+//! request and response types for new endpoints.
+//!
+//! The spec is published as YAML and must be converted to JSON first, for example with `yq`:
+//!
+//! ```text
+//! yq -o json openapi.yaml > openapi.json
+//! cargo run -p ryst-codegen -- openapi.json generated.rs CreateEmbeddingRequest
+//! ```
+//!
+//! The named schemas are generated, along with every schema they refer to. All of the schemas
+//! are generated if none are named. The generated types are plain serde structs and enums, and
+//! the builders and helpers are written by hand on top of them.
+
+mod generate;
+
+use std::env;
+use std::fs;
+
+use ryst_error::{CliError, InvalidArgumentError};
+use serde_json::Value;
+
+const USAGE: &str = "Usage: ryst-codegen <spec.json> <output.rs> [schema...]";
+
+fn main() -> Result<(), CliError> {
+    let mut args = env::args().skip(1);
+    let (Some(spec_path), Some(output_path)) = (args.next(), args.next()) else {
+        return Err(InvalidArgumentError::new("args", USAGE).into());
+    };
+    let names = args.collect::<Vec<_>>();
+
+    let spec: Value = serde_json::from_str(&fs::read_to_string(spec_path)?)?;
+    let code = generate::generate(&spec, &names)?;
+    fs::write(output_path, code)?;
+
+    Ok(())
+}
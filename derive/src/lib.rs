@@ -0,0 +1,456 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Derive macros for ryst-agent tool functions.
+//!
+//! These are re-exported by ryst-agent with the `derive` feature, and the generated code refers
+//! to items in ryst-agent, so they are not used directly.
+
+use proc_macro::TokenStream;
+use proc_macro2::{TokenStream as TokenStream2, TokenTree};
+use quote::quote;
+use syn::meta::ParseNestedMeta;
+use syn::{
+    parse_macro_input, token, Attribute, Data, DeriveInput, Error, Expr, Fields, Lit, LitStr, Token,
+};
+
+/// Implements `ToolFunction` and `ToolParameter` for a struct with named fields, generating the
+/// JSON Schema of its fields.
+///
+/// The tool name defaults to the struct name in snake case and the description to the doc
+/// comment on the struct. Either may be set with `#[tool(name = "...", description = "...")]`.
+/// Doc comments on fields become the descriptions of their properties. Fields are required
+/// unless their type is an `Option`, which may also be null.
+///
+/// The serde attributes `rename`, `rename_all`, `default`, `skip` and `skip_deserializing` are
+/// followed, so the schema describes the JSON the struct is deserialized from. Attributes which
+/// change its shape further, such as `flatten`, are rejected.
+#[proc_macro_derive(ToolFunction, attributes(tool))]
+pub fn derive_tool_function(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    tool_function(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// Implements `ToolParameter` for a struct with named fields, which becomes a nested object, or
+/// for an enum of unit variants, which becomes a string enum of the variant names.
+///
+/// Serde attributes are followed as with `ToolFunction`, including `rename_all` and `rename` on
+/// the variants of an enum.
+#[proc_macro_derive(ToolParameter)]
+pub fn derive_tool_parameter(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    tool_parameter(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn tool_function(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let object = object_schema(input)?;
+
+    let (mut name, mut description) = (None, None);
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("tool"))
+    {
+        attr.parse_nested_meta(|meta| {
+            let value: LitStr = meta.value()?.parse()?;
+            if meta.path.is_ident("name") {
+                name = Some(value.value());
+            } else if meta.path.is_ident("description") {
+                description = Some(value.value());
+            } else {
+                return Err(meta.error("expected `name` or `description`"));
+            }
+            Ok(())
+        })?;
+    }
+    let name = name.unwrap_or_else(|| snake_case(&ident.to_string()));
+    let description = description
+        .or_else(|| doc_comment(&input.attrs))
+        .unwrap_or_default();
+
+    Ok(quote! {
+        impl #impl_generics ::ryst_agent::ToolFunction for #ident #ty_generics #where_clause {
+            const NAME: &'static str = #name;
+            const DESCRIPTION: &'static str = #description;
+
+            fn parameters() -> ::ryst_agent::serde_json::Value {
+                #object
+            }
+        }
+
+        impl #impl_generics ::ryst_agent::ToolParameter for #ident #ty_generics #where_clause {
+            fn schema() -> ::ryst_agent::serde_json::Value {
+                <Self as ::ryst_agent::ToolFunction>::parameters()
+            }
+        }
+    })
+}
+
+fn tool_parameter(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let schema = match &input.data {
+        Data::Enum(data) => {
+            let container = serde_attrs(&input.attrs)?;
+            let mut variants = Vec::new();
+            for variant in &data.variants {
+                if !matches!(variant.fields, Fields::Unit) {
+                    return Err(Error::new_spanned(
+                        variant,
+                        "ToolParameter can only be derived for enums with unit variants",
+                    ));
+                }
+                let serde = serde_attrs(&variant.attrs)?;
+                if serde.skip {
+                    continue;
+                }
+                variants.push(serde.rename.unwrap_or_else(|| {
+                    let name = variant.ident.to_string();
+                    match container.rename_all {
+                        Some(rule) => rule.apply_to_variant(&name),
+                        None => name,
+                    }
+                }));
+            }
+            quote! {
+                ::ryst_agent::serde_json::json!({
+                    "type": "string",
+                    "enum": [#(#variants),*]
+                })
+            }
+        }
+        _ => object_schema(input)?,
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::ryst_agent::ToolParameter for #ident #ty_generics #where_clause {
+            fn schema() -> ::ryst_agent::serde_json::Value {
+                #schema
+            }
+        }
+    })
+}
+
+/// Generate an expression building the object schema of a struct with named fields.
+fn object_schema(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(Error::new_spanned(
+                    &input.ident,
+                    "tool arguments must be a struct with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(Error::new_spanned(
+                &input.ident,
+                "tool arguments must be a struct with named fields",
+            ))
+        }
+    };
+
+    let container = serde_attrs(&input.attrs)?;
+    let mut properties = Vec::new();
+    for field in fields {
+        let serde = serde_attrs(&field.attrs)?;
+        if serde.skip {
+            continue;
+        }
+        let ty = &field.ty;
+        let name = serde.rename.unwrap_or_else(|| {
+            let name = field
+                .ident
+                .as_ref()
+                .map(|ident| ident.to_string().trim_start_matches("r#").to_string())
+                .unwrap_or_default();
+            match container.rename_all {
+                Some(rule) => rule.apply_to_field(&name),
+                None => name,
+            }
+        });
+        let optional = serde.default || container.default;
+        let describe = doc_comment(&field.attrs).map(|description| {
+            quote! {
+                if let Some(object) = schema.as_object_mut() {
+                    object.insert("description".to_string(), #description.into());
+                }
+            }
+        });
+
+        properties.push(quote! {
+            {
+                let mut schema = <#ty as ::ryst_agent::ToolParameter>::schema();
+                #describe
+                properties.insert(#name.to_string(), schema);
+                if !#optional && <#ty as ::ryst_agent::ToolParameter>::REQUIRED {
+                    required.push(::ryst_agent::serde_json::Value::from(#name));
+                }
+            }
+        });
+    }
+
+    Ok(quote! {
+        {
+            let mut properties = ::ryst_agent::serde_json::Map::new();
+            let mut required = ::std::vec::Vec::new();
+            #(#properties)*
+            ::ryst_agent::serde_json::json!({
+                "type": "object",
+                "properties": properties,
+                "required": required,
+                "additionalProperties": false
+            })
+        }
+    })
+}
+
+/// The serde attributes of an item which change the JSON it is deserialized from.
+#[derive(Default)]
+struct SerdeAttrs {
+    rename: Option<String>,
+    rename_all: Option<RenameRule>,
+    default: bool,
+    skip: bool,
+}
+
+/// Read the serde attributes of an item, rejecting those the schema cannot describe.
+fn serde_attrs(attrs: &[Attribute]) -> Result<SerdeAttrs, Error> {
+    let mut serde = SerdeAttrs::default();
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("serde")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                if let Some(rename) = deserialize_name(&meta)? {
+                    serde.rename = Some(rename.value());
+                }
+            } else if meta.path.is_ident("rename_all") {
+                if let Some(rule) = deserialize_name(&meta)? {
+                    serde.rename_all = Some(RenameRule::parse(&rule)?);
+                }
+            } else if meta.path.is_ident("default") {
+                if meta.input.peek(Token![=]) {
+                    meta.value()?.parse::<LitStr>()?;
+                }
+                serde.default = true;
+            } else if meta.path.is_ident("skip") || meta.path.is_ident("skip_deserializing") {
+                serde.skip = true;
+            } else if ["flatten", "tag", "content", "untagged", "transparent"]
+                .iter()
+                .any(|name| meta.path.is_ident(name))
+            {
+                return Err(meta.error(
+                    "this serde attribute changes the JSON in a way the ToolFunction and \
+                     ToolParameter derives cannot describe, so implement ToolParameter by hand",
+                ));
+            } else if meta.input.peek(Token![=]) {
+                // Attributes such as `alias` and `deserialize_with` keep the schema valid
+                meta.value()?.parse::<Expr>()?;
+            } else if meta.input.peek(token::Paren) {
+                meta.input.parse::<TokenTree>()?;
+            }
+            Ok(())
+        })?;
+    }
+    Ok(serde)
+}
+
+/// The name given by `rename = "..."`, or by `deserialize` in `rename(deserialize = "...")`,
+/// as only deserialization matters for tool arguments.
+fn deserialize_name(meta: &ParseNestedMeta) -> Result<Option<LitStr>, Error> {
+    if meta.input.peek(Token![=]) {
+        return Ok(Some(meta.value()?.parse()?));
+    }
+
+    let mut name = None;
+    meta.parse_nested_meta(|nested| {
+        let value: LitStr = nested.value()?.parse()?;
+        if nested.path.is_ident("deserialize") {
+            name = Some(value);
+        } else if !nested.path.is_ident("serialize") {
+            return Err(nested.error("expected `serialize` or `deserialize`"));
+        }
+        Ok(())
+    })?;
+    Ok(name)
+}
+
+/// The cases of serde's `rename_all`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RenameRule {
+    Lower,
+    Upper,
+    Pascal,
+    Camel,
+    Snake,
+    ScreamingSnake,
+    Kebab,
+    ScreamingKebab,
+}
+
+impl RenameRule {
+    fn parse(rule: &LitStr) -> Result<Self, Error> {
+        Ok(match rule.value().as_str() {
+            "lowercase" => Self::Lower,
+            "UPPERCASE" => Self::Upper,
+            "PascalCase" => Self::Pascal,
+            "camelCase" => Self::Camel,
+            "snake_case" => Self::Snake,
+            "SCREAMING_SNAKE_CASE" => Self::ScreamingSnake,
+            "kebab-case" => Self::Kebab,
+            "SCREAMING-KEBAB-CASE" => Self::ScreamingKebab,
+            _ => return Err(Error::new_spanned(rule, "unknown rename rule")),
+        })
+    }
+
+    /// Rename a field, which is written in snake case, as serde does.
+    fn apply_to_field(self, field: &str) -> String {
+        match self {
+            Self::Lower | Self::Snake => field.to_string(),
+            Self::Upper | Self::ScreamingSnake => field.to_ascii_uppercase(),
+            Self::Pascal | Self::Camel => {
+                let mut renamed = String::with_capacity(field.len());
+                let mut capitalize = self == Self::Pascal;
+                for c in field.chars() {
+                    if c == '_' {
+                        capitalize = true;
+                    } else if capitalize {
+                        renamed.push(c.to_ascii_uppercase());
+                        capitalize = false;
+                    } else {
+                        renamed.push(c);
+                    }
+                }
+                renamed
+            }
+            Self::Kebab => field.replace('_', "-"),
+            Self::ScreamingKebab => field.replace('_', "-").to_ascii_uppercase(),
+        }
+    }
+
+    /// Rename a variant, which is written in Pascal case, as serde does.
+    fn apply_to_variant(self, variant: &str) -> String {
+        match self {
+            Self::Pascal => variant.to_string(),
+            Self::Lower => variant.to_ascii_lowercase(),
+            Self::Upper => variant.to_ascii_uppercase(),
+            Self::Camel => {
+                let mut chars = variant.chars();
+                chars
+                    .next()
+                    .map(|first| first.to_ascii_lowercase().to_string() + chars.as_str())
+                    .unwrap_or_default()
+            }
+            Self::Snake | Self::ScreamingSnake | Self::Kebab | Self::ScreamingKebab => {
+                // Unlike `snake_case`, serde splits before every capital
+                let mut snake = String::with_capacity(variant.len() + 4);
+                for (i, c) in variant.char_indices() {
+                    if i > 0 && c.is_uppercase() {
+                        snake.push('_');
+                    }
+                    snake.push(c.to_ascii_lowercase());
+                }
+                match self {
+                    Self::Snake => snake,
+                    rule => rule.apply_to_field(&snake),
+                }
+            }
+        }
+    }
+}
+
+/// Join the doc comments on an item into one line, or `None` if there are none.
+fn doc_comment(attrs: &[Attribute]) -> Option<String> {
+    let lines: Vec<String> = attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("doc"))
+        .filter_map(|attr| match &attr.meta.require_name_value().ok()?.value {
+            Expr::Lit(expr) => match &expr.lit {
+                Lit::Str(doc) => Some(doc.value().trim().to_string()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .filter(|line| !line.is_empty())
+        .collect();
+
+    (!lines.is_empty()).then(|| lines.join(" "))
+}
+
+fn snake_case(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut snake = String::with_capacity(name.len() + 4);
+    for (i, c) in chars.iter().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            let prev = chars[i - 1];
+            let next_lower = chars.get(i + 1).is_some_and(|next| next.is_lowercase());
+            // Split before a word, and before the last capital of an acronym followed by a word
+            if prev.is_lowercase() || prev.is_ascii_digit() || (prev.is_uppercase() && next_lower) {
+                snake.push('_');
+            }
+        }
+        snake.extend(c.to_lowercase());
+    }
+    snake
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proc_macro2::Span;
+
+    #[test]
+    // Verify that struct names are converted to snake case tool names
+    fn test_snake_case() {
+        assert_eq!(snake_case("GetWeather"), "get_weather");
+        assert_eq!(snake_case("FetchHTTPPage"), "fetch_http_page");
+        assert_eq!(snake_case("Search2Web"), "search2_web");
+        assert_eq!(snake_case("add"), "add");
+    }
+
+    #[test]
+    // Verify that fields and variants are renamed as serde's rename_all does
+    fn test_rename_rule() {
+        let rule = |rule: &str| RenameRule::parse(&LitStr::new(rule, Span::call_site())).unwrap();
+
+        assert_eq!(
+            rule("camelCase").apply_to_field("max_results"),
+            "maxResults"
+        );
+        assert_eq!(
+            rule("PascalCase").apply_to_field("max_results"),
+            "MaxResults"
+        );
+        assert_eq!(
+            rule("SCREAMING-KEBAB-CASE").apply_to_field("max_results"),
+            "MAX-RESULTS"
+        );
+        assert_eq!(rule("snake_case").apply_to_variant("HttpGet"), "http_get");
+        assert_eq!(
+            rule("kebab-case").apply_to_variant("HTTPGet"),
+            "h-t-t-p-get"
+        );
+        assert_eq!(rule("camelCase").apply_to_variant("HttpGet"), "httpGet");
+        assert_eq!(rule("lowercase").apply_to_variant("HttpGet"), "httpget");
+        assert!(RenameRule::parse(&LitStr::new("Title Case", Span::call_site())).is_err());
+    }
+}
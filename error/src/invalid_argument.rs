@@ -25,6 +25,7 @@ use std::fmt;
 pub struct InvalidArgumentError {
     argument: String,
     message: String,
+    source: Option<Box<dyn error::Error + Send + Sync>>,
 }
 
 impl InvalidArgumentError {
@@ -49,9 +50,33 @@ impl InvalidArgumentError {
         Self {
             argument: argument.into(),
             message: message.into(),
+            source: None,
         }
     }
 
+    /// Attaches the error which caused the argument to be rejected, such as the response of a
+    /// remote service which validated it.
+    ///
+    /// The display string is unchanged.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::error::Error;
+    ///
+    /// use ryst_error::InvalidArgumentError;
+    ///
+    /// let io_err = std::io::Error::new(std::io::ErrorKind::Other, "io error");
+    /// let invalid_arg_error =
+    ///     InvalidArgumentError::new("arg1", "argument too long").with_source(Box::new(io_err));
+    /// assert_eq!(format!("{}", invalid_arg_error), "argument too long (arg1)");
+    /// assert!(invalid_arg_error.source().is_some());
+    /// ```
+    pub fn with_source(mut self, source: Box<dyn error::Error + Send + Sync>) -> Self {
+        self.source = Some(source);
+        self
+    }
+
     /// Returns the name of the invalid argument.
     pub fn argument(&self) -> String {
         self.argument.clone()
@@ -63,7 +88,13 @@ impl InvalidArgumentError {
     }
 }
 
-impl error::Error for InvalidArgumentError {}
+impl error::Error for InvalidArgumentError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        self.source
+            .as_ref()
+            .map(|source| source.as_ref() as &(dyn error::Error + 'static))
+    }
+}
 
 impl fmt::Display for InvalidArgumentError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
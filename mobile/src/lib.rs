@@ -0,0 +1,234 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! UniFFI bindings for ryst-openai, so iOS and Android apps can reuse its chat, completion and
+//! streaming APIs from Swift and Kotlin.
+//!
+//! Requests and responses cross the boundary as JSON in the API's format. Generate the bindings
+//! from the built library with the bundled bindgen:
+//!
+//! ```text
+//! cargo build -p ryst-mobile --release
+//! cargo run -p ryst-mobile --bin uniffi-bindgen -- generate \
+//!     --library target/release/libryst_mobile.so --language kotlin --out-dir out
+//! ```
+
+use std::fmt;
+use std::sync::Arc;
+
+use ryst_openai::{
+    ChatCompletionRequest, ChatCompletionResponseStream, CompletionRequest,
+    CompletionResponseStream, KeySource, OpenAIError,
+};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+uniffi::setup_scaffolding!();
+
+/// An error returned to the app.
+#[derive(Debug, uniffi::Error)]
+pub enum RystError {
+    /// The request was invalid, or was rejected by the API
+    InvalidArgument { message: String },
+    /// The request could not be completed
+    Internal { message: String },
+}
+
+impl fmt::Display for RystError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RystError::InvalidArgument { message } | RystError::Internal { message } => {
+                f.write_str(message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RystError {}
+
+impl From<OpenAIError> for RystError {
+    fn from(err: OpenAIError) -> Self {
+        match err {
+            OpenAIError::InvalidArgument(_) => RystError::InvalidArgument {
+                message: err.to_string(),
+            },
+            _ => RystError::Internal {
+                message: err.to_string(),
+            },
+        }
+    }
+}
+
+fn from_json<T: DeserializeOwned>(json: &str) -> Result<T, RystError> {
+    serde_json::from_str(json).map_err(|err| RystError::InvalidArgument {
+        message: err.to_string(),
+    })
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<String, RystError> {
+    serde_json::to_string(value).map_err(|err| RystError::Internal {
+        message: err.to_string(),
+    })
+}
+
+/// A client which sends requests with an API key.
+#[derive(uniffi::Object)]
+pub struct RystClient {
+    key_source: Option<KeySource>,
+}
+
+#[uniffi::export(async_runtime = "tokio")]
+impl RystClient {
+    /// Create a client which sends requests with the given API key, or the key from the
+    /// `OPENAI_API_KEY` environment variable if none is given.
+    #[uniffi::constructor]
+    pub fn new(api_key: Option<String>) -> Arc<Self> {
+        Arc::new(Self {
+            key_source: api_key.map(KeySource::Static),
+        })
+    }
+
+    /// Submit a chat completion request given as JSON, returning the response as JSON.
+    pub async fn submit_chat(&self, request_json: String) -> Result<String, RystError> {
+        let response = self.chat_request(&request_json)?.submit().await?;
+        to_json(&response)
+    }
+
+    /// Submit a chat completion request given as JSON as a stream.
+    pub async fn stream_chat(&self, request_json: String) -> Result<Arc<ChatStream>, RystError> {
+        let stream = self.chat_request(&request_json)?.stream().await?;
+        Ok(Arc::new(ChatStream {
+            stream: Mutex::new(stream),
+        }))
+    }
+
+    /// Submit a completion request given as JSON, returning the response as JSON.
+    pub async fn submit_completion(&self, request_json: String) -> Result<String, RystError> {
+        let response = self.completion_request(&request_json)?.submit().await?;
+        to_json(&response)
+    }
+
+    /// Submit a completion request given as JSON as a stream.
+    pub async fn stream_completion(
+        &self,
+        request_json: String,
+    ) -> Result<Arc<CompletionStream>, RystError> {
+        let stream = self.completion_request(&request_json)?.stream().await?;
+        Ok(Arc::new(CompletionStream {
+            stream: Mutex::new(stream),
+        }))
+    }
+}
+
+impl RystClient {
+    fn chat_request(&self, request_json: &str) -> Result<ChatCompletionRequest, RystError> {
+        let request: ChatCompletionRequest = from_json(request_json)?;
+        Ok(match &self.key_source {
+            Some(key_source) => request.with_key_source(key_source.clone()),
+            None => request,
+        })
+    }
+
+    fn completion_request(&self, request_json: &str) -> Result<CompletionRequest, RystError> {
+        let request: CompletionRequest = from_json(request_json)?;
+        Ok(match &self.key_source {
+            Some(key_source) => request.with_key_source(key_source.clone()),
+            None => request,
+        })
+    }
+}
+
+/// The responses of a streamed chat completion request.
+#[derive(uniffi::Object)]
+pub struct ChatStream {
+    stream: Mutex<ChatCompletionResponseStream>,
+}
+
+#[uniffi::export(async_runtime = "tokio")]
+impl ChatStream {
+    /// Returns the next response as JSON, or `None` at the end of the stream.
+    pub async fn next(&self) -> Result<Option<String>, RystError> {
+        let response = self.stream.lock().await.next().await?;
+        response.as_ref().map(to_json).transpose()
+    }
+}
+
+/// The responses of a streamed completion request.
+#[derive(uniffi::Object)]
+pub struct CompletionStream {
+    stream: Mutex<CompletionResponseStream>,
+}
+
+#[uniffi::export(async_runtime = "tokio")]
+impl CompletionStream {
+    /// Returns the next response as JSON, or `None` at the end of the stream.
+    pub async fn next(&self) -> Result<Option<String>, RystError> {
+        let response = self.stream.lock().await.next().await?;
+        response.as_ref().map(to_json).transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::stream;
+    use ryst_openai::reqwest::Result as ReqwestResult;
+
+    #[tokio::test]
+    // Verify that invalid requests are returned as invalid argument errors
+    async fn test_invalid_request() {
+        let client = RystClient::new(Some("sk-test".to_string()));
+
+        let err = client
+            .submit_chat("not json".to_string())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, RystError::InvalidArgument { .. }));
+
+        let request = r#"{"model": "gpt-4o", "messages": [], "temperature": 1, "top_p": 1}"#;
+        let err = client.stream_chat(request.to_string()).await.err().unwrap();
+        assert!(matches!(err, RystError::InvalidArgument { .. }));
+        assert!(err.to_string().contains("temperature or top_p"));
+    }
+
+    #[tokio::test]
+    // Verify that a stream returns each response as JSON and then None
+    async fn test_chat_stream_next() {
+        let json = serde_json::json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "gpt-4o",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": "Hello"},
+                "finish_reason": "stop"
+            }],
+            "usage": {"prompt_tokens": 1, "completion_tokens": 1, "total_tokens": 2}
+        });
+        let chunks: Vec<ReqwestResult<bytes::Bytes>> = vec![Ok(json.to_string().into())];
+        let stream = ChatStream {
+            stream: Mutex::new(ChatCompletionResponseStream::new(Box::pin(stream::iter(
+                chunks,
+            )))),
+        };
+
+        let response: serde_json::Value =
+            serde_json::from_str(&stream.next().await.unwrap().unwrap()).unwrap();
+        assert_eq!(response["choices"][0]["message"]["content"], "Hello");
+        assert!(stream.next().await.unwrap().is_none());
+    }
+}
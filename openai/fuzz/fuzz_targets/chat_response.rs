@@ -0,0 +1,27 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Parse arbitrary bytes as a chat completion response, and format any that parse.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ryst_openai::ChatCompletionResponse;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(response) = serde_json::from_slice::<ChatCompletionResponse>(data) {
+        let _ = response.to_string();
+        let _ = response.code_blocks();
+    }
+});
@@ -0,0 +1,36 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Track arbitrary text through the markdown tracker, split into pieces at arbitrary points.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ryst_openai::markdown::{code_blocks, MarkdownTracker};
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&piece_len, text)) = data.split_first() else {
+        return;
+    };
+    let text = String::from_utf8_lossy(text);
+    let chars = text.chars().collect::<Vec<_>>();
+
+    let mut tracker = MarkdownTracker::new();
+    for piece in chars.chunks(usize::from(piece_len).max(1)) {
+        tracker.push(&piece.iter().collect::<String>());
+    }
+    tracker.finish();
+
+    code_blocks(&text);
+});
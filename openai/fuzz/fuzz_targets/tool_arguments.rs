@@ -0,0 +1,39 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Validate arbitrary tool call arguments against an arbitrary parameters schema, given as the
+//! first line of the input.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ryst_openai::{FunctionCall, Tool, ToolCall};
+
+fuzz_target!(|text: &str| {
+    let (schema, arguments) = text.split_once('\n').unwrap_or((text, ""));
+    let Ok(schema) = serde_json::from_str(schema) else {
+        return;
+    };
+
+    let tool = Tool::function("tool", "", schema);
+    let call = ToolCall {
+        id: "call".to_string(),
+        kind: "function".to_string(),
+        function: FunctionCall {
+            name: "tool".to_string(),
+            arguments: arguments.to_string(),
+        },
+    };
+    let _ = call.validated_arguments(&tool);
+});
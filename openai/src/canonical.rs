@@ -0,0 +1,248 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing a canonical serialization of requests and the fingerprints derived from it.
+//!
+//! Two requests which send the same body to the same endpoint have the same fingerprint, whatever
+//! order their fields or extra fields were set in, so fingerprints can be used as cache keys.
+
+use std::fmt::Write;
+
+use ryst_error::InternalError;
+use serde::Serialize;
+use serde_json::{json, Map, Number, Value};
+use sha2::{Digest, Sha256};
+
+use crate::error::OpenAIError;
+use crate::http::{self, RequestOptions};
+use crate::{ChatCompletionRequest, CompletionRequest};
+
+/// Serialize a value to JSON with the keys of every object sorted and numbers normalized.
+///
+/// Floats with no fractional part are written as integers, so `1.0` and `1` are the same, and
+/// floats are written in their shortest form. No whitespace is added.
+pub fn to_canonical_json<T: Serialize>(value: &T) -> Result<String, OpenAIError> {
+    // Serializing to a string first writes f32 fields in their shortest form, which would be
+    // lost by widening them to f64 in `serde_json::to_value`
+    let value = serde_json::to_string(value)
+        .and_then(|json| serde_json::from_str::<Value>(&json))
+        .map_err(|err| OpenAIError::Internal(InternalError::from_source(Box::new(err))))?;
+
+    let mut canonical = String::new();
+    write_canonical(&value, &mut canonical);
+    Ok(canonical)
+}
+
+/// Returns the hex encoded SHA-256 hash of the canonical JSON of a value.
+pub fn fingerprint<T: Serialize>(value: &T) -> Result<String, OpenAIError> {
+    let digest = Sha256::digest(to_canonical_json(value)?.as_bytes());
+
+    let mut hex = String::with_capacity(digest.len() * 2);
+    for byte in digest {
+        let _ = write!(hex, "{byte:02x}");
+    }
+    Ok(hex)
+}
+
+/// What the fingerprint of a request covers: its body and the settings outside the body which
+/// change the response.
+#[derive(Serialize)]
+struct Fingerprinted<'a, T> {
+    body: &'a T,
+    base_url: &'a str,
+    query: Vec<(&'a str, &'a str)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_best_of: Option<Value>,
+}
+
+impl<'a, T> Fingerprinted<'a, T> {
+    fn new(body: &'a T, options: &'a RequestOptions) -> Self {
+        Self {
+            body,
+            base_url: http::base_url(options),
+            query: http::query(options),
+            client_best_of: None,
+        }
+    }
+}
+
+impl ChatCompletionRequest {
+    /// Returns a hash of the body the request sends to the API, along with the base URL, the
+    /// query parameters and the settings of `with_client_best_of` or `with_client_best_of_by`.
+    ///
+    /// Settings which do not change the response, such as the key source, do not change the
+    /// fingerprint.
+    pub fn fingerprint(&self) -> Result<String, OpenAIError> {
+        let mut fingerprinted = Fingerprinted::new(self, self.options());
+        fingerprinted.client_best_of = self
+            .client_best_of()
+            .map(|(n, best_of)| json!({"n": n, "pick": best_of.settings()}));
+        fingerprint(&fingerprinted)
+    }
+}
+
+impl CompletionRequest {
+    /// Returns a hash of the body the request sends to the API, along with the base URL and the
+    /// query parameters.
+    ///
+    /// Settings which do not change the response, such as the key source, do not change the
+    /// fingerprint.
+    pub fn fingerprint(&self) -> Result<String, OpenAIError> {
+        fingerprint(&Fingerprinted::new(self, self.options()))
+    }
+}
+
+fn write_canonical(value: &Value, out: &mut String) {
+    match value {
+        Value::Object(map) => write_object(map, out),
+        Value::Array(values) => {
+            out.push('[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(value, out);
+            }
+            out.push(']');
+        }
+        Value::Number(number) => out.push_str(&normalize(number).to_string()),
+        // Strings are escaped by serde_json, which always escapes the same way
+        value => out.push_str(&value.to_string()),
+    }
+}
+
+fn write_object(map: &Map<String, Value>, out: &mut String) {
+    let mut entries = map.iter().collect::<Vec<_>>();
+    entries.sort_by_key(|(key, _)| *key);
+
+    out.push('{');
+    for (i, (key, value)) in entries.into_iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str(&Value::String(key.clone()).to_string());
+        out.push(':');
+        write_canonical(value, out);
+    }
+    out.push('}');
+}
+
+fn normalize(number: &Number) -> Number {
+    match number.as_f64() {
+        Some(float) if number.is_f64() && float.fract() == 0.0 && float.abs() < 1e15 => {
+            // Also turns -0.0 into 0
+            Number::from(float as i64)
+        }
+        _ => number.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::judge::Judge;
+    use crate::{ChoiceStrategy, KeySource, Message, OpenAIClient};
+
+    #[test]
+    // Verify that keys are sorted and numbers normalized at every level
+    fn test_to_canonical_json() {
+        let value = json!({
+            "b": [{"z": 1.0, "a": -0.0}, 2.5],
+            "a": {"y": "\"quoted\"", "x": null}
+        });
+
+        assert_eq!(
+            to_canonical_json(&value).unwrap(),
+            r#"{"a":{"x":null,"y":"\"quoted\""},"b":[{"a":0,"z":1},2.5]}"#
+        );
+    }
+
+    #[test]
+    // Verify that requests differing only in field order or unsent settings share a fingerprint
+    fn test_request_fingerprint() {
+        let messages = [Message::new("user", "Hello")];
+        let request = ChatCompletionRequest::new("gpt-4o", &messages)
+            .with_temperature(0.7)
+            .with_extra("seed", json!(1))
+            .with_extra("metadata", json!({"b": 1, "a": 2}));
+        let reordered = ChatCompletionRequest::new("gpt-4o", &messages)
+            .with_extra("metadata", json!({"a": 2, "b": 1.0}))
+            .with_extra("seed", json!(1))
+            .with_temperature(0.7)
+            .with_key_source(KeySource::Static("sk-test".to_string()));
+
+        let fingerprint = request.fingerprint().unwrap();
+        assert_eq!(fingerprint.len(), 64);
+        assert_eq!(fingerprint, reordered.fingerprint().unwrap());
+        assert!(to_canonical_json(&request)
+            .unwrap()
+            .contains(r#""temperature":0.7"#));
+
+        let changed = ChatCompletionRequest::new("gpt-4o", &messages).with_temperature(0.8);
+        assert_ne!(fingerprint, changed.fingerprint().unwrap());
+    }
+
+    #[test]
+    // Verify that each setting outside the body which changes the response changes the fingerprint
+    fn test_request_fingerprint_settings() {
+        let request = || ChatCompletionRequest::new("gpt-4o", &[Message::new("user", "Hello")]);
+        let judge = Judge::new("gpt-4o", "Be correct.");
+        let variants = [
+            request(),
+            request().with_base_url("http://localhost:4000"),
+            request().with_client(OpenAIClient::new("sk-test").with_base_url("http://gateway")),
+            request().with_query("api-version", "2024-06-01"),
+            request().with_query("api-version", "2024-10-21"),
+            request().with_client(OpenAIClient::new("sk-test").with_default_query("route", "a")),
+            request().with_client_best_of(3, ChoiceStrategy::Longest),
+            request().with_client_best_of(4, ChoiceStrategy::Longest),
+            request().with_client_best_of(3, ChoiceStrategy::HighestMeanLogprob),
+            request().with_client_best_of_by(3, judge.clone()),
+            request().with_client_best_of_by(3, judge.clone().with_scale(1, 10)),
+            request().with_client_best_of_by(3, Judge::new("gpt-4o", "Be brief.")),
+        ];
+
+        let fingerprints = variants
+            .iter()
+            .map(|request| request.fingerprint().unwrap())
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(fingerprints.len(), variants.len());
+
+        // A client sending to the default URL is the same endpoint as no client
+        assert_eq!(
+            request()
+                .with_client(OpenAIClient::new("sk-test"))
+                .fingerprint()
+                .unwrap(),
+            request().fingerprint().unwrap()
+        );
+
+        let completion = || CompletionRequest::new("babbage-002", "Hello");
+        assert_ne!(
+            completion().fingerprint().unwrap(),
+            completion()
+                .with_query("api-version", "2024-06-01")
+                .fingerprint()
+                .unwrap()
+        );
+        assert_ne!(
+            completion().fingerprint().unwrap(),
+            completion()
+                .with_base_url("http://localhost:4000")
+                .fingerprint()
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,441 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing validation of tool call arguments against the JSON Schema declared by the
+//! tool, so invalid arguments can be reported back to the model instead of reaching user code.
+//!
+//! The commonly used subset of JSON Schema is supported: `type`, `enum`, `const`, `properties`,
+//! `required`, `additionalProperties`, `items`, `anyOf`, `oneOf`, `allOf` and the numeric,
+//! length and item count bounds. Other keywords, including `$ref`, are ignored.
+
+use std::error::Error;
+use std::fmt;
+
+use ryst_error::InvalidArgumentError;
+use serde_json::{Map, Value};
+
+use super::tools::{Tool, ToolCall};
+use crate::error::OpenAIError;
+
+/// A value in the arguments which does not match the schema.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArgumentViolation {
+    /// Where the value is, such as `items[0].name`, or empty for the arguments themselves
+    pub path: String,
+    /// What is wrong with the value
+    pub message: String,
+}
+
+impl fmt::Display for ArgumentViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.path.is_empty() {
+            f.write_str(&self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+/// Returned when the arguments of a tool call cannot be used.
+///
+/// The message is written to be sent back to the model so it can correct the call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ToolArgumentError {
+    /// The arguments are not valid JSON
+    Malformed { tool: String, message: String },
+    /// The arguments do not match the tool's schema
+    Invalid {
+        tool: String,
+        violations: Vec<ArgumentViolation>,
+    },
+}
+
+impl Error for ToolArgumentError {}
+
+impl fmt::Display for ToolArgumentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ToolArgumentError::Malformed { tool, message } => {
+                write!(f, "Invalid arguments for tool {tool}: {message}")
+            }
+            ToolArgumentError::Invalid { tool, violations } => {
+                let violations = violations
+                    .iter()
+                    .map(ArgumentViolation::to_string)
+                    .collect::<Vec<_>>();
+                write!(
+                    f,
+                    "Invalid arguments for tool {tool}: {}",
+                    violations.join("; ")
+                )
+            }
+        }
+    }
+}
+
+impl From<ToolArgumentError> for OpenAIError {
+    fn from(err: ToolArgumentError) -> Self {
+        OpenAIError::InvalidArgument(InvalidArgumentError::new("arguments", err.to_string()))
+    }
+}
+
+impl Tool {
+    /// Check the arguments against the tool's parameters schema.
+    ///
+    /// Tools without a schema accept any arguments.
+    pub fn validate_arguments(&self, arguments: &Value) -> Result<(), ToolArgumentError> {
+        let Some(schema) = &self.function.parameters else {
+            return Ok(());
+        };
+
+        let mut violations = Vec::new();
+        validate(schema, arguments, "", &mut violations);
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(ToolArgumentError::Invalid {
+                tool: self.function.name.clone(),
+                violations,
+            })
+        }
+    }
+}
+
+impl ToolCall {
+    /// Parse the JSON arguments of the call and check them against the tool's schema.
+    pub fn validated_arguments(&self, tool: &Tool) -> Result<Value, ToolArgumentError> {
+        let arguments = serde_json::from_str::<Value>(&self.function.arguments).map_err(|err| {
+            ToolArgumentError::Malformed {
+                tool: self.function.name.clone(),
+                message: err.to_string(),
+            }
+        })?;
+        tool.validate_arguments(&arguments)?;
+        Ok(arguments)
+    }
+}
+
+fn validate(schema: &Value, value: &Value, path: &str, violations: &mut Vec<ArgumentViolation>) {
+    // `true` and non-object schemas accept anything, `false` accepts nothing
+    let schema = match schema {
+        Value::Object(schema) => schema,
+        Value::Bool(false) => {
+            return violate(violations, path, "no value is allowed here".to_string())
+        }
+        _ => return,
+    };
+
+    if let Some(types) = schema.get("type") {
+        let types = match types {
+            Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+            Value::String(kind) => vec![kind.as_str()],
+            _ => vec![],
+        };
+        if !types.is_empty() && !types.iter().any(|kind| is_type(value, kind)) {
+            return violate(
+                violations,
+                path,
+                format!(
+                    "expected {}, found {}",
+                    types.join(" or "),
+                    type_name(value)
+                ),
+            );
+        }
+    }
+
+    if let Some(Value::Array(allowed)) = schema.get("enum") {
+        if !allowed.contains(value) {
+            let allowed = allowed.iter().map(Value::to_string).collect::<Vec<_>>();
+            violate(
+                violations,
+                path,
+                format!("{value} is not one of {}", allowed.join(", ")),
+            );
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            violate(violations, path, format!("expected {expected}"));
+        }
+    }
+
+    for keyword in ["anyOf", "oneOf"] {
+        if let Some(Value::Array(options)) = schema.get(keyword) {
+            let matching = options
+                .iter()
+                .filter(|option| {
+                    let mut option_violations = Vec::new();
+                    validate(option, value, path, &mut option_violations);
+                    option_violations.is_empty()
+                })
+                .count();
+            if matching == 0 {
+                violate(
+                    violations,
+                    path,
+                    "does not match any of the allowed schemas".to_string(),
+                );
+            } else if keyword == "oneOf" && matching > 1 {
+                violate(
+                    violations,
+                    path,
+                    "matches more than one of the allowed schemas".to_string(),
+                );
+            }
+        }
+    }
+    if let Some(Value::Array(all)) = schema.get("allOf") {
+        for option in all {
+            validate(option, value, path, violations);
+        }
+    }
+
+    match value {
+        Value::Object(object) => validate_object(schema, object, path, violations),
+        Value::Array(items) => validate_array(schema, items, path, violations),
+        Value::String(text) => {
+            let len = text.chars().count() as f64;
+            check_bound(schema, "minLength", path, violations, |min| {
+                (len < min).then(|| format!("must be at least {min} characters"))
+            });
+            check_bound(schema, "maxLength", path, violations, |max| {
+                (len > max).then(|| format!("must be at most {max} characters"))
+            });
+        }
+        Value::Number(number) => {
+            let number = number.as_f64().unwrap_or_default();
+            check_bound(schema, "minimum", path, violations, |min| {
+                (number < min).then(|| format!("must be at least {min}"))
+            });
+            check_bound(schema, "maximum", path, violations, |max| {
+                (number > max).then(|| format!("must be at most {max}"))
+            });
+            check_bound(schema, "exclusiveMinimum", path, violations, |min| {
+                (number <= min).then(|| format!("must be greater than {min}"))
+            });
+            check_bound(schema, "exclusiveMaximum", path, violations, |max| {
+                (number >= max).then(|| format!("must be less than {max}"))
+            });
+        }
+        Value::Null | Value::Bool(_) => (),
+    }
+}
+
+fn validate_object(
+    schema: &Map<String, Value>,
+    object: &Map<String, Value>,
+    path: &str,
+    violations: &mut Vec<ArgumentViolation>,
+) {
+    if let Some(Value::Array(required)) = schema.get("required") {
+        for key in required.iter().filter_map(Value::as_str) {
+            if !object.contains_key(key) {
+                violate(
+                    violations,
+                    &join(path, key),
+                    "is required but missing".to_string(),
+                );
+            }
+        }
+    }
+
+    let properties = schema.get("properties").and_then(Value::as_object);
+    for (key, value) in object {
+        match properties.and_then(|properties| properties.get(key)) {
+            Some(property) => validate(property, value, &join(path, key), violations),
+            None => match schema.get("additionalProperties") {
+                Some(Value::Bool(false)) => violate(
+                    violations,
+                    &join(path, key),
+                    "is not an allowed property".to_string(),
+                ),
+                Some(additional) => validate(additional, value, &join(path, key), violations),
+                None => (),
+            },
+        }
+    }
+}
+
+fn validate_array(
+    schema: &Map<String, Value>,
+    items: &[Value],
+    path: &str,
+    violations: &mut Vec<ArgumentViolation>,
+) {
+    let len = items.len() as f64;
+    check_bound(schema, "minItems", path, violations, |min| {
+        (len < min).then(|| format!("must have at least {min} items"))
+    });
+    check_bound(schema, "maxItems", path, violations, |max| {
+        (len > max).then(|| format!("must have at most {max} items"))
+    });
+
+    if let Some(item_schema) = schema.get("items") {
+        for (i, item) in items.iter().enumerate() {
+            validate(item_schema, item, &format!("{path}[{i}]"), violations);
+        }
+    }
+}
+
+fn check_bound<F>(
+    schema: &Map<String, Value>,
+    keyword: &str,
+    path: &str,
+    violations: &mut Vec<ArgumentViolation>,
+    check: F,
+) where
+    F: Fn(f64) -> Option<String>,
+{
+    if let Some(message) = schema.get(keyword).and_then(Value::as_f64).and_then(check) {
+        violate(violations, path, message);
+    }
+}
+
+fn is_type(value: &Value, kind: &str) -> bool {
+    match kind {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        "number" => value.is_number(),
+        // Floats with no fractional part, such as 2.0, are integers in JSON Schema
+        "integer" => value.as_f64().is_some_and(|number| number.fract() == 0.0),
+        _ => true,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Object(_) => "object",
+        Value::Array(_) => "array",
+        Value::String(_) => "string",
+        Value::Bool(_) => "boolean",
+        Value::Null => "null",
+        Value::Number(number) if number.is_f64() => "number",
+        Value::Number(_) => "integer",
+    }
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{path}.{key}")
+    }
+}
+
+fn violate(violations: &mut Vec<ArgumentViolation>, path: &str, message: String) {
+    violations.push(ArgumentViolation {
+        path: path.to_string(),
+        message,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::FunctionCall;
+
+    fn tool() -> Tool {
+        Tool::function(
+            "search",
+            "Search the catalog",
+            json!({
+                "type": "object",
+                "properties": {
+                    "query": {"type": "string", "minLength": 1},
+                    "sort": {"enum": ["price", "rating"]},
+                    "limit": {"type": "integer", "minimum": 1, "maximum": 50},
+                    "filters": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "properties": {"field": {"type": "string"}},
+                            "required": ["field"]
+                        }
+                    }
+                },
+                "required": ["query"],
+                "additionalProperties": false
+            }),
+        )
+    }
+
+    fn call(arguments: &str) -> ToolCall {
+        ToolCall {
+            id: "call_1".to_string(),
+            kind: "function".to_string(),
+            function: FunctionCall {
+                name: "search".to_string(),
+                arguments: arguments.to_string(),
+            },
+        }
+    }
+
+    #[test]
+    // Verify that arguments matching the schema are accepted
+    fn test_valid_arguments() {
+        let arguments = call(
+            r#"{"query": "lamp", "sort": "price", "limit": 10.0, "filters": [{"field": "color"}]}"#,
+        )
+        .validated_arguments(&tool())
+        .unwrap();
+        assert_eq!(arguments["query"], "lamp");
+    }
+
+    #[test]
+    // Verify that every violation is reported with its path
+    fn test_invalid_arguments() {
+        let err = call(r#"{"sort": "Price", "limit": 0, "filters": [{}], "page": 2}"#)
+            .validated_arguments(&tool())
+            .unwrap_err();
+
+        let ToolArgumentError::Invalid { violations, .. } = &err else {
+            panic!("expected violations, found {err:?}");
+        };
+        let paths = violations
+            .iter()
+            .map(|violation| violation.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec!["query", "filters[0].field", "limit", "page", "sort"]
+        );
+        assert!(err
+            .to_string()
+            .contains(r#"sort: "Price" is not one of "price", "rating""#));
+        assert!(err.to_string().contains("limit: must be at least 1"));
+    }
+
+    #[test]
+    // Verify that malformed JSON and wrong types are reported
+    fn test_malformed_and_type_errors() {
+        assert!(matches!(
+            call("{").validated_arguments(&tool()),
+            Err(ToolArgumentError::Malformed { .. })
+        ));
+        assert_eq!(
+            call(r#"{"query": 5}"#)
+                .validated_arguments(&tool())
+                .unwrap_err()
+                .to_string(),
+            "Invalid arguments for tool search: query: expected string, found integer"
+        );
+    }
+}
@@ -0,0 +1,144 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+use super::response::ChatChoice;
+
+/// An annotation on an assistant message, such as the sources cited by a search-enabled model.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Annotation {
+    /// The type of the annotation, such as `url_citation`
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// The cited web page, on `url_citation` annotations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url_citation: Option<UrlCitation>,
+}
+
+/// A web page cited by part of a message, as returned by the API.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct UrlCitation {
+    /// The index of the first character of the message which cites the page
+    pub start_index: usize,
+    /// The index after the last character of the message which cites the page
+    pub end_index: usize,
+    pub url: String,
+    #[serde(default)]
+    pub title: String,
+}
+
+/// A source cited by part of a choice's message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Citation {
+    pub url: String,
+    pub title: String,
+    /// The characters of the message which cite the source
+    pub span: Range<usize>,
+}
+
+impl Citation {
+    /// Returns the text of the message which cites the source, or `None` if the span is not
+    /// within the message.
+    pub fn cited_text<'a>(&self, content: &'a str) -> Option<&'a str> {
+        if self.span.start > self.span.end {
+            return None;
+        }
+        let mut indices = content
+            .char_indices()
+            .map(|(index, _)| index)
+            .chain([content.len()]);
+        let start = indices.nth(self.span.start)?;
+        let end = match self.span.end - self.span.start {
+            0 => start,
+            len => indices.nth(len - 1)?,
+        };
+        Some(&content[start..end])
+    }
+}
+
+impl ChatChoice {
+    /// Returns the web pages cited by the message, in the order the API listed them.
+    ///
+    /// Annotations other than URL citations are skipped.
+    pub fn citations(&self) -> Vec<Citation> {
+        self.message
+            .annotations
+            .iter()
+            .flatten()
+            .filter_map(|annotation| annotation.url_citation.as_ref())
+            .map(|citation| Citation {
+                url: citation.url.clone(),
+                title: citation.title.clone(),
+                span: citation.start_index..citation.end_index,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    // Verify that URL citations are read from the message annotations with their cited text
+    fn test_choice_citations() {
+        let choice: ChatChoice = serde_json::from_value(json!({
+            "index": 0,
+            "message": {
+                "role": "assistant",
+                "content": "Café prices rose 3% (Example News).",
+                "annotations": [
+                    {
+                        "type": "url_citation",
+                        "url_citation": {
+                            "start_index": 20,
+                            "end_index": 34,
+                            "url": "https://news.example.com/coffee",
+                            "title": "Coffee prices"
+                        }
+                    },
+                    {"type": "file_citation"}
+                ]
+            },
+            "finish_reason": "stop"
+        }))
+        .unwrap();
+
+        let citations = choice.citations();
+        assert_eq!(
+            citations,
+            vec![Citation {
+                url: "https://news.example.com/coffee".to_string(),
+                title: "Coffee prices".to_string(),
+                span: 20..34,
+            }]
+        );
+
+        let content = choice.message.content().as_text().unwrap();
+        assert_eq!(citations[0].cited_text(content), Some("(Example News)"));
+
+        let outside = Citation {
+            span: 30..40,
+            ..citations[0].clone()
+        };
+        assert_eq!(outside.cited_text(content), None);
+    }
+}
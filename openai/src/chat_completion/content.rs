@@ -0,0 +1,550 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ryst_error::{InternalError, InvalidArgumentError};
+use serde::{Deserialize, Serialize};
+
+use crate::error::OpenAIError;
+use crate::redact::{Redacted, RedactedOption};
+
+/// The largest image accepted by the vision models.
+const MAX_IMAGE_SIZE: u64 = 20 * 1024 * 1024;
+
+/// The largest image read to be scaled down, as it is decoded in memory.
+#[cfg(feature = "image")]
+const MAX_SOURCE_IMAGE_SIZE: u64 = 200 * 1024 * 1024;
+
+/// The largest file accepted as a `file` content part.
+const MAX_FILE_SIZE: u64 = 32 * 1024 * 1024;
+
+/// The content of a chat message.
+///
+/// Plain text is serialized as a string, while multi-part content (such as text combined with
+/// images) is serialized as an array of typed parts.
+///
+/// The text is redacted when debug printed, see [`crate::redact`].
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+impl MessageContent {
+    /// Returns the text if this is plain text content.
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            MessageContent::Text(text) => Some(text),
+            MessageContent::Parts(_) => None,
+        }
+    }
+}
+
+/// Deserialize content which may be `null`, such as on assistant messages which only contain tool
+/// calls, as empty text.
+pub(crate) fn deserialize_nullable<'de, D>(deserializer: D) -> Result<MessageContent, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Option::<MessageContent>::deserialize(deserializer).map(Option::unwrap_or_default)
+}
+
+impl fmt::Debug for MessageContent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageContent::Text(text) => f.debug_tuple("Text").field(&Redacted(text)).finish(),
+            MessageContent::Parts(parts) => f.debug_tuple("Parts").field(parts).finish(),
+        }
+    }
+}
+
+impl Default for MessageContent {
+    fn default() -> Self {
+        MessageContent::Text(String::new())
+    }
+}
+
+impl From<&str> for MessageContent {
+    fn from(text: &str) -> Self {
+        MessageContent::Text(text.to_string())
+    }
+}
+
+impl From<String> for MessageContent {
+    fn from(text: String) -> Self {
+        MessageContent::Text(text)
+    }
+}
+
+impl PartialEq<str> for MessageContent {
+    fn eq(&self, other: &str) -> bool {
+        self.as_text() == Some(other)
+    }
+}
+
+impl PartialEq<&str> for MessageContent {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_text() == Some(*other)
+    }
+}
+
+/// A single part of a multi-part message.
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+    File { file: FileInput },
+}
+
+impl fmt::Debug for ContentPart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContentPart::Text { text } => f
+                .debug_struct("Text")
+                .field("text", &Redacted(text))
+                .finish(),
+            ContentPart::ImageUrl { image_url } => f
+                .debug_struct("ImageUrl")
+                .field("image_url", image_url)
+                .finish(),
+            ContentPart::File { file } => f.debug_struct("File").field("file", file).finish(),
+        }
+    }
+}
+
+impl ContentPart {
+    /// Create a text part
+    pub fn text(text: &str) -> Self {
+        ContentPart::Text {
+            text: text.to_string(),
+        }
+    }
+
+    /// Create an image part referencing either a remote url or a `data:` url
+    pub fn image_url(url: &str) -> Self {
+        ContentPart::ImageUrl {
+            image_url: ImageUrl {
+                url: url.to_string(),
+                detail: None,
+            },
+        }
+    }
+
+    /// Create a file part referencing a file previously uploaded to the files API
+    pub fn file_id(file_id: &str) -> Self {
+        ContentPart::File {
+            file: FileInput {
+                file_id: Some(file_id.to_string()),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Create a file part embedding the file contents as a base64 `data:` url
+    pub fn file_data(filename: &str, data_url: &str) -> Self {
+        ContentPart::File {
+            file: FileInput {
+                filename: Some(filename.to_string()),
+                file_data: Some(data_url.to_string()),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// A document, such as a PDF, passed to the model.
+///
+/// Either `file_id` or `file_data` should be set.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Default)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct FileInput {
+    /// The ID of a file previously uploaded to the files API
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_id: Option<String>,
+    /// The name of the file, used when passing the file as `file_data`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    /// The base64 encoded file contents as a `data:` url
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_data: Option<String>,
+}
+
+/// The location of an image passed to a vision model.
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ImageUrl {
+    pub url: String,
+    /// The fidelity the model should use to view the image: `low`, `high` or `auto`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl fmt::Debug for FileInput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileInput")
+            .field("file_id", &self.file_id)
+            .field("filename", &RedactedOption(self.filename.as_deref()))
+            .field("file_data", &RedactedOption(self.file_data.as_deref()))
+            .finish()
+    }
+}
+
+impl fmt::Debug for ImageUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ImageUrl")
+            .field("url", &Redacted(&self.url))
+            .field("detail", &self.detail)
+            .finish()
+    }
+}
+
+/// Read an image from disk and encode it as a base64 `data:` url.
+pub(crate) fn image_data_url(path: &Path) -> Result<String, OpenAIError> {
+    let bytes = read_file(path, MAX_IMAGE_SIZE)?;
+    let mime = image_mime_type(path, &bytes)?;
+    Ok(data_url(mime, &bytes))
+}
+
+/// Read an image from disk, shrink it so neither side exceeds `max_dimension`, and encode it as a
+/// base64 `data:` url.
+///
+/// Images already within the limit are sent unchanged. The size limit of the vision models is
+/// checked after scaling, so images too large to send as they are can still be scaled down.
+#[cfg(feature = "image")]
+pub(crate) fn scaled_image_data_url(
+    path: &Path,
+    max_dimension: u32,
+) -> Result<String, OpenAIError> {
+    scaled_image_data_url_within(path, max_dimension, MAX_IMAGE_SIZE)
+}
+
+#[cfg(feature = "image")]
+fn scaled_image_data_url_within(
+    path: &Path,
+    max_dimension: u32,
+    max_size: u64,
+) -> Result<String, OpenAIError> {
+    use std::io::Cursor;
+
+    use image::{GenericImageView, ImageFormat};
+
+    let bytes = read_file(path, MAX_SOURCE_IMAGE_SIZE)?;
+    let mime = image_mime_type(path, &bytes)?;
+
+    let img = image::load_from_memory(&bytes).map_err(|err| {
+        OpenAIError::InvalidArgument(InvalidArgumentError::new(
+            "path",
+            format!("Unable to decode image: {err}"),
+        ))
+    })?;
+
+    let (width, height) = img.dimensions();
+    if width <= max_dimension && height <= max_dimension {
+        check_size("File", bytes.len() as u64, max_size)?;
+        return Ok(data_url(mime, &bytes));
+    }
+
+    // GIF and WEBP encoding support is limited, so anything other than JPEG is re-encoded as PNG
+    let (format, mime) = match mime {
+        "image/jpeg" => (ImageFormat::Jpeg, "image/jpeg"),
+        _ => (ImageFormat::Png, "image/png"),
+    };
+
+    let mut scaled = Cursor::new(Vec::new());
+    img.resize(
+        max_dimension,
+        max_dimension,
+        image::imageops::FilterType::Lanczos3,
+    )
+    .write_to(&mut scaled, format)
+    .map_err(|err| {
+        OpenAIError::Internal(InternalError::from_source_with_prefix(
+            Box::new(err),
+            "Unable to encode scaled image",
+        ))
+    })?;
+
+    check_size("Scaled image", scaled.get_ref().len() as u64, max_size)?;
+    Ok(data_url(mime, scaled.get_ref()))
+}
+
+/// Read a file from disk and encode it as a `file` content part.
+pub(crate) fn file_part(path: &Path) -> Result<ContentPart, OpenAIError> {
+    let bytes = read_file(path, MAX_FILE_SIZE)?;
+    let mime = file_mime_type(path, &bytes)?;
+    let filename = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    Ok(ContentPart::file_data(&filename, &data_url(mime, &bytes)))
+}
+
+/// Read a file, checking its size before loading it into memory.
+fn read_file(path: &Path, max_size: u64) -> Result<Vec<u8>, OpenAIError> {
+    let to_internal = |err: std::io::Error| {
+        OpenAIError::Internal(InternalError::from_source_with_prefix(
+            Box::new(err),
+            format!("Unable to read {}", path.display()),
+        ))
+    };
+
+    check_size(
+        "File",
+        fs::metadata(path).map_err(to_internal)?.len(),
+        max_size,
+    )?;
+    fs::read(path).map_err(to_internal)
+}
+
+fn check_size(what: &str, size: u64, max_size: u64) -> Result<(), OpenAIError> {
+    if size > max_size {
+        return Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
+            "path",
+            format!("{what} is {size} bytes, the maximum size is {max_size} bytes"),
+        )));
+    }
+    Ok(())
+}
+
+fn data_url(mime: &str, bytes: &[u8]) -> String {
+    format!("data:{mime};base64,{}", STANDARD.encode(bytes))
+}
+
+/// Determine the MIME type of an image supported by the vision models.
+///
+/// The file's magic bytes take precedence, the extension is only used when the contents are not
+/// recognized.
+fn image_mime_type(path: &Path, bytes: &[u8]) -> Result<&'static str, OpenAIError> {
+    if bytes.starts_with(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]) {
+        return Ok("image/png");
+    }
+    if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        return Ok("image/jpeg");
+    }
+    if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        return Ok("image/gif");
+    }
+    if bytes.len() >= 12 && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        return Ok("image/webp");
+    }
+
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("png") => Ok("image/png"),
+        Some("jpg") | Some("jpeg") => Ok("image/jpeg"),
+        Some("gif") => Ok("image/gif"),
+        Some("webp") => Ok("image/webp"),
+        _ => Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
+            "path",
+            "Unsupported image type, expected PNG, JPEG, GIF or WEBP",
+        ))),
+    }
+}
+
+/// Determine the MIME type of a document passed as a `file` content part.
+fn file_mime_type(path: &Path, bytes: &[u8]) -> Result<&'static str, OpenAIError> {
+    if bytes.starts_with(b"%PDF-") {
+        return Ok("application/pdf");
+    }
+
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("pdf") => Ok("application/pdf"),
+        Some("txt") => Ok("text/plain"),
+        Some("md") => Ok("text/markdown"),
+        Some("csv") => Ok("text/csv"),
+        Some("json") => Ok("application/json"),
+        Some("html") | Some("htm") => Ok("text/html"),
+        Some("docx") => {
+            Ok("application/vnd.openxmlformats-officedocument.wordprocessingml.document")
+        }
+        _ => Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
+            "path",
+            "Unsupported file type",
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Verify that plain text content serializes as a string and parts serialize as typed objects
+    fn test_content_serialization() {
+        let text = MessageContent::from("hello");
+        assert_eq!(serde_json::to_string(&text).unwrap(), "\"hello\"");
+
+        let parts = MessageContent::Parts(vec![
+            ContentPart::text("what is this?"),
+            ContentPart::image_url("https://example.com/a.png"),
+        ]);
+        assert_eq!(
+            serde_json::to_string(&parts).unwrap(),
+            "[{\"type\":\"text\",\"text\":\"what is this?\"},\
+             {\"type\":\"image_url\",\"image_url\":{\"url\":\"https://example.com/a.png\"}}]"
+        );
+
+        let round_trip: MessageContent =
+            serde_json::from_str(&serde_json::to_string(&parts).unwrap()).unwrap();
+        assert_eq!(round_trip, parts);
+    }
+
+    #[test]
+    // Verify that the MIME type is detected from the magic bytes before the extension
+    fn test_image_mime_type() {
+        let png = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, 0, 0];
+        assert_eq!(
+            image_mime_type(Path::new("image.jpg"), &png).unwrap(),
+            "image/png"
+        );
+        assert_eq!(
+            image_mime_type(Path::new("image.JPEG"), b"unknown").unwrap(),
+            "image/jpeg"
+        );
+        assert_eq!(
+            image_mime_type(Path::new("image.webp"), b"RIFF\0\0\0\0WEBPVP8 ").unwrap(),
+            "image/webp"
+        );
+        assert!(image_mime_type(Path::new("notes.txt"), b"hello").is_err());
+    }
+
+    #[test]
+    // Verify that an image read from disk is encoded as a base64 data url
+    fn test_image_data_url() {
+        let path = std::env::temp_dir().join("ryst_test_image_data_url.gif");
+        fs::write(&path, b"GIF89a").unwrap();
+
+        let url = image_data_url(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(url, "data:image/gif;base64,R0lGODlh");
+    }
+
+    #[test]
+    // Verify that file parts serialize with only the fields that are set
+    fn test_file_part_serialization() {
+        assert_eq!(
+            serde_json::to_string(&ContentPart::file_id("file-abc")).unwrap(),
+            "{\"type\":\"file\",\"file\":{\"file_id\":\"file-abc\"}}"
+        );
+        assert_eq!(
+            serde_json::to_string(&ContentPart::file_data(
+                "a.pdf",
+                "data:application/pdf;base64,"
+            ))
+            .unwrap(),
+            "{\"type\":\"file\",\"file\":{\"filename\":\"a.pdf\",\
+             \"file_data\":\"data:application/pdf;base64,\"}}"
+        );
+    }
+
+    #[test]
+    // Verify that a PDF read from disk is detected and embedded with its file name
+    fn test_file_part() {
+        let path = std::env::temp_dir().join("ryst_test_file_part.bin");
+        fs::write(&path, b"%PDF-1.7").unwrap();
+
+        let part = file_part(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            part,
+            ContentPart::file_data(
+                "ryst_test_file_part.bin",
+                "data:application/pdf;base64,JVBERi0xLjc="
+            )
+        );
+    }
+
+    #[test]
+    // Verify that files larger than the maximum size are rejected before being read
+    fn test_read_file_too_large() {
+        let path = std::env::temp_dir().join("ryst_test_read_file_too_large.txt");
+        fs::write(&path, b"0123456789").unwrap();
+
+        let result = read_file(&path, 4);
+        fs::remove_file(&path).unwrap();
+
+        match result {
+            Err(OpenAIError::InvalidArgument(err)) => assert_eq!(err.argument(), "path"),
+            res => panic!("expected InvalidArgument, got {res:?}"),
+        }
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    // Verify that images larger than the max dimension are scaled down, preserving aspect ratio
+    fn test_scaled_image_data_url() {
+        use image::{GenericImageView, ImageFormat, RgbImage};
+
+        let path = std::env::temp_dir().join("ryst_test_scaled_image_data_url.png");
+        RgbImage::new(400, 200)
+            .save_with_format(&path, ImageFormat::Png)
+            .unwrap();
+
+        let url = scaled_image_data_url(&path, 100).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let encoded = url.strip_prefix("data:image/png;base64,").unwrap();
+        let scaled = image::load_from_memory(&STANDARD.decode(encoded).unwrap()).unwrap();
+        assert_eq!(scaled.dimensions(), (100, 50));
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    // Verify that the size limit is checked after scaling, not on the original image
+    fn test_scaled_image_size_checked_after_scaling() {
+        use image::{ImageFormat, RgbImage};
+
+        let path = std::env::temp_dir().join("ryst_test_scaled_image_size.png");
+        // Noise compresses poorly, so the original is far larger than the scaled image
+        let mut seed = 1u32;
+        RgbImage::from_fn(400, 400, |_, _| {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let [r, g, b, _] = seed.to_le_bytes();
+            image::Rgb([r, g, b])
+        })
+        .save_with_format(&path, ImageFormat::Png)
+        .unwrap();
+        let original = fs::metadata(&path).unwrap().len();
+
+        let scaled = scaled_image_data_url_within(&path, 50, original / 4);
+        let unscaled = scaled_image_data_url_within(&path, 400, original / 4);
+        let too_small = scaled_image_data_url_within(&path, 50, 100);
+        fs::remove_file(&path).unwrap();
+
+        assert!(scaled.is_ok());
+        assert!(matches!(unscaled, Err(OpenAIError::InvalidArgument(_))));
+        assert!(matches!(too_small, Err(OpenAIError::InvalidArgument(_))));
+    }
+}
@@ -0,0 +1,174 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Content filter annotations returned by Azure OpenAI.
+
+use serde::{Deserialize, Serialize};
+
+/// The severity assigned to content by a content filter category.
+///
+/// Severities are ordered from `Safe` to `High`. Severities this crate does not recognize are
+/// read as `Unknown`, which orders above `High` so that they are never mistaken for safe content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum FilterSeverity {
+    Safe,
+    Low,
+    Medium,
+    High,
+    #[serde(other)]
+    Unknown,
+}
+
+/// The result of a content filter category which grades severity
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SeverityResult {
+    pub filtered: bool,
+    pub severity: FilterSeverity,
+}
+
+/// The result of a content filter category which only detects
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct DetectionResult {
+    pub filtered: bool,
+    pub detected: bool,
+}
+
+/// An error which prevented the content filter from running
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ContentFilterError {
+    pub code: String,
+    pub message: String,
+}
+
+/// The content filter results for a prompt or choice. Categories which were not run are `None`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ContentFilterResults {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hate: Option<SeverityResult>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub self_harm: Option<SeverityResult>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sexual: Option<SeverityResult>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub violence: Option<SeverityResult>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jailbreak: Option<DetectionResult>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profanity: Option<DetectionResult>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protected_material_text: Option<DetectionResult>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protected_material_code: Option<DetectionResult>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ContentFilterError>,
+}
+
+impl ContentFilterResults {
+    /// Returns whether any category filtered the content.
+    pub fn is_filtered(&self) -> bool {
+        self.severities().any(|result| result.filtered)
+            || self.detections().any(|result| result.filtered)
+    }
+
+    /// Returns the highest severity across the graded categories, if any were graded.
+    pub fn max_severity(&self) -> Option<FilterSeverity> {
+        self.severities().map(|result| result.severity).max()
+    }
+
+    fn severities(&self) -> impl Iterator<Item = &SeverityResult> {
+        [&self.hate, &self.self_harm, &self.sexual, &self.violence]
+            .into_iter()
+            .flatten()
+    }
+
+    fn detections(&self) -> impl Iterator<Item = &DetectionResult> {
+        [
+            &self.jailbreak,
+            &self.profanity,
+            &self.protected_material_text,
+            &self.protected_material_code,
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
+/// The content filter results for one of the prompts of a request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct PromptFilterResult {
+    pub prompt_index: i32,
+    #[serde(default)]
+    pub content_filter_results: ContentFilterResults,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::ChatCompletionResponse;
+
+    #[test]
+    // Verify that Azure content filter annotations are read from a response
+    fn test_azure_response() {
+        let response: ChatCompletionResponse = serde_json::from_value(json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 1700000000,
+            "model": "gpt-4o",
+            "prompt_filter_results": [{
+                "prompt_index": 0,
+                "content_filter_results": {
+                    "hate": {"filtered": false, "severity": "safe"},
+                    "jailbreak": {"filtered": false, "detected": false}
+                }
+            }],
+            "choices": [{
+                "message": {"role": "assistant", "content": "..."},
+                "index": 0,
+                "finish_reason": "content_filter",
+                "content_filter_results": {
+                    "hate": {"filtered": false, "severity": "low"},
+                    "violence": {"filtered": true, "severity": "medium"}
+                }
+            }],
+            "usage": {"prompt_tokens": 1, "completion_tokens": 2, "total_tokens": 3}
+        }))
+        .unwrap();
+
+        let prompt = &response.prompt_filter_results.as_ref().unwrap()[0];
+        assert!(!prompt.content_filter_results.is_filtered());
+        assert_eq!(
+            prompt.content_filter_results.max_severity(),
+            Some(FilterSeverity::Safe)
+        );
+
+        let results = response.choices[0].content_filter_results.as_ref().unwrap();
+        assert!(results.is_filtered());
+        assert_eq!(results.max_severity(), Some(FilterSeverity::Medium));
+
+        let unknown: SeverityResult =
+            serde_json::from_value(json!({"filtered": false, "severity": "very_high"})).unwrap();
+        assert_eq!(unknown.severity, FilterSeverity::Unknown);
+        assert!(FilterSeverity::Unknown > FilterSeverity::High);
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Deterministic chat completion responses for use in downstream unit tests.
+
+use crate::tags::RequestTags;
+
+use super::{ChatChoice, ChatCompletionResponse, ChatUsage, Message};
+
+const FAKE_ID: &str = "chatcmpl-fake";
+const FAKE_MODEL: &str = "gpt-3.5-turbo";
+
+impl ChatCompletionResponse {
+    /// Create a response containing a single assistant choice with the given content.
+    pub fn fake_with_content(content: &str) -> Self {
+        Self::fake_with_choices(vec![ChatChoice::fake(0, content)])
+    }
+
+    /// Create a response containing the given choices.
+    ///
+    /// The usage reports one completion token per whitespace separated word in the choices.
+    pub fn fake_with_choices(choices: Vec<ChatChoice>) -> Self {
+        let completion_tokens = choices
+            .iter()
+            .map(|choice| {
+                choice
+                    .message
+                    .content
+                    .as_text()
+                    .map(|text| text.split_whitespace().count() as i32)
+                    .unwrap_or(0)
+            })
+            .sum();
+
+        Self {
+            id: FAKE_ID.to_string(),
+            object: "chat.completion".into(),
+            created: 0,
+            model: FAKE_MODEL.into(),
+            choices,
+            usage: ChatUsage::fake(0, completion_tokens),
+            prompt_filter_results: None,
+            tags: RequestTags::default(),
+        }
+    }
+
+    /// Replace the model of a fake response.
+    pub fn with_model(mut self, model: &str) -> Self {
+        self.model = model.into();
+        self
+    }
+
+    /// Replace the usage of a fake response.
+    pub fn with_usage(mut self, usage: ChatUsage) -> Self {
+        self.usage = usage;
+        self
+    }
+}
+
+impl ChatChoice {
+    /// Create an assistant choice that finished with `stop`.
+    pub fn fake(index: i32, content: &str) -> Self {
+        Self {
+            message: Message::new("assistant", content),
+            index,
+            logprobs: None,
+            finish_reason: "stop".into(),
+            content_filter_results: None,
+        }
+    }
+
+    /// Replace the finish reason of a fake choice, e.g. `length` or `content_filter`.
+    pub fn with_finish_reason(mut self, finish_reason: &str) -> Self {
+        self.finish_reason = finish_reason.into();
+        self
+    }
+}
+
+impl ChatUsage {
+    /// Create usage with the total computed from the prompt and completion tokens.
+    pub fn fake(prompt_tokens: i32, completion_tokens: i32) -> Self {
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Verify that a fake response is deterministic and contains the given content
+    fn test_fake_with_content() {
+        let response = ChatCompletionResponse::fake_with_content("hi there");
+
+        assert_eq!(
+            response,
+            ChatCompletionResponse::fake_with_content("hi there")
+        );
+        assert_eq!(response.choices.len(), 1);
+        assert_eq!(response.choices[0].message.content, "hi there");
+        assert_eq!(&*response.choices[0].message.role, "assistant");
+        assert_eq!(response.usage, ChatUsage::fake(0, 2));
+    }
+
+    #[test]
+    // Verify that fake choices and usage can be customized
+    fn test_fake_with_choices() {
+        let response = ChatCompletionResponse::fake_with_choices(vec![
+            ChatChoice::fake(0, "a"),
+            ChatChoice::fake(1, "b").with_finish_reason("length"),
+        ])
+        .with_model("gpt-4")
+        .with_usage(ChatUsage::fake(10, 5));
+
+        assert_eq!(&*response.model, "gpt-4");
+        assert_eq!(response.choices[1].index, 1);
+        assert_eq!(&*response.choices[1].finish_reason, "length");
+        assert_eq!(response.usage.total_tokens, 15);
+    }
+}
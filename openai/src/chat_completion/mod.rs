@@ -15,11 +15,33 @@
 //! This module contains a set of structs for communicating with OpenAI
 //! completions API.
 
+mod arguments;
+mod citations;
+mod content;
+mod content_filter;
+#[cfg(feature = "testing")]
+mod fixtures;
+mod multi_stream;
 mod request;
 mod response;
-
+mod roles;
+mod tools;
+
+pub use arguments::{ArgumentViolation, ToolArgumentError};
+pub use citations::{Annotation, Citation, UrlCitation};
+pub use content::{ContentPart, FileInput, ImageUrl, MessageContent};
+pub use content_filter::{
+    ContentFilterError, ContentFilterResults, DetectionResult, FilterSeverity, PromptFilterResult,
+    SeverityResult,
+};
+pub use multi_stream::MultiStream;
 pub use request::{ChatCompletionRequest, Message};
-pub use response::{ChatChoice, ChatCompletionResponse, ChatCompletionResponseStream, ChatUsage};
+pub use response::{
+    ChatChoice, ChatCompletionResponse, ChatCompletionResponseStream, ChatLogprobs, ChatUsage,
+    TokenLogprob, TopLogprob,
+};
+pub use roles::{AssistantMessage, SystemMessage, ToolMessage, UserMessage};
+pub use tools::{FunctionCall, FunctionDefinition, Tool, ToolCall};
 
 // The following tests require that OPENAI_API_KEY (optionally OPENAI_API_ORG)
 // are set. We are using the "ada" model as this is the cheapest and the tests
@@ -86,6 +108,22 @@ mod tests {
         assert!(response_none.is_none());
     }
 
+    #[tokio::test]
+    // Verify that a chat completion with logprobs returns with logprobs correctly
+    async fn test_chat_completion_logprobs() {
+        let response = ChatCompletionRequest::new(
+            "gpt-3.5-turbo",
+            &[Message::new("user", "Say this is a test.")],
+        )
+        .with_logprobs(true)
+        .with_top_logprobs(2)
+        .submit()
+        .await
+        .unwrap();
+
+        assert!(response.choices[0].mean_logprob().is_some());
+    }
+
     #[tokio::test]
     // Verify that a chat complicated completion returns as expected
     async fn test_chat_completion_max_tokens_n() {
@@ -144,4 +182,30 @@ mod tests {
 
         assert!(!response.choices.is_empty());
     }
+
+    #[tokio::test]
+    // Verify that a tool is called when the prompt requires it
+    async fn test_chat_completion_tool_call() {
+        let tool = Tool::function(
+            "get_weather",
+            "Get the current weather for a city",
+            serde_json::json!({
+                "type": "object",
+                "properties": {"city": {"type": "string"}},
+                "required": ["city"]
+            }),
+        );
+
+        let response = ChatCompletionRequest::new(
+            "gpt-3.5-turbo",
+            &[Message::new("user", "What is the weather in Paris?")],
+        )
+        .with_tools(&[tool])
+        .submit()
+        .await
+        .unwrap();
+
+        let calls = response.choices[0].message.tool_calls();
+        assert_eq!(calls[0].function.name, "get_weather");
+    }
 }
@@ -0,0 +1,164 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::stream::{self, BoxStream, SelectAll};
+use futures::{Stream, StreamExt};
+
+use crate::error::OpenAIError;
+
+use super::{ChatCompletionResponse, ChatCompletionResponseStream};
+
+/// Drives several chat completion streams concurrently, merging their output into one stream.
+///
+/// Each stream is added with a key identifying it, such as a request ID or a model name. Items
+/// are yielded tagged with the key of the stream that produced them, in the order they arrive.
+/// A stream that returns an error yields it once and is then dropped, without affecting the
+/// other streams.
+pub struct MultiStream<K> {
+    streams: SelectAll<BoxStream<'static, (K, Result<ChatCompletionResponse, OpenAIError>)>>,
+}
+
+impl<K: Clone + Send + 'static> MultiStream<K> {
+    /// Create an empty `MultiStream`.
+    pub fn new() -> Self {
+        Self {
+            streams: SelectAll::new(),
+        }
+    }
+
+    /// Add a stream whose items will be tagged with `key`.
+    pub fn push(&mut self, key: K, stream: ChatCompletionResponseStream) {
+        let tagged = stream::unfold(Some(stream), move |state| {
+            let key = key.clone();
+            async move {
+                let mut stream = state?;
+                match stream.next().await {
+                    Ok(Some(response)) => Some(((key, Ok(response)), Some(stream))),
+                    Ok(None) => None,
+                    Err(err) => Some(((key, Err(err)), None)),
+                }
+            }
+        });
+
+        self.streams.push(tagged.boxed());
+    }
+
+    /// Returns the number of streams that have not finished.
+    pub fn len(&self) -> usize {
+        self.streams.len()
+    }
+
+    /// Returns true if every stream has finished.
+    pub fn is_empty(&self) -> bool {
+        self.streams.is_empty()
+    }
+
+    /// Get the next item from whichever stream produces one first.
+    ///
+    /// Returns `None` once every stream has finished.
+    pub async fn next(&mut self) -> Option<(K, Result<ChatCompletionResponse, OpenAIError>)> {
+        self.streams.next().await
+    }
+}
+
+impl<K: Clone + Send + 'static> Default for MultiStream<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Clone + Send + 'static> FromIterator<(K, ChatCompletionResponseStream)> for MultiStream<K> {
+    fn from_iter<I: IntoIterator<Item = (K, ChatCompletionResponseStream)>>(iter: I) -> Self {
+        let mut multi = Self::new();
+        for (key, stream) in iter {
+            multi.push(key, stream);
+        }
+        multi
+    }
+}
+
+impl<K> Stream for MultiStream<K> {
+    type Item = (K, Result<ChatCompletionResponse, OpenAIError>);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.streams.poll_next_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+
+    fn response_stream(content: &str) -> ChatCompletionResponseStream {
+        let json = format!(
+            r#"{{"id":"chatcmpl-1","object":"chat.completion","created":0,"model":"gpt-3.5-turbo",
+            "choices":[{{"message":{{"role":"assistant","content":"{content}"}},"index":0,
+            "finish_reason":"stop"}}],"usage":{{"prompt_tokens":1,"completion_tokens":1,
+            "total_tokens":2}}}}"#
+        );
+        ChatCompletionResponseStream::new(Box::pin(stream::iter(vec![Ok(Bytes::from(json))])))
+    }
+
+    #[tokio::test]
+    // Verify that items from every stream are yielded tagged with their stream's key
+    async fn test_multi_stream() {
+        let mut multi: MultiStream<&str> = vec![
+            ("a", response_stream("first")),
+            ("b", response_stream("second")),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(multi.len(), 2);
+
+        let mut items = Vec::new();
+        while let Some((key, result)) = multi.next().await {
+            let content = result.unwrap().choices[0].message.content.clone();
+            items.push((key, content));
+        }
+        items.sort_by_key(|(key, _)| *key);
+
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].0, "a");
+        assert_eq!(items[0].1, "first");
+        assert_eq!(items[1].0, "b");
+        assert_eq!(items[1].1, "second");
+        assert!(multi.is_empty());
+    }
+
+    #[tokio::test]
+    // Verify that an error ends only the stream that produced it
+    async fn test_multi_stream_error() {
+        let mut multi = MultiStream::new();
+        multi.push(1, response_stream("ok"));
+        multi.push(
+            2,
+            ChatCompletionResponseStream::new(Box::pin(stream::iter(vec![Ok(Bytes::from(
+                "not json",
+            ))]))),
+        );
+
+        let mut results: Vec<(i32, bool)> = multi
+            .map(|(key, result)| (key, result.is_ok()))
+            .collect()
+            .await;
+        results.sort();
+
+        assert_eq!(results, vec![(1, true), (2, false)]);
+    }
+}
@@ -12,45 +12,169 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::env;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use reqwest::Client;
-use ryst_error::{InternalError, InvalidArgumentError, InvalidStateError};
-use serde::{Deserialize, Serialize};
+use ryst_error::{InvalidArgumentError, InvalidStateError};
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::{Map, Value};
 
+use crate::choice::{BestOf, ChoiceStrategy};
+use crate::client::OpenAIClient;
+use crate::clock::Jitter;
+use crate::credentials::KeySource;
 use crate::error::OpenAIError;
-use crate::OPEN_AI_URL;
+use crate::http::{self, PreSendHook, RequestOptions, ResponseMetadata};
+use crate::intern;
+use crate::judge::Judge;
+use crate::latency::SlowResponsePolicy;
+use crate::rate_limit::RateLimiter;
+use crate::redact::{RedactedOption, RedactedValues};
+use crate::retry::{self, RateLimitRetry, RetryEvent, RetryObserver, SharedRetryObserver};
+use crate::tags::RequestTags;
+use crate::trace::{ExchangeTrace, TraceEvent};
 
+use super::citations::Annotation;
+use super::content::{self, ContentPart, MessageContent};
+use super::tools::{Tool, ToolCall};
 use super::{ChatCompletionResponse, ChatCompletionResponseStream};
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Default, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Message {
-    pub role: String,
-    pub content: String,
+    #[serde(deserialize_with = "crate::intern::deserialize")]
+    pub role: Arc<str>,
+    #[serde(default, deserialize_with = "content::deserialize_nullable")]
+    pub content: MessageContent,
+    /// The tools the model called, on assistant messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    /// The ID of the tool call this message is the result of, on tool messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    /// The sources cited by the message, on assistant messages from search-enabled models
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<Vec<Annotation>>,
 }
 
 impl Message {
     pub fn new(role: &str, content: &str) -> Self {
         Self {
-            role: role.to_string(),
-            content: content.to_string(),
+            role: intern::intern(role),
+            content: content.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Create a message made up of multiple content parts, such as text and images.
+    pub fn with_parts(role: &str, parts: &[ContentPart]) -> Self {
+        Self {
+            role: intern::intern(role),
+            content: MessageContent::Parts(parts.to_vec()),
+            ..Default::default()
+        }
+    }
+
+    /// Create a tool message containing the result of a tool call.
+    pub fn tool_result(tool_call_id: &str, content: &str) -> Self {
+        Self {
+            role: intern::intern("tool"),
+            content: content.into(),
+            tool_call_id: Some(tool_call_id.to_string()),
+            ..Default::default()
         }
     }
 
+    /// Create a user message containing a local image.
+    ///
+    /// The image is read from `path` and embedded as a base64 `data:` url. PNG, JPEG, GIF and
+    /// WEBP images are supported.
+    pub fn user_with_image_path<P: AsRef<Path>>(path: P) -> Result<Self, OpenAIError> {
+        let url = content::image_data_url(path.as_ref())?;
+        Ok(Self::with_parts("user", &[ContentPart::image_url(&url)]))
+    }
+
+    /// Create a user message containing a local document, such as a PDF.
+    ///
+    /// The file is read from `path` and embedded as base64 `file_data`. Files over 32MB are
+    /// rejected. To reference a file already uploaded to the files API, use
+    /// `ContentPart::file_id` with `Message::with_parts` instead.
+    pub fn user_with_file_path<P: AsRef<Path>>(path: P) -> Result<Self, OpenAIError> {
+        let part = content::file_part(path.as_ref())?;
+        Ok(Self::with_parts("user", &[part]))
+    }
+
+    /// Create a user message containing a local image, downscaled so that neither side is larger
+    /// than `max_dimension` pixels.
+    ///
+    /// Large images are resized by the API anyway, so scaling them down locally reduces the size
+    /// of the request without changing the result. The API's 20MB limit applies to the scaled
+    /// image, so larger files can be sent this way.
+    #[cfg(feature = "image")]
+    pub fn user_with_scaled_image_path<P: AsRef<Path>>(
+        path: P,
+        max_dimension: u32,
+    ) -> Result<Self, OpenAIError> {
+        let url = content::scaled_image_data_url(path.as_ref(), max_dimension)?;
+        Ok(Self::with_parts("user", &[ContentPart::image_url(&url)]))
+    }
+
     pub fn role(&self) -> &str {
         &self.role
     }
 
-    pub fn content(&self) -> &str {
+    pub fn content(&self) -> &MessageContent {
         &self.content
     }
+
+    /// Returns the tool calls made by the model, which is empty if there are none.
+    pub fn tool_calls(&self) -> &[ToolCall] {
+        self.tool_calls.as_deref().unwrap_or_default()
+    }
 }
 
+/// The prompt sent after the partial reply when resuming an interrupted stream.
+const CONTINUE_PROMPT: &str =
+    "Your reply was cut off. Continue exactly where it stopped, without repeating any of it.";
+
+/// The wait before the first reconnect of a stream, when not set with `with_reconnect_delay`.
+const DEFAULT_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+/// The longest wait between reconnects, unless the first wait is set longer.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// The fields of the request body, which extra fields may not replace.
+const FIELDS: &[&str] = &[
+    "model",
+    "messages",
+    "temperature",
+    "top_p",
+    "n",
+    "stream",
+    "stop",
+    "max_tokens",
+    "presence_penalty",
+    "frequency_penalty",
+    "logit_bias",
+    "logprobs",
+    "top_logprobs",
+    "user",
+    "tools",
+];
+
 /// Builder for creating the chat completion request and submitting to OpenAI API.
-#[derive(Debug, Serialize, PartialEq, Default)]
+///
+/// Prompts, the user identifier and extra fields are redacted when debug printed, see
+/// [`crate::redact`].
+#[derive(Serialize, Deserialize, PartialEq, Default, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct ChatCompletionRequest {
     model: String,
+    #[serde(serialize_with = "serialize_messages")]
     messages: Vec<Message>,
     #[serde(skip_serializing_if = "Option::is_none")]
     temperature: Option<f32>,
@@ -71,7 +195,63 @@ pub struct ChatCompletionRequest {
     #[serde(skip_serializing_if = "Option::is_none")]
     logit_bias: Option<HashMap<String, i8>>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    logprobs: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_logprobs: Option<i8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tools: Option<Vec<Tool>>,
+    /// Fields added with `with_extra`, which are sent after the fields above
+    #[serde(flatten)]
+    extra: Map<String, Value>,
+    /// Set with `with_client_best_of` or `with_client_best_of_by`, reducing the response to its
+    /// best choice
+    #[serde(skip)]
+    client_best_of: Option<(i8, BestOf)>,
+    /// Set with `with_resume_on_interrupt`, the number of times an interrupted stream is resumed
+    #[serde(skip)]
+    resume_attempts: u32,
+    /// Set with `with_reconnect`, the number of times a failed stream connection is reopened
+    #[serde(skip)]
+    reconnect_attempts: u32,
+    /// Set with `with_reconnect_delay`, the wait before the first reconnect
+    #[serde(skip)]
+    reconnect_delay: Option<Duration>,
+    /// Set with `with_reconnect_jitter`, randomizing the waits before reconnecting
+    #[serde(skip)]
+    reconnect_jitter: Option<Jitter>,
+    #[serde(skip)]
+    options: RequestOptions,
+}
+
+impl fmt::Debug for ChatCompletionRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChatCompletionRequest")
+            .field("model", &self.model)
+            .field("messages", &self.messages)
+            .field("temperature", &self.temperature)
+            .field("top_p", &self.top_p)
+            .field("n", &self.n)
+            .field("stream", &self.stream)
+            .field("stop", &self.stop)
+            .field("max_tokens", &self.max_tokens)
+            .field("presence_penalty", &self.presence_penalty)
+            .field("frequency_penalty", &self.frequency_penalty)
+            .field("logit_bias", &self.logit_bias)
+            .field("logprobs", &self.logprobs)
+            .field("top_logprobs", &self.top_logprobs)
+            .field("user", &RedactedOption(self.user.as_deref()))
+            .field("tools", &self.tools)
+            .field("extra", &RedactedValues(&self.extra))
+            .field("client_best_of", &self.client_best_of)
+            .field("resume_attempts", &self.resume_attempts)
+            .field("reconnect_attempts", &self.reconnect_attempts)
+            .field("reconnect_delay", &self.reconnect_delay)
+            .field("reconnect_jitter", &self.reconnect_jitter)
+            .field("options", &self.options)
+            .finish()
+    }
 }
 
 impl ChatCompletionRequest {
@@ -88,40 +268,29 @@ impl ChatCompletionRequest {
 
     /// Submit the completion request to the OpenAI url.
     ///
-    /// Requires that `OPENAI_API_KEY` environment variable is set. Optionally,
-    /// the org will be added if `OPENAI_API_ORG` is set.
+    /// The API key is read from the source set with `with_key_source`, or by `OpenAIClient` when
+    /// sent through one. Otherwise, requires that either the `OPENAI_API_KEY` or
+    /// `OPENAI_API_KEY_FILE` environment variable is set. Optionally, the org will be added if
+    /// `OPENAI_API_ORG` is set.
     pub async fn submit(self) -> Result<ChatCompletionResponse, OpenAIError> {
-        let api_key = env::var("OPENAI_API_KEY").map_err(|_| {
-            OpenAIError::InvalidState(InvalidStateError::with_message(
-                "OPENAI_API_KEY env variable must be set".to_string(),
-            ))
-        })?;
-
-        let mut request = Client::new()
-            .post(format!("{OPEN_AI_URL}/v1/chat/completions"))
-            .header("Authorization", format!("Bearer {api_key}"))
-            .header("Content-Type", "application/json")
-            .json(&self);
-
-        if let Ok(org) = env::var("OPENAI_API_ORG") {
-            request = request.header("OpenAI-Organization", org)
-        };
+        self.submit_with_metadata()
+            .await
+            .map(|(response, _)| response)
+    }
 
-        if let Some(stops) = self.stop {
-            if stops.len() > 4 {
-                return Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
-                    "stop",
-                    "You can only provide up to 4 stop sequences",
-                )));
+    /// Submit the request as `submit` does, also returning details of the HTTP response such as
+    /// the processing time and any headers requested with `with_captured_header`.
+    pub async fn submit_with_metadata(
+        mut self,
+    ) -> Result<(ChatCompletionResponse, ResponseMetadata), OpenAIError> {
+        if let Some((n, best_of)) = &self.client_best_of {
+            self.n = Some(*n);
+            if *best_of == BestOf::Strategy(ChoiceStrategy::HighestMeanLogprob) {
+                self.logprobs = Some(true);
             }
         }
 
-        if self.temperature.is_some() && self.top_p.is_some() {
-            return Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
-                "temperature",
-                "Use temperature or top_p but not both",
-            )));
-        }
+        self.validate()?;
 
         if self.stream == Some(true) {
             return Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
@@ -130,66 +299,158 @@ impl ChatCompletionRequest {
             )));
         }
 
-        match request.send().await {
-            Ok(response) => {
-                // Check if the status is a 2XX code.
-                let status = response.status();
-                if status.is_success() {
-                    let result =
-                        response
-                            .json::<ChatCompletionResponse>()
-                            .await
-                            .map_err(|err| {
-                                OpenAIError::InvalidState(InvalidStateError::with_message(
-                                    err.to_string(),
-                                ))
-                            })?;
-                    Ok(result)
-                } else {
-                    let text = response.text().await.map_err(|err| {
-                        OpenAIError::InvalidState(InvalidStateError::with_message(err.to_string()))
-                    })?;
-                    if status.is_client_error() {
-                        Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
-                            "request", text,
-                        )))
-                    } else {
-                        Err(OpenAIError::Internal(InternalError::with_message(text)))
-                    }
-                }
+        let response = http::post("/v1/chat/completions", &self, &self.options).await?;
+
+        let metadata = ResponseMetadata::from_response(&response, &self.options.captured_headers);
+        let response = {
+            let response = response
+                .json::<ChatCompletionResponse>()
+                .await
+                .map_err(|err| {
+                    OpenAIError::InvalidState(InvalidStateError::with_message(err.to_string()))
+                });
+            if let Some(trace) = &self.options.trace {
+                trace.record_result(&response);
+            }
+            let mut response = response?;
+            response.tags = self.options.tags.clone();
+            response
+        };
+
+        match &self.client_best_of {
+            Some((_, BestOf::Strategy(strategy))) => {
+                Ok((response.into_best_choice(*strategy), metadata))
+            }
+            Some((_, BestOf::Judge(judge))) => {
+                let input = self
+                    .messages
+                    .iter()
+                    .rev()
+                    .find(|message| message.role() == "user")
+                    .and_then(|message| message.content().as_text())
+                    .unwrap_or_default();
+                Ok((judge.keep_best_choice(input, response).await?, metadata))
             }
-            Err(err) => Err(OpenAIError::Internal(InternalError::from_source(Box::new(
-                err,
-            )))),
+            None => Ok((response, metadata)),
         }
     }
 
     /// Submit the chat completion request to the OpenAI url and stream back the response.
     ///
-    /// Requires that `OPENAI_API_KEY` environment variable is set. Optionally,
-    /// the org will be added if `OPENAI_API_ORG` is set.
-    /// Submit the completion request to the OpenAI url.
+    /// The API key is read from the source set with `with_key_source`, or by `OpenAIClient` when
+    /// sent through one. Otherwise, requires that either the `OPENAI_API_KEY` or
+    /// `OPENAI_API_KEY_FILE` environment variable is set. Optionally, the org will be added if
+    /// `OPENAI_API_ORG` is set.
     ///
-    /// Requires that `OPENAI_API_KEY` environment variable is set. Optionally,
-    /// the org will be added if `OPENAI_API_ORG` is set.
-    pub async fn stream(mut self) -> Result<ChatCompletionResponseStream, OpenAIError> {
-        let api_key = env::var("OPENAI_API_KEY").map_err(|_| {
-            OpenAIError::InvalidState(InvalidStateError::with_message(
-                "OPENAI_API_KEY env variable must be set".to_string(),
-            ))
-        })?;
-
-        let mut request = Client::new()
-            .post(format!("{OPEN_AI_URL}/v1/chat/completions"))
-            .header("Authorization", format!("Bearer {api_key}"))
-            .header("Content-Type", "application/json")
-            .json(&self);
-
-        if let Ok(org) = env::var("OPENAI_API_ORG") {
-            request = request.header("OpenAI-Organization", org)
+    /// With a policy set by `with_slow_response_policy`, the request is sent again with the
+    /// policy's fallback model if its response does not start arriving in time.
+    pub async fn stream(self) -> Result<ChatCompletionResponseStream, OpenAIError> {
+        let Some(policy) = self.options.slow_response_policy.clone() else {
+            return self.stream_once().await;
         };
 
-        if let Some(stops) = self.stop {
+        let fallback = self.clone().with_model(policy.fallback_model());
+        if let Some(result) = policy
+            .response_started_within(self.clone().stream_once())
+            .await
+        {
+            return result;
+        }
+
+        let reason = policy.reason(&self.model);
+        if let Some(trace) = &self.options.trace {
+            trace.record(TraceEvent::Retry {
+                reason: reason.clone(),
+            });
+        }
+        retry::report(
+            self.options.retry_observer.as_ref(),
+            RetryEvent {
+                path: "/v1/chat/completions".to_string(),
+                attempt: 2,
+                delay: Duration::ZERO,
+                reason,
+                status: None,
+                retry_after: None,
+            },
+        );
+
+        fallback.stream_once().await
+    }
+
+    pub(crate) async fn stream_once(self) -> Result<ChatCompletionResponseStream, OpenAIError> {
+        self.validate()?;
+
+        if self.client_best_of.is_some() {
+            return Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
+                "client_best_of",
+                "Client-side best-of is only supported by submit",
+            )));
+        }
+
+        let started = Instant::now();
+        let response = http::post("/v1/chat/completions", &self, &self.options).await?;
+        let metadata = ResponseMetadata::from_response(&response, &self.options.captured_headers);
+
+        let mut stream = ChatCompletionResponseStream::new(Box::pin(response.bytes_stream()))
+            .with_metadata(metadata)
+            .with_started(started);
+        if let Some(trace) = &self.options.trace {
+            stream = stream.with_trace(trace.clone());
+        }
+        Ok(stream.with_request(self))
+    }
+
+    /// The request to send to continue a reply which was cut off after the partial text.
+    pub(crate) fn continuing(mut self, partial: &str) -> Self {
+        self.messages.push(Message::new("assistant", partial));
+        self.messages.push(Message::new("user", CONTINUE_PROMPT));
+        self.resume_attempts = 0;
+        self
+    }
+
+    /// The request to send to reconnect to a stream after the last event read.
+    pub(crate) fn reconnecting(mut self, last_event_id: &str) -> Self {
+        self.options.last_event_id = Some(last_event_id.to_string());
+        self
+    }
+
+    pub(crate) fn options(&self) -> &RequestOptions {
+        &self.options
+    }
+
+    pub(crate) fn client_best_of(&self) -> Option<&(i8, BestOf)> {
+        self.client_best_of.as_ref()
+    }
+
+    pub(crate) fn resume_attempts(&self) -> u32 {
+        self.resume_attempts
+    }
+
+    pub(crate) fn reconnect_attempts(&self) -> u32 {
+        self.reconnect_attempts
+    }
+
+    /// The wait before reconnecting a stream for the `attempt`th time, counting from 1, which
+    /// doubles with each attempt.
+    pub(crate) fn reconnect_delay(&self, attempt: u32) -> Duration {
+        let first = self.reconnect_delay.unwrap_or(DEFAULT_RECONNECT_DELAY);
+        let max = MAX_RECONNECT_DELAY.max(first);
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let delay = first.saturating_mul(factor).min(max);
+        match &self.reconnect_jitter {
+            Some(jitter) => jitter.apply(delay).min(max),
+            None => delay,
+        }
+    }
+
+    pub(crate) fn retry_observer(&self) -> Option<&SharedRetryObserver> {
+        self.options.retry_observer.as_ref()
+    }
+
+    /// Check the parameters that would otherwise be rejected by the API.
+    pub(crate) fn validate(&self) -> Result<(), OpenAIError> {
+        if let Some(stops) = &self.stop {
             if stops.len() > 4 {
                 return Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
                     "stop",
@@ -205,33 +466,102 @@ impl ChatCompletionRequest {
             )));
         }
 
-        self.stream = Some(true);
-
-        match request.send().await {
-            Ok(response) => {
-                // Check if the status is a 2XX code.
-                let status = response.status();
-                if status.is_success() {
-                    Ok(ChatCompletionResponseStream::new(Box::pin(
-                        response.bytes_stream(),
-                    )))
-                } else {
-                    let text = response.text().await.map_err(|err| {
-                        OpenAIError::InvalidState(InvalidStateError::with_message(err.to_string()))
-                    })?;
-                    if status.is_client_error() {
-                        Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
-                            "request", text,
-                        )))
-                    } else {
-                        Err(OpenAIError::Internal(InternalError::with_message(text)))
-                    }
-                }
-            }
-            Err(err) => Err(OpenAIError::Internal(InternalError::from_source(Box::new(
-                err,
-            )))),
+        if let Some(key) = self.extra.keys().find(|key| FIELDS.contains(&key.as_str())) {
+            return Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
+                "extra",
+                format!("{key} is a field of the request and cannot be set as an extra field"),
+            )));
         }
+
+        if self.top_logprobs.is_some() && self.logprobs != Some(true) {
+            return Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
+                "top_logprobs",
+                "top_logprobs requires logprobs to be enabled",
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Add a message to the end of the conversation, such as one built for its role with
+    /// `UserMessage` or `ToolMessage`.
+    pub fn with_message<M: Into<Message>>(mut self, message: M) -> Self {
+        self.messages.push(message.into());
+        self
+    }
+
+    /// Add a message to the end of the conversation, such as a follow up after a reply.
+    pub(crate) fn push_message(&mut self, message: Message) {
+        self.messages.push(message);
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+
+    pub fn temperature(&self) -> Option<f32> {
+        self.temperature
+    }
+
+    pub fn top_p(&self) -> Option<f32> {
+        self.top_p
+    }
+
+    pub fn n(&self) -> Option<i8> {
+        self.n
+    }
+
+    /// Returns whether the request asks for a streamed response.
+    pub fn is_stream(&self) -> bool {
+        self.stream == Some(true)
+    }
+
+    pub fn stops(&self) -> &[String] {
+        self.stop.as_deref().unwrap_or_default()
+    }
+
+    pub fn max_tokens(&self) -> Option<i32> {
+        self.max_tokens
+    }
+
+    pub fn presence_penalty(&self) -> Option<f32> {
+        self.presence_penalty
+    }
+
+    pub fn frequency_penalty(&self) -> Option<f32> {
+        self.frequency_penalty
+    }
+
+    pub fn logit_bias(&self) -> Option<&HashMap<String, i8>> {
+        self.logit_bias.as_ref()
+    }
+
+    pub fn logprobs(&self) -> Option<bool> {
+        self.logprobs
+    }
+
+    pub fn top_logprobs(&self) -> Option<i8> {
+        self.top_logprobs
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    pub fn tools(&self) -> &[Tool] {
+        self.tools.as_deref().unwrap_or_default()
+    }
+
+    pub fn extra(&self) -> &Map<String, Value> {
+        &self.extra
+    }
+
+    pub fn tags(&self) -> &RequestTags {
+        &self.options.tags
     }
 
     /// The maximum number of tokens to generate in the completion.
@@ -263,6 +593,27 @@ impl ChatCompletionRequest {
         self
     }
 
+    /// Generate `n` choices and return only the best, picked with the strategy, as the single
+    /// choice of the response. This mirrors `best_of` on completions, which chat completions lack.
+    ///
+    /// Replaces any `n` set with `with_n`, and requests logprobs for
+    /// `ChoiceStrategy::HighestMeanLogprob`. The usage covers every generated choice. Only
+    /// supported by `submit`.
+    pub fn with_client_best_of(mut self, n: i8, strategy: ChoiceStrategy) -> Self {
+        self.client_best_of = Some((n, BestOf::Strategy(strategy)));
+        self
+    }
+
+    /// Generate `n` choices and return only the one the judge scores highest, as the single
+    /// choice of the response, as with `with_client_best_of`.
+    ///
+    /// The judge is shown the text of the last user message as the input. Its requests are sent
+    /// with its own client and key source, and its usage is not included in the response.
+    pub fn with_client_best_of_by(mut self, n: i8, judge: Judge) -> Self {
+        self.client_best_of = Some((n, BestOf::Judge(Box::new(judge))));
+        self
+    }
+
     /// The sequence where the API will stop generating further tokens.
     ///
     /// The returned text will not contain the stop sequence. Use of `with_stops` will overwrite this
@@ -311,9 +662,525 @@ impl ChatCompletionRequest {
         self
     }
 
+    /// Set `max_tokens` to the room left in the model's context window after the prompt, less a
+    /// small margin, capped at the most tokens the model can generate.
+    ///
+    /// Call this after the prompt has been set. Fails if the model's tokenizer or context window
+    /// is unknown, or the prompt leaves no room for a reply.
+    #[cfg(feature = "tokens")]
+    pub fn with_max_tokens_auto(mut self) -> Result<Self, OpenAIError> {
+        let prompt_tokens = crate::tokens::count_message_tokens(&self.model, &self.messages)?;
+        self.max_tokens = Some(crate::tokens::auto_max_tokens(&self.model, prompt_tokens)?);
+        Ok(self)
+    }
+
+    /// Modify the likelihood of words appearing in the completion, encoding them with the
+    /// tokenizer of the request's model.
+    ///
+    /// Each word is encoded with and without a leading space and every resulting token is given
+    /// the bias, replacing the bias of tokens which were already set. Fails if no tokenizer is
+    /// known for the model.
+    #[cfg(feature = "tokens")]
+    pub fn with_logit_bias_words(mut self, words: &[(&str, i8)]) -> Result<Self, OpenAIError> {
+        let words = crate::tokens::logit_bias_words(&self.model, words)?;
+        self.logit_bias
+            .get_or_insert_with(HashMap::new)
+            .extend(words);
+        Ok(self)
+    }
+
+    /// Return the log probabilities of each generated token.
+    pub fn with_logprobs(mut self, logprobs: bool) -> Self {
+        self.logprobs = Some(logprobs);
+        self
+    }
+
+    /// The number of most likely tokens to return at each position, between 0 and 20.
+    ///
+    /// Requires `with_logprobs(true)`.
+    pub fn with_top_logprobs(mut self, top_logprobs: i8) -> Self {
+        self.top_logprobs = Some(top_logprobs);
+        self
+    }
+
     /// A unique ID representing your end-user, which can help OpenAI to monitor and detect abuse.
     pub fn with_user(mut self, user: &str) -> Self {
         self.user = Some(user.to_string());
         self
     }
+
+    /// Append a system message instructing the model to reply in the given language, whatever
+    /// language the conversation is in.
+    ///
+    /// Call this after the other messages have been set, as later messages may override it.
+    pub fn with_reply_language(mut self, language: &str) -> Self {
+        self.messages.push(Message::new(
+            "system",
+            &format!(
+                "Always reply in {language}, even if the user writes in a different language."
+            ),
+        ));
+        self
+    }
+
+    /// The tools the model may call. Calls are returned in `Message::tool_calls` on the choices
+    /// of the response.
+    pub fn with_tools(mut self, tools: &[Tool]) -> Self {
+        self.tools = Some(tools.to_vec());
+        self
+    }
+
+    /// Add a field to the request body which this crate does not model, such as the `top_k` or
+    /// `repetition_penalty` parameters of OpenAI-compatible servers like vLLM.
+    ///
+    /// Setting the same key again replaces its value. Keys which name one of the request's own
+    /// fields are rejected when the request is sent.
+    pub fn with_extra(mut self, key: &str, value: Value) -> Self {
+        self.extra.insert(key.to_string(), value);
+        self
+    }
+
+    /// Where to read the API key from when the request is sent.
+    ///
+    /// Defaults to the `OPENAI_API_KEY` environment variable, falling back to the file named by
+    /// `OPENAI_API_KEY_FILE`.
+    pub fn with_key_source(mut self, key_source: KeySource) -> Self {
+        self.options.key_source = Some(key_source);
+        self
+    }
+
+    /// Send the request to another URL instead of `https://api.openai.com`, such as a proxy,
+    /// gateway or OpenAI-compatible server like vLLM or LiteLLM.
+    ///
+    /// The API path, such as `/v1/chat/completions`, is appended to it.
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.options.base_url = Some(base_url.to_string());
+        self
+    }
+
+    /// Send the request through the client, using its credentials and HTTP connection pool.
+    pub fn with_client(mut self, client: OpenAIClient) -> Self {
+        self.options.client = Some(client);
+        self
+    }
+
+    /// Append a query parameter to the request URL, such as the `api-version` required by Azure
+    /// OpenAI or a gateway's routing parameter. Parameters are sent in the order they are added,
+    /// after the client's `with_default_query` parameters, replacing any with the same key.
+    pub fn with_query(mut self, key: &str, value: &str) -> Self {
+        self.options
+            .query
+            .push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Copy the response header into `ResponseMetadata::headers`, such as a gateway's trace id.
+    pub fn with_captured_header(mut self, name: &str) -> Self {
+        self.options.captured_headers.push(name.to_string());
+        self
+    }
+
+    /// Send the header with the request, such as a tracing id, a gateway's auth token or an
+    /// `OpenAI-Beta` feature flag. Headers replace any the library or the client set with the same
+    /// name, and invalid names or values are rejected when the request is sent.
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.options
+            .headers
+            .push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Send the user agent instead of the default `ryst-openai/<version>`, such as to name the
+    /// application to a gateway.
+    pub fn with_user_agent(mut self, user_agent: &str) -> Self {
+        self.options.user_agent = Some(user_agent.to_string());
+        self
+    }
+
+    /// Send `X-Ryst-Lang`, `X-Ryst-Package-Version`, `X-Ryst-OS` and `X-Ryst-Arch` headers
+    /// describing the client, so providers and gateways can attribute traffic.
+    pub fn with_client_metadata(mut self) -> Self {
+        self.options.client_metadata = true;
+        self
+    }
+
+    /// Switch to a faster model when streaming if the response is slow to arrive; see
+    /// `SlowResponsePolicy`.
+    pub fn with_slow_response_policy(mut self, policy: SlowResponsePolicy) -> Self {
+        self.options.slow_response_policy = Some(policy);
+        self
+    }
+
+    /// Resume a stream which fails after some of the reply has been read, up to `attempts`
+    /// times.
+    ///
+    /// The partial reply is sent back as an assistant message, followed by a prompt to continue
+    /// it, and the continuation is joined to the partial text with `stitch::stitch`, trimming
+    /// any text it repeats. Without resuming, or once the attempts run out, the partial text is
+    /// returned in `OpenAIError::StreamInterrupted`.
+    pub fn with_resume_on_interrupt(mut self, attempts: u32) -> Self {
+        self.resume_attempts = attempts;
+        self
+    }
+
+    /// Reopen the connection when a stream fails, up to `attempts` times, continuing after the
+    /// last event read rather than failing the whole generation.
+    ///
+    /// The request is sent again with a `Last-Event-ID` header naming the `id` of the last
+    /// server-sent event, which gateways that support it use to replay the events after it.
+    /// Streams without event IDs cannot be reconnected, and fail as before. Only
+    /// `ChatCompletionResponseStream::next_chunk` reconnects, as the JSON document read by `next`
+    /// has no events to continue after. See also `ChatCompletionResponseStream::resume`.
+    ///
+    /// Each reconnect waits first, see `with_reconnect_delay`.
+    pub fn with_reconnect(mut self, attempts: u32) -> Self {
+        self.reconnect_attempts = attempts;
+        self
+    }
+
+    /// The wait before the first reconnect of a stream set up with `with_reconnect`, which
+    /// doubles with each further attempt up to 30 seconds. Defaults to half a second.
+    pub fn with_reconnect_delay(mut self, delay: Duration) -> Self {
+        self.reconnect_delay = Some(delay);
+        self
+    }
+
+    /// Randomize each wait before reconnecting, so that many clients cut off together do not
+    /// reconnect in step. Seed it with `Jitter::seeded` for reproducible tests.
+    pub fn with_reconnect_jitter(mut self, jitter: Jitter) -> Self {
+        self.reconnect_jitter = Some(jitter);
+        self
+    }
+
+    /// Use another model, keeping every other setting.
+    pub(crate) fn with_model(mut self, model: &str) -> Self {
+        self.model = model.to_string();
+        self
+    }
+
+    /// Tag the request with the prompt template and experiment it belongs to.
+    ///
+    /// Tags are not sent to the API; see `RequestTags` for where they are recorded.
+    pub fn with_tags(mut self, tags: RequestTags) -> Self {
+        self.options.tags = tags;
+        self
+    }
+    /// A callback invoked with the final HTTP request immediately before it is sent.
+    ///
+    /// Use this to sign requests for gateways that require an HMAC of the body or additional
+    /// timestamp headers.
+    pub fn with_pre_send_hook(mut self, hook: PreSendHook) -> Self {
+        self.options.pre_send_hook = Some(hook);
+        self
+    }
+
+    /// Retry the request if it is rejected with a 429, after the wait the API recommends.
+    ///
+    /// Without a policy, the recommended wait is returned in `OpenAIError::RateLimited`.
+    pub fn with_rate_limit_retry(mut self, rate_limit_retry: RateLimitRetry) -> Self {
+        self.options.rate_limit_retry = Some(rate_limit_retry);
+        self
+    }
+
+    /// Wait before sending the request to stay within the limiter's requests and tokens per
+    /// minute, see [`crate::rate_limit`].
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.options.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Report each time the request is sent again, such as after refreshing a rejected key.
+    pub fn with_retry_observer<O: RetryObserver + 'static>(mut self, observer: O) -> Self {
+        self.options.retry_observer = Some(SharedRetryObserver::new(observer));
+        self
+    }
+
+    /// Record the request, its response and any retries or errors in the trace.
+    pub fn with_trace(mut self, trace: ExchangeTrace) -> Self {
+        self.options.trace = Some(trace);
+        self
+    }
+}
+
+/// Serialize the messages without their annotations, which the API returns but does not accept.
+fn serialize_messages<S: Serializer>(
+    messages: &[Message],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(messages.iter().map(|message| match message.annotations {
+        None => Cow::Borrowed(message),
+        Some(_) => Cow::Owned(Message {
+            annotations: None,
+            ..message.clone()
+        }),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    use crate::FunctionCall;
+
+    fn content_part() -> impl Strategy<Value = ContentPart> {
+        prop_oneof![
+            any::<String>().prop_map(|text| ContentPart::text(&text)),
+            any::<String>().prop_map(|url| ContentPart::image_url(&url)),
+            any::<String>().prop_map(|id| ContentPart::file_id(&id)),
+        ]
+    }
+
+    fn tool_call() -> impl Strategy<Value = ToolCall> {
+        (any::<String>(), any::<String>(), any::<String>()).prop_map(|(id, name, arguments)| {
+            ToolCall {
+                id,
+                kind: "function".to_string(),
+                function: FunctionCall { name, arguments },
+            }
+        })
+    }
+
+    fn message() -> impl Strategy<Value = Message> {
+        (
+            any::<String>(),
+            prop_oneof![
+                any::<String>().prop_map(MessageContent::Text),
+                prop::collection::vec(content_part(), 0..4).prop_map(MessageContent::Parts),
+            ],
+            prop::option::of(prop::collection::vec(tool_call(), 0..3)),
+            prop::option::of(any::<String>()),
+        )
+            .prop_map(|(role, content, tool_calls, tool_call_id)| Message {
+                role: role.into(),
+                content,
+                tool_calls,
+                tool_call_id,
+                annotations: None,
+            })
+    }
+
+    // Floats are drawn from a fixed grid so the JSON representation is exact
+    fn float() -> impl Strategy<Value = f32> {
+        (-200i32..=200).prop_map(|x| x as f32 / 100.0)
+    }
+
+    prop_compose! {
+        fn request()(
+            model in any::<String>(),
+            messages in prop::collection::vec(message(), 0..4),
+            temperature in prop::option::of(float()),
+            top_p in prop::option::of(float()),
+            n in prop::option::of(any::<i8>()),
+            stop in prop::option::of(prop::collection::vec(any::<String>(), 0..5)),
+            max_tokens in prop::option::of(any::<i32>()),
+            presence_penalty in prop::option::of(float()),
+            frequency_penalty in prop::option::of(float()),
+            logit_bias in prop::option::of(prop::collection::hash_map(any::<String>(), any::<i8>(), 0..4)),
+            (logprobs, top_logprobs) in (prop::option::of(any::<bool>()), prop::option::of(any::<i8>())),
+            user in prop::option::of(any::<String>()),
+        ) -> ChatCompletionRequest {
+            ChatCompletionRequest {
+                model,
+                messages,
+                temperature,
+                top_p,
+                n,
+                stream: None,
+                stop,
+                max_tokens,
+                presence_penalty,
+                frequency_penalty,
+                logit_bias,
+                logprobs,
+                top_logprobs,
+                user,
+                tools: None,
+                extra: Map::new(),
+                client_best_of: None,
+                resume_attempts: 0,
+                reconnect_attempts: 0,
+                reconnect_delay: None,
+                reconnect_jitter: None,
+                options: RequestOptions::default(),
+            }
+        }
+    }
+
+    #[test]
+    // Verify that a continuation sends the partial reply back and does not resume again itself
+    fn test_continuing() {
+        let request = ChatCompletionRequest::new("gpt-4o", &[Message::new("user", "Count to 9")])
+            .with_resume_on_interrupt(2)
+            .continuing("1 2 3");
+
+        assert_eq!(request.messages.len(), 3);
+        assert_eq!(request.messages[1].role(), "assistant");
+        assert_eq!(request.messages[1].content(), "1 2 3");
+        assert_eq!(request.messages[2].role(), "user");
+        assert_eq!(request.messages[2].content(), CONTINUE_PROMPT);
+        assert_eq!(request.resume_attempts, 0);
+    }
+
+    #[test]
+    // Verify that the reply language instruction is appended as a system message
+    fn test_with_reply_language() {
+        let request = ChatCompletionRequest::new("gpt-4o", &[Message::new("user", "Bonjour")])
+            .with_reply_language("German");
+
+        assert_eq!(request.messages.len(), 2);
+        assert_eq!(request.messages[1].role(), "system");
+        assert_eq!(
+            request.messages[1].content(),
+            "Always reply in German, even if the user writes in a different language."
+        );
+    }
+
+    #[cfg(feature = "tokens")]
+    #[test]
+    // Verify that words are encoded into the logit bias with the model's tokenizer
+    fn test_with_logit_bias_words() {
+        let request = ChatCompletionRequest::new("gpt-4", &[])
+            .with_logit_bias(&HashMap::from([("50256".to_string(), -100)]))
+            .with_logit_bias_words(&[("world", 20)])
+            .unwrap();
+
+        assert_eq!(
+            request.logit_bias(),
+            Some(&HashMap::from([
+                ("50256".to_string(), -100),
+                ("14957".to_string(), 20),
+                ("1917".to_string(), 20),
+            ]))
+        );
+        assert!(ChatCompletionRequest::new("not-a-model", &[])
+            .with_logit_bias_words(&[("world", 20)])
+            .is_err());
+    }
+
+    #[test]
+    // Verify that extra fields are merged into the body and cannot replace the request's fields
+    fn test_with_extra() {
+        let request = ChatCompletionRequest::new("gpt-4o", &[Message::new("user", "Hello")])
+            .with_extra("top_k", serde_json::json!(40))
+            .with_extra("repetition_penalty", serde_json::json!(1.1));
+
+        let body = serde_json::to_value(&request).unwrap();
+        assert_eq!(body["model"], "gpt-4o");
+        assert_eq!(body["top_k"], 40);
+        assert_eq!(body["repetition_penalty"], 1.1);
+        assert!(request.validate().is_ok());
+        assert_eq!(
+            serde_json::from_value::<ChatCompletionRequest>(body).unwrap(),
+            request
+        );
+
+        let request = request.with_extra("model", serde_json::json!("gpt-4o-mini"));
+        assert!(request.validate().is_err());
+    }
+
+    proptest! {
+        #[test]
+        // Verify that a request deserializes back to the same request after being serialized
+        fn test_request_round_trip(request in request()) {
+            let json = serde_json::to_string(&request).unwrap();
+            prop_assert_eq!(serde_json::from_str::<ChatCompletionRequest>(&json).unwrap(), request);
+        }
+    }
+
+    #[test]
+    // Verify that tags are kept on the request but not sent in its body
+    fn test_with_tags() {
+        let tags = RequestTags::new()
+            .with_template("greeting", "3")
+            .with_experiment("friendly");
+        let request =
+            ChatCompletionRequest::new("gpt-4o", &[Message::new("user", "Hi")]).with_tags(tags);
+
+        assert_eq!(request.tags().template_version.as_deref(), Some("3"));
+        let body = serde_json::to_value(&request).unwrap();
+        assert_eq!(body.as_object().unwrap().len(), 2);
+    }
+
+    #[test]
+    // Verify that the wait before each reconnect doubles up to the maximum, with optional jitter
+    fn test_reconnect_delay() {
+        let request = ChatCompletionRequest::new("gpt-4o", &[]);
+        assert_eq!(request.reconnect_delay(1), Duration::from_millis(500));
+        assert_eq!(request.reconnect_delay(3), Duration::from_secs(2));
+        assert_eq!(request.reconnect_delay(100), Duration::from_secs(30));
+
+        let request = request.with_reconnect_delay(Duration::from_secs(40));
+        assert_eq!(request.reconnect_delay(2), Duration::from_secs(40));
+
+        let request = ChatCompletionRequest::new("gpt-4o", &[])
+            .with_reconnect_delay(Duration::from_secs(1))
+            .with_reconnect_jitter(Jitter::seeded(0.5, 7));
+        for attempt in 1..4 {
+            let delay = request.reconnect_delay(attempt).as_secs_f64();
+            let expected = f64::from(2u32.pow(attempt - 1));
+            assert!(delay >= expected * 0.5 && delay <= expected * 1.5);
+        }
+    }
+
+    #[test]
+    // Verify that annotations on messages from earlier responses are not sent back
+    fn test_annotations_not_sent() {
+        let mut answer = Message::new("assistant", "See the docs");
+        answer.annotations = Some(vec![Annotation {
+            kind: "url_citation".to_string(),
+            url_citation: None,
+        }]);
+        let request =
+            ChatCompletionRequest::new("gpt-4o", &[Message::new("user", "Where?"), answer.clone()]);
+
+        let body = serde_json::to_value(&request).unwrap();
+        assert!(body["messages"][1].get("annotations").is_none());
+        assert_eq!(body["messages"][1]["content"], "See the docs");
+        assert!(serde_json::to_value(&answer)
+            .unwrap()
+            .get("annotations")
+            .is_some());
+    }
+
+    #[test]
+    // Verify that debug printing a request hides the prompt, tool arguments, user and extra fields
+    fn test_debug_redacted() {
+        let mut assistant = Message::new("assistant", "");
+        assistant.tool_calls = Some(vec![ToolCall {
+            id: "call_1".to_string(),
+            kind: "function".to_string(),
+            function: FunctionCall {
+                name: "lookup".to_string(),
+                arguments: r#"{"account":"acct-secret"}"#.to_string(),
+            },
+        }]);
+        let request = ChatCompletionRequest::new(
+            "gpt-3.5-turbo",
+            &[
+                Message::new("user", "my password is hunter2"),
+                Message::with_parts(
+                    "user",
+                    &[ContentPart::image_url("https://example.com/private.png")],
+                ),
+                assistant,
+            ],
+        )
+        .with_user("user-secret")
+        .with_extra("metadata", serde_json::json!({"tag": "extra-secret"}));
+
+        let debug = format!("{request:?}");
+        assert!(debug.contains("gpt-3.5-turbo"));
+        assert!(debug.contains("lookup"));
+        for secret in [
+            "hunter2",
+            "private.png",
+            "acct-secret",
+            "user-secret",
+            "extra-secret",
+        ] {
+            assert!(!debug.contains(secret), "{secret} leaked in {debug}");
+        }
+    }
 }
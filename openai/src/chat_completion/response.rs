@@ -13,39 +13,63 @@
 // limitations under the License.
 
 use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use bytes::{Bytes, BytesMut};
-use futures::Stream;
-use futures::StreamExt;
+use futures::stream::{self, Stream, StreamExt};
 use reqwest::Result as ReqwestResult;
 use ryst_error::{InternalError, InvalidStateError};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWrite;
 
 use crate::error::OpenAIError;
+use crate::http::ResponseMetadata;
+use crate::retry::{self, RetryEvent};
+use crate::rolling::RollingWindow;
+use crate::simulated::{truncate_chars, SimulatedStream};
+use crate::sse::EventIds;
+use crate::stitch::stitch;
+use crate::stream_stats::{StatsRecorder, StreamStats};
+use crate::tags::RequestTags;
+use crate::trace::{ExchangeTrace, TraceEvent};
+use crate::transcript::write_transcript;
 
-use super::request::Message;
+use super::content_filter::{ContentFilterResults, PromptFilterResult};
+use super::request::{ChatCompletionRequest, Message};
+use super::MessageContent;
 
 const STREAM_TERMINATION_STRING: &str = "[DONE]";
 
 /// The response returned from a completion request.
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct ChatCompletionResponse {
     /// Request ID
     pub id: String,
     /// Response type
-    pub object: String,
+    #[serde(deserialize_with = "crate::intern::deserialize")]
+    pub object: Arc<str>,
     /// Timestamp of the completion was created
     pub created: i32,
     /// The model the response was created with
-    pub model: String,
+    #[serde(deserialize_with = "crate::intern::deserialize")]
+    pub model: Arc<str>,
     /// The list of generated completions
     pub choices: Vec<ChatChoice>,
     /// The tokens used by this response and associated request
     pub usage: ChatUsage,
+    /// The content filter results for the prompts, returned by Azure OpenAI
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_filter_results: Option<Vec<PromptFilterResult>>,
+    /// The tags of the request, set with `with_tags`, which are not part of the API's response
+    #[serde(skip)]
+    pub tags: RequestTags,
 }
 
 /// The tokens consumed by the completion
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct ChatUsage {
     pub prompt_tokens: i32,
     pub completion_tokens: i32,
@@ -53,49 +77,843 @@ pub struct ChatUsage {
 }
 
 /// A generated completion
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct ChatChoice {
     pub message: Message,
     pub index: i32,
-    pub finish_reason: String,
+    /// The log probabilities of the generated tokens, if requested with `with_logprobs`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<ChatLogprobs>,
+    #[serde(deserialize_with = "crate::intern::deserialize")]
+    pub finish_reason: Arc<str>,
+    /// The content filter results for the choice, returned by Azure OpenAI
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_filter_results: Option<ContentFilterResults>,
+}
+
+impl ChatCompletionResponse {
+    /// Replay the response a chunk of text at a time, waiting `delay` between chunks, so that a
+    /// response which arrived all at once can be shown like a streamed one.
+    ///
+    /// `chunk_size` is in characters. See `SimulatedStream`.
+    pub fn into_simulated_stream(
+        self,
+        chunk_size: usize,
+        delay: Duration,
+    ) -> SimulatedStream<Self> {
+        let len = self
+            .choices
+            .iter()
+            .filter_map(|choice| choice.message.content.as_text())
+            .map(|text| text.chars().count())
+            .max()
+            .unwrap_or(0);
+        SimulatedStream::new(
+            self,
+            len,
+            |response, chars| {
+                let mut response = response.clone();
+                for choice in &mut response.choices {
+                    if let MessageContent::Text(text) = &mut choice.message.content {
+                        truncate_chars(text, chars);
+                    }
+                }
+                response
+            },
+            chunk_size,
+            delay,
+        )
+    }
+}
+
+impl ChatChoice {
+    /// Returns the mean log probability of the generated tokens, if logprobs were returned.
+    pub fn mean_logprob(&self) -> Option<f64> {
+        let content = self.logprobs.as_ref()?.content.as_ref()?;
+        if content.is_empty() {
+            return None;
+        }
+
+        let total: f64 = content.iter().map(|token| token.logprob).sum();
+        Some(total / content.len() as f64)
+    }
+}
+
+/// The log probabilities of the tokens in a chat choice
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ChatLogprobs {
+    pub content: Option<Vec<TokenLogprob>>,
+}
+
+/// A generated token and its log probability
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct TokenLogprob {
+    pub token: String,
+    #[serde(deserialize_with = "crate::finite::deserialize")]
+    pub logprob: f64,
+    /// The UTF-8 bytes of the token, which may be part of a multi-token character
+    pub bytes: Option<Vec<u8>>,
+    /// The most likely tokens at this position, if requested with `with_top_logprobs`
+    #[serde(default)]
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+/// One of the most likely tokens at a position
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct TopLogprob {
+    pub token: String,
+    #[serde(deserialize_with = "crate::finite::deserialize")]
+    pub logprob: f64,
+    pub bytes: Option<Vec<u8>>,
 }
 
 /// The response that contains a stream returned from a chat completion request.
 pub struct ChatCompletionResponseStream {
     stream: Pin<Box<dyn Stream<Item = ReqwestResult<Bytes>> + Send + 'static>>,
+    metadata: Option<ResponseMetadata>,
+    trace: Option<ExchangeTrace>,
+    stats: StatsRecorder,
+    tee: Option<Pin<Box<dyn AsyncWrite + Send>>>,
+    window: Option<RollingWindow>,
+    /// The request the stream was returned by, to resume or reconnect it
+    request: Option<Box<ChatCompletionRequest>>,
+    resume_attempts: u32,
+    reconnect_attempts: u32,
+    /// The number of times the connection has been reopened
+    reconnects: u32,
+    event_ids: EventIds,
+    /// The bytes of an event which is not complete yet, held back while the stream can
+    /// reconnect since the event is sent again in full after reconnecting
+    incomplete: BytesMut,
 }
 
 impl ChatCompletionResponseStream {
     pub fn new(stream: Pin<Box<dyn Stream<Item = ReqwestResult<Bytes>> + Send + 'static>>) -> Self {
-        Self { stream }
+        Self {
+            stream,
+            metadata: None,
+            trace: None,
+            stats: StatsRecorder::new(Instant::now()),
+            tee: None,
+            window: None,
+            request: None,
+            resume_attempts: 0,
+            reconnect_attempts: 0,
+            reconnects: 0,
+            event_ids: EventIds::default(),
+            incomplete: BytesMut::new(),
+        }
+    }
+
+    pub(crate) fn with_metadata(mut self, metadata: ResponseMetadata) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    /// Record each chunk read from the stream, and the response parsed from them, in the trace.
+    pub fn with_trace(mut self, trace: ExchangeTrace) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    /// Copy the text of each response to the writer as it is read, such as to keep a transcript
+    /// file of an interactive session.
+    ///
+    /// The text of each choice is written followed by a newline, and the writer is flushed
+    /// after each response. A failed write is returned as an error from `next`.
+    pub fn tee_to<W: AsyncWrite + Send + 'static>(mut self, writer: W) -> Self {
+        self.tee = Some(Box::pin(writer));
+        self
+    }
+
+    /// Keep the request the stream was returned by, to resume or reconnect it as the request
+    /// allows.
+    pub(crate) fn with_request(mut self, request: ChatCompletionRequest) -> Self {
+        self.resume_attempts = request.resume_attempts();
+        self.reconnect_attempts = request.reconnect_attempts();
+        self.request = Some(Box::new(request));
+        self
+    }
+
+    /// Measure the stream's timings from when the request was sent, rather than from when the
+    /// stream was created.
+    pub(crate) fn with_started(mut self, started: Instant) -> Self {
+        self.stats = StatsRecorder::new(started);
+        self
+    }
+
+    /// Timings of the stream, once `next` has read it to the end.
+    pub fn stats(&self) -> Option<&StreamStats> {
+        self.stats.stats()
+    }
+
+    /// Keep the last `len` bytes read by `next_chunk`, to look back over the end of a very long
+    /// response without keeping all of it.
+    pub fn with_window(mut self, len: usize) -> Self {
+        self.window = Some(RollingWindow::new(len));
+        self
+    }
+
+    /// The most recent bytes read by `next_chunk`, if a window was set with `with_window`.
+    pub fn window(&self) -> Option<&[u8]> {
+        self.window.as_ref().map(RollingWindow::as_bytes)
+    }
+
+    /// Details of the HTTP response the stream is read from, if it was returned by a request.
+    pub fn metadata(&self) -> Option<&ResponseMetadata> {
+        self.metadata.as_ref()
+    }
+
+    /// The `id` of the last server-sent event read from the stream, if any event had one.
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.event_ids.last()
+    }
+
+    /// Reopen the connection after a network failure, to continue reading after the last event
+    /// read.
+    ///
+    /// The request is sent again with a `Last-Event-ID` header, which requires a gateway that
+    /// replays the events after it, and the new body continues the one read by `next_chunk`.
+    /// Bytes of an event which was only partly read are read again in full. Returns an error if
+    /// the stream was not returned by a request or no event had an `id`. Streams from requests
+    /// set up with `with_reconnect` do this automatically.
+    pub async fn resume(&mut self) -> Result<(), OpenAIError> {
+        let (Some(request), Some(last_event_id)) = (&self.request, self.event_ids.last()) else {
+            return Err(OpenAIError::InvalidState(InvalidStateError::with_message(
+                "The stream has no request and event ID to resume from".to_string(),
+            )));
+        };
+
+        let reconnected = request.as_ref().clone().reconnecting(last_event_id);
+        let reconnected = reconnected.stream_once().await?;
+        self.stream = reconnected.stream;
+        self.metadata = reconnected.metadata;
+        self.event_ids.discard_partial();
+        self.incomplete.clear();
+        Ok(())
+    }
+
+    /// Read the next chunk of the body as server-sent events, reopening the connection if it
+    /// fails and reconnect attempts remain.
+    ///
+    /// While the stream can reconnect, chunks end with a complete event, so that an event cut
+    /// off by the failure is not returned twice.
+    async fn read_chunk(&mut self) -> Option<Result<Bytes, OpenAIError>> {
+        loop {
+            match self.stream.next().await {
+                Some(Ok(bytes)) => {
+                    let complete = self.event_ids.push(&bytes);
+                    if self.reconnect_attempts == 0 {
+                        return Some(Ok(bytes));
+                    }
+                    let complete = self.incomplete.len() + complete;
+                    self.incomplete.extend_from_slice(&bytes);
+                    if complete > 0 {
+                        return Some(Ok(self.incomplete.split_to(complete).freeze()));
+                    }
+                }
+                // A body which does not end with a complete event is returned as it is
+                None if !self.incomplete.is_empty() => {
+                    return Some(Ok(self.incomplete.split().freeze()))
+                }
+                None => return None,
+                Some(Err(err))
+                    if self.reconnects < self.reconnect_attempts
+                        && self.event_ids.last().is_some() =>
+                {
+                    self.reconnects += 1;
+                    let delay = self.request.as_ref().map_or(Duration::ZERO, |request| {
+                        request.reconnect_delay(self.reconnects)
+                    });
+                    self.report_reconnect(&err, delay);
+                    tokio::time::sleep(delay).await;
+                    if let Err(err) = self.resume().await {
+                        return Some(Err(err));
+                    }
+                }
+                Some(Err(err)) => return Some(Err(self.read_error(err))),
+            }
+        }
+    }
+
+    /// Report a reconnection in the trace and to the retry observer.
+    fn report_reconnect(&self, err: &reqwest::Error, delay: Duration) {
+        let reason = format!(
+            "Stream failed after event {}, reconnecting: {err}",
+            self.event_ids.last().unwrap_or_default()
+        );
+        if let Some(trace) = &self.trace {
+            trace.record(TraceEvent::Retry {
+                reason: reason.clone(),
+            });
+        }
+        retry::report(
+            self.request
+                .as_ref()
+                .and_then(|request| request.retry_observer()),
+            RetryEvent {
+                path: "/v1/chat/completions".to_string(),
+                attempt: self.reconnects + 1,
+                delay,
+                reason,
+                status: None,
+                retry_after: None,
+            },
+        );
+    }
+
+    /// Wait until the first chunk of the body arrives, keeping it to be read as usual.
+    ///
+    /// A read error is kept in the same way, to be returned by the next read.
+    pub(crate) async fn wait_for_first_chunk(&mut self) {
+        if let Some(first) = self.stream.next().await {
+            let rest = std::mem::replace(&mut self.stream, Box::pin(stream::empty()));
+            self.stream = Box::pin(stream::once(async { first }).chain(rest));
+        }
+    }
+
+    /// Read the next chunk of the response body as it arrives, without keeping it.
+    ///
+    /// Unlike `next`, which accumulates the whole body before parsing it, only the current chunk
+    /// and the window set with `with_window` are held, so memory stays bounded however long the
+    /// response is. Making sense of the raw bytes is left to the caller. Chunks are still
+    /// recorded in the trace if one is set, but no stats are kept and nothing is written to the
+    /// tee.
+    ///
+    /// This is the only read which reconnects with `Last-Event-ID`, for bodies of server-sent
+    /// events such as those of a gateway which streams them.
+    pub async fn next_chunk(&mut self) -> Result<Option<Bytes>, OpenAIError> {
+        while let Some(value) = self.read_chunk().await {
+            let bytes = value?;
+            self.record_chunk(&bytes);
+            if bytes != STREAM_TERMINATION_STRING.as_bytes() {
+                if let Some(window) = &mut self.window {
+                    window.push(&bytes);
+                }
+                return Ok(Some(bytes));
+            }
+        }
+        Ok(None)
     }
 
     /// Use the stream to get the full response
+    ///
+    /// If the stream fails after some of the reply has been read, the text read so far is
+    /// returned in `OpenAIError::StreamInterrupted`, unless the request was set to resume with
+    /// `with_resume_on_interrupt`.
     pub async fn next(&mut self) -> Result<Option<ChatCompletionResponse>, OpenAIError> {
+        // Errors are not `Send`, so each read is handled before the next await
+        let mut partial = String::new();
+        let mut step = {
+            let read = self.read_response().await;
+            self.step(read, &mut partial)?
+        };
+        while let Step::Resume(request) = step {
+            let mut continuation =
+                request
+                    .continuing(&partial)
+                    .stream_once()
+                    .await
+                    .map_err(|err| OpenAIError::StreamInterrupted {
+                        partial: partial.clone(),
+                        source: Box::new(err),
+                    })?;
+            step = {
+                let read = continuation.read_response().await;
+                self.step(read, &mut partial)?
+            };
+        }
+        let Step::Done(response) = step else {
+            unreachable!("resuming continues until done");
+        };
+
+        if let (Some(tee), Some(response)) = (&mut self.tee, &response) {
+            let texts = response
+                .choices
+                .iter()
+                .filter_map(|choice| choice.message.content().as_text())
+                .collect::<Vec<_>>();
+            write_transcript(tee.as_mut(), &texts).await?;
+        }
+        Ok(response)
+    }
+
+    /// Read the rest of the stream and parse it as the full response.
+    async fn read_response(&mut self) -> Result<Option<ChatCompletionResponse>, OpenAIError> {
         let mut full_bytes = BytesMut::new();
         while let Some(value) = self.stream.next().await {
             match value {
                 Ok(bytes) => {
+                    self.stats.chunk();
+                    self.record_chunk(&bytes);
                     if bytes != STREAM_TERMINATION_STRING.as_bytes() {
                         full_bytes.extend_from_slice(&bytes)
                     }
                 }
                 Err(err) => {
-                    return Err(OpenAIError::Internal(InternalError::from_source(Box::new(
-                        err,
-                    ))))
+                    self.stats.finish(None);
+                    let err = self.read_error(err);
+                    return match partial_content(&full_bytes) {
+                        Some(partial) => Err(OpenAIError::StreamInterrupted {
+                            partial,
+                            source: Box::new(err),
+                        }),
+                        None => Err(err),
+                    };
                 }
             }
         }
 
         if full_bytes.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(
-                serde_json::from_slice::<ChatCompletionResponse>(&full_bytes).map_err(|err| {
-                    OpenAIError::InvalidState(InvalidStateError::with_message(err.to_string()))
-                })?,
-            ))
+            self.stats.finish(None);
+            return Ok(None);
+        }
+
+        self.parse(&full_bytes).map(Some)
+    }
+
+    /// Decide what to do after reading a response, adding to the partial text if the read was
+    /// interrupted.
+    ///
+    /// An interrupted read is resumed while attempts remain. A completed read has its first
+    /// choice stitched onto the partial text of any earlier reads.
+    fn step(
+        &mut self,
+        read: Result<Option<ChatCompletionResponse>, OpenAIError>,
+        partial: &mut String,
+    ) -> Result<Step, OpenAIError> {
+        match read {
+            Ok(Some(mut response)) if !partial.is_empty() => {
+                if let Some(choice) = response.choices.first_mut() {
+                    if let MessageContent::Text(text) = &mut choice.message.content {
+                        *text = stitch(partial, text);
+                    }
+                }
+                Ok(Step::Done(Some(response)))
+            }
+            Ok(response) => Ok(Step::Done(response)),
+            Err(OpenAIError::StreamInterrupted {
+                partial: more,
+                source,
+            }) => {
+                *partial = stitch(partial, &more);
+                match &self.request {
+                    Some(request) if self.resume_attempts > 0 => {
+                        self.resume_attempts -= 1;
+                        Ok(Step::Resume(request.clone()))
+                    }
+                    _ => Err(OpenAIError::StreamInterrupted {
+                        partial: std::mem::take(partial),
+                        source,
+                    }),
+                }
+            }
+            Err(err) if !partial.is_empty() => Err(OpenAIError::StreamInterrupted {
+                partial: std::mem::take(partial),
+                source: Box::new(err),
+            }),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Record a chunk read from the stream in the trace.
+    fn record_chunk(&self, bytes: &[u8]) {
+        if let Some(trace) = &self.trace {
+            trace.record(TraceEvent::Chunk {
+                data: String::from_utf8_lossy(bytes).into_owned(),
+            });
+        }
+    }
+
+    /// Convert an error reading the stream, recording it in the trace.
+    fn read_error(&self, err: reqwest::Error) -> OpenAIError {
+        let err = OpenAIError::Internal(InternalError::from_source(Box::new(err)));
+        if let Some(trace) = &self.trace {
+            trace.record(TraceEvent::Error {
+                message: err.to_string(),
+            });
+        }
+        err
+    }
+
+    /// Parse the full response, recording it in the trace and finishing the stats.
+    fn parse(&mut self, bytes: &[u8]) -> Result<ChatCompletionResponse, OpenAIError> {
+        let response = serde_json::from_slice::<ChatCompletionResponse>(bytes)
+            .map(|mut response| {
+                if let Some(request) = &self.request {
+                    response.tags = request.tags().clone();
+                }
+                response
+            })
+            .map_err(|err| {
+                OpenAIError::InvalidState(InvalidStateError::with_message(err.to_string()))
+            });
+        if let Some(trace) = &self.trace {
+            trace.record_result(&response);
+        }
+        self.stats.finish(
+            response
+                .as_ref()
+                .ok()
+                .map(|response| response.usage.completion_tokens),
+        );
+        response
+    }
+}
+
+/// What `next` does after each read of a response.
+enum Step {
+    /// Return the response
+    Done(Option<ChatCompletionResponse>),
+    /// Send the request again to continue the partial text
+    Resume(Box<ChatCompletionRequest>),
+}
+
+/// Recover the text of the first choice from a response body which was cut off, if any was
+/// read.
+fn partial_content(body: &[u8]) -> Option<String> {
+    const KEY: &str = "\"content\"";
+
+    // A character split across chunks is dropped along with the rest of the body
+    let body = match std::str::from_utf8(body) {
+        Ok(body) => body,
+        Err(err) => std::str::from_utf8(&body[..err.valid_up_to()]).ok()?,
+    };
+    let start = body.find(KEY)? + KEY.len();
+    let text = body[start..]
+        .trim_start()
+        .strip_prefix(':')?
+        .trim_start()
+        .strip_prefix('"')?;
+
+    // Find the end of the string, or of the last complete character if it was cut off
+    let mut end = text.len();
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => {
+                end = i;
+                break;
+            }
+            '\\' => {
+                let len = if text[i + 1..].starts_with('u') { 6 } else { 2 };
+                if i + len > text.len() {
+                    end = i;
+                    break;
+                }
+                chars.nth(len - 2);
+            }
+            _ => (),
+        }
+    }
+
+    serde_json::from_str::<String>(&format!("\"{}\"", &text[..end]))
+        .ok()
+        .filter(|partial| !partial.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+    use tokio::io::AsyncWriteExt;
+
+    fn choice() -> impl Strategy<Value = ChatChoice> {
+        (
+            any::<String>(),
+            any::<String>(),
+            any::<i32>(),
+            any::<String>(),
+        )
+            .prop_map(|(role, content, index, finish_reason)| ChatChoice {
+                message: Message::new(&role, &content),
+                index,
+                logprobs: None,
+                finish_reason: finish_reason.into(),
+                content_filter_results: None,
+            })
+    }
+
+    prop_compose! {
+        fn response()(
+            (id, object, created, model) in (any::<String>(), any::<String>(), any::<i32>(), any::<String>()),
+            choices in prop::collection::vec(choice(), 0..3),
+            (prompt_tokens, completion_tokens, total_tokens) in (any::<i32>(), any::<i32>(), any::<i32>()),
+        ) -> ChatCompletionResponse {
+            ChatCompletionResponse {
+                id,
+                object: object.into(),
+                created,
+                model: model.into(),
+                choices,
+                usage: ChatUsage {
+                    prompt_tokens,
+                    completion_tokens,
+                    total_tokens,
+                },
+                prompt_filter_results: None,
+                tags: RequestTags::default(),
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        // Verify that a response deserializes back to the same response after being serialized
+        fn test_response_round_trip(response in response()) {
+            let json = serde_json::to_string(&response).unwrap();
+            prop_assert_eq!(serde_json::from_str::<ChatCompletionResponse>(&json).unwrap(), response);
+        }
+    }
+
+    #[tokio::test]
+    // Verify that the text of a streamed response is copied to the writer
+    async fn test_stream_tee_to() {
+        let json = serde_json::json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "gpt-4o",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": "Hello there"},
+                "finish_reason": "stop"
+            }],
+            "usage": {"prompt_tokens": 1, "completion_tokens": 2, "total_tokens": 3}
+        })
+        .to_string();
+        let chunks = vec![Ok(Bytes::from(json))];
+
+        let (writer, mut reader) = tokio::io::duplex(1024);
+        let mut stream = ChatCompletionResponseStream::new(Box::pin(futures::stream::iter(chunks)))
+            .tee_to(writer);
+
+        let response = stream.next().await.unwrap().unwrap();
+        assert_eq!(response.choices[0].message.content(), "Hello there");
+        drop(stream);
+
+        let mut transcript = String::new();
+        tokio::io::AsyncReadExt::read_to_string(&mut reader, &mut transcript)
+            .await
+            .unwrap();
+        assert_eq!(transcript, "Hello there\n");
+    }
+
+    #[tokio::test]
+    // Verify that chunks are read one at a time, keeping only the window of recent bytes
+    async fn test_stream_next_chunk() {
+        let chunks = ["first ", "second ", "third", STREAM_TERMINATION_STRING]
+            .into_iter()
+            .map(|chunk| Ok(Bytes::from(chunk)))
+            .collect::<Vec<_>>();
+        let mut stream = ChatCompletionResponseStream::new(Box::pin(futures::stream::iter(chunks)))
+            .with_window(8);
+
+        assert_eq!(stream.next_chunk().await.unwrap().unwrap(), "first ");
+        assert_eq!(stream.window(), Some(&b"first "[..]));
+        assert_eq!(stream.next_chunk().await.unwrap().unwrap(), "second ");
+        assert_eq!(stream.next_chunk().await.unwrap().unwrap(), "third");
+        assert_eq!(stream.window(), Some(&b"nd third"[..]));
+        assert!(stream.next_chunk().await.unwrap().is_none());
+        assert!(stream.stats().is_none());
+    }
+
+    /// A stream of the chunks which then fails.
+    fn failing_stream(chunks: &[&str]) -> ChatCompletionResponseStream {
+        let err = reqwest::Client::new().get("not a url").build().unwrap_err();
+        let chunks = chunks
+            .iter()
+            .map(|chunk| Ok(Bytes::from(chunk.to_string())))
+            .chain([Err(err)])
+            .collect::<Vec<_>>();
+        ChatCompletionResponseStream::new(Box::pin(futures::stream::iter(chunks)))
+    }
+
+    #[tokio::test]
+    // Verify that the text read before a stream fails is returned with the error
+    async fn test_stream_interrupted() {
+        let mut stream = failing_stream(&[
+            r#"{"id":"chatcmpl-1","object":"chat.completion","created":0,"model":"gpt-4o","#,
+            r#""choices":[{"index":0,"message":{"role":"assistant","content":"Hello "#,
+            r#"the"#,
+        ]);
+        match stream.next().await.unwrap_err() {
+            OpenAIError::StreamInterrupted { partial, source } => {
+                assert_eq!(partial, "Hello the");
+                assert!(matches!(*source, OpenAIError::Internal(_)));
+            }
+            err => panic!("Unexpected error {err}"),
         }
+
+        let mut stream = failing_stream(&[r#"{"id":"chatcmpl-1","object":"chat.completion","#]);
+        assert!(matches!(
+            stream.next().await.unwrap_err(),
+            OpenAIError::Internal(_)
+        ));
+    }
+
+    #[test]
+    // Verify that the content is recovered from a body cut off at any point
+    fn test_partial_content() {
+        let content = |body: &[u8]| partial_content(body);
+
+        assert_eq!(
+            content(br#"{"message":{"content":"Say \"hi\"\n","#).as_deref(),
+            Some("Say \"hi\"\n")
+        );
+        assert_eq!(
+            content(br#"{"message":{"content" : "caf\u00e9 \u00"#).as_deref(),
+            Some("caf\u{e9} ")
+        );
+        assert_eq!(content(br#"{"content":"line\"#).as_deref(), Some("line"));
+        assert_eq!(
+            content(&"{\"content\":\"na\u{ef}ve".as_bytes()[..15]).as_deref(),
+            Some("na")
+        );
+        assert_eq!(content(br#"{"content":null,"#), None);
+        assert_eq!(content(br#"{"content":""#), None);
+        assert_eq!(content(br#"{"id":"chatcmpl-1""#), None);
+    }
+
+    #[tokio::test]
+    // Verify that event IDs are tracked and a stream without a request cannot be resumed
+    async fn test_stream_last_event_id() {
+        let mut stream = failing_stream(&["id: 1\ndata: a\n\n", "id: 2\ndata: b\n\n"]);
+        assert_eq!(stream.last_event_id(), None);
+
+        stream.next_chunk().await.unwrap();
+        stream.next_chunk().await.unwrap();
+        assert_eq!(stream.last_event_id(), Some("2"));
+
+        assert!(matches!(
+            stream.next_chunk().await.unwrap_err(),
+            OpenAIError::Internal(_)
+        ));
+        assert!(matches!(
+            stream.resume().await.unwrap_err(),
+            OpenAIError::InvalidState(_)
+        ));
+    }
+
+    /// Read a request from the connection, returning its head.
+    async fn read_request(connection: &mut tokio::net::TcpStream) -> String {
+        use tokio::io::AsyncReadExt;
+
+        let mut request = Vec::new();
+        let mut buffer = [0; 1024];
+        let head_len = loop {
+            let read = connection.read(&mut buffer).await.unwrap();
+            request.extend_from_slice(&buffer[..read]);
+            if let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+                break end + 4;
+            }
+        };
+        let head = String::from_utf8_lossy(&request[..head_len]).to_lowercase();
+        let body_len = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length:"))
+            .map_or(0, |len| len.trim().parse::<usize>().unwrap());
+        // Read the whole body, so closing the connection does not reset it
+        while request.len() < head_len + body_len {
+            let read = connection.read(&mut buffer).await.unwrap();
+            request.extend_from_slice(&buffer[..read]);
+        }
+        head
+    }
+
+    #[tokio::test]
+    // Verify that a stream whose connection drops reconnects after the last complete event
+    async fn test_stream_reconnect() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let head = "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\n\
+                        transfer-encoding: chunked\r\n\r\n";
+
+            // The connection drops partway through the second event
+            let (mut connection, _) = listener.accept().await.unwrap();
+            read_request(&mut connection).await;
+            let events = "id: 1\ndata: a\n\nid: 2\ndata: b";
+            let response = format!("{head}{:x}\r\n{events}\r\n", events.len());
+            connection.write_all(response.as_bytes()).await.unwrap();
+            drop(connection);
+
+            let (mut connection, _) = listener.accept().await.unwrap();
+            let request = read_request(&mut connection).await;
+            let events = "id: 2\ndata: b\n\n";
+            let response = format!("{head}{:x}\r\n{events}\r\n0\r\n\r\n", events.len());
+            connection.write_all(response.as_bytes()).await.unwrap();
+            request
+        });
+
+        let mut stream = ChatCompletionRequest::new("gpt-4o", &[Message::new("user", "Hi")])
+            .with_key_source(crate::KeySource::Static("sk-test".to_string()))
+            .with_base_url(&format!("http://{address}"))
+            .with_reconnect(1)
+            .with_reconnect_delay(Duration::from_millis(50))
+            .stream()
+            .await
+            .unwrap();
+        let started = Instant::now();
+        let mut body = Vec::new();
+        while let Some(chunk) = stream.next_chunk().await.unwrap() {
+            body.extend_from_slice(&chunk);
+        }
+
+        assert_eq!(body, b"id: 1\ndata: a\n\nid: 2\ndata: b\n\n");
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(stream.last_event_id(), Some("2"));
+        assert!(server.await.unwrap().contains("last-event-id: 1\r\n"));
+    }
+
+    #[tokio::test]
+    // Verify that the tags of a submitted request are carried by its response
+    async fn test_response_tags() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut connection, _) = listener.accept().await.unwrap();
+            read_request(&mut connection).await;
+            let body = serde_json::json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion",
+                "created": 0,
+                "model": "gpt-4o",
+                "choices": [{
+                    "index": 0,
+                    "message": {"role": "assistant", "content": "Hello"},
+                    "finish_reason": "stop"
+                }],
+                "usage": {"prompt_tokens": 1, "completion_tokens": 1, "total_tokens": 2}
+            })
+            .to_string();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\n\
+                 content-length: {}\r\n\r\n{body}",
+                body.len()
+            );
+            connection.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        let tags = RequestTags::new().with_template("greeting", "2");
+        let response = ChatCompletionRequest::new("gpt-4o", &[Message::new("user", "Hi")])
+            .with_key_source(crate::KeySource::Static("sk-test".to_string()))
+            .with_base_url(&format!("http://{address}"))
+            .with_tags(tags.clone())
+            .submit()
+            .await
+            .unwrap();
+        server.await.unwrap();
+
+        assert_eq!(response.tags, tags);
+        assert!(serde_json::to_value(&response)
+            .unwrap()
+            .get("tags")
+            .is_none());
     }
 }
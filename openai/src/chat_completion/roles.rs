@@ -0,0 +1,209 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Builders for messages of each role, which only allow the content that role may send.
+//!
+//! Images and files can only be added to a `UserMessage`, tool calls to an `AssistantMessage`
+//! and a tool call ID to a `ToolMessage`, so messages the API would reject for their structure
+//! fail to compile instead. Each builder converts into a `Message`.
+
+use std::path::Path;
+
+use crate::error::OpenAIError;
+
+use super::content::{self, ContentPart, MessageContent};
+use super::request::Message;
+use super::tools::ToolCall;
+
+/// A system message, which holds text only.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SystemMessage {
+    text: String,
+}
+
+impl SystemMessage {
+    /// Create a system message with the text.
+    pub fn new(text: &str) -> Self {
+        Self {
+            text: text.to_string(),
+        }
+    }
+}
+
+impl From<SystemMessage> for Message {
+    fn from(message: SystemMessage) -> Self {
+        Message::new("system", &message.text)
+    }
+}
+
+/// A user message, the only role which may include images and files.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserMessage {
+    parts: Vec<ContentPart>,
+}
+
+impl UserMessage {
+    /// Create a user message starting with the text.
+    pub fn new(text: &str) -> Self {
+        Self {
+            parts: vec![ContentPart::text(text)],
+        }
+    }
+
+    /// Add more text after the parts added so far.
+    pub fn with_text(mut self, text: &str) -> Self {
+        self.parts.push(ContentPart::text(text));
+        self
+    }
+
+    /// Add an image from a remote url or a `data:` url.
+    pub fn with_image_url(mut self, url: &str) -> Self {
+        self.parts.push(ContentPart::image_url(url));
+        self
+    }
+
+    /// Add a local image, embedded as in `Message::user_with_image_path`.
+    pub fn with_image_path<P: AsRef<Path>>(mut self, path: P) -> Result<Self, OpenAIError> {
+        let url = content::image_data_url(path.as_ref())?;
+        self.parts.push(ContentPart::image_url(&url));
+        Ok(self)
+    }
+
+    /// Add a file previously uploaded to the files API.
+    pub fn with_file_id(mut self, file_id: &str) -> Self {
+        self.parts.push(ContentPart::file_id(file_id));
+        self
+    }
+
+    /// Add a local document, embedded as in `Message::user_with_file_path`.
+    pub fn with_file_path<P: AsRef<Path>>(mut self, path: P) -> Result<Self, OpenAIError> {
+        self.parts.push(content::file_part(path.as_ref())?);
+        Ok(self)
+    }
+}
+
+// Text on its own is sent as a plain string, as the API expects from most clients
+impl From<UserMessage> for Message {
+    fn from(message: UserMessage) -> Self {
+        match message.parts.as_slice() {
+            [ContentPart::Text { text }] => Message::new("user", text),
+            _ => Message::with_parts("user", &message.parts),
+        }
+    }
+}
+
+/// An assistant message, the only role which may include tool calls, such as a reply added to
+/// the history before the results of its calls.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssistantMessage {
+    text: String,
+    tool_calls: Vec<ToolCall>,
+}
+
+impl AssistantMessage {
+    /// Create an assistant message with the text and no tool calls.
+    pub fn new(text: &str) -> Self {
+        Self {
+            text: text.to_string(),
+            tool_calls: Vec::new(),
+        }
+    }
+
+    /// Add a call the model made to one of the request's tools.
+    pub fn with_tool_call(mut self, tool_call: ToolCall) -> Self {
+        self.tool_calls.push(tool_call);
+        self
+    }
+}
+
+impl From<AssistantMessage> for Message {
+    fn from(message: AssistantMessage) -> Self {
+        Message {
+            content: MessageContent::Text(message.text),
+            tool_calls: (!message.tool_calls.is_empty()).then_some(message.tool_calls),
+            ..Message::new("assistant", "")
+        }
+    }
+}
+
+/// The result of a tool call, the only role which refers to a tool call ID.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolMessage {
+    tool_call_id: String,
+    text: String,
+}
+
+impl ToolMessage {
+    /// Create the result of the call with the ID given in `ToolCall::id`.
+    pub fn new(tool_call_id: &str, text: &str) -> Self {
+        Self {
+            tool_call_id: tool_call_id.to_string(),
+            text: text.to_string(),
+        }
+    }
+}
+
+impl From<ToolMessage> for Message {
+    fn from(message: ToolMessage) -> Self {
+        Message::tool_result(&message.tool_call_id, &message.text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    // Verify that each builder converts to a message with its role's fields
+    fn test_role_messages() {
+        let system = Message::from(SystemMessage::new("Be brief."));
+        assert_eq!(
+            serde_json::to_value(system).unwrap(),
+            json!({"role": "system", "content": "Be brief."})
+        );
+
+        let user = Message::from(UserMessage::new("What is this?"));
+        assert_eq!(user.content(), "What is this?");
+
+        let user = Message::from(
+            UserMessage::new("What is this?")
+                .with_image_url("https://example.com/a.png")
+                .with_file_id("file-1"),
+        );
+        assert_eq!(
+            serde_json::to_value(user).unwrap(),
+            json!({"role": "user", "content": [
+                {"type": "text", "text": "What is this?"},
+                {"type": "image_url", "image_url": {"url": "https://example.com/a.png"}},
+                {"type": "file", "file": {"file_id": "file-1"}}
+            ]})
+        );
+
+        let call: ToolCall = serde_json::from_value(json!({
+            "id": "call_1",
+            "type": "function",
+            "function": {"name": "lookup", "arguments": "{}"}
+        }))
+        .unwrap();
+        let assistant = Message::from(AssistantMessage::new("").with_tool_call(call.clone()));
+        assert_eq!(assistant.role(), "assistant");
+        assert_eq!(assistant.tool_calls(), &[call]);
+        assert_eq!(Message::from(AssistantMessage::new("Hi")).tool_calls, None);
+
+        let tool = Message::from(ToolMessage::new("call_1", "42"));
+        assert_eq!(tool, Message::tool_result("call_1", "42"));
+    }
+}
@@ -0,0 +1,172 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing the types used for tool calling in chat completions.
+
+use std::fmt;
+
+use ryst_error::InvalidArgumentError;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::OpenAIError;
+use crate::redact::Redacted;
+
+/// A tool the model may call, sent with `ChatCompletionRequest::with_tools`.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Tool {
+    /// The type of the tool, currently always `function`
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionDefinition,
+}
+
+impl Tool {
+    /// Create a function tool.
+    ///
+    /// `parameters` is a JSON Schema object describing the arguments the function accepts.
+    pub fn function(name: &str, description: &str, parameters: Value) -> Self {
+        Self {
+            kind: "function".to_string(),
+            function: FunctionDefinition {
+                name: name.to_string(),
+                description: Some(description.to_string()),
+                parameters: Some(parameters),
+            },
+        }
+    }
+}
+
+/// The definition of a function the model may call.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct FunctionDefinition {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// A JSON Schema object describing the arguments
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<Value>,
+}
+
+/// A call to a tool made by the model, found in `Message::tool_calls`.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ToolCall {
+    /// The ID to reply with in `Message::tool_result`
+    pub id: String,
+    /// The type of the tool, currently always `function`
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+impl ToolCall {
+    /// Parse the JSON arguments of the call.
+    pub fn parse_arguments<T: DeserializeOwned>(&self) -> Result<T, OpenAIError> {
+        serde_json::from_str(&self.function.arguments).map_err(|err| {
+            OpenAIError::InvalidArgument(InvalidArgumentError::new(
+                "arguments",
+                format!("Invalid arguments for tool {}: {}", self.function.name, err),
+            ))
+        })
+    }
+}
+
+/// The function and arguments of a tool call.
+///
+/// The arguments are redacted when debug printed, see [`crate::redact`].
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct FunctionCall {
+    pub name: String,
+    /// The arguments as a JSON string, which the model may not have generated correctly
+    pub arguments: String,
+}
+
+impl fmt::Debug for FunctionCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FunctionCall")
+            .field("name", &self.name)
+            .field("arguments", &Redacted(&self.arguments))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    // Verify that a tool serializes in the format expected by the API
+    fn test_tool_wire_format() {
+        let tool = Tool::function(
+            "get_weather",
+            "Get the weather for a city",
+            json!({"type": "object", "properties": {"city": {"type": "string"}}}),
+        );
+
+        assert_eq!(
+            serde_json::to_value(&tool).unwrap(),
+            json!({
+                "type": "function",
+                "function": {
+                    "name": "get_weather",
+                    "description": "Get the weather for a city",
+                    "parameters": {"type": "object", "properties": {"city": {"type": "string"}}}
+                }
+            })
+        );
+    }
+
+    #[test]
+    // Verify that tool call arguments are parsed, and invalid arguments are an error
+    fn test_tool_call_parse_arguments() {
+        let mut call: ToolCall = serde_json::from_value(json!({
+            "id": "call_1",
+            "type": "function",
+            "function": {"name": "get_weather", "arguments": "{\"city\": \"Paris\"}"}
+        }))
+        .unwrap();
+
+        let args: Value = call.parse_arguments().unwrap();
+        assert_eq!(args["city"], "Paris");
+
+        call.function.arguments = "{\"city\": ".to_string();
+        assert!(call.parse_arguments::<Value>().is_err());
+    }
+
+    #[test]
+    // Verify that an assistant message with null content and tool calls deserializes
+    fn test_tool_call_message() {
+        let message: crate::Message = serde_json::from_value(json!({
+            "role": "assistant",
+            "content": null,
+            "tool_calls": [{
+                "id": "call_1",
+                "type": "function",
+                "function": {"name": "get_weather", "arguments": "{}"}
+            }]
+        }))
+        .unwrap();
+
+        assert_eq!(message.content(), "");
+        assert_eq!(message.tool_calls()[0].function.name, "get_weather");
+        assert!(crate::Message::new("user", "hi").tool_calls().is_empty());
+    }
+}
@@ -0,0 +1,278 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing strategies for picking one of several generated choices.
+
+use serde_json::{json, Value};
+
+use crate::judge::Judge;
+use crate::{ChatChoice, ChatCompletionResponse, CompletionChoice, CompletionResponse};
+
+/// How to pick the best of several choices when more than one is generated with `with_n`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChoiceStrategy {
+    /// The choice with the most characters of text
+    Longest,
+    /// The choice whose tokens have the highest mean log probability.
+    ///
+    /// Requires that logprobs were requested. Choices without logprobs are never picked.
+    HighestMeanLogprob,
+}
+
+/// How a request sent with `with_client_best_of` or `with_client_best_of_by` picks its choice.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum BestOf {
+    Strategy(ChoiceStrategy),
+    Judge(Box<Judge>),
+}
+
+impl BestOf {
+    /// The settings which change the choice picked, for fingerprints.
+    pub(crate) fn settings(&self) -> Value {
+        match self {
+            BestOf::Strategy(strategy) => json!(format!("{strategy:?}")),
+            BestOf::Judge(judge) => judge.settings(),
+        }
+    }
+}
+
+impl ChatCompletionResponse {
+    /// Returns the best choice according to the given strategy.
+    ///
+    /// Ties are resolved in favor of the choice that comes first. Returns `None` if there are no
+    /// choices the strategy can score.
+    pub fn best_choice(&self, strategy: ChoiceStrategy) -> Option<&ChatChoice> {
+        match strategy {
+            ChoiceStrategy::Longest => self.best_choice_by(|choice| {
+                choice
+                    .message
+                    .content
+                    .as_text()
+                    .map(|text| text.chars().count() as f64)
+            }),
+            ChoiceStrategy::HighestMeanLogprob => self.best_choice_by(ChatChoice::mean_logprob),
+        }
+    }
+
+    /// Returns the choice with the highest score, for scoring methods such as a judge model.
+    ///
+    /// Choices scored as `None` or NaN are skipped and ties are resolved in favor of the choice
+    /// that comes first.
+    pub fn best_choice_by<F>(&self, score: F) -> Option<&ChatChoice>
+    where
+        F: Fn(&ChatChoice) -> Option<f64>,
+    {
+        highest_scoring(&self.choices, score)
+    }
+
+    /// Keep only the best choice, as index 0, falling back to the first choice when none can be
+    /// scored.
+    pub(crate) fn into_best_choice(self, strategy: ChoiceStrategy) -> Self {
+        let best = self.best_choice(strategy).map(|best| best.index);
+        self.into_choice(best)
+    }
+
+    /// Keep only the choice with the given index, as index 0, falling back to the first choice
+    /// when there is no such choice.
+    pub(crate) fn into_choice(mut self, index: Option<i32>) -> Self {
+        let best = index
+            .and_then(|index| self.choices.iter().position(|choice| choice.index == index))
+            .unwrap_or(0);
+
+        if best < self.choices.len() {
+            let mut choice = self.choices.swap_remove(best);
+            choice.index = 0;
+            self.choices = vec![choice];
+        }
+        self
+    }
+}
+
+impl CompletionResponse {
+    /// Returns the best choice according to the given strategy.
+    ///
+    /// Ties are resolved in favor of the choice that comes first. Returns `None` if there are no
+    /// choices the strategy can score.
+    pub fn best_choice(&self, strategy: ChoiceStrategy) -> Option<&CompletionChoice> {
+        match strategy {
+            ChoiceStrategy::Longest => {
+                self.best_choice_by(|choice| Some(choice.text.chars().count() as f64))
+            }
+            ChoiceStrategy::HighestMeanLogprob => {
+                self.best_choice_by(CompletionChoice::mean_logprob)
+            }
+        }
+    }
+
+    /// Returns the choice with the highest score, for scoring methods such as a judge model.
+    ///
+    /// Choices scored as `None` or NaN are skipped and ties are resolved in favor of the choice
+    /// that comes first.
+    pub fn best_choice_by<F>(&self, score: F) -> Option<&CompletionChoice>
+    where
+        F: Fn(&CompletionChoice) -> Option<f64>,
+    {
+        highest_scoring(&self.choices, score)
+    }
+}
+
+fn highest_scoring<T, F>(items: &[T], score: F) -> Option<&T>
+where
+    F: Fn(&T) -> Option<f64>,
+{
+    let mut best: Option<(&T, f64)> = None;
+
+    for item in items {
+        if let Some(score) = score(item).filter(|score| !score.is_nan()) {
+            match best {
+                Some((_, best_score)) if best_score >= score => (),
+                _ => best = Some((item, score)),
+            }
+        }
+    }
+
+    best.map(|(item, _)| item)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{ChatUsage, CompletionUsage, Message, RequestTags};
+
+    fn completion_choice(index: i32, text: &str, logprobs: Option<Vec<f64>>) -> CompletionChoice {
+        CompletionChoice {
+            text: text.to_string(),
+            index,
+            logprobs: logprobs.map(|token_logprobs| crate::Logprobs {
+                tokens: vec![],
+                token_logprobs,
+                top_logprobs: Default::default(),
+                text_offset: vec![],
+            }),
+            finish_reason: "stop".into(),
+        }
+    }
+
+    fn completion_response(choices: Vec<CompletionChoice>) -> CompletionResponse {
+        CompletionResponse {
+            id: "cmpl-1".to_string(),
+            object: "text_completion".into(),
+            created: 0,
+            model: "babbage-002".into(),
+            choices,
+            usage: CompletionUsage {
+                prompt_tokens: 0,
+                completion_tokens: 0,
+                total_tokens: 0,
+            },
+        }
+    }
+
+    #[test]
+    // Verify that the longest choice is picked, preferring the first on ties
+    fn test_best_choice_longest() {
+        let response = completion_response(vec![
+            completion_choice(0, "short", None),
+            completion_choice(1, "much longer", None),
+            completion_choice(2, "equally long", None),
+            completion_choice(3, "much longer", None),
+        ]);
+
+        let best = response.best_choice(ChoiceStrategy::Longest).unwrap();
+        assert_eq!(best.index, 2);
+    }
+
+    #[test]
+    // Verify that the choice with the highest mean logprob is picked, skipping choices without
+    fn test_best_choice_highest_mean_logprob() {
+        let response = completion_response(vec![
+            completion_choice(0, "a", None),
+            completion_choice(1, "b", Some(vec![-1.0, -3.0])),
+            completion_choice(2, "c", Some(vec![-0.5, -1.5])),
+        ]);
+
+        let best = response
+            .best_choice(ChoiceStrategy::HighestMeanLogprob)
+            .unwrap();
+        assert_eq!(best.index, 2);
+
+        let without_logprobs = completion_response(vec![completion_choice(0, "a", None)]);
+        assert!(without_logprobs
+            .best_choice(ChoiceStrategy::HighestMeanLogprob)
+            .is_none());
+    }
+
+    #[test]
+    // Verify that chat choices can be picked by mean logprob or a custom score
+    fn test_chat_best_choice() {
+        let choice = |index: i32, content: &str, logprob: Option<f64>| ChatChoice {
+            message: Message::new("assistant", content),
+            index,
+            logprobs: logprob.map(|logprob| crate::ChatLogprobs {
+                content: Some(vec![crate::TokenLogprob {
+                    token: content.to_string(),
+                    logprob,
+                    bytes: None,
+                    top_logprobs: vec![],
+                }]),
+            }),
+            finish_reason: "stop".into(),
+            content_filter_results: None,
+        };
+
+        let response = ChatCompletionResponse {
+            id: "chatcmpl-1".to_string(),
+            object: "chat.completion".into(),
+            created: 0,
+            model: "gpt-3.5-turbo".into(),
+            choices: vec![
+                choice(0, "longest answer", Some(-2.0)),
+                choice(1, "short", Some(-0.1)),
+                choice(2, "mid size", None),
+            ],
+            usage: ChatUsage {
+                prompt_tokens: 0,
+                completion_tokens: 0,
+                total_tokens: 0,
+            },
+            prompt_filter_results: None,
+            tags: RequestTags::default(),
+        };
+
+        assert_eq!(
+            response.best_choice(ChoiceStrategy::Longest).unwrap().index,
+            0
+        );
+        assert_eq!(
+            response
+                .best_choice(ChoiceStrategy::HighestMeanLogprob)
+                .unwrap()
+                .index,
+            1
+        );
+        assert_eq!(
+            response
+                .best_choice_by(|choice| (choice.index == 2).then_some(1.0))
+                .unwrap()
+                .index,
+            2
+        );
+
+        let best = response.into_best_choice(ChoiceStrategy::HighestMeanLogprob);
+        assert_eq!(best.choices.len(), 1);
+        assert_eq!(best.choices[0].index, 0);
+        assert_eq!(best.choices[0].message.content(), "short");
+    }
+}
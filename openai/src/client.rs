@@ -0,0 +1,380 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing a client which holds the credentials and HTTP connection pool used to send
+//! requests.
+
+use std::fmt;
+use std::sync::OnceLock;
+
+use reqwest::Client;
+use ryst_error::{InternalError, InvalidArgumentError, InvalidStateError};
+
+use crate::credentials::KeySource;
+use crate::error::OpenAIError;
+use crate::models::{ListModels, Model};
+use crate::rate_limit::RateLimiter;
+use crate::redact::RedactedHeaders;
+use crate::retry::RateLimitRetry;
+use crate::{
+    ChatCompletionRequest, ChatCompletionResponse, ChatCompletionResponseStream, CompletionRequest,
+    CompletionResponse, CompletionResponseStream,
+};
+
+/// Sends requests with an explicitly configured API key, organization and base URL, rather
+/// than reading them from the environment.
+///
+/// Requests sent through a client share its HTTP connection pool. Settings made on a request
+/// itself, such as `with_key_source`, take precedence over the client's.
+///
+/// ```no_run
+/// # async fn example() -> Result<(), ryst_openai::OpenAIError> {
+/// use ryst_openai::{ChatCompletionRequest, Message, OpenAIClient};
+///
+/// let client = OpenAIClient::new("sk-...").with_org("org-...");
+/// let request = ChatCompletionRequest::new("gpt-4o", &[Message::new("user", "Hello")]);
+/// let response = client.submit(request).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct OpenAIClient {
+    key_source: Option<KeySource>,
+    org: Option<String>,
+    base_url: Option<String>,
+    rate_limit_retry: Option<RateLimitRetry>,
+    rate_limiter: Option<RateLimiter>,
+    default_headers: Vec<(String, String)>,
+    default_query: Vec<(String, String)>,
+    http: Client,
+}
+
+impl OpenAIClient {
+    /// Create a client which sends the given API key.
+    pub fn new(api_key: &str) -> Self {
+        Self::default().with_key_source(KeySource::Static(api_key.to_string()))
+    }
+
+    /// Read the API key from the source, such as a `SharedKey` rotated by a secrets manager.
+    ///
+    /// A client without a key source reads the key from the environment, as requests sent on
+    /// their own do.
+    pub fn with_key_source(mut self, key_source: KeySource) -> Self {
+        self.key_source = Some(key_source);
+        self
+    }
+
+    /// Send the organization instead of the one set by `OPENAI_API_ORG`.
+    pub fn with_org(mut self, org: &str) -> Self {
+        self.org = Some(org.to_string());
+        self
+    }
+
+    /// Send requests to another URL, see `ChatCompletionRequest::with_base_url`.
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = Some(base_url.to_string());
+        self
+    }
+
+    /// Retry requests rejected with a 429 after the wait the API recommends, unless a request
+    /// sets its own policy.
+    pub fn with_rate_limit_retry(mut self, rate_limit_retry: RateLimitRetry) -> Self {
+        self.rate_limit_retry = Some(rate_limit_retry);
+        self
+    }
+
+    /// Wait before sending each request to stay within the limiter's requests and tokens per
+    /// minute, unless a request sets its own limiter.
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Send the header with every request, unless the request sets its own with `with_header`.
+    pub fn with_default_header(mut self, name: &str, value: &str) -> Self {
+        self.default_headers
+            .push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Append the query parameter to the URL of every request, such as the `api-version` required
+    /// by Azure OpenAI, unless the request sets the same parameter with `with_query`.
+    pub fn with_default_query(mut self, key: &str, value: &str) -> Self {
+        self.default_query
+            .push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Send requests with the given HTTP client, such as one configured with a proxy or timeouts.
+    pub fn with_http_client(mut self, http: Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// Send requests through the proxy, replacing any HTTP client set with `with_http_client`.
+    ///
+    /// Without a proxy, the `HTTPS_PROXY`, `HTTP_PROXY` and `NO_PROXY` environment variables are
+    /// honored. Returns an `InvalidArgument` error if the proxy URL cannot be parsed.
+    pub fn with_proxy(mut self, proxy: Proxy) -> Result<Self, OpenAIError> {
+        self.http = Client::builder()
+            .proxy(proxy.to_reqwest()?)
+            .build()
+            .map_err(|err| OpenAIError::Internal(InternalError::from_source(Box::new(err))))?;
+        Ok(self)
+    }
+
+    /// The source the API key is read from, if one is set.
+    pub fn key_source(&self) -> Option<&KeySource> {
+        self.key_source.as_ref()
+    }
+
+    /// The organization sent with requests, if one is set.
+    pub fn org(&self) -> Option<&str> {
+        self.org.as_deref()
+    }
+
+    /// The URL requests are sent to instead of the OpenAI API, if one is set.
+    pub fn base_url(&self) -> Option<&str> {
+        self.base_url.as_deref()
+    }
+
+    /// The policy for retrying rate limited requests, if one is set.
+    pub fn rate_limit_retry(&self) -> Option<&RateLimitRetry> {
+        self.rate_limit_retry.as_ref()
+    }
+
+    /// The rate limiter requests wait on, if one is set.
+    pub fn rate_limiter(&self) -> Option<&RateLimiter> {
+        self.rate_limiter.as_ref()
+    }
+
+    /// The headers sent with every request.
+    pub fn default_headers(&self) -> &[(String, String)] {
+        &self.default_headers
+    }
+
+    /// The query parameters appended to the URL of every request.
+    pub fn default_query(&self) -> &[(String, String)] {
+        &self.default_query
+    }
+
+    pub(crate) fn http(&self) -> &Client {
+        &self.http
+    }
+
+    /// Submit a chat completion request with this client's credentials.
+    pub async fn submit(
+        &self,
+        request: ChatCompletionRequest,
+    ) -> Result<ChatCompletionResponse, OpenAIError> {
+        request.with_client(self.clone()).submit().await
+    }
+
+    /// Submit a chat completion request with this client's credentials and stream back the
+    /// response.
+    pub async fn stream(
+        &self,
+        request: ChatCompletionRequest,
+    ) -> Result<ChatCompletionResponseStream, OpenAIError> {
+        request.with_client(self.clone()).stream().await
+    }
+
+    /// List the models available to this client's API key, sorted by id.
+    pub async fn list_models(&self) -> Result<Vec<Model>, OpenAIError> {
+        ListModels::new().with_client(self.clone()).send().await
+    }
+
+    /// Submit a completion request with this client's credentials.
+    pub async fn submit_completion(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<CompletionResponse, OpenAIError> {
+        request.with_client(self.clone()).submit().await
+    }
+
+    /// Submit a completion request with this client's credentials and stream back the response.
+    pub async fn stream_completion(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<CompletionResponseStream, OpenAIError> {
+        request.with_client(self.clone()).stream().await
+    }
+}
+
+static GLOBAL: OnceLock<OpenAIClient> = OnceLock::new();
+
+/// Returns the client used by requests which aren't sent through one, so small programs don't
+/// need to pass a client around.
+///
+/// Unless `set_global` is called first, the global client is initialized on first use without
+/// any settings, reading credentials from the environment.
+pub fn global() -> &'static OpenAIClient {
+    GLOBAL.get_or_init(OpenAIClient::default)
+}
+
+/// Set the client used by requests which aren't sent through one.
+///
+/// Call this at startup: returns an `InvalidState` error if the global client was already set,
+/// or initialized by a request sent before this call.
+///
+/// ```no_run
+/// # async fn example() -> Result<(), ryst_openai::OpenAIError> {
+/// use ryst_openai::{ChatCompletionRequest, Message, OpenAIClient};
+///
+/// ryst_openai::set_global(OpenAIClient::new("sk-...").with_org("org-..."))?;
+///
+/// let request = ChatCompletionRequest::new("gpt-4o", &[Message::new("user", "Hello")]);
+/// let response = request.submit().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn set_global(client: OpenAIClient) -> Result<(), OpenAIError> {
+    GLOBAL.set(client).map_err(|_| {
+        OpenAIError::InvalidState(InvalidStateError::with_message(
+            "The global client is already initialized".to_string(),
+        ))
+    })
+}
+
+/// An HTTP, HTTPS or SOCKS proxy which a client sends its requests through.
+///
+/// SOCKS proxies, with a `socks5://` or `socks5h://` URL, require the `socks` feature.
+///
+/// ```
+/// # fn example() -> Result<(), ryst_openai::OpenAIError> {
+/// use ryst_openai::{OpenAIClient, Proxy};
+///
+/// let proxy = Proxy::new("http://proxy.internal:3128").with_basic_auth("alice", "secret");
+/// let client = OpenAIClient::new("sk-...").with_proxy(proxy)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, PartialEq)]
+pub struct Proxy {
+    url: String,
+    credentials: Option<(String, String)>,
+}
+
+impl Proxy {
+    /// Create a proxy for all requests, such as `http://proxy.internal:3128`.
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            credentials: None,
+        }
+    }
+
+    /// Authenticate with the proxy with a username and password.
+    pub fn with_basic_auth(mut self, username: &str, password: &str) -> Self {
+        self.credentials = Some((username.to_string(), password.to_string()));
+        self
+    }
+
+    /// The URL of the proxy.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn to_reqwest(&self) -> Result<reqwest::Proxy, OpenAIError> {
+        let proxy = reqwest::Proxy::all(&self.url).map_err(|err| {
+            OpenAIError::InvalidArgument(InvalidArgumentError::new("proxy", err.to_string()))
+        })?;
+
+        Ok(match &self.credentials {
+            Some((username, password)) => proxy.basic_auth(username, password),
+            None => proxy,
+        })
+    }
+}
+
+impl fmt::Debug for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Proxy")
+            .field("url", &self.url)
+            .field(
+                "credentials",
+                &self
+                    .credentials
+                    .as_ref()
+                    .map(|(username, _)| (username, "<redacted>")),
+            )
+            .finish()
+    }
+}
+
+impl fmt::Debug for OpenAIClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OpenAIClient")
+            .field("key_source", &self.key_source)
+            .field("org", &self.org)
+            .field("base_url", &self.base_url)
+            .field("rate_limit_retry", &self.rate_limit_retry)
+            .field("rate_limiter", &self.rate_limiter)
+            .field("default_headers", &RedactedHeaders(&self.default_headers))
+            .field("default_query", &self.default_query)
+            .field("http", &self.http)
+            .finish()
+    }
+}
+
+// The HTTP client cannot be compared, so clients are equal when they send the same credentials
+impl PartialEq for OpenAIClient {
+    fn eq(&self, other: &Self) -> bool {
+        self.key_source == other.key_source
+            && self.org == other.org
+            && self.base_url == other.base_url
+            && self.rate_limit_retry == other.rate_limit_retry
+            && self.rate_limiter == other.rate_limiter
+            && self.default_headers == other.default_headers
+            && self.default_query == other.default_query
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Verify that a proxy is configured on the client and its password is kept out of Debug
+    fn test_with_proxy() {
+        let proxy = Proxy::new("http://proxy.internal:3128").with_basic_auth("alice", "secret");
+        assert!(!format!("{proxy:?}").contains("secret"));
+        assert!(OpenAIClient::new("sk-test").with_proxy(proxy).is_ok());
+
+        assert!(matches!(
+            OpenAIClient::new("sk-test").with_proxy(Proxy::new("not a url")),
+            Err(OpenAIError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    // Verify that default header values are kept out of Debug
+    fn test_debug_redacts_headers() {
+        let client = OpenAIClient::new("sk-test").with_default_header("X-Gateway-Token", "secret");
+        let debug = format!("{client:?}");
+        assert!(debug.contains("X-Gateway-Token"));
+        assert!(!debug.contains("secret"));
+    }
+
+    #[test]
+    // Verify that the global client is initialized once and can't be replaced afterwards
+    fn test_global() {
+        assert!(std::ptr::eq(global(), global()));
+        assert!(matches!(
+            set_global(OpenAIClient::new("sk-test")),
+            Err(OpenAIError::InvalidState(_))
+        ));
+        assert_eq!(global().key_source(), None);
+    }
+}
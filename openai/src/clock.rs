@@ -0,0 +1,241 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing the `Clock` which components that wait read time and sleep through, and
+//! the seedable `Jitter` they randomize waits with, so tests can control both.
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// A source of the current time and of sleeps.
+pub trait Clock: Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+
+    /// Returns a future which completes once the duration has passed.
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+}
+
+/// The clock of the tokio runtime, which is the default.
+///
+/// Time follows `tokio::time::pause` and `tokio::time::advance`, so tests using a paused runtime
+/// run without waiting.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// A clock which only moves when advanced, for tests which do not run on a tokio runtime.
+///
+/// Sleeping advances the clock by the duration and completes immediately. Clones share the same
+/// time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl ManualClock {
+    /// Create a clock starting at the current time.
+    pub fn new() -> Self {
+        Self {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Move the clock forward.
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self
+            .now
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        *now += duration;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self
+            .now
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        self.advance(duration);
+        Box::pin(std::future::ready(()))
+    }
+}
+
+/// Randomly lengthens or shortens waits, so that many clients backing off together do not
+/// retry in step.
+///
+/// Clones share the same random sequence, and jitters are only equal to their clones.
+#[derive(Clone, PartialEq)]
+pub struct Jitter {
+    fraction: f64,
+    rng: Rng,
+}
+
+impl Jitter {
+    /// Create a jitter which changes each wait by up to the fraction of it, in either
+    /// direction, seeded from the current time.
+    pub fn new(fraction: f64) -> Self {
+        Self {
+            fraction: fraction.clamp(0.0, 1.0),
+            rng: Rng::from_time(),
+        }
+    }
+
+    /// Create a jitter which produces the same sequence for the same seed, for reproducible
+    /// tests.
+    pub fn seeded(fraction: f64, seed: u64) -> Self {
+        Self {
+            fraction: fraction.clamp(0.0, 1.0),
+            rng: Rng::seeded(seed),
+        }
+    }
+
+    /// Returns the duration changed by a random amount within the fraction, saturating at
+    /// `Duration::MAX`.
+    pub fn apply(&self, duration: Duration) -> Duration {
+        // A value from -1 to 1
+        let unit = self.rng.next_f64() * 2.0 - 1.0;
+        Duration::try_from_secs_f64(duration.as_secs_f64() * (1.0 + unit * self.fraction))
+            .unwrap_or(Duration::MAX)
+    }
+}
+
+/// A small random number generator, good enough for spreading out waits and picking samples but
+/// not for anything security related. Clones share the same sequence.
+#[derive(Debug, Clone)]
+pub(crate) struct Rng {
+    state: Arc<Mutex<u64>>,
+}
+
+impl PartialEq for Rng {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.state, &other.state)
+    }
+}
+
+impl Rng {
+    /// Create a generator which produces the same sequence for the same seed.
+    pub fn seeded(seed: u64) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(seed)),
+        }
+    }
+
+    /// Create a generator seeded from the current time.
+    pub fn from_time() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos() as u64)
+            .unwrap_or_default();
+        Self::seeded(seed)
+    }
+
+    // SplitMix64, which is small and fast
+    pub fn next_u64(&self) -> u64 {
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a value from 0 up to but not including 1.
+    pub fn next_f64(&self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Returns an index below `len`, which must not be zero.
+    pub fn below(&self, len: usize) -> usize {
+        (self.next_f64() * len as f64) as usize
+    }
+
+    /// Shuffle the items in place.
+    pub fn shuffle<T>(&self, items: &mut [T]) {
+        for index in (1..items.len()).rev() {
+            items.swap(index, self.below(index + 1));
+        }
+    }
+}
+
+impl fmt::Debug for Jitter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Jitter")
+            .field("fraction", &self.fraction)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Verify that sleeping on a manual clock advances it without waiting
+    fn test_manual_clock() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+
+        futures::executor::block_on(clock.clone().sleep(Duration::from_secs(3600)));
+        clock.advance(Duration::from_secs(1));
+
+        assert_eq!(clock.now() - start, Duration::from_secs(3601));
+    }
+
+    #[test]
+    // Verify that seeded jitter is reproducible and stays within its fraction
+    fn test_jitter_seeded() {
+        let durations = |jitter: Jitter| {
+            (0..100)
+                .map(|_| jitter.apply(Duration::from_secs(10)))
+                .collect::<Vec<_>>()
+        };
+
+        let first = durations(Jitter::seeded(0.2, 7));
+        assert_eq!(first, durations(Jitter::seeded(0.2, 7)));
+        assert_ne!(first, durations(Jitter::seeded(0.2, 8)));
+        assert!(first
+            .iter()
+            .all(|d| *d >= Duration::from_secs(8) && *d <= Duration::from_secs(12)));
+        assert_eq!(
+            Jitter::seeded(0.0, 7).apply(Duration::from_secs(10)),
+            Duration::from_secs(10)
+        );
+    }
+}
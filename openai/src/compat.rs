@@ -0,0 +1,207 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing conversions to and from the types of the `async-openai` crate, to ease
+//! migrating a codebase to ryst one call site at a time.
+//!
+//! Both crates model the same OpenAI wire format, so values are converted through their JSON
+//! representation. Conversions fail where the types disagree, such as a response without usage,
+//! which this crate requires, or a single `stop` string, which this crate sends as a list.
+
+use async_openai::types::{
+    ChatCompletionRequestMessage, CreateChatCompletionRequest, CreateChatCompletionResponse,
+    CreateCompletionRequest, CreateCompletionResponse,
+};
+use ryst_error::InvalidArgumentError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::OpenAIError;
+use crate::{
+    ChatCompletionRequest, ChatCompletionResponse, CompletionRequest, CompletionResponse, Message,
+};
+
+/// Convert a value to the type of the other crate with the same JSON representation.
+fn convert<T, U>(value: &T, argument: &str) -> Result<U, OpenAIError>
+where
+    T: Serialize,
+    U: DeserializeOwned,
+{
+    serde_json::to_value(value)
+        .and_then(serde_json::from_value)
+        .map_err(|err| {
+            OpenAIError::InvalidArgument(InvalidArgumentError::new(
+                argument,
+                format!("Unable to convert {argument}: {err}"),
+            ))
+        })
+}
+
+macro_rules! impl_conversions {
+    ($ours:ty, $theirs:ty, $argument:literal) => {
+        impl TryFrom<&$ours> for $theirs {
+            type Error = OpenAIError;
+
+            fn try_from(value: &$ours) -> Result<Self, Self::Error> {
+                convert(value, $argument)
+            }
+        }
+
+        impl TryFrom<$ours> for $theirs {
+            type Error = OpenAIError;
+
+            fn try_from(value: $ours) -> Result<Self, Self::Error> {
+                convert(&value, $argument)
+            }
+        }
+
+        impl TryFrom<&$theirs> for $ours {
+            type Error = OpenAIError;
+
+            fn try_from(value: &$theirs) -> Result<Self, Self::Error> {
+                convert(value, $argument)
+            }
+        }
+
+        impl TryFrom<$theirs> for $ours {
+            type Error = OpenAIError;
+
+            fn try_from(value: $theirs) -> Result<Self, Self::Error> {
+                convert(&value, $argument)
+            }
+        }
+    };
+}
+
+impl_conversions!(Message, ChatCompletionRequestMessage, "message");
+impl_conversions!(
+    ChatCompletionRequest,
+    CreateChatCompletionRequest,
+    "request"
+);
+impl_conversions!(
+    ChatCompletionResponse,
+    CreateChatCompletionResponse,
+    "response"
+);
+impl_conversions!(CompletionRequest, CreateCompletionRequest, "request");
+impl_conversions!(CompletionResponse, CreateCompletionResponse, "response");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_openai::types::{
+        ChatCompletionRequestMessageContentPartText, ChatCompletionRequestUserMessage,
+        ChatCompletionRequestUserMessageContent, ChatCompletionRequestUserMessageContentPart,
+    };
+    use serde_json::json;
+
+    use crate::{ContentPart, MessageContent};
+
+    #[test]
+    // Verify that messages convert in both directions, including content parts
+    fn test_message_conversions() {
+        let message = Message::new("system", "Be brief.");
+        let converted = ChatCompletionRequestMessage::try_from(&message).unwrap();
+        assert!(matches!(converted, ChatCompletionRequestMessage::System(_)));
+        assert_eq!(Message::try_from(converted).unwrap(), message);
+
+        let theirs = ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+            content: ChatCompletionRequestUserMessageContent::Array(vec![
+                ChatCompletionRequestUserMessageContentPart::Text(
+                    ChatCompletionRequestMessageContentPartText {
+                        text: "Hello".to_string(),
+                    },
+                ),
+            ]),
+            name: None,
+        });
+        let message = Message::try_from(&theirs).unwrap();
+        assert_eq!(message.role(), "user");
+        assert_eq!(
+            message.content,
+            MessageContent::Parts(vec![ContentPart::Text {
+                text: "Hello".to_string()
+            }])
+        );
+    }
+
+    #[test]
+    // Verify that requests convert in both directions, keeping the sampling parameters
+    fn test_request_conversions() {
+        let request = ChatCompletionRequest::new("gpt-4o", &[Message::new("user", "Hi")])
+            .with_temperature(0.5)
+            .with_max_tokens(10);
+
+        let theirs = CreateChatCompletionRequest::try_from(&request).unwrap();
+        assert_eq!(theirs.model, "gpt-4o");
+        assert_eq!(theirs.temperature, Some(0.5));
+        assert_eq!(theirs.messages.len(), 1);
+
+        assert_eq!(ChatCompletionRequest::try_from(theirs).unwrap(), request);
+    }
+
+    #[test]
+    // Verify that responses convert in both directions, and a response without usage is an error
+    fn test_response_conversions() {
+        let json = json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "gpt-4o",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": "Hello"},
+                "finish_reason": "stop"
+            }],
+            "usage": {"prompt_tokens": 1, "completion_tokens": 2, "total_tokens": 3}
+        });
+        let theirs: CreateChatCompletionResponse = serde_json::from_value(json).unwrap();
+
+        let response = ChatCompletionResponse::try_from(&theirs).unwrap();
+        assert_eq!(response.choices[0].message.content(), "Hello");
+        assert_eq!(
+            CreateChatCompletionResponse::try_from(&response).unwrap(),
+            theirs
+        );
+
+        let without_usage = CreateChatCompletionResponse {
+            usage: None,
+            ..theirs
+        };
+        assert!(ChatCompletionResponse::try_from(without_usage).is_err());
+    }
+
+    #[test]
+    // Verify that completion requests and responses convert
+    fn test_completion_conversions() {
+        let request = CompletionRequest::new("babbage-002", "Say hi").with_max_tokens(5);
+        let theirs = CreateCompletionRequest::try_from(&request).unwrap();
+        assert_eq!(theirs.max_tokens, Some(5));
+        assert_eq!(CompletionRequest::try_from(theirs).unwrap(), request);
+
+        let json = json!({
+            "id": "cmpl-1",
+            "object": "text_completion",
+            "created": 0,
+            "model": "babbage-002",
+            "choices": [{"text": "hi", "index": 0, "logprobs": null, "finish_reason": "stop"}],
+            "usage": {"prompt_tokens": 2, "completion_tokens": 1, "total_tokens": 3}
+        });
+        let theirs: CreateCompletionResponse = serde_json::from_value(json).unwrap();
+        let response = CompletionResponse::try_from(theirs).unwrap();
+        assert_eq!(response.choices[0].text, "hi");
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Deterministic completion responses for use in downstream unit tests.
+
+use super::{CompletionChoice, CompletionResponse, CompletionUsage};
+
+const FAKE_ID: &str = "cmpl-fake";
+const FAKE_MODEL: &str = "babbage-002";
+
+impl CompletionResponse {
+    /// Create a response containing a single choice with the given text.
+    pub fn fake_with_text(text: &str) -> Self {
+        Self::fake_with_choices(vec![CompletionChoice::fake(0, text)])
+    }
+
+    /// Create a response containing the given choices.
+    ///
+    /// The usage reports one completion token per whitespace separated word in the choices.
+    pub fn fake_with_choices(choices: Vec<CompletionChoice>) -> Self {
+        let completion_tokens = choices
+            .iter()
+            .map(|choice| choice.text.split_whitespace().count() as i32)
+            .sum();
+
+        Self {
+            id: FAKE_ID.to_string(),
+            object: "text_completion".into(),
+            created: 0,
+            model: FAKE_MODEL.into(),
+            choices,
+            usage: CompletionUsage::fake(0, completion_tokens),
+        }
+    }
+
+    /// Replace the model of a fake response.
+    pub fn with_model(mut self, model: &str) -> Self {
+        self.model = model.into();
+        self
+    }
+
+    /// Replace the usage of a fake response.
+    pub fn with_usage(mut self, usage: CompletionUsage) -> Self {
+        self.usage = usage;
+        self
+    }
+}
+
+impl CompletionChoice {
+    /// Create a choice without logprobs that finished with `stop`.
+    pub fn fake(index: i32, text: &str) -> Self {
+        Self {
+            text: text.to_string(),
+            index,
+            logprobs: None,
+            finish_reason: "stop".into(),
+        }
+    }
+
+    /// Replace the finish reason of a fake choice, e.g. `length`.
+    pub fn with_finish_reason(mut self, finish_reason: &str) -> Self {
+        self.finish_reason = finish_reason.into();
+        self
+    }
+}
+
+impl CompletionUsage {
+    /// Create usage with the total computed from the prompt and completion tokens.
+    pub fn fake(prompt_tokens: i32, completion_tokens: i32) -> Self {
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Verify that a fake response is deterministic and contains the given text
+    fn test_fake_with_text() {
+        let response = CompletionResponse::fake_with_text("hello world");
+
+        assert_eq!(response, CompletionResponse::fake_with_text("hello world"));
+        assert_eq!(response.choices[0].text, "hello world");
+        assert_eq!(response.usage, CompletionUsage::fake(0, 2));
+    }
+
+    #[test]
+    // Verify that fake choices and usage can be customized
+    fn test_fake_with_choices() {
+        let response = CompletionResponse::fake_with_choices(vec![
+            CompletionChoice::fake(0, "a"),
+            CompletionChoice::fake(1, "b").with_finish_reason("length"),
+        ])
+        .with_model("davinci-002")
+        .with_usage(CompletionUsage::fake(3, 4));
+
+        assert_eq!(&*response.model, "davinci-002");
+        assert_eq!(&*response.choices[1].finish_reason, "length");
+        assert_eq!(response.usage.total_tokens, 7);
+    }
+}
@@ -15,12 +15,14 @@
 //! This module contains a set of structs for communicating with OpenAI
 //! completions API.
 
+#[cfg(feature = "testing")]
+mod fixtures;
 mod request;
 mod response;
 
 pub use request::CompletionRequest;
 pub use response::{
-    CompletionChoice, CompletionResponse, CompletionResponseStream, CompletionUsage,
+    CompletionChoice, CompletionResponse, CompletionResponseStream, CompletionUsage, Logprobs,
 };
 
 // The following tests require that OPENAI_API_KEY (optionally OPENAI_API_ORG)
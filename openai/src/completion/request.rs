@@ -13,19 +13,51 @@
 // limitations under the License.
 
 use std::collections::HashMap;
-use std::env;
+use std::fmt;
+use std::time::Instant;
 
-use reqwest::Client;
-use ryst_error::{InternalError, InvalidArgumentError, InvalidStateError};
-use serde::Serialize;
+use ryst_error::{InvalidArgumentError, InvalidStateError};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
+use crate::client::OpenAIClient;
+use crate::credentials::KeySource;
 use crate::error::OpenAIError;
-use crate::OPEN_AI_URL;
+use crate::http::{self, PreSendHook, RequestOptions, ResponseMetadata};
+use crate::rate_limit::RateLimiter;
+use crate::redact::{Redacted, RedactedOption, RedactedValues};
+use crate::retry::{RateLimitRetry, RetryObserver, SharedRetryObserver};
+use crate::tags::RequestTags;
+use crate::trace::ExchangeTrace;
 
 use super::{CompletionResponse, CompletionResponseStream};
 
+/// The fields of the request body, which extra fields may not replace.
+const FIELDS: &[&str] = &[
+    "model",
+    "prompt",
+    "suffix",
+    "max_tokens",
+    "temperature",
+    "top_p",
+    "n",
+    "stream",
+    "logprobs",
+    "echo",
+    "stop",
+    "presence_penalty",
+    "frequency_penalty",
+    "best_of",
+    "logit_bias",
+    "user",
+];
+
 /// Builder for creating the completion request and submitting to OpenAI API.
-#[derive(Debug, Serialize, PartialEq, Default)]
+///
+/// Prompts, the user identifier and extra fields are redacted when debug printed, see
+/// [`crate::redact`].
+#[derive(Serialize, Deserialize, PartialEq, Default, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct CompletionRequest {
     model: String,
     prompt: String,
@@ -57,6 +89,36 @@ pub struct CompletionRequest {
     logit_bias: Option<HashMap<String, i8>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     user: Option<String>,
+    /// Fields added with `with_extra`, which are sent after the fields above
+    #[serde(flatten)]
+    extra: Map<String, Value>,
+    #[serde(skip)]
+    options: RequestOptions,
+}
+
+impl fmt::Debug for CompletionRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompletionRequest")
+            .field("model", &self.model)
+            .field("prompt", &Redacted(&self.prompt))
+            .field("suffix", &RedactedOption(self.suffix.as_deref()))
+            .field("max_tokens", &self.max_tokens)
+            .field("temperature", &self.temperature)
+            .field("top_p", &self.top_p)
+            .field("n", &self.n)
+            .field("stream", &self.stream)
+            .field("logprobs", &self.logprobs)
+            .field("echo", &self.echo)
+            .field("stop", &self.stop)
+            .field("presence_penalty", &self.presence_penalty)
+            .field("frequency_penalty", &self.frequency_penalty)
+            .field("best_of", &self.best_of)
+            .field("logit_bias", &self.logit_bias)
+            .field("user", &RedactedOption(self.user.as_deref()))
+            .field("extra", &RedactedValues(&self.extra))
+            .field("options", &self.options)
+            .finish()
+    }
 }
 
 impl CompletionRequest {
@@ -73,40 +135,22 @@ impl CompletionRequest {
 
     /// Submit the completion request to the OpenAI url.
     ///
-    /// Requires that `OPENAI_API_KEY` environment variable is set. Optionally,
-    /// the org will be added if `OPENAI_API_ORG` is set.
+    /// The API key is read from the source set with `with_key_source`, or by `OpenAIClient` when
+    /// sent through one. Otherwise, requires that either the `OPENAI_API_KEY` or
+    /// `OPENAI_API_KEY_FILE` environment variable is set. Optionally, the org will be added if
+    /// `OPENAI_API_ORG` is set.
     pub async fn submit(self) -> Result<CompletionResponse, OpenAIError> {
-        let api_key = env::var("OPENAI_API_KEY").map_err(|_| {
-            OpenAIError::InvalidState(InvalidStateError::with_message(
-                "OPENAI_API_KEY env variable must be set".to_string(),
-            ))
-        })?;
-
-        let mut request = Client::new()
-            .post(format!("{OPEN_AI_URL}/v1/completions"))
-            .header("Authorization", format!("Bearer {api_key}"))
-            .header("Content-Type", "application/json")
-            .json(&self);
-
-        if let Ok(org) = env::var("OPENAI_API_ORG") {
-            request = request.header("OpenAI-Organization", org)
-        };
-
-        if let Some(stops) = self.stop {
-            if stops.len() > 4 {
-                return Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
-                    "stop",
-                    "You can only provide up to 4 stop sequences",
-                )));
-            }
-        }
+        self.submit_with_metadata()
+            .await
+            .map(|(response, _)| response)
+    }
 
-        if self.temperature.is_some() && self.top_p.is_some() {
-            return Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
-                "temperature",
-                "Use temperature or top_p but not both",
-            )));
-        }
+    /// Submit the request as `submit` does, also returning details of the HTTP response such as
+    /// the processing time and any headers requested with `with_captured_header`.
+    pub async fn submit_with_metadata(
+        self,
+    ) -> Result<(CompletionResponse, ResponseMetadata), OpenAIError> {
+        self.validate()?;
 
         if self.stream == Some(true) {
             return Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
@@ -115,60 +159,49 @@ impl CompletionRequest {
             )));
         }
 
-        match request.send().await {
-            Ok(response) => {
-                // Check if the status is a 2XX code.
-                let status = response.status();
-                if status.is_success() {
-                    let result = response.json::<CompletionResponse>().await.map_err(|err| {
-                        OpenAIError::InvalidState(InvalidStateError::with_message(err.to_string()))
-                    })?;
-                    Ok(result)
-                } else {
-                    let text = response.text().await.map_err(|err| {
-                        OpenAIError::InvalidState(InvalidStateError::with_message(err.to_string()))
-                    })?;
-                    if status.is_client_error() {
-                        Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
-                            "request", text,
-                        )))
-                    } else {
-                        Err(OpenAIError::Internal(InternalError::with_message(text)))
-                    }
-                }
-            }
-            Err(err) => Err(OpenAIError::Internal(InternalError::from_source(Box::new(
-                err,
-            )))),
+        let response = http::post("/v1/completions", &self, &self.options).await?;
+
+        let metadata = ResponseMetadata::from_response(&response, &self.options.captured_headers);
+        let response = response.json::<CompletionResponse>().await.map_err(|err| {
+            OpenAIError::InvalidState(InvalidStateError::with_message(err.to_string()))
+        });
+        if let Some(trace) = &self.options.trace {
+            trace.record_result(&response);
         }
+        let response = response?;
+
+        Ok((response, metadata))
     }
 
     /// Submit the completion request to the OpenAI url and stream back the response.
     ///
-    /// Requires that `OPENAI_API_KEY` environment variable is set. Optionally,
-    /// the org will be added if `OPENAI_API_ORG` is set.
-    /// Submit the completion request to the OpenAI url.
-    ///
-    /// Requires that `OPENAI_API_KEY` environment variable is set. Optionally,
-    /// the org will be added if `OPENAI_API_ORG` is set.
-    pub async fn stream(mut self) -> Result<CompletionResponseStream, OpenAIError> {
-        let api_key = env::var("OPENAI_API_KEY").map_err(|_| {
-            OpenAIError::InvalidState(InvalidStateError::with_message(
-                "OPENAI_API_KEY env variable must be set".to_string(),
-            ))
-        })?;
-
-        let mut request = Client::new()
-            .post(format!("{OPEN_AI_URL}/v1/completions"))
-            .header("Authorization", format!("Bearer {api_key}"))
-            .header("Content-Type", "application/json")
-            .json(&self);
-
-        if let Ok(org) = env::var("OPENAI_API_ORG") {
-            request = request.header("OpenAI-Organization", org)
-        };
-
-        if let Some(stops) = self.stop {
+    /// The API key is read from the source set with `with_key_source`, or by `OpenAIClient` when
+    /// sent through one. Otherwise, requires that either the `OPENAI_API_KEY` or
+    /// `OPENAI_API_KEY_FILE` environment variable is set. Optionally, the org will be added if
+    /// `OPENAI_API_ORG` is set.
+    pub async fn stream(self) -> Result<CompletionResponseStream, OpenAIError> {
+        self.validate()?;
+
+        let started = Instant::now();
+        let response = http::post("/v1/completions", &self, &self.options).await?;
+        let metadata = ResponseMetadata::from_response(&response, &self.options.captured_headers);
+
+        let mut stream = CompletionResponseStream::new(Box::pin(response.bytes_stream()))
+            .with_metadata(metadata)
+            .with_started(started);
+        if let Some(trace) = &self.options.trace {
+            stream = stream.with_trace(trace.clone());
+        }
+        Ok(stream)
+    }
+
+    pub(crate) fn options(&self) -> &RequestOptions {
+        &self.options
+    }
+
+    /// Check the parameters that would otherwise be rejected by the API.
+    fn validate(&self) -> Result<(), OpenAIError> {
+        if let Some(stops) = &self.stop {
             if stops.len() > 4 {
                 return Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
                     "stop",
@@ -184,33 +217,14 @@ impl CompletionRequest {
             )));
         }
 
-        self.stream = Some(true);
-
-        match request.send().await {
-            Ok(response) => {
-                // Check if the status is a 2XX code.
-                let status = response.status();
-                if status.is_success() {
-                    Ok(CompletionResponseStream::new(Box::pin(
-                        response.bytes_stream(),
-                    )))
-                } else {
-                    let text = response.text().await.map_err(|err| {
-                        OpenAIError::InvalidState(InvalidStateError::with_message(err.to_string()))
-                    })?;
-                    if status.is_client_error() {
-                        Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
-                            "request", text,
-                        )))
-                    } else {
-                        Err(OpenAIError::Internal(InternalError::with_message(text)))
-                    }
-                }
-            }
-            Err(err) => Err(OpenAIError::Internal(InternalError::from_source(Box::new(
-                err,
-            )))),
+        if let Some(key) = self.extra.keys().find(|key| FIELDS.contains(&key.as_str())) {
+            return Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
+                "extra",
+                format!("{key} is a field of the request and cannot be set as an extra field"),
+            )));
         }
+
+        Ok(())
     }
 
     /// Add a suffix that comes after a completion of inserted text.
@@ -318,9 +332,232 @@ impl CompletionRequest {
         self
     }
 
+    /// Set `max_tokens` to the room left in the model's context window after the prompt, less a
+    /// small margin, capped at the most tokens the model can generate.
+    ///
+    /// Call this after the prompt has been set. Fails if the model's tokenizer or context window
+    /// is unknown, or the prompt leaves no room for a reply.
+    #[cfg(feature = "tokens")]
+    pub fn with_max_tokens_auto(mut self) -> Result<Self, OpenAIError> {
+        let prompt_tokens = crate::tokens::count_tokens(&self.model, &self.prompt)?;
+        self.max_tokens = Some(crate::tokens::auto_max_tokens(&self.model, prompt_tokens)?);
+        Ok(self)
+    }
+
+    /// Modify the likelihood of words appearing in the completion, encoding them with the
+    /// tokenizer of the request's model.
+    ///
+    /// Each word is encoded with and without a leading space and every resulting token is given
+    /// the bias, replacing the bias of tokens which were already set. Fails if no tokenizer is
+    /// known for the model.
+    #[cfg(feature = "tokens")]
+    pub fn with_logit_bias_words(mut self, words: &[(&str, i8)]) -> Result<Self, OpenAIError> {
+        let words = crate::tokens::logit_bias_words(&self.model, words)?;
+        self.logit_bias
+            .get_or_insert_with(HashMap::new)
+            .extend(words);
+        Ok(self)
+    }
+
     /// A unique ID representing your end-user, which can help OpenAI to monitor and detect abuse.
     pub fn with_user(mut self, user: &str) -> Self {
         self.user = Some(user.to_string());
         self
     }
+
+    /// Add a field to the request body which this crate does not model, such as the `top_k` or
+    /// `repetition_penalty` parameters of OpenAI-compatible servers like vLLM.
+    ///
+    /// Setting the same key again replaces its value. Keys which name one of the request's own
+    /// fields are rejected when the request is sent.
+    pub fn with_extra(mut self, key: &str, value: Value) -> Self {
+        self.extra.insert(key.to_string(), value);
+        self
+    }
+
+    /// Where to read the API key from when the request is sent.
+    ///
+    /// Defaults to the `OPENAI_API_KEY` environment variable, falling back to the file named by
+    /// `OPENAI_API_KEY_FILE`.
+    pub fn with_key_source(mut self, key_source: KeySource) -> Self {
+        self.options.key_source = Some(key_source);
+        self
+    }
+
+    /// Send the request to another URL instead of `https://api.openai.com`, such as a proxy,
+    /// gateway or OpenAI-compatible server like vLLM or LiteLLM.
+    ///
+    /// The API path, such as `/v1/completions`, is appended to it.
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.options.base_url = Some(base_url.to_string());
+        self
+    }
+
+    /// Send the request through the client, using its credentials and HTTP connection pool.
+    pub fn with_client(mut self, client: OpenAIClient) -> Self {
+        self.options.client = Some(client);
+        self
+    }
+
+    /// Append a query parameter to the request URL, such as the `api-version` required by Azure
+    /// OpenAI or a gateway's routing parameter. Parameters are sent in the order they are added,
+    /// after the client's `with_default_query` parameters, replacing any with the same key.
+    pub fn with_query(mut self, key: &str, value: &str) -> Self {
+        self.options
+            .query
+            .push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Copy the response header into `ResponseMetadata::headers`, such as a gateway's trace id.
+    pub fn with_captured_header(mut self, name: &str) -> Self {
+        self.options.captured_headers.push(name.to_string());
+        self
+    }
+
+    /// Send the header with the request, such as a tracing id, a gateway's auth token or an
+    /// `OpenAI-Beta` feature flag. Headers replace any the library or the client set with the same
+    /// name, and invalid names or values are rejected when the request is sent.
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.options
+            .headers
+            .push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Send the user agent instead of the default `ryst-openai/<version>`, such as to name the
+    /// application to a gateway.
+    pub fn with_user_agent(mut self, user_agent: &str) -> Self {
+        self.options.user_agent = Some(user_agent.to_string());
+        self
+    }
+
+    /// Send `X-Ryst-Lang`, `X-Ryst-Package-Version`, `X-Ryst-OS` and `X-Ryst-Arch` headers
+    /// describing the client, so providers and gateways can attribute traffic.
+    pub fn with_client_metadata(mut self) -> Self {
+        self.options.client_metadata = true;
+        self
+    }
+
+    /// Tag the request with the prompt template and experiment it belongs to.
+    ///
+    /// Tags are not sent to the API; see `RequestTags` for where they are recorded.
+    pub fn with_tags(mut self, tags: RequestTags) -> Self {
+        self.options.tags = tags;
+        self
+    }
+    /// A callback invoked with the final HTTP request immediately before it is sent.
+    ///
+    /// Use this to sign requests for gateways that require an HMAC of the body or additional
+    /// timestamp headers.
+    pub fn with_pre_send_hook(mut self, hook: PreSendHook) -> Self {
+        self.options.pre_send_hook = Some(hook);
+        self
+    }
+
+    /// Retry the request if it is rejected with a 429, after the wait the API recommends.
+    ///
+    /// Without a policy, the recommended wait is returned in `OpenAIError::RateLimited`.
+    pub fn with_rate_limit_retry(mut self, rate_limit_retry: RateLimitRetry) -> Self {
+        self.options.rate_limit_retry = Some(rate_limit_retry);
+        self
+    }
+
+    /// Wait before sending the request to stay within the limiter's requests and tokens per
+    /// minute, see [`crate::rate_limit`].
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.options.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Report each time the request is sent again, such as after refreshing a rejected key.
+    pub fn with_retry_observer<O: RetryObserver + 'static>(mut self, observer: O) -> Self {
+        self.options.retry_observer = Some(SharedRetryObserver::new(observer));
+        self
+    }
+
+    /// Record the request, its response and any retries or errors in the trace.
+    pub fn with_trace(mut self, trace: ExchangeTrace) -> Self {
+        self.options.trace = Some(trace);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    // Floats are drawn from a fixed grid so the JSON representation is exact
+    fn float() -> impl Strategy<Value = f32> {
+        (-200i32..=200).prop_map(|x| x as f32 / 100.0)
+    }
+
+    prop_compose! {
+        fn request()(
+            (model, prompt, suffix) in (any::<String>(), any::<String>(), prop::option::of(any::<String>())),
+            (max_tokens, n, logprobs, best_of) in (
+                prop::option::of(any::<i32>()),
+                prop::option::of(any::<i8>()),
+                prop::option::of(any::<i8>()),
+                prop::option::of(any::<i8>()),
+            ),
+            (temperature, top_p, presence_penalty, frequency_penalty) in (
+                prop::option::of(float()),
+                prop::option::of(float()),
+                prop::option::of(float()),
+                prop::option::of(float()),
+            ),
+            echo in prop::option::of(any::<bool>()),
+            stop in prop::option::of(prop::collection::vec(any::<String>(), 0..5)),
+            logit_bias in prop::option::of(prop::collection::hash_map(any::<String>(), any::<i8>(), 0..4)),
+            user in prop::option::of(any::<String>()),
+        ) -> CompletionRequest {
+            CompletionRequest {
+                model,
+                prompt,
+                suffix,
+                max_tokens,
+                temperature,
+                top_p,
+                n,
+                stream: None,
+                logprobs,
+                echo,
+                stop,
+                presence_penalty,
+                frequency_penalty,
+                best_of,
+                logit_bias,
+                user,
+                extra: Map::new(),
+                options: RequestOptions::default(),
+            }
+        }
+    }
+
+    #[test]
+    // Verify that extra fields are merged into the body and cannot replace the request's fields
+    fn test_with_extra() {
+        let request = CompletionRequest::new("davinci-002", "Say this is a test")
+            .with_extra("top_k", serde_json::json!(40));
+
+        let body = serde_json::to_value(&request).unwrap();
+        assert_eq!(body["prompt"], "Say this is a test");
+        assert_eq!(body["top_k"], 40);
+        assert!(request.validate().is_ok());
+
+        let request = request.with_extra("prompt", serde_json::json!("Something else"));
+        assert!(request.validate().is_err());
+    }
+
+    proptest! {
+        #[test]
+        // Verify that a request deserializes back to the same request after being serialized
+        fn test_request_round_trip(request in request()) {
+            let json = serde_json::to_string(&request).unwrap();
+            prop_assert_eq!(serde_json::from_str::<CompletionRequest>(&json).unwrap(), request);
+        }
+    }
 }
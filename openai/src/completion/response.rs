@@ -15,6 +15,8 @@
 use std::collections::HashMap;
 use std::fmt;
 use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use bytes::{Bytes, BytesMut};
 use futures::Stream;
@@ -22,23 +24,34 @@ use futures::StreamExt;
 use reqwest::Result as ReqwestResult;
 use ryst_error::{InternalError, InvalidStateError};
 use serde::de::{Deserializer, Visitor};
-use serde::Deserialize;
+use serde::ser::{SerializeSeq, Serializer};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWrite;
 
 use crate::error::OpenAIError;
+use crate::http::ResponseMetadata;
+use crate::rolling::RollingWindow;
+use crate::simulated::{truncate_chars, SimulatedStream};
+use crate::stream_stats::{StatsRecorder, StreamStats};
+use crate::trace::{ExchangeTrace, TraceEvent};
+use crate::transcript::write_transcript;
 
 const STREAM_TERMINATION_STRING: &str = "[DONE]";
 
 /// The response returned from a completion request.
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct CompletionResponse {
     /// Request ID
     pub id: String,
     /// Response type
-    pub object: String,
+    #[serde(deserialize_with = "crate::intern::deserialize")]
+    pub object: Arc<str>,
     /// Timestamp of the completion was created
     pub created: i32,
     /// The model the response was created with
-    pub model: String,
+    #[serde(deserialize_with = "crate::intern::deserialize")]
+    pub model: Arc<str>,
     /// The list of generated completions
     pub choices: Vec<CompletionChoice>,
     /// The tokens used by this response and associated request
@@ -46,7 +59,8 @@ pub struct CompletionResponse {
 }
 
 /// The tokens consumed by the completion
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct CompletionUsage {
     pub prompt_tokens: i32,
     pub completion_tokens: i32,
@@ -54,31 +68,84 @@ pub struct CompletionUsage {
 }
 
 /// A generated completion
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct CompletionChoice {
     pub text: String,
     pub index: i32,
     pub logprobs: Option<Logprobs>,
-    pub finish_reason: String,
+    #[serde(deserialize_with = "crate::intern::deserialize")]
+    pub finish_reason: Arc<str>,
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
+impl CompletionResponse {
+    /// Replay the response a chunk of text at a time, waiting `delay` between chunks, so that a
+    /// response which arrived all at once can be shown like a streamed one.
+    ///
+    /// `chunk_size` is in characters. See `SimulatedStream`.
+    pub fn into_simulated_stream(
+        self,
+        chunk_size: usize,
+        delay: Duration,
+    ) -> SimulatedStream<Self> {
+        let len = self
+            .choices
+            .iter()
+            .map(|choice| choice.text.chars().count())
+            .max()
+            .unwrap_or(0);
+        SimulatedStream::new(
+            self,
+            len,
+            |response, chars| {
+                let mut response = response.clone();
+                for choice in &mut response.choices {
+                    truncate_chars(&mut choice.text, chars);
+                }
+                response
+            },
+            chunk_size,
+            delay,
+        )
+    }
+}
+
+impl CompletionChoice {
+    /// Returns the mean log probability of the generated tokens, if logprobs were returned.
+    pub fn mean_logprob(&self) -> Option<f64> {
+        let logprobs = &self.logprobs.as_ref()?.token_logprobs;
+        if logprobs.is_empty() {
+            return None;
+        }
+
+        let total: f64 = logprobs.iter().sum();
+        Some(total / logprobs.len() as f64)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Logprobs {
     pub tokens: Vec<String>,
-    pub token_logprobs: Vec<f32>,
-    #[serde(deserialize_with = "flatten_log_probs")]
-    pub top_logprobs: HashMap<String, f32>,
+    #[serde(deserialize_with = "crate::finite::deserialize_vec")]
+    pub token_logprobs: Vec<f64>,
+    #[serde(
+        deserialize_with = "flatten_log_probs",
+        serialize_with = "nest_log_probs"
+    )]
+    #[cfg_attr(feature = "schema", schemars(with = "Vec<HashMap<String, f64>>"))]
+    pub top_logprobs: HashMap<String, f64>,
     pub text_offset: Vec<i32>,
 }
 
-fn flatten_log_probs<'de, D>(deserializer: D) -> Result<HashMap<String, f32>, D::Error>
+fn flatten_log_probs<'de, D>(deserializer: D) -> Result<HashMap<String, f64>, D::Error>
 where
     D: Deserializer<'de>,
 {
     struct LogProbsVisitor;
 
     impl<'de> Visitor<'de> for LogProbsVisitor {
-        type Value = HashMap<String, f32>;
+        type Value = HashMap<String, f64>;
 
         fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
             formatter.write_str("a sequence of maps")
@@ -90,8 +157,8 @@ where
         {
             let mut result = HashMap::new();
 
-            while let Some(map) = seq.next_element::<HashMap<String, f32>>()? {
-                for (key, value) in map {
+            while let Some(map) = seq.next_element::<HashMap<String, f64>>()? {
+                for (key, value) in crate::finite::check_map(map)? {
                     result.insert(key, value);
                 }
             }
@@ -103,14 +170,107 @@ where
     deserializer.deserialize_seq(LogProbsVisitor)
 }
 
+/// Serialize the flattened top logprobs back into the sequence of maps sent by the API.
+fn nest_log_probs<S>(top_logprobs: &HashMap<String, f64>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let mut seq = serializer.serialize_seq(Some(1))?;
+    seq.serialize_element(top_logprobs)?;
+    seq.end()
+}
+
 /// The response that contains a stream returned from a completion request.
 pub struct CompletionResponseStream {
     stream: Pin<Box<dyn Stream<Item = ReqwestResult<Bytes>> + Send + 'static>>,
+    metadata: Option<ResponseMetadata>,
+    trace: Option<ExchangeTrace>,
+    stats: StatsRecorder,
+    tee: Option<Pin<Box<dyn AsyncWrite + Send>>>,
+    window: Option<RollingWindow>,
 }
 
 impl CompletionResponseStream {
     pub fn new(stream: Pin<Box<dyn Stream<Item = ReqwestResult<Bytes>> + Send + 'static>>) -> Self {
-        Self { stream }
+        Self {
+            stream,
+            metadata: None,
+            trace: None,
+            stats: StatsRecorder::new(Instant::now()),
+            tee: None,
+            window: None,
+        }
+    }
+
+    pub(crate) fn with_metadata(mut self, metadata: ResponseMetadata) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    /// Record each chunk read from the stream, and the response parsed from them, in the trace.
+    pub fn with_trace(mut self, trace: ExchangeTrace) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    /// Copy the text of each response to the writer as it is read, such as to keep a transcript
+    /// file of an interactive session.
+    ///
+    /// The text of each choice is written followed by a newline, and the writer is flushed
+    /// after each response. A failed write is returned as an error from `next`.
+    pub fn tee_to<W: AsyncWrite + Send + 'static>(mut self, writer: W) -> Self {
+        self.tee = Some(Box::pin(writer));
+        self
+    }
+
+    /// Measure the stream's timings from when the request was sent, rather than from when the
+    /// stream was created.
+    pub(crate) fn with_started(mut self, started: Instant) -> Self {
+        self.stats = StatsRecorder::new(started);
+        self
+    }
+
+    /// Timings of the stream, once `next` has read it to the end.
+    pub fn stats(&self) -> Option<&StreamStats> {
+        self.stats.stats()
+    }
+
+    /// Keep the last `len` bytes read by `next_chunk`, to look back over the end of a very long
+    /// response without keeping all of it.
+    pub fn with_window(mut self, len: usize) -> Self {
+        self.window = Some(RollingWindow::new(len));
+        self
+    }
+
+    /// The most recent bytes read by `next_chunk`, if a window was set with `with_window`.
+    pub fn window(&self) -> Option<&[u8]> {
+        self.window.as_ref().map(RollingWindow::as_bytes)
+    }
+
+    /// Details of the HTTP response the stream is read from, if it was returned by a request.
+    pub fn metadata(&self) -> Option<&ResponseMetadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Read the next chunk of the response body as it arrives, without keeping it.
+    ///
+    /// Unlike `next`, which accumulates the whole body before parsing it, only the current chunk
+    /// and the window set with `with_window` are held, so memory stays bounded however long the
+    /// response is. Making sense of the raw bytes is left to the caller. Chunks are still
+    /// recorded in the trace if one is set, but no stats are kept and nothing is written to the
+    /// tee.
+    pub async fn next_chunk(&mut self) -> Result<Option<Bytes>, OpenAIError> {
+        while let Some(value) = self.stream.next().await {
+            let bytes = value.map_err(|err| self.read_error(err))?;
+            self.record_chunk(&bytes);
+            if bytes != STREAM_TERMINATION_STRING.as_bytes() {
+                if let Some(window) = &mut self.window {
+                    window.push(&bytes);
+                }
+                return Ok(Some(bytes));
+            }
+        }
+        Ok(None)
     }
 
     /// Use the stream to get the full response
@@ -119,26 +279,154 @@ impl CompletionResponseStream {
         while let Some(value) = self.stream.next().await {
             match value {
                 Ok(bytes) => {
+                    self.stats.chunk();
+                    self.record_chunk(&bytes);
                     if bytes != STREAM_TERMINATION_STRING.as_bytes() {
                         full_bytes.extend_from_slice(&bytes)
                     }
                 }
                 Err(err) => {
-                    return Err(OpenAIError::Internal(InternalError::from_source(Box::new(
-                        err,
-                    ))))
+                    self.stats.finish(None);
+                    return Err(self.read_error(err));
                 }
             }
         }
 
         if full_bytes.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(
-                serde_json::from_slice::<CompletionResponse>(&full_bytes).map_err(|err| {
-                    OpenAIError::InvalidState(InvalidStateError::with_message(err.to_string()))
-                })?,
-            ))
+            self.stats.finish(None);
+            return Ok(None);
+        }
+
+        let response = self.parse(&full_bytes)?;
+        if let Some(tee) = &mut self.tee {
+            let texts = response
+                .choices
+                .iter()
+                .map(|choice| choice.text.as_str())
+                .collect::<Vec<_>>();
+            write_transcript(tee.as_mut(), &texts).await?;
         }
+        Ok(Some(response))
+    }
+
+    /// Record a chunk read from the stream in the trace.
+    fn record_chunk(&self, bytes: &[u8]) {
+        if let Some(trace) = &self.trace {
+            trace.record(TraceEvent::Chunk {
+                data: String::from_utf8_lossy(bytes).into_owned(),
+            });
+        }
+    }
+
+    /// Convert an error reading the stream, recording it in the trace.
+    fn read_error(&self, err: reqwest::Error) -> OpenAIError {
+        let err = OpenAIError::Internal(InternalError::from_source(Box::new(err)));
+        if let Some(trace) = &self.trace {
+            trace.record(TraceEvent::Error {
+                message: err.to_string(),
+            });
+        }
+        err
+    }
+
+    /// Parse the full response, recording it in the trace and finishing the stats.
+    fn parse(&mut self, bytes: &[u8]) -> Result<CompletionResponse, OpenAIError> {
+        let response = serde_json::from_slice::<CompletionResponse>(bytes).map_err(|err| {
+            OpenAIError::InvalidState(InvalidStateError::with_message(err.to_string()))
+        });
+        if let Some(trace) = &self.trace {
+            trace.record_result(&response);
+        }
+        self.stats.finish(
+            response
+                .as_ref()
+                .ok()
+                .map(|response| response.usage.completion_tokens),
+        );
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    // Floats are drawn from a fixed grid so the JSON representation is exact
+    fn float() -> impl Strategy<Value = f64> {
+        (-2000i32..=0).prop_map(|x| x as f64 / 100.0)
+    }
+
+    fn logprobs() -> impl Strategy<Value = Logprobs> {
+        (
+            prop::collection::vec(any::<String>(), 0..4),
+            prop::collection::vec(float(), 0..4),
+            prop::collection::hash_map(any::<String>(), float(), 0..4),
+            prop::collection::vec(any::<i32>(), 0..4),
+        )
+            .prop_map(
+                |(tokens, token_logprobs, top_logprobs, text_offset)| Logprobs {
+                    tokens,
+                    token_logprobs,
+                    top_logprobs,
+                    text_offset,
+                },
+            )
+    }
+
+    fn choice() -> impl Strategy<Value = CompletionChoice> {
+        (
+            any::<String>(),
+            any::<i32>(),
+            prop::option::of(logprobs()),
+            any::<String>(),
+        )
+            .prop_map(|(text, index, logprobs, finish_reason)| CompletionChoice {
+                text,
+                index,
+                logprobs,
+                finish_reason: finish_reason.into(),
+            })
+    }
+
+    prop_compose! {
+        fn response()(
+            (id, object, created, model) in (any::<String>(), any::<String>(), any::<i32>(), any::<String>()),
+            choices in prop::collection::vec(choice(), 0..3),
+            (prompt_tokens, completion_tokens, total_tokens) in (any::<i32>(), any::<i32>(), any::<i32>()),
+        ) -> CompletionResponse {
+            CompletionResponse {
+                id,
+                object: object.into(),
+                created,
+                model: model.into(),
+                choices,
+                usage: CompletionUsage {
+                    prompt_tokens,
+                    completion_tokens,
+                    total_tokens,
+                },
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        // Verify that a response deserializes back to the same response after being serialized
+        fn test_response_round_trip(response in response()) {
+            let json = serde_json::to_string(&response).unwrap();
+            prop_assert_eq!(serde_json::from_str::<CompletionResponse>(&json).unwrap(), response);
+        }
+    }
+
+    #[test]
+    // Verify that top logprobs are flattened from the sequence of maps returned by the API
+    fn test_top_logprobs_wire_format() {
+        let json = r#"{"tokens":["a"],"token_logprobs":[-0.5],"top_logprobs":[{"a":-0.5}],"text_offset":[0]}"#;
+        let logprobs: Logprobs = serde_json::from_str(json).unwrap();
+
+        assert_eq!(logprobs.top_logprobs.get("a"), Some(&-0.5));
+        assert_eq!(serde_json::to_string(&logprobs).unwrap(), json);
     }
 }
@@ -0,0 +1,554 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing compression of message histories before they are submitted, to cut the
+//! tokens spent on long contexts.
+//!
+//! Fenced code blocks and inline code spans are never changed, and tool messages are left alone
+//! as their content is usually data which must be kept exact.
+
+use ryst_error::InvalidStateError;
+
+use crate::client::OpenAIClient;
+use crate::credentials::KeySource;
+use crate::error::OpenAIError;
+use crate::{ChatCompletionRequest, ContentPart, Message, MessageContent};
+
+/// The approximate number of characters per token of English text, used to count tokens when no
+/// tokenizer is available.
+const CHARS_PER_TOKEN: usize = 4;
+
+/// Messages shorter than this are not sent for model compression, as the request would cost
+/// more than it saves.
+const MIN_MODEL_COMPRESSION_CHARS: usize = 1000;
+
+/// Words which rarely change the meaning of a prompt. Negations are deliberately absent.
+const STOPWORDS: &[&str] = &[
+    "a",
+    "actually",
+    "an",
+    "basically",
+    "just",
+    "kindly",
+    "please",
+    "quite",
+    "really",
+    "that",
+    "the",
+    "very",
+];
+
+const SYSTEM_PROMPT: &str = "Rewrite the text provided by the user as tersely as possible for \
+    another language model to read.\n\
+    - Keep every fact, name, number, instruction, constraint and question.\n\
+    - Keep code, URLs, identifiers and quoted text exactly.\n\
+    - Drop pleasantries, repetition and filler.\n\
+    - Reply with the rewritten text only.";
+
+/// The result of compressing messages, with the tokens they use before and after.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompressionReport {
+    /// The compressed messages
+    pub messages: Vec<Message>,
+    /// The prompt tokens of the original messages
+    pub original_tokens: usize,
+    /// The prompt tokens of the compressed messages
+    pub compressed_tokens: usize,
+}
+
+impl CompressionReport {
+    /// Returns the number of prompt tokens saved.
+    pub fn saved_tokens(&self) -> usize {
+        self.original_tokens.saturating_sub(self.compressed_tokens)
+    }
+
+    /// Returns the fraction of the original prompt tokens saved, from 0 to 1.
+    pub fn reduction(&self) -> f64 {
+        if self.original_tokens == 0 {
+            return 0.0;
+        }
+        self.saved_tokens() as f64 / self.original_tokens as f64
+    }
+}
+
+/// Compresses the text of messages with a series of passes.
+///
+/// Whitespace minification is always applied. Markdown minification, stopword pruning and
+/// rewriting by a model are enabled with their builder methods.
+#[derive(Debug, Clone)]
+pub struct PromptCompressor {
+    model: String,
+    markdown: bool,
+    stopwords: bool,
+    compression_model: Option<String>,
+    key_source: Option<KeySource>,
+    client: Option<OpenAIClient>,
+}
+
+impl PromptCompressor {
+    /// Create a compressor for messages which will be sent to the model.
+    ///
+    /// With the `tokens` feature, the model's tokenizer is used to count tokens for the report.
+    /// Otherwise they are estimated at about 4 characters per token.
+    pub fn new(model: &str) -> Self {
+        Self {
+            model: model.to_string(),
+            markdown: false,
+            stopwords: false,
+            compression_model: None,
+            key_source: None,
+            client: None,
+        }
+    }
+
+    /// Remove emphasis markers and horizontal rules from prose.
+    pub fn with_markdown_minification(mut self, markdown: bool) -> Self {
+        self.markdown = markdown;
+        self
+    }
+
+    /// Remove filler words such as `the`, `please` and `really` from prose.
+    ///
+    /// This saves the most tokens of the local passes, but makes the text read less naturally.
+    pub fn with_stopword_pruning(mut self, stopwords: bool) -> Self {
+        self.stopwords = stopwords;
+        self
+    }
+
+    /// Ask the model to rewrite long messages tersely in `compress_with_model`.
+    pub fn with_model_compression(mut self, model: &str) -> Self {
+        self.compression_model = Some(model.to_string());
+        self
+    }
+
+    /// Where to read the API key from, see `ChatCompletionRequest::with_key_source`.
+    pub fn with_key_source(mut self, key_source: KeySource) -> Self {
+        self.key_source = Some(key_source);
+        self
+    }
+
+    /// Send the requests through the client, see `ChatCompletionRequest::with_client`.
+    pub fn with_client(mut self, client: OpenAIClient) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Apply the local passes to the messages.
+    pub fn compress(&self, messages: &[Message]) -> CompressionReport {
+        let compressed = messages
+            .iter()
+            .map(|message| self.map_text(message, |text| self.compress_text(text)))
+            .collect();
+        self.report(messages, compressed)
+    }
+
+    /// Apply the local passes, then ask the compression model to rewrite each long message.
+    ///
+    /// Without a compression model set, this is the same as `compress`.
+    pub async fn compress_with_model(
+        &self,
+        messages: &[Message],
+    ) -> Result<CompressionReport, OpenAIError> {
+        let mut compressed = self.compress(messages).messages;
+
+        if let Some(model) = &self.compression_model {
+            for message in compressed.iter_mut() {
+                let text = match message.content() {
+                    MessageContent::Text(text)
+                        if message.role() != "tool"
+                            && text.chars().count() >= MIN_MODEL_COMPRESSION_CHARS =>
+                    {
+                        text
+                    }
+                    _ => continue,
+                };
+
+                let rewritten = self.rewrite(model, text).await?;
+                if rewritten.len() < text.len() {
+                    *message = Message {
+                        content: MessageContent::Text(rewritten),
+                        ..message.clone()
+                    };
+                }
+            }
+        }
+
+        Ok(self.report(messages, compressed))
+    }
+
+    async fn rewrite(&self, model: &str, text: &str) -> Result<String, OpenAIError> {
+        let mut request = ChatCompletionRequest::new(
+            model,
+            &[
+                Message::new("system", SYSTEM_PROMPT),
+                Message::new("user", text),
+            ],
+        )
+        .with_temperature(0.0);
+
+        if let Some(key_source) = &self.key_source {
+            request = request.with_key_source(key_source.clone());
+        }
+
+        if let Some(client) = &self.client {
+            request = request.with_client(client.clone());
+        }
+
+        let response = request.submit().await?;
+        response
+            .choices
+            .first()
+            .and_then(|choice| choice.message.content().as_text())
+            .map(|text| text.trim().to_string())
+            .ok_or_else(|| {
+                OpenAIError::InvalidState(InvalidStateError::with_message(
+                    "Compression response contained no text".to_string(),
+                ))
+            })
+    }
+
+    fn map_text<F>(&self, message: &Message, compress: F) -> Message
+    where
+        F: Fn(&str) -> String,
+    {
+        if message.role() == "tool" {
+            return message.clone();
+        }
+
+        let content = match message.content() {
+            MessageContent::Text(text) => MessageContent::Text(compress(text)),
+            MessageContent::Parts(parts) => MessageContent::Parts(
+                parts
+                    .iter()
+                    .map(|part| match part {
+                        ContentPart::Text { text } => ContentPart::text(&compress(text)),
+                        part => part.clone(),
+                    })
+                    .collect(),
+            ),
+        };
+
+        Message {
+            content,
+            ..message.clone()
+        }
+    }
+
+    /// Compress the prose lines of the text, copying fenced code blocks unchanged.
+    fn compress_text(&self, text: &str) -> String {
+        let mut lines = Vec::new();
+        let mut fence: Option<String> = None;
+        let mut blank = false;
+
+        for line in text.lines() {
+            let trimmed = line.trim_start();
+            let marker = fence_marker(trimmed);
+
+            if let Some(open) = &fence {
+                lines.push(line.to_string());
+                if marker.is_some_and(|marker| marker.starts_with(open.as_str()))
+                    && trimmed
+                        .trim_start_matches(open.chars().next().unwrap_or('`'))
+                        .trim()
+                        .is_empty()
+                {
+                    fence = None;
+                }
+                continue;
+            }
+            if let Some(marker) = marker {
+                fence = Some(marker.to_string());
+                blank = false;
+                lines.push(line.to_string());
+                continue;
+            }
+
+            let line = self.compress_line(line);
+            if line.is_empty() {
+                // Keep single blank lines, as they separate paragraphs and list items
+                if !blank && !lines.is_empty() {
+                    lines.push(String::new());
+                }
+                blank = true;
+            } else {
+                blank = false;
+                lines.push(line);
+            }
+        }
+
+        if blank {
+            lines.pop();
+        }
+        lines.join("\n")
+    }
+
+    fn compress_line(&self, line: &str) -> String {
+        let trimmed = line.trim();
+        if self.markdown && is_horizontal_rule(trimmed) {
+            return String::new();
+        }
+
+        // Leading indentation is kept, as it nests lists
+        let indent = &line[..line.len() - line.trim_start().len()];
+        let mut compressed = String::new();
+        for (segment, code) in inline_code_spans(trimmed) {
+            if code {
+                compressed.push_str(segment);
+            } else {
+                compressed.push_str(&self.compress_prose(segment));
+            }
+        }
+
+        let compressed = compressed.trim();
+        if compressed.is_empty() {
+            String::new()
+        } else {
+            format!("{indent}{compressed}")
+        }
+    }
+
+    /// Minify the prose between inline code spans, keeping whether it starts or ends with
+    /// whitespace so it stays separated from the code around it.
+    fn compress_prose(&self, prose: &str) -> String {
+        let prose = if self.markdown {
+            strip_emphasis(prose)
+        } else {
+            prose.to_string()
+        };
+        let words = prose
+            .split_whitespace()
+            .filter(|word| !(self.stopwords && STOPWORDS.contains(&word.to_lowercase().as_str())))
+            .collect::<Vec<_>>();
+
+        let starts = prose.starts_with(char::is_whitespace);
+        let ends = prose.ends_with(char::is_whitespace);
+        if words.is_empty() {
+            return if starts || ends { " " } else { "" }.to_string();
+        }
+        format!(
+            "{}{}{}",
+            if starts { " " } else { "" },
+            words.join(" "),
+            if ends { " " } else { "" }
+        )
+    }
+
+    fn report(&self, original: &[Message], compressed: Vec<Message>) -> CompressionReport {
+        CompressionReport {
+            original_tokens: count_tokens(&self.model, original),
+            compressed_tokens: count_tokens(&self.model, &compressed),
+            messages: compressed,
+        }
+    }
+}
+
+/// Split the line into prose and inline code spans, flagging the code spans.
+///
+/// A span opens with a run of backticks and closes with the next run of the same length.
+/// Backticks without a closing run are prose.
+fn inline_code_spans(line: &str) -> Vec<(&str, bool)> {
+    let mut segments = Vec::new();
+    let mut prose_start = 0;
+    let mut at = 0;
+
+    while let Some(found) = line[at..].find('`') {
+        let open = at + found;
+        let run = line[open..].len() - line[open..].trim_start_matches('`').len();
+        let ticks = &line[open..open + run];
+
+        // The closing run must be exactly as long as the opening one
+        let mut search = open + run;
+        let close = loop {
+            let Some(found) = line[search..].find(ticks) else {
+                break None;
+            };
+            let candidate = search + found;
+            let len = line[candidate..].len() - line[candidate..].trim_start_matches('`').len();
+            if len == run {
+                break Some(candidate);
+            }
+            search = candidate + len;
+        };
+
+        match close {
+            Some(close) => {
+                if prose_start < open {
+                    segments.push((&line[prose_start..open], false));
+                }
+                segments.push((&line[open..close + run], true));
+                prose_start = close + run;
+                at = prose_start;
+            }
+            None => at = open + run,
+        }
+    }
+    if prose_start < line.len() {
+        segments.push((&line[prose_start..], false));
+    }
+    segments
+}
+
+/// Remove `**` and `__` emphasis markers which are paired and on word boundaries, so markers
+/// inside words such as `2**10` or `snake__case` are kept.
+fn strip_emphasis(text: &str) -> String {
+    let mut removed = Vec::new();
+    for marker in ["**", "__"] {
+        let mut open = None;
+        let mut at = 0;
+        while let Some(found) = text[at..].find(marker) {
+            let position = at + found;
+            let before = text[..position].chars().next_back();
+            let after = text[position + marker.len()..].chars().next();
+            let can_open = before.is_none_or(|c| !c.is_alphanumeric())
+                && after.is_some_and(|c| !c.is_whitespace());
+            let can_close = before.is_some_and(|c| !c.is_whitespace())
+                && after.is_none_or(|c| !c.is_alphanumeric());
+
+            match open {
+                Some(start) if can_close => {
+                    removed.push(start);
+                    removed.push(position);
+                    open = None;
+                }
+                _ if can_open => open = Some(position),
+                _ => {}
+            }
+            at = position + marker.len();
+        }
+    }
+    removed.sort_unstable();
+
+    let mut stripped = String::with_capacity(text.len());
+    let mut from = 0;
+    for position in removed {
+        stripped.push_str(&text[from..position]);
+        from = position + 2;
+    }
+    stripped.push_str(&text[from..]);
+    stripped
+}
+
+/// Returns the backticks or tildes opening or closing a fenced code block.
+fn fence_marker(line: &str) -> Option<&str> {
+    let fence_char = line.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let len = line.len() - line.trim_start_matches(fence_char).len();
+    (len >= 3).then(|| &line[..len])
+}
+
+fn is_horizontal_rule(line: &str) -> bool {
+    let mut chars = line.chars().filter(|c| !c.is_whitespace());
+    match chars.next() {
+        Some(first @ ('-' | '*' | '_')) => {
+            let rest = chars.collect::<Vec<_>>();
+            rest.len() >= 2 && rest.iter().all(|c| *c == first)
+        }
+        _ => false,
+    }
+}
+
+/// Count the prompt tokens of the messages, estimating them when no tokenizer is available.
+pub(crate) fn count_tokens(model: &str, messages: &[Message]) -> usize {
+    #[cfg(feature = "tokens")]
+    if let Ok(tokens) = crate::tokens::count_message_tokens(model, messages) {
+        return tokens;
+    }
+    #[cfg(not(feature = "tokens"))]
+    let _ = model;
+
+    let chars: usize = messages
+        .iter()
+        .map(|message| match message.content() {
+            MessageContent::Text(text) => text.chars().count(),
+            MessageContent::Parts(parts) => parts
+                .iter()
+                .map(|part| match part {
+                    ContentPart::Text { text } => text.chars().count(),
+                    ContentPart::ImageUrl { .. } | ContentPart::File { .. } => 0,
+                })
+                .sum(),
+        })
+        .sum();
+    chars.div_ceil(CHARS_PER_TOKEN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Verify that whitespace is minified in prose but not in code blocks
+    fn test_compress_whitespace() {
+        let text = "Please  fix   this:\n\n\n\n```python\ndef f():\n    return  1\n```\n\n  - item   one\n";
+        let report = PromptCompressor::new("gpt-4o").compress(&[Message::new("user", text)]);
+
+        assert_eq!(
+            report.messages[0].content(),
+            "Please fix this:\n\n```python\ndef f():\n    return  1\n```\n\n  - item one"
+        );
+        assert!(report.compressed_tokens <= report.original_tokens);
+    }
+
+    #[test]
+    // Verify that markdown and stopwords are removed from prose, and tool messages are untouched
+    fn test_compress_passes() {
+        let compressor = PromptCompressor::new("gpt-4o")
+            .with_markdown_minification(true)
+            .with_stopword_pruning(true);
+        let tool = Message::tool_result("call_1", "{\"the\":  \"very **raw**\"}");
+        let report = compressor.compress(&[
+            Message::new(
+                "user",
+                "Please summarize **the** report.\n\n---\n\nIt is not very long, just a page.",
+            ),
+            tool.clone(),
+        ]);
+
+        assert_eq!(
+            report.messages[0].content(),
+            "summarize report.\n\nIt is not long, page."
+        );
+        assert_eq!(report.messages[1], tool);
+        assert!(report.saved_tokens() > 0);
+        assert!(report.reduction() > 0.0 && report.reduction() < 1.0);
+    }
+
+    #[test]
+    // Verify that inline code and emphasis markers inside words are kept exactly
+    fn test_compress_keeps_code() {
+        let compressor = PromptCompressor::new("gpt-4o")
+            .with_markdown_minification(true)
+            .with_stopword_pruning(true);
+        let report = compressor.compress(&[Message::new(
+            "user",
+            "Call `__init__` and `SELECT  the  id`, then **really** compute 2**10 for `a`.",
+        )]);
+
+        assert_eq!(
+            report.messages[0].content(),
+            "Call `__init__` and `SELECT  the  id`, then compute 2**10 for `a`."
+        );
+    }
+
+    #[test]
+    // Verify that only paired emphasis markers on word boundaries are removed
+    fn test_strip_emphasis() {
+        assert_eq!(strip_emphasis("**bold** and __also__"), "bold and also");
+        assert_eq!(
+            strip_emphasis("2**10 and snake__case__name"),
+            "2**10 and snake__case__name"
+        );
+        assert_eq!(strip_emphasis("(**note**): a ** b"), "(note): a ** b");
+        assert_eq!(strip_emphasis("**unclosed"), "**unclosed");
+    }
+}
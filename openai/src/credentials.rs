@@ -0,0 +1,479 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing the sources an API key can be read from.
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+
+use arc_swap::ArcSwap;
+use ryst_error::{InternalError, InvalidStateError};
+use tokio::fs;
+use tokio::process::Command;
+
+use crate::error::OpenAIError;
+
+const API_KEY_ENV: &str = "OPENAI_API_KEY";
+const API_KEY_FILE_ENV: &str = "OPENAI_API_KEY_FILE";
+
+/// The future returned by an `ApiKeyProvider`.
+pub type KeyFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, OpenAIError>> + Send + 'a>>;
+
+/// Where to read the OpenAI API key from.
+///
+/// Keys read from files or commands have surrounding whitespace trimmed, so files ending in a
+/// newline and commands such as `pass show openai` can be used directly. They are read once and
+/// cached for the process, and read again when the API rejects the cached key.
+#[derive(Clone, PartialEq)]
+pub enum KeySource {
+    /// Read the key from the named environment variable
+    Env(String),
+    /// Read the key from a file, such as a mounted secret
+    File(PathBuf),
+    /// Run a program with the given arguments and use its standard output as the key
+    Command(String, Vec<String>),
+    /// Use the given key
+    Static(String),
+    /// Use the current value of a key that can be rotated at runtime
+    Shared(SharedKey),
+    /// Ask the provider for the key each time a request is sent, such as to fetch short-lived
+    /// tokens from a vault
+    Provider(SharedKeyProvider),
+}
+
+impl KeySource {
+    /// Create a source which asks the provider for the key each time a request is sent.
+    pub fn provider<P: ApiKeyProvider + 'static>(provider: P) -> Self {
+        KeySource::Provider(SharedKeyProvider(Arc::new(provider)))
+    }
+
+    /// Resolve the API key from this source.
+    pub async fn resolve(&self) -> Result<String, OpenAIError> {
+        let cached = self
+            .read_key()
+            .and_then(|read_key| cache().get(&read_key).cloned());
+        let key = match (self, cached) {
+            (_, Some(key)) => key,
+            (KeySource::Env(name), _) => env::var(name).map_err(|_| {
+                OpenAIError::InvalidState(InvalidStateError::with_message(format!(
+                    "{name} env variable must be set"
+                )))
+            })?,
+            (KeySource::File(path), _) => fs::read_to_string(path).await.map_err(|err| {
+                OpenAIError::Internal(InternalError::from_source_with_prefix(
+                    Box::new(err),
+                    format!("Unable to read API key from {}", path.display()),
+                ))
+            })?,
+            (KeySource::Command(program, args), _) => {
+                let output = Command::new(program)
+                    .args(args)
+                    .output()
+                    .await
+                    .map_err(|err| {
+                        OpenAIError::Internal(InternalError::from_source_with_prefix(
+                            Box::new(err),
+                            format!("Unable to run {program}"),
+                        ))
+                    })?;
+
+                if !output.status.success() {
+                    return Err(OpenAIError::InvalidState(InvalidStateError::with_message(
+                        format!(
+                            "{program} exited with {}: {}",
+                            output.status,
+                            String::from_utf8_lossy(&output.stderr).trim()
+                        ),
+                    )));
+                }
+
+                String::from_utf8(output.stdout).map_err(|err| {
+                    OpenAIError::Internal(InternalError::from_source_with_prefix(
+                        Box::new(err),
+                        format!("{program} returned an invalid API key"),
+                    ))
+                })?
+            }
+            (KeySource::Static(key), _) => key.clone(),
+            (KeySource::Shared(key), _) => key.get(),
+            (KeySource::Provider(provider), _) => provider.0.api_key().await?,
+        };
+
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(OpenAIError::InvalidState(InvalidStateError::with_message(
+                format!("API key read from {self:?} is empty"),
+            )));
+        }
+
+        if let Some(read_key) = self.read_key() {
+            cache().insert(read_key, key.to_string());
+        }
+        Ok(key.to_string())
+    }
+
+    /// The key a file or command source is cached under.
+    fn read_key(&self) -> Option<ReadKey> {
+        match self {
+            KeySource::File(path) => Some(ReadKey::File(path.clone())),
+            KeySource::Command(program, args) => {
+                Some(ReadKey::Command(program.clone(), args.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Fetch a new key after the API rejected the current one, returning false if this source
+    /// cannot.
+    ///
+    /// Files and commands are read again, which fetches a new key if it has changed since it was
+    /// cached.
+    pub(crate) async fn refresh(&self) -> Result<bool, OpenAIError> {
+        match self {
+            KeySource::Shared(key) => key.refresh().await,
+            KeySource::Provider(provider) => provider.0.refresh().await,
+            KeySource::File(_) | KeySource::Command(..) => {
+                let previous = self
+                    .read_key()
+                    .and_then(|read_key| cache().remove(&read_key));
+                let key = self.resolve().await?;
+                Ok(previous.is_none_or(|previous| previous != key))
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+/// A file or command a key is read from.
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum ReadKey {
+    File(PathBuf),
+    Command(String, Vec<String>),
+}
+
+/// The keys read from files and commands, so the file is not read and the command not run
+/// again for every request.
+fn cache() -> MutexGuard<'static, HashMap<ReadKey, String>> {
+    static CACHE: OnceLock<Mutex<HashMap<ReadKey, String>>> = OnceLock::new();
+    CACHE
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Provides the API key at request time, for keys which change while the process runs, such as
+/// rotated keys, vault-backed secrets or short-lived tokens.
+///
+/// The key is asked for each time a request is sent, so providers which fetch it from elsewhere
+/// should cache it. Both methods are async, so a provider can fetch the key over the network
+/// without blocking the runtime the request is sent from. `KeySource` is itself a provider, so
+/// `KeySource::Env` and `KeySource::Static` can be used where a provider is expected, and so can
+/// closures returning the key.
+pub trait ApiKeyProvider: Send + Sync {
+    /// Returns the current API key.
+    fn api_key(&self) -> KeyFuture<'_, String>;
+
+    /// Called when the API rejects the key, to fetch a new one. Returns whether a new key was
+    /// fetched, in which case the request is sent once more.
+    fn refresh(&self) -> KeyFuture<'_, bool> {
+        Box::pin(async { Ok(false) })
+    }
+}
+
+impl ApiKeyProvider for KeySource {
+    fn api_key(&self) -> KeyFuture<'_, String> {
+        Box::pin(self.resolve())
+    }
+
+    fn refresh(&self) -> KeyFuture<'_, bool> {
+        Box::pin(KeySource::refresh(self))
+    }
+}
+
+impl<F> ApiKeyProvider for F
+where
+    F: Fn() -> Result<String, OpenAIError> + Send + Sync,
+{
+    fn api_key(&self) -> KeyFuture<'_, String> {
+        Box::pin(async move { self() })
+    }
+}
+
+/// A provider shared by the requests it is set on, created with `KeySource::provider`.
+#[derive(Clone)]
+pub struct SharedKeyProvider(Arc<dyn ApiKeyProvider>);
+
+// Providers are equal when they are the same provider
+impl PartialEq for SharedKeyProvider {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for SharedKeyProvider {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ApiKeyProvider")
+    }
+}
+
+// The key itself is never included, so that sources can be logged safely
+impl fmt::Debug for KeySource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeySource::Env(name) => f.debug_tuple("Env").field(name).finish(),
+            KeySource::File(path) => f.debug_tuple("File").field(path).finish(),
+            KeySource::Command(program, args) => {
+                f.debug_tuple("Command").field(program).field(args).finish()
+            }
+            KeySource::Static(_) => f.debug_tuple("Static").field(&"<redacted>").finish(),
+            KeySource::Shared(key) => f.debug_tuple("Shared").field(key).finish(),
+            KeySource::Provider(provider) => f.debug_tuple("Provider").field(provider).finish(),
+        }
+    }
+}
+
+type RefreshFn = dyn Fn() -> KeyFuture<'static, String> + Send + Sync;
+
+/// An API key that can be replaced while requests are being made.
+///
+/// Clones share the same key, so a key rotated through one handle is used by every request
+/// built with any of its clones. The key is swapped atomically; requests already in flight keep
+/// the key they were sent with.
+///
+/// A refresh callback may be provided, which is called to fetch a new key when the API rejects
+/// the current one with a 401. The request is then retried once with the new key.
+#[derive(Clone)]
+pub struct SharedKey {
+    key: Arc<ArcSwap<String>>,
+    refresh: Option<Arc<RefreshFn>>,
+}
+
+impl SharedKey {
+    /// Create a new `SharedKey` holding the given key.
+    pub fn new(key: &str) -> Self {
+        Self {
+            key: Arc::new(ArcSwap::from_pointee(key.to_string())),
+            refresh: None,
+        }
+    }
+
+    /// Set the callback used to fetch a new key when the current key is rejected.
+    pub fn with_refresh<F, Fut>(mut self, refresh: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<String, OpenAIError>> + Send + 'static,
+    {
+        self.refresh = Some(Arc::new(move || Box::pin(refresh())));
+        self
+    }
+
+    /// Replace the key used by subsequent requests.
+    pub fn set(&self, key: &str) {
+        self.key.store(Arc::new(key.to_string()));
+    }
+
+    /// Returns the current key.
+    pub fn get(&self) -> String {
+        self.key.load().to_string()
+    }
+
+    /// Fetch and store a new key using the refresh callback.
+    ///
+    /// Returns false if there is no refresh callback.
+    pub(crate) async fn refresh(&self) -> Result<bool, OpenAIError> {
+        match &self.refresh {
+            Some(refresh) => {
+                self.set(&refresh().await?);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+// Handles are equal when they share the same underlying key
+impl PartialEq for SharedKey {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.key, &other.key)
+    }
+}
+
+impl fmt::Debug for SharedKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SharedKey")
+            .field("key", &"<redacted>")
+            .field("refresh", &self.refresh.is_some())
+            .finish()
+    }
+}
+
+/// Resolve the API key from `source`, or from the environment if no source is given.
+///
+/// Without a source, `OPENAI_API_KEY` is used if set, otherwise the key is read from the file
+/// named by `OPENAI_API_KEY_FILE`.
+pub(crate) async fn api_key(source: Option<&KeySource>) -> Result<String, OpenAIError> {
+    match source {
+        Some(source) => source.resolve().await,
+        None => match env::var_os(API_KEY_FILE_ENV) {
+            Some(path) if env::var_os(API_KEY_ENV).is_none() => {
+                KeySource::File(PathBuf::from(path)).resolve().await
+            }
+            _ => KeySource::Env(API_KEY_ENV.to_string()).resolve().await,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    // Verify that a static key is returned with surrounding whitespace trimmed
+    async fn test_resolve_static() {
+        let source = KeySource::Static(" sk-test\n".to_string());
+        assert_eq!(source.resolve().await.unwrap(), "sk-test");
+    }
+
+    #[tokio::test]
+    // Verify that a key is read from a file
+    async fn test_resolve_file() {
+        let path = env::temp_dir().join("ryst_test_resolve_file");
+        std::fs::write(&path, "sk-file\n").unwrap();
+
+        let key = KeySource::File(path.clone()).resolve().await;
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(key.unwrap(), "sk-file");
+    }
+
+    #[tokio::test]
+    // Verify that a file's key is cached until refreshed, which reads the file again
+    async fn test_file_key_cached() {
+        let path = env::temp_dir().join("ryst_test_file_key_cached");
+        std::fs::write(&path, "sk-old").unwrap();
+        let source = KeySource::File(path.clone());
+        assert_eq!(source.resolve().await.unwrap(), "sk-old");
+
+        std::fs::write(&path, "sk-new").unwrap();
+        assert_eq!(source.resolve().await.unwrap(), "sk-old");
+        assert!(source.refresh().await.unwrap());
+        assert_eq!(source.resolve().await.unwrap(), "sk-new");
+        assert!(!source.refresh().await.unwrap());
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(source.resolve().await.unwrap(), "sk-new");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    // Verify that a key is read from a command's output and that failing commands are errors
+    async fn test_resolve_command() {
+        let source = KeySource::Command("echo".to_string(), vec!["sk-command".to_string()]);
+        assert_eq!(source.resolve().await.unwrap(), "sk-command");
+
+        assert!(KeySource::Command("false".to_string(), vec![])
+            .resolve()
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    // Verify that empty keys and unset variables are errors
+    async fn test_resolve_errors() {
+        assert!(KeySource::Static("  ".to_string()).resolve().await.is_err());
+        assert!(KeySource::Env("RYST_TEST_UNSET_VARIABLE".to_string())
+            .resolve()
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    // Verify that a rotated key is seen through every clone of a shared key
+    async fn test_shared_key_rotation() {
+        let key = SharedKey::new("sk-old");
+        let source = KeySource::Shared(key.clone());
+        assert_eq!(source.resolve().await.unwrap(), "sk-old");
+
+        key.set("sk-new");
+        assert_eq!(source.resolve().await.unwrap(), "sk-new");
+        assert_eq!(source, KeySource::Shared(key));
+        assert_ne!(source, KeySource::Shared(SharedKey::new("sk-new")));
+    }
+
+    #[tokio::test]
+    // Verify that refreshing stores the key returned by the callback
+    async fn test_shared_key_refresh() {
+        let key = SharedKey::new("sk-old");
+        assert!(!key.refresh().await.unwrap());
+
+        let key = key.with_refresh(|| async { Ok("sk-refreshed".to_string()) });
+        assert!(key.refresh().await.unwrap());
+        assert_eq!(key.get(), "sk-refreshed");
+    }
+
+    #[tokio::test]
+    // Verify that a provider is asked for the key on each resolve and can refresh it
+    async fn test_resolve_provider() {
+        struct Rotating(SharedKey);
+
+        impl ApiKeyProvider for Rotating {
+            fn api_key(&self) -> KeyFuture<'_, String> {
+                Box::pin(async {
+                    // As a provider fetching the key over the network would
+                    tokio::task::yield_now().await;
+                    Ok(self.0.get())
+                })
+            }
+
+            fn refresh(&self) -> KeyFuture<'_, bool> {
+                Box::pin(async {
+                    self.0.set("sk-refreshed");
+                    Ok(true)
+                })
+            }
+        }
+
+        let key = SharedKey::new("sk-old");
+        let source = KeySource::provider(Rotating(key.clone()));
+        assert_eq!(source.resolve().await.unwrap(), "sk-old");
+
+        key.set("sk-new");
+        assert_eq!(source.resolve().await.unwrap(), "sk-new");
+        assert!(source.refresh().await.unwrap());
+        assert_eq!(source.resolve().await.unwrap(), "sk-refreshed");
+
+        let source = KeySource::provider(|| Ok(" sk-closure\n".to_string()));
+        assert_eq!(source.resolve().await.unwrap(), "sk-closure");
+        assert!(!source.refresh().await.unwrap());
+
+        let source = KeySource::provider(KeySource::Static("sk-static".to_string()));
+        assert_eq!(source.resolve().await.unwrap(), "sk-static");
+        assert_eq!(format!("{source:?}"), "Provider(ApiKeyProvider)");
+    }
+
+    #[tokio::test]
+    // Verify that the debug output does not contain a static key
+    async fn test_debug_redacts_static_key() {
+        let source = KeySource::Static("sk-secret".to_string());
+        assert_eq!(format!("{source:?}"), "Static(\"<redacted>\")");
+
+        let source = KeySource::Shared(SharedKey::new("sk-secret"));
+        assert!(!format!("{source:?}").contains("sk-secret"));
+    }
+}
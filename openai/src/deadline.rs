@@ -0,0 +1,124 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing deadlines which are set around a block of code, such as by web framework
+//! middleware for the request being handled, and inherited by every request sent within it.
+//!
+//! Requests sent within `with_deadline` or `with_budget` time out when the deadline passes, and
+//! do not wait to retry a rate limited request past it. A request sent once the deadline has
+//! passed fails without being sent. Deadlines are task-local, so they are not inherited by tasks
+//! started with `tokio::spawn`.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), ryst_openai::OpenAIError> {
+//! use std::time::Duration;
+//!
+//! use ryst_openai::{deadline, ChatCompletionRequest, Message};
+//!
+//! let response = deadline::with_budget(Duration::from_secs(5), async {
+//!     let request = ChatCompletionRequest::new("gpt-4o", &[Message::new("user", "Hello")]);
+//!     request.submit().await
+//! })
+//! .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::future::Future;
+use std::time::Duration;
+
+use ryst_error::InvalidStateError;
+use tokio::time::Instant;
+
+use crate::error::OpenAIError;
+
+tokio::task_local! {
+    static DEADLINE: Instant;
+}
+
+/// Run the future with a deadline for the requests sent within it.
+///
+/// Within an enclosing deadline the earlier of the two applies, so a nested call can shorten its
+/// budget but not extend it.
+pub async fn with_deadline<F: Future>(deadline: Instant, future: F) -> F::Output {
+    let deadline = current().map_or(deadline, |current| current.min(deadline));
+    DEADLINE.scope(deadline, future).await
+}
+
+/// Run the future with a deadline the given duration from now, see `with_deadline`.
+pub async fn with_budget<F: Future>(budget: Duration, future: F) -> F::Output {
+    with_deadline(Instant::now() + budget, future).await
+}
+
+/// Returns the deadline of the current task, if one is set.
+pub fn current() -> Option<Instant> {
+    DEADLINE.try_with(|deadline| *deadline).ok()
+}
+
+/// Returns the time left until the current task's deadline, if one is set.
+pub fn remaining() -> Option<Duration> {
+    current().map(|deadline| deadline.saturating_duration_since(Instant::now()))
+}
+
+/// The error returned when a request's deadline passes.
+pub(crate) fn exceeded() -> OpenAIError {
+    OpenAIError::InvalidState(InvalidStateError::with_message(
+        "The deadline for the request has passed".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    // Verify that nested deadlines can only shorten the enclosing one
+    async fn test_nested_deadlines() {
+        assert_eq!(remaining(), None);
+
+        with_budget(Duration::from_secs(10), async {
+            assert_eq!(remaining(), Some(Duration::from_secs(10)));
+
+            tokio::time::advance(Duration::from_secs(4)).await;
+            assert_eq!(remaining(), Some(Duration::from_secs(6)));
+
+            with_budget(Duration::from_secs(60), async {
+                assert_eq!(remaining(), Some(Duration::from_secs(6)));
+            })
+            .await;
+
+            with_budget(Duration::from_secs(1), async {
+                assert_eq!(remaining(), Some(Duration::from_secs(1)));
+            })
+            .await;
+
+            tokio::time::advance(Duration::from_secs(30)).await;
+            assert_eq!(remaining(), Some(Duration::ZERO));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    // Verify that a request is not sent once the deadline has passed
+    async fn test_request_after_deadline() {
+        let request =
+            crate::ChatCompletionRequest::new("gpt-4o", &[crate::Message::new("user", "Hello")])
+                .with_key_source(crate::KeySource::Static("sk-test".to_string()))
+                .with_base_url("http://127.0.0.1:9");
+
+        let result = with_budget(Duration::ZERO, request.submit()).await;
+
+        assert!(matches!(result, Err(OpenAIError::InvalidState(_))));
+    }
+}
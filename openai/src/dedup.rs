@@ -0,0 +1,265 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing removal of near-identical outputs when several are generated for the same
+//! prompt.
+//!
+//! Outputs are compared by the Jaccard similarity of their word shingles, or by any other
+//! similarity such as the cosine similarity of embeddings computed by the caller.
+
+use std::collections::HashSet;
+
+use crate::{ChatCompletionResponse, CompletionResponse};
+
+/// The number of words in each shingle when not set with `with_shingle_size`.
+const DEFAULT_SHINGLE_SIZE: usize = 3;
+
+/// Removes outputs which are too similar to an earlier output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Deduplicator {
+    threshold: f64,
+    shingle_size: usize,
+}
+
+impl Deduplicator {
+    /// Create a deduplicator which treats outputs with a similarity of at least `threshold`, from
+    /// 0 to 1, as duplicates.
+    pub fn new(threshold: f64) -> Self {
+        Self {
+            threshold,
+            shingle_size: DEFAULT_SHINGLE_SIZE,
+        }
+    }
+
+    /// The number of consecutive words in each shingle. Smaller shingles judge texts which use
+    /// the same words in a different order as more similar.
+    pub fn with_shingle_size(mut self, shingle_size: usize) -> Self {
+        self.shingle_size = shingle_size.max(1);
+        self
+    }
+
+    /// Returns the indices of the texts to keep, in order.
+    ///
+    /// The first of each group of similar texts is kept.
+    pub fn unique_indices<S: AsRef<str>>(&self, texts: &[S]) -> Vec<usize> {
+        let shingles = texts
+            .iter()
+            .map(|text| shingles(text.as_ref(), self.shingle_size))
+            .collect::<Vec<_>>();
+        self.unique_indices_by(&shingles, jaccard_similarity)
+    }
+
+    /// Returns the indices of the items to keep, in order, using the given similarity.
+    ///
+    /// This can be used with `cosine_similarity` to compare embeddings of the texts.
+    pub fn unique_indices_by<T, F>(&self, items: &[T], similarity: F) -> Vec<usize>
+    where
+        F: Fn(&T, &T) -> f64,
+    {
+        let mut kept: Vec<usize> = Vec::new();
+        for (index, item) in items.iter().enumerate() {
+            if kept
+                .iter()
+                .all(|kept| similarity(&items[*kept], item) < self.threshold)
+            {
+                kept.push(index);
+            }
+        }
+        kept
+    }
+
+    /// Returns the texts with duplicates removed.
+    pub fn dedup<S: AsRef<str>>(&self, texts: &[S]) -> Vec<String> {
+        self.unique_indices(texts)
+            .into_iter()
+            .map(|index| texts[index].as_ref().to_string())
+            .collect()
+    }
+}
+
+impl ChatCompletionResponse {
+    /// Remove choices whose text is a near duplicate of an earlier choice.
+    ///
+    /// Choices without text content, such as tool calls, are always kept. The indices of the
+    /// remaining choices are unchanged.
+    pub fn dedup_choices(&mut self, deduplicator: &Deduplicator) {
+        let texts = self
+            .choices
+            .iter()
+            .map(|choice| choice.message.content.as_text())
+            .collect::<Vec<_>>();
+        let keep = retained(&texts, deduplicator);
+
+        let mut keep = keep.into_iter();
+        self.choices.retain(|_| keep.next().unwrap_or(true));
+    }
+}
+
+impl CompletionResponse {
+    /// Remove choices whose text is a near duplicate of an earlier choice.
+    ///
+    /// The indices of the remaining choices are unchanged.
+    pub fn dedup_choices(&mut self, deduplicator: &Deduplicator) {
+        let texts = self
+            .choices
+            .iter()
+            .map(|choice| Some(choice.text.as_str()))
+            .collect::<Vec<_>>();
+        let keep = retained(&texts, deduplicator);
+
+        let mut keep = keep.into_iter();
+        self.choices.retain(|_| keep.next().unwrap_or(true));
+    }
+}
+
+/// Returns whether to keep each text, keeping every `None`.
+fn retained(texts: &[Option<&str>], deduplicator: &Deduplicator) -> Vec<bool> {
+    let present = texts
+        .iter()
+        .enumerate()
+        .filter_map(|(index, text)| text.map(|text| (index, text)))
+        .collect::<Vec<_>>();
+    let unique =
+        deduplicator.unique_indices(&present.iter().map(|(_, text)| *text).collect::<Vec<_>>());
+
+    let mut keep = vec![true; texts.len()];
+    for (position, (index, _)) in present.iter().enumerate() {
+        keep[*index] = unique.contains(&position);
+    }
+    keep
+}
+
+/// Returns the set of shingles of `size` consecutive words in the text, ignoring case and
+/// punctuation.
+///
+/// Texts with fewer words than `size` have a single shingle of all their words.
+pub fn shingles(text: &str, size: usize) -> HashSet<String> {
+    let words = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>();
+
+    if words.len() <= size {
+        return HashSet::from([words.join(" ")]);
+    }
+    words
+        .windows(size.max(1))
+        .map(|window| window.join(" "))
+        .collect()
+}
+
+/// Returns the size of the intersection of the sets divided by the size of their union, from 0
+/// to 1. Two empty sets are identical.
+pub fn jaccard_similarity(a: &HashSet<String>, b: &HashSet<String>) -> f64 {
+    let union = a.union(b).count();
+    if union == 0 {
+        return 1.0;
+    }
+    a.intersection(b).count() as f64 / union as f64
+}
+
+/// Returns the cosine of the angle between two vectors, such as `f32` or `f64` embeddings, from
+/// -1 to 1.
+///
+/// Returns 0 if either vector is all zeros, contains NaN or infinity, or they differ in length.
+pub fn cosine_similarity<T: Copy + Into<f64>>(a: &[T], b: &[T]) -> f64 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+
+    let (mut dot, mut norm_a, mut norm_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        let (x, y): (f64, f64) = ((*x).into(), (*y).into());
+        dot += x * y;
+        norm_a += x * x;
+        norm_b += y * y;
+    }
+
+    let similarity = dot / (norm_a.sqrt() * norm_b.sqrt());
+    if similarity.is_finite() {
+        similarity
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    // Verify that near-identical texts are removed and distinct texts are kept
+    fn test_dedup_texts() {
+        let texts = [
+            "The capital of France is Paris.",
+            "the capital of France is Paris!",
+            "Paris is the capital city of France, on the Seine.",
+            "The capital of France is Paris, of course.",
+        ];
+        let deduplicator = Deduplicator::new(0.6);
+
+        assert_eq!(deduplicator.unique_indices(&texts), vec![0, 2]);
+        assert_eq!(
+            Deduplicator::new(1.0).dedup(&texts),
+            vec![texts[0], texts[2], texts[3]]
+        );
+    }
+
+    #[test]
+    // Verify that items can be deduplicated by the cosine similarity of embeddings
+    fn test_dedup_by_cosine_similarity() {
+        let embeddings = vec![vec![1.0, 0.0], vec![0.99, 0.1], vec![0.0, 1.0]];
+        let kept =
+            Deduplicator::new(0.95).unique_indices_by(&embeddings, |a, b| cosine_similarity(a, b));
+
+        assert_eq!(kept, vec![0, 2]);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+        assert_eq!(cosine_similarity(&[f64::NAN, 1.0], &[1.0, 1.0]), 0.0);
+        assert_eq!(cosine_similarity(&[1.0f32, 0.0], &[1.0, 0.0]), 1.0);
+    }
+
+    #[test]
+    // Verify that duplicate chat choices are removed while tool calls are kept
+    fn test_dedup_chat_choices() {
+        let choice = |index: i32, message: serde_json::Value| json!({"index": index, "message": message, "finish_reason": "stop"});
+        let mut response: ChatCompletionResponse = serde_json::from_value(json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "gpt-4o",
+            "choices": [
+                choice(0, json!({"role": "assistant", "content": "Sure, here it is."})),
+                choice(1, json!({"role": "assistant", "content": null, "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": {"name": "lookup", "arguments": "{}"}
+                }]})),
+                choice(2, json!({"role": "assistant", "content": "Sure, here it is!"})),
+            ],
+            "usage": {"prompt_tokens": 0, "completion_tokens": 0, "total_tokens": 0}
+        }))
+        .unwrap();
+
+        response.dedup_choices(&Deduplicator::new(0.9));
+        let indices = response
+            .choices
+            .iter()
+            .map(|choice| choice.index)
+            .collect::<Vec<_>>();
+        assert_eq!(indices, vec![0, 1]);
+    }
+}
@@ -0,0 +1,165 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing a structural diff of requests, for finding why two calls which look the
+//! same behaved differently.
+
+use std::fmt;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::canonical::to_canonical_json;
+use crate::error::OpenAIError;
+use crate::{ChatCompletionRequest, CompletionRequest};
+
+/// A value which differs between two serialized values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Difference {
+    /// Where the value is, such as `messages[1].content`
+    pub path: String,
+    /// The value on the left, or `None` if it is missing
+    pub left: Option<Value>,
+    /// The value on the right, or `None` if it is missing
+    pub right: Option<Value>,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let show = |value: &Option<Value>| match value {
+            Some(value) => value.to_string(),
+            None => "(missing)".to_string(),
+        };
+        write!(
+            f,
+            "{}: {} -> {}",
+            self.path,
+            show(&self.left),
+            show(&self.right)
+        )
+    }
+}
+
+/// Returns the differences between the JSON of two values, in the order of their sorted keys.
+///
+/// Numbers are compared after normalizing them as in `to_canonical_json`, so `1` and `1.0` are
+/// the same.
+pub fn diff<L: Serialize, R: Serialize>(
+    left: &L,
+    right: &R,
+) -> Result<Vec<Difference>, OpenAIError> {
+    let parse = |json: String| serde_json::from_str::<Value>(&json).unwrap_or_default();
+    let left = parse(to_canonical_json(left)?);
+    let right = parse(to_canonical_json(right)?);
+
+    let mut differences = Vec::new();
+    diff_values("", Some(&left), Some(&right), &mut differences);
+    Ok(differences)
+}
+
+impl ChatCompletionRequest {
+    /// Returns the differences between the bodies the two requests send to the API.
+    pub fn diff(&self, other: &Self) -> Result<Vec<Difference>, OpenAIError> {
+        diff(self, other)
+    }
+}
+
+impl CompletionRequest {
+    /// Returns the differences between the bodies the two requests send to the API.
+    pub fn diff(&self, other: &Self) -> Result<Vec<Difference>, OpenAIError> {
+        diff(self, other)
+    }
+}
+
+fn diff_values(
+    path: &str,
+    left: Option<&Value>,
+    right: Option<&Value>,
+    differences: &mut Vec<Difference>,
+) {
+    match (left, right) {
+        (Some(Value::Object(left)), Some(Value::Object(right))) => {
+            let mut keys = left.keys().chain(right.keys()).collect::<Vec<_>>();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{path}.{key}")
+                };
+                diff_values(&path, left.get(key), right.get(key), differences);
+            }
+        }
+        (Some(Value::Array(left)), Some(Value::Array(right))) => {
+            for i in 0..left.len().max(right.len()) {
+                diff_values(
+                    &format!("{path}[{i}]"),
+                    left.get(i),
+                    right.get(i),
+                    differences,
+                );
+            }
+        }
+        (left, right) if left != right => differences.push(Difference {
+            path: path.to_string(),
+            left: left.cloned(),
+            right: right.cloned(),
+        }),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::Message;
+
+    #[test]
+    // Verify that changed, added and removed values are reported with their paths
+    fn test_request_diff() {
+        let left = ChatCompletionRequest::new(
+            "gpt-4o",
+            &[
+                Message::new("system", "Be brief."),
+                Message::new("user", "Hi"),
+            ],
+        )
+        .with_temperature(0.7)
+        .with_extra("seed", json!(1.0));
+        let right = ChatCompletionRequest::new("gpt-4o", &[Message::new("system", "Be brief. ")])
+            .with_extra("seed", json!(1))
+            .with_user("user-1");
+
+        let differences = left.diff(&right).unwrap();
+        let lines = differences
+            .iter()
+            .map(Difference::to_string)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            lines,
+            vec![
+                r#"messages[0].content: "Be brief." -> "Be brief. ""#,
+                r#"messages[1]: {"content":"Hi","role":"user"} -> (missing)"#,
+                "temperature: 0.7 -> (missing)",
+                r#"user: (missing) -> "user-1""#,
+            ]
+        );
+        assert!(left.diff(&left).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,302 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Module containing at-rest encryption of the prompts and responses the crate persists.
+//!
+//! Data is sealed with AES-256-GCM under a key supplied by the caller, using a random nonce for
+//! every record. Encrypted data starts with a short header so data written before encryption was
+//! turned on, such as queued jobs, can be told apart and still be read. Records can also be bound
+//! to associated data, such as the row they are stored in, so they cannot be read anywhere else.
+//!
+//! ```
+//! use ryst_openai::encryption::EncryptionKey;
+//!
+//! let key = EncryptionKey::new([7; 32]);
+//! let sealed = key.encrypt(b"What is the capital of France?");
+//! assert_eq!(key.decrypt(&sealed).unwrap(), b"What is the capital of France?");
+//! ```
+
+use std::fmt;
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ryst_error::{InternalError, InvalidArgumentError, InvalidStateError};
+
+use crate::error::OpenAIError;
+
+/// Marks data sealed by `EncryptionKey`, and the version of its format.
+const HEADER: &[u8] = b"RYSTENC1";
+
+/// The size of an AES-GCM nonce.
+const NONCE_LEN: usize = 12;
+
+/// A 256-bit key used to encrypt stored prompts and responses.
+///
+/// The key is never included in `Debug` output.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    cipher: Aes256Gcm,
+}
+
+impl EncryptionKey {
+    /// Create a key from 32 bytes, which should come from a secure random source such as a
+    /// secrets manager.
+    pub fn new(key: [u8; 32]) -> Self {
+        Self {
+            cipher: Aes256Gcm::new(&key.into()),
+        }
+    }
+
+    /// Create a key from 32 bytes encoded as standard base64, such as the output of
+    /// `openssl rand -base64 32`.
+    pub fn from_base64(encoded: &str) -> Result<Self, OpenAIError> {
+        let invalid = |message: String| {
+            OpenAIError::InvalidArgument(InvalidArgumentError::new("encoded", message))
+        };
+
+        let bytes = STANDARD
+            .decode(encoded.trim())
+            .map_err(|err| invalid(format!("Key is not valid base64: {err}")))?;
+        let key = <[u8; 32]>::try_from(bytes.as_slice())
+            .map_err(|_| invalid(format!("Key must be 32 bytes, not {}", bytes.len())))?;
+        Ok(Self::new(key))
+    }
+
+    /// Encrypt data, returning a header, the random nonce and the ciphertext.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        self.encrypt_with_associated_data(plaintext, &[])
+    }
+
+    /// Encrypt data bound to the associated data, such as the id of the row it is stored in.
+    ///
+    /// The associated data is not part of the result. Decrypting requires the same associated
+    /// data, so sealed data copied elsewhere cannot be read.
+    pub fn encrypt_with_associated_data(
+        &self,
+        plaintext: &[u8],
+        associated_data: &[u8],
+    ) -> Vec<u8> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: plaintext,
+            aad: associated_data,
+        };
+        // Encryption only fails for inputs far larger than can be held in memory
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, payload)
+            .expect("plaintext is within the AES-GCM size limit");
+
+        let mut sealed = Vec::with_capacity(HEADER.len() + NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(HEADER);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Decrypt data produced by `encrypt`.
+    ///
+    /// Returns an error if the data was not encrypted, was encrypted with another key, or has
+    /// been modified.
+    pub fn decrypt(&self, sealed: &[u8]) -> Result<Vec<u8>, OpenAIError> {
+        self.decrypt_with_associated_data(sealed, &[])
+    }
+
+    /// Decrypt data produced by `encrypt_with_associated_data`.
+    ///
+    /// Returns an error like `decrypt`, and also if the associated data differs from the data
+    /// was encrypted with.
+    pub fn decrypt_with_associated_data(
+        &self,
+        sealed: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, OpenAIError> {
+        let rest = sealed
+            .strip_prefix(HEADER)
+            .filter(|rest| rest.len() >= NONCE_LEN)
+            .ok_or_else(|| {
+                OpenAIError::InvalidArgument(InvalidArgumentError::new(
+                    "sealed",
+                    "Data is not encrypted".to_string(),
+                ))
+            })?;
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: associated_data,
+        };
+
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| {
+                OpenAIError::InvalidState(InvalidStateError::with_message(
+                    "Unable to decrypt data, the key is wrong or the data was modified".to_string(),
+                ))
+            })
+    }
+
+    /// Decrypt the data if it is encrypted, otherwise return it unchanged.
+    pub(crate) fn decrypt_if_encrypted(&self, data: Vec<u8>) -> Result<Vec<u8>, OpenAIError> {
+        if is_encrypted(&data) {
+            self.decrypt(&data)
+        } else {
+            Ok(data)
+        }
+    }
+
+    /// Encrypt text for storage in a text column, as base64.
+    pub(crate) fn encrypt_text(&self, plaintext: &str) -> String {
+        self.seal_text(plaintext, &[])
+    }
+
+    /// Encrypt text bound to the associated data for storage in a text column, as base64.
+    pub(crate) fn seal_text(&self, plaintext: &str, associated_data: &[u8]) -> String {
+        STANDARD.encode(self.encrypt_with_associated_data(plaintext.as_bytes(), associated_data))
+    }
+
+    /// Decrypt text produced by `seal_text`, rejecting text which is not encrypted.
+    pub(crate) fn open_text(
+        &self,
+        stored: &str,
+        associated_data: &[u8],
+    ) -> Result<String, OpenAIError> {
+        let sealed = STANDARD
+            .decode(stored)
+            .ok()
+            .filter(|sealed| is_encrypted(sealed))
+            .ok_or_else(|| {
+                OpenAIError::InvalidState(InvalidStateError::with_message(
+                    "Stored data is not encrypted, but a key is set".to_string(),
+                ))
+            })?;
+
+        String::from_utf8(self.decrypt_with_associated_data(&sealed, associated_data)?)
+            .map_err(|err| OpenAIError::Internal(InternalError::from_source(Box::new(err))))
+    }
+
+    /// Decrypt text produced by `encrypt_text`, returning text which was stored before
+    /// encryption was turned on unchanged.
+    ///
+    /// JSON records start with `{` or `[`, which base64 never does.
+    pub(crate) fn decrypt_text(&self, stored: String) -> Result<String, OpenAIError> {
+        let Ok(sealed) = STANDARD.decode(&stored) else {
+            return Ok(stored);
+        };
+        if !is_encrypted(&sealed) {
+            return Ok(stored);
+        }
+
+        String::from_utf8(self.decrypt(&sealed)?)
+            .map_err(|err| OpenAIError::Internal(InternalError::from_source(Box::new(err))))
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// Returns whether the data was produced by `EncryptionKey::encrypt`.
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(HEADER)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Verify that encrypted data round trips and uses a fresh nonce each time
+    fn test_encrypt_decrypt() {
+        let key = EncryptionKey::new([1; 32]);
+
+        let first = key.encrypt(b"secret prompt");
+        let second = key.encrypt(b"secret prompt");
+        assert!(is_encrypted(&first));
+        assert_ne!(first, second);
+        assert!(!first.windows(6).any(|window| window == b"secret"));
+
+        assert_eq!(key.decrypt(&first).unwrap(), b"secret prompt");
+        assert_eq!(key.decrypt(&second).unwrap(), b"secret prompt");
+    }
+
+    #[test]
+    // Verify that decryption fails with another key, modified data or unencrypted data
+    fn test_decrypt_errors() {
+        let key = EncryptionKey::new([1; 32]);
+        let mut sealed = key.encrypt(b"secret prompt");
+
+        assert!(EncryptionKey::new([2; 32]).decrypt(&sealed).is_err());
+        assert!(key.decrypt(b"{\"plain\":true}").is_err());
+        assert!(key.decrypt(HEADER).is_err());
+
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(key.decrypt(&sealed).is_err());
+    }
+
+    #[test]
+    // Verify that text round trips and text stored before encryption is passed through
+    fn test_text() {
+        let key = EncryptionKey::new([1; 32]);
+
+        let sealed = key.encrypt_text("{\"content\":\"hi\"}");
+        assert!(!sealed.contains("content"));
+        assert_eq!(key.decrypt_text(sealed).unwrap(), "{\"content\":\"hi\"}");
+
+        assert_eq!(
+            key.decrypt_text("{\"a\":1}".to_string()).unwrap(),
+            "{\"a\":1}"
+        );
+        assert_eq!(key.decrypt_text("abcd".to_string()).unwrap(), "abcd");
+    }
+
+    #[test]
+    // Verify that data bound to associated data is only read with the same associated data, and
+    // that sealed text must be encrypted
+    fn test_associated_data() {
+        let key = EncryptionKey::new([1; 32]);
+
+        let sealed = key.encrypt_with_associated_data(b"secret prompt", b"row 1");
+        assert_eq!(
+            key.decrypt_with_associated_data(&sealed, b"row 1").unwrap(),
+            b"secret prompt"
+        );
+        assert!(key.decrypt_with_associated_data(&sealed, b"row 2").is_err());
+        assert!(key.decrypt(&sealed).is_err());
+
+        let sealed = key.seal_text("{\"content\":\"hi\"}", b"row 1");
+        assert_eq!(
+            key.open_text(&sealed, b"row 1").unwrap(),
+            "{\"content\":\"hi\"}"
+        );
+        assert!(key.open_text(&sealed, b"row 2").is_err());
+        assert!(key.open_text("{\"content\":\"hi\"}", b"row 1").is_err());
+    }
+
+    #[test]
+    // Verify that keys are read from base64 and must be 32 bytes
+    fn test_from_base64() {
+        let encoded = STANDARD.encode([1; 32]);
+        let key = EncryptionKey::from_base64(&format!("{encoded}\n")).unwrap();
+        let sealed = EncryptionKey::new([1; 32]).encrypt(b"hi");
+        assert_eq!(key.decrypt(&sealed).unwrap(), b"hi");
+
+        assert!(EncryptionKey::from_base64("not base64!").is_err());
+        assert!(EncryptionKey::from_base64(&STANDARD.encode([1; 16])).is_err());
+        assert_eq!(format!("{key:?}"), "EncryptionKey(..)");
+    }
+}
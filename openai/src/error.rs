@@ -15,6 +15,7 @@
 //! Module containing OpenAIError implementation.
 
 use std::error::Error;
+use std::time::Duration;
 
 use ryst_error::{InternalError, InvalidArgumentError, InvalidStateError};
 
@@ -28,6 +29,74 @@ pub enum OpenAIError {
     /// An error returned when an operation cannot be completed because the state of the underlying
     // struct is inconsistent.
     InvalidState(InvalidStateError),
+    /// An error returned when a stream fails after some of the response has been read, keeping
+    /// the text generated before it failed.
+    StreamInterrupted {
+        /// The text of the first choice read before the stream failed
+        partial: String,
+        /// The error the stream failed with
+        source: Box<OpenAIError>,
+    },
+    /// An error returned when the API rejects a request with a 429, because a rate limit was
+    /// reached or the quota is used up.
+    RateLimited {
+        /// The body of the response
+        message: String,
+        /// The wait recommended by the response's headers before retrying, if any
+        retry_after: Option<Duration>,
+        /// The `x-request-id` of the response, if any
+        request_id: Option<String>,
+    },
+}
+
+/// A response from the API with an error status, kept as the source of the `InvalidArgument` or
+/// `Internal` error it is returned as.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiError {
+    /// The HTTP status code
+    pub status: u16,
+    /// The body of the response
+    pub message: String,
+    /// The `x-request-id` of the response, if any
+    pub request_id: Option<String>,
+}
+
+impl Error for ApiError {}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl OpenAIError {
+    /// Returns the wait the API recommended before retrying, if the request was rate limited.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            OpenAIError::RateLimited { retry_after, .. } => *retry_after,
+            OpenAIError::StreamInterrupted { source, .. } => source.retry_after(),
+            _ => None,
+        }
+    }
+
+    /// Returns the ID OpenAI assigned the request, to quote when contacting support, if the
+    /// error was returned by the API.
+    pub fn request_id(&self) -> Option<&str> {
+        match self {
+            OpenAIError::RateLimited { request_id, .. } => request_id.as_deref(),
+            OpenAIError::StreamInterrupted { source, .. } => source.request_id(),
+            _ => {
+                let mut source = self.source();
+                while let Some(err) = source {
+                    if let Some(api_error) = err.downcast_ref::<ApiError>() {
+                        return api_error.request_id.as_deref();
+                    }
+                    source = err.source();
+                }
+                None
+            }
+        }
+    }
 }
 
 impl Error for OpenAIError {
@@ -36,6 +105,8 @@ impl Error for OpenAIError {
             OpenAIError::Internal(e) => Some(e),
             OpenAIError::InvalidArgument(e) => Some(e),
             OpenAIError::InvalidState(e) => Some(e),
+            OpenAIError::StreamInterrupted { source, .. } => Some(source.as_ref()),
+            OpenAIError::RateLimited { .. } => None,
         }
     }
 }
@@ -46,6 +117,21 @@ impl std::fmt::Display for OpenAIError {
             OpenAIError::Internal(e) => e.fmt(f),
             OpenAIError::InvalidArgument(e) => e.fmt(f),
             OpenAIError::InvalidState(e) => e.fmt(f),
+            OpenAIError::StreamInterrupted { partial, source } => write!(
+                f,
+                "Stream interrupted after {} characters: {source}",
+                partial.chars().count()
+            ),
+            OpenAIError::RateLimited {
+                message,
+                retry_after: Some(retry_after),
+                ..
+            } => write!(
+                f,
+                "Rate limited, retry after {}ms: {message}",
+                retry_after.as_millis()
+            ),
+            OpenAIError::RateLimited { message, .. } => write!(f, "Rate limited: {message}"),
         }
     }
 }
@@ -0,0 +1,409 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing a beam search over continuations of a conversation, for tree-of-thought
+//! style exploration.
+//!
+//! Each round, every conversation in the beam is expanded into several continuations by
+//! requesting multiple choices, the continuations are scored, and only the best are kept for the
+//! next round. The requests of a round are submitted in parallel.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures::future::{join_all, try_join_all};
+
+use crate::client::OpenAIClient;
+use crate::conversation::Conversation;
+use crate::credentials::KeySource;
+use crate::error::OpenAIError;
+use crate::{ChatCompletionRequest, ChatCompletionResponse, Message};
+
+type Scorer = dyn Fn(Conversation) -> Pin<Box<dyn Future<Output = Result<f64, OpenAIError>> + Send>>
+    + Send
+    + Sync;
+type Handler = dyn Fn(
+        ChatCompletionRequest,
+    ) -> Pin<Box<dyn Future<Output = Result<ChatCompletionResponse, OpenAIError>> + Send>>
+    + Send
+    + Sync;
+
+/// A continuation generated during a search.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExploredNode {
+    /// The position of the node in `Exploration::nodes`
+    pub id: usize,
+    /// The node this continues, or `None` if it continues the starting conversation
+    pub parent: Option<usize>,
+    /// The round the node was generated in, starting from 1
+    pub depth: usize,
+    /// The generated message
+    pub message: Message,
+    /// The score given by the scorer
+    pub score: f64,
+    /// Whether the node was kept in the beam to be expanded further
+    pub kept: bool,
+}
+
+/// The result of a search.
+#[derive(Debug, Clone)]
+pub struct Exploration {
+    /// The conversation ending with the best scoring node of the last round, or the starting
+    /// conversation if nothing was generated
+    pub best: Conversation,
+    /// The best node, if anything was generated
+    pub best_node: Option<usize>,
+    /// Every node generated, in the order they were generated
+    pub nodes: Vec<ExploredNode>,
+    /// The tokens used by every request
+    pub tokens_used: i32,
+    /// Whether the search stopped early because the token budget was used up
+    pub budget_exhausted: bool,
+}
+
+impl Exploration {
+    /// Returns the nodes on the path to the best node, from the first round to the last.
+    pub fn best_path(&self) -> Vec<&ExploredNode> {
+        let mut path = std::iter::successors(self.best_node.map(|id| &self.nodes[id]), |node| {
+            node.parent.map(|id| &self.nodes[id])
+        })
+        .collect::<Vec<_>>();
+        path.reverse();
+        path
+    }
+}
+
+struct BeamEntry {
+    conversation: Conversation,
+    node: Option<usize>,
+}
+
+/// Explores continuations of a conversation, keeping the best scoring ones each round.
+pub struct BeamSearch {
+    model: String,
+    scorer: Arc<Scorer>,
+    branching: i8,
+    beam_width: usize,
+    depth: usize,
+    temperature: Option<f32>,
+    step_prompt: Option<String>,
+    token_budget: Option<i32>,
+    key_source: Option<KeySource>,
+    client: Option<OpenAIClient>,
+    handler: Option<Arc<Handler>>,
+}
+
+impl BeamSearch {
+    /// Create a search using the model, scoring each continuation with the scorer.
+    ///
+    /// The scorer is given the conversation ending with the continuation, and higher scores are
+    /// better. It could apply a heuristic or ask a model to grade the reasoning. By default each
+    /// conversation is expanded into 3 continuations, the best 2 are kept, and the search runs
+    /// for 3 rounds.
+    pub fn new<F, Fut>(model: &str, scorer: F) -> Self
+    where
+        F: Fn(Conversation) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<f64, OpenAIError>> + Send + 'static,
+    {
+        Self {
+            model: model.to_string(),
+            scorer: Arc::new(move |conversation| Box::pin(scorer(conversation))),
+            branching: 3,
+            beam_width: 2,
+            depth: 3,
+            temperature: None,
+            step_prompt: None,
+            token_budget: None,
+            key_source: None,
+            client: None,
+            handler: None,
+        }
+    }
+
+    /// The number of continuations generated from each conversation in the beam.
+    pub fn with_branching(mut self, branching: i8) -> Self {
+        self.branching = branching.max(1);
+        self
+    }
+
+    /// The number of continuations kept after each round.
+    pub fn with_beam_width(mut self, beam_width: usize) -> Self {
+        self.beam_width = beam_width.max(1);
+        self
+    }
+
+    /// The number of rounds to run.
+    pub fn with_depth(mut self, depth: usize) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    /// The sampling temperature, where higher values make continuations more varied.
+    pub fn with_temperature(mut self, temperature: f32) -> Self {
+        self.temperature = Some(temperature);
+        self
+    }
+
+    /// A user message added after each continuation before it is expanded, such as
+    /// "Continue with the next step."
+    pub fn with_step_prompt(mut self, step_prompt: &str) -> Self {
+        self.step_prompt = Some(step_prompt.to_string());
+        self
+    }
+
+    /// Stop starting new rounds once this many tokens have been used, returning the best
+    /// continuation found so far.
+    ///
+    /// The budget is checked before each round, so the round which crosses it completes.
+    pub fn with_token_budget(mut self, token_budget: i32) -> Self {
+        self.token_budget = Some(token_budget);
+        self
+    }
+
+    /// Where to read the API key from, see `ChatCompletionRequest::with_key_source`.
+    pub fn with_key_source(mut self, key_source: KeySource) -> Self {
+        self.key_source = Some(key_source);
+        self
+    }
+
+    /// Send the requests through the client, see `ChatCompletionRequest::with_client`.
+    pub fn with_client(mut self, client: OpenAIClient) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Submit requests with the handler instead of submitting them to OpenAI.
+    pub fn with_handler<F, Fut>(mut self, handler: F) -> Self
+    where
+        F: Fn(ChatCompletionRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<ChatCompletionResponse, OpenAIError>> + Send + 'static,
+    {
+        self.handler = Some(Arc::new(move |request| Box::pin(handler(request))));
+        self
+    }
+
+    /// Run the search from the conversation.
+    pub async fn explore(&self, start: &Conversation) -> Result<Exploration, OpenAIError> {
+        let mut exploration = Exploration {
+            best: start.fork(),
+            best_node: None,
+            nodes: Vec::new(),
+            tokens_used: 0,
+            budget_exhausted: false,
+        };
+        let mut beam = vec![BeamEntry {
+            conversation: start.fork(),
+            node: None,
+        }];
+
+        for depth in 1..=self.depth {
+            if let Some(budget) = self.token_budget {
+                if exploration.tokens_used >= budget {
+                    exploration.budget_exhausted = true;
+                    break;
+                }
+            }
+
+            for entry in beam.iter_mut().filter(|entry| entry.node.is_some()) {
+                if let Some(step_prompt) = &self.step_prompt {
+                    entry.conversation.push(Message::new("user", step_prompt));
+                }
+            }
+
+            let responses = try_join_all(
+                beam.iter()
+                    .map(|entry| self.submit(self.request(&entry.conversation))),
+            )
+            .await?;
+
+            let mut children = Vec::new();
+            for (entry, response) in beam.iter().zip(responses) {
+                exploration.tokens_used += response.usage.total_tokens;
+                for choice in response.choices {
+                    let mut conversation = entry.conversation.fork();
+                    conversation.push(choice.message);
+                    children.push((entry.node, conversation));
+                }
+            }
+            if children.is_empty() {
+                break;
+            }
+
+            let scores = join_all(
+                children
+                    .iter()
+                    .map(|(_, conversation)| (self.scorer)(conversation.fork())),
+            )
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+
+            let first_id = exploration.nodes.len();
+            for (i, ((parent, conversation), score)) in children.iter().zip(&scores).enumerate() {
+                exploration.nodes.push(ExploredNode {
+                    id: first_id + i,
+                    parent: *parent,
+                    depth,
+                    message: conversation.last().cloned().unwrap_or_default(),
+                    score: *score,
+                    kept: false,
+                });
+            }
+
+            // Rank by score, with NaN scores last and ties kept in generation order
+            let mut ranked = (0..children.len()).collect::<Vec<_>>();
+            ranked.sort_by(|a, b| {
+                scores[*b]
+                    .partial_cmp(&scores[*a])
+                    .unwrap_or_else(|| scores[*a].is_nan().cmp(&scores[*b].is_nan()))
+            });
+            ranked.truncate(self.beam_width);
+
+            beam = ranked
+                .iter()
+                .map(|i| {
+                    exploration.nodes[first_id + i].kept = true;
+                    BeamEntry {
+                        conversation: children[*i].1.fork(),
+                        node: Some(first_id + i),
+                    }
+                })
+                .collect();
+
+            exploration.best = beam[0].conversation.fork();
+            exploration.best_node = beam[0].node;
+        }
+
+        Ok(exploration)
+    }
+
+    fn request(&self, conversation: &Conversation) -> ChatCompletionRequest {
+        let mut request = conversation.request(&self.model).with_n(self.branching);
+        if let Some(temperature) = self.temperature {
+            request = request.with_temperature(temperature);
+        }
+        request
+    }
+
+    async fn submit(
+        &self,
+        mut request: ChatCompletionRequest,
+    ) -> Result<ChatCompletionResponse, OpenAIError> {
+        if let Some(handler) = &self.handler {
+            return handler(request).await;
+        }
+        if let Some(key_source) = &self.key_source {
+            request = request.with_key_source(key_source.clone());
+        }
+        if let Some(client) = &self.client {
+            request = request.with_client(client.clone());
+        }
+        request.submit().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    // Replies with one choice per requested `n`, appending the choice's index to the content of
+    // the last assistant message
+    async fn handler(
+        request: ChatCompletionRequest,
+    ) -> Result<ChatCompletionResponse, OpenAIError> {
+        let previous = request
+            .messages()
+            .iter()
+            .rev()
+            .find(|message| message.role() == "assistant")
+            .and_then(|message| message.content().as_text())
+            .unwrap_or_default()
+            .to_string();
+        let choices = (0..request.n().unwrap_or(1))
+            .map(|i| {
+                json!({
+                    "index": i,
+                    "message": {"role": "assistant", "content": format!("{previous}{i}")},
+                    "finish_reason": "stop"
+                })
+            })
+            .collect::<Vec<_>>();
+
+        Ok(serde_json::from_value(json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "gpt-4o",
+            "choices": choices,
+            "usage": {"prompt_tokens": 5, "completion_tokens": 5, "total_tokens": 10}
+        }))
+        .unwrap())
+    }
+
+    async fn score(conversation: Conversation) -> Result<f64, OpenAIError> {
+        let content = conversation.last().unwrap().content().as_text().unwrap();
+        Ok(content.parse::<f64>().unwrap())
+    }
+
+    #[tokio::test]
+    // Verify that the best path is found while only the beam is expanded each round
+    async fn test_beam_search() {
+        let start = Conversation::from_messages(&[Message::new("user", "Think")]);
+        let exploration = BeamSearch::new("gpt-4o", score)
+            .with_step_prompt("Next")
+            .with_handler(handler)
+            .explore(&start)
+            .await
+            .unwrap();
+
+        let best = exploration.best.to_messages();
+        let contents = best
+            .iter()
+            .map(|message| message.content().as_text().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(contents, vec!["Think", "2", "Next", "22", "Next", "222"]);
+
+        // 3 choices in the first round, then 3 for each of the 2 kept in later rounds
+        assert_eq!(exploration.nodes.len(), 15);
+        assert_eq!(exploration.tokens_used, 50);
+        assert_eq!(exploration.nodes.iter().filter(|node| node.kept).count(), 6);
+
+        let path = exploration
+            .best_path()
+            .iter()
+            .map(|node| (node.depth, node.score))
+            .collect::<Vec<_>>();
+        assert_eq!(path, vec![(1, 2.0), (2, 22.0), (3, 222.0)]);
+    }
+
+    #[tokio::test]
+    // Verify that the search stops before a round once the token budget is used up
+    async fn test_beam_search_budget() {
+        let exploration = BeamSearch::new("gpt-4o", score)
+            .with_token_budget(15)
+            .with_handler(handler)
+            .explore(&Conversation::from_messages(&[Message::new(
+                "user", "Think",
+            )]))
+            .await
+            .unwrap();
+
+        assert!(exploration.budget_exhausted);
+        assert_eq!(exploration.tokens_used, 30);
+        assert_eq!(exploration.best.last().unwrap().content(), "22");
+    }
+}
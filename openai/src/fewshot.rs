@@ -0,0 +1,315 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing the selection of few-shot examples from a labeled pool, since which
+//! examples a prompt shows the model materially affects its accuracy.
+//!
+//! ```
+//! # fn example() -> Result<(), ryst_openai::OpenAIError> {
+//! use ryst_openai::fewshot::{Example, FewShotSampler, Sampling};
+//! use ryst_openai::{ChatCompletionRequest, Message};
+//!
+//! let pool = vec![
+//!     Example::new("I loved it", "positive").with_label("positive"),
+//!     Example::new("Never again", "negative").with_label("negative"),
+//!     Example::new("Best purchase this year", "positive").with_label("positive"),
+//! ];
+//!
+//! let mut messages = vec![Message::new("system", "Classify the sentiment of the review.")];
+//! for example in FewShotSampler::new(Sampling::Stratified).sample(&pool, 2)? {
+//!     messages.extend(example.messages());
+//! }
+//! messages.push(Message::new("user", "It broke after a day"));
+//! let request = ChatCompletionRequest::new("gpt-4o", &messages);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::BTreeMap;
+
+use ryst_error::InvalidArgumentError;
+
+use crate::clock::Rng;
+use crate::dedup::cosine_similarity;
+use crate::error::OpenAIError;
+use crate::Message;
+
+/// An input and the output the model should reply with, shown to the model as an example.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Example {
+    pub input: String,
+    pub output: String,
+    /// The class of the example, which `Sampling::Stratified` balances
+    pub label: Option<String>,
+    /// An embedding of the input computed by the caller, which `Sampling::Diverse` compares
+    pub embedding: Option<Vec<f32>>,
+}
+
+impl Example {
+    /// Create an example without a label or embedding.
+    pub fn new(input: &str, output: &str) -> Self {
+        Self {
+            input: input.to_string(),
+            output: output.to_string(),
+            label: None,
+            embedding: None,
+        }
+    }
+
+    /// The class of the example, such as the sentiment of a review.
+    pub fn with_label(mut self, label: &str) -> Self {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    /// An embedding of the input, such as one returned by the embeddings API.
+    pub fn with_embedding(mut self, embedding: Vec<f32>) -> Self {
+        self.embedding = Some(embedding);
+        self
+    }
+
+    /// The example as a user message followed by the assistant's reply.
+    pub fn messages(&self) -> [Message; 2] {
+        [
+            Message::new("user", &self.input),
+            Message::new("assistant", &self.output),
+        ]
+    }
+}
+
+/// How examples are chosen from the pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sampling {
+    /// Examples are chosen at random.
+    Random,
+    /// Examples are taken from each label in turn, so every label is shown about equally often
+    /// and examples of one label are not grouped together. Examples without a label are treated
+    /// as one more label.
+    Stratified,
+    /// Examples are chosen to be as unlike each other as possible, by the cosine similarity of
+    /// their embeddings. The first is chosen at random, then each next one is the example least
+    /// similar to its most similar chosen example. Every example needs an embedding.
+    Diverse,
+}
+
+/// Chooses few-shot examples from a pool.
+///
+/// Clones share the same random sequence.
+#[derive(Debug, Clone)]
+pub struct FewShotSampler {
+    sampling: Sampling,
+    rng: Rng,
+}
+
+impl FewShotSampler {
+    /// Create a sampler using the strategy, seeded from the current time.
+    pub fn new(sampling: Sampling) -> Self {
+        Self {
+            sampling,
+            rng: Rng::from_time(),
+        }
+    }
+
+    /// Create a sampler which chooses the same examples for the same seed, for reproducible
+    /// evaluations.
+    pub fn seeded(sampling: Sampling, seed: u64) -> Self {
+        Self {
+            sampling,
+            rng: Rng::seeded(seed),
+        }
+    }
+
+    /// Choose up to `k` examples from the pool, in the order they should be shown.
+    ///
+    /// Returns an `InvalidArgument` error if the sampling is `Diverse` and an example has no
+    /// embedding.
+    pub fn sample<'a>(
+        &self,
+        pool: &'a [Example],
+        k: usize,
+    ) -> Result<Vec<&'a Example>, OpenAIError> {
+        let indices = match self.sampling {
+            Sampling::Random => self.random(pool.len(), k),
+            Sampling::Stratified => self.stratified(pool, k),
+            Sampling::Diverse => self.diverse(pool, k)?,
+        };
+        Ok(indices.into_iter().map(|index| &pool[index]).collect())
+    }
+
+    fn random(&self, len: usize, k: usize) -> Vec<usize> {
+        let mut indices: Vec<usize> = (0..len).collect();
+        self.rng.shuffle(&mut indices);
+        indices.truncate(k);
+        indices
+    }
+
+    fn stratified(&self, pool: &[Example], k: usize) -> Vec<usize> {
+        let mut groups: BTreeMap<Option<&str>, Vec<usize>> = BTreeMap::new();
+        for (index, example) in pool.iter().enumerate() {
+            groups
+                .entry(example.label.as_deref())
+                .or_default()
+                .push(index);
+        }
+
+        let mut groups: Vec<Vec<usize>> = groups.into_values().collect();
+        for group in &mut groups {
+            self.rng.shuffle(group);
+            // Taken from the back below
+            group.reverse();
+        }
+        // Labels which can't all be shown are picked at random
+        self.rng.shuffle(&mut groups);
+
+        let mut indices = Vec::with_capacity(k.min(pool.len()));
+        while indices.len() < k && groups.iter().any(|group| !group.is_empty()) {
+            for group in &mut groups {
+                if indices.len() == k {
+                    break;
+                }
+                if let Some(index) = group.pop() {
+                    indices.push(index);
+                }
+            }
+        }
+        indices
+    }
+
+    fn diverse(&self, pool: &[Example], k: usize) -> Result<Vec<usize>, OpenAIError> {
+        let embeddings = pool
+            .iter()
+            .enumerate()
+            .map(|(index, example)| {
+                example.embedding.as_deref().ok_or_else(|| {
+                    OpenAIError::InvalidArgument(InvalidArgumentError::new(
+                        "pool",
+                        format!("Example {index} has no embedding to compare"),
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if pool.is_empty() || k == 0 {
+            return Ok(vec![]);
+        }
+
+        let mut indices = vec![self.rng.below(pool.len())];
+        // The similarity of each example to its most similar chosen example
+        let mut closest: Vec<f64> = embeddings
+            .iter()
+            .map(|embedding| cosine_similarity(embedding, embeddings[indices[0]]))
+            .collect();
+        while indices.len() < k.min(pool.len()) {
+            let next = (0..pool.len())
+                .filter(|index| !indices.contains(index))
+                .min_by(|a, b| closest[*a].total_cmp(&closest[*b]))
+                .expect("fewer examples are chosen than the pool has");
+            indices.push(next);
+            for (index, embedding) in embeddings.iter().enumerate() {
+                closest[index] = closest[index].max(cosine_similarity(embedding, embeddings[next]));
+            }
+        }
+        Ok(indices)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+
+    fn labeled(labels: &[&str]) -> Vec<Example> {
+        labels
+            .iter()
+            .enumerate()
+            .map(|(index, label)| Example::new(&index.to_string(), label).with_label(label))
+            .collect()
+    }
+
+    #[test]
+    // Verify that random samples are distinct, bounded by the pool and reproducible by seed
+    fn test_random() {
+        let pool = labeled(&["a"; 10]);
+        let sampler = || FewShotSampler::seeded(Sampling::Random, 3);
+
+        let sample = sampler().sample(&pool, 4).unwrap();
+        assert_eq!(sample.len(), 4);
+        assert_eq!(
+            sample
+                .iter()
+                .map(|example| &example.input)
+                .collect::<HashSet<_>>()
+                .len(),
+            4
+        );
+        assert_eq!(sample, sampler().sample(&pool, 4).unwrap());
+        assert_eq!(sampler().sample(&pool, 20).unwrap().len(), 10);
+    }
+
+    #[test]
+    // Verify that stratified samples take from each label in turn until a label runs out
+    fn test_stratified() {
+        let pool = labeled(&["a", "a", "a", "a", "b", "b", "c"]);
+        let sampler = FewShotSampler::seeded(Sampling::Stratified, 1);
+
+        let labels = |k| {
+            let mut labels: Vec<_> = sampler
+                .sample(&pool, k)
+                .unwrap()
+                .into_iter()
+                .map(|example| example.output.as_str())
+                .collect();
+            labels.sort();
+            labels
+        };
+        assert_eq!(labels(3), ["a", "b", "c"]);
+        assert_eq!(labels(5), ["a", "a", "b", "b", "c"]);
+        assert_eq!(labels(7), ["a", "a", "a", "a", "b", "b", "c"]);
+
+        let first_round: HashSet<_> = sampler.sample(&pool, 6).unwrap()[..3]
+            .iter()
+            .map(|example| example.output.as_str())
+            .collect();
+        assert_eq!(first_round.len(), 3);
+    }
+
+    #[test]
+    // Verify that diverse samples avoid near duplicates and require embeddings
+    fn test_diverse() {
+        let pool = vec![
+            Example::new("cat", "").with_embedding(vec![1.0, 0.0]),
+            Example::new("kitten", "").with_embedding(vec![0.99, 0.1]),
+            Example::new("car", "").with_embedding(vec![0.0, 1.0]),
+            Example::new("truck", "").with_embedding(vec![0.1, 0.99]),
+        ];
+
+        for seed in 0..8 {
+            let sample = FewShotSampler::seeded(Sampling::Diverse, seed)
+                .sample(&pool, 2)
+                .unwrap();
+            let animals = sample
+                .iter()
+                .filter(|example| example.input == "cat" || example.input == "kitten")
+                .count();
+            assert_eq!(animals, 1, "{sample:?}");
+        }
+
+        let mut missing = pool.clone();
+        missing[2].embedding = None;
+        assert!(matches!(
+            FewShotSampler::new(Sampling::Diverse).sample(&missing, 2),
+            Err(OpenAIError::InvalidArgument(_))
+        ));
+    }
+}
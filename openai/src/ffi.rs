@@ -0,0 +1,298 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing a C-compatible API, for embedding in C, C++, Swift or game engines.
+//!
+//! Requests and responses cross the boundary as JSON in the API's format. A client handle owns
+//! the runtime requests are run on and the API key they are sent with:
+//!
+//! ```c
+//! RystClient *client = ryst_client_new(NULL);
+//! char *error = NULL;
+//! char *response = ryst_submit_chat(client, request_json, &error);
+//! if (response == NULL) {
+//!     fprintf(stderr, "%s\n", error);
+//!     ryst_string_free(error);
+//! } else {
+//!     ryst_string_free(response);
+//! }
+//! ryst_client_free(client);
+//! ```
+//!
+//! Build the library with `cargo rustc -p ryst-openai --features ffi --crate-type cdylib` and
+//! generate the header with `cbindgen --config openai/cbindgen.toml --output ryst.h openai`.
+
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use ryst_error::{InternalError, InvalidArgumentError};
+use tokio::runtime::Runtime;
+
+use crate::error::OpenAIError;
+use crate::{ChatCompletionRequest, KeySource};
+
+/// A client, created with `ryst_client_new` and freed with `ryst_client_free`.
+pub struct RystClient {
+    runtime: Runtime,
+    key_source: Option<KeySource>,
+}
+
+/// Called with each response of a stream as JSON, and the `user_data` given to
+/// `ryst_stream_chat`. Return `false` to stop reading the stream.
+pub type RystStreamCallback =
+    extern "C" fn(response_json: *const c_char, user_data: *mut c_void) -> bool;
+
+/// Create a client which sends requests with the given API key.
+///
+/// If `api_key` is null, the key is read from the `OPENAI_API_KEY` or `OPENAI_API_KEY_FILE`
+/// environment variables. Returns null if the client's runtime can't be started.
+///
+/// # Safety
+///
+/// `api_key` must be null or a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ryst_client_new(api_key: *const c_char) -> *mut RystClient {
+    let key_source = if api_key.is_null() {
+        None
+    } else {
+        Some(KeySource::Static(
+            CStr::from_ptr(api_key).to_string_lossy().into_owned(),
+        ))
+    };
+
+    match Runtime::new() {
+        Ok(runtime) => Box::into_raw(Box::new(RystClient {
+            runtime,
+            key_source,
+        })),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Free a client created with `ryst_client_new`.
+///
+/// # Safety
+///
+/// `client` must be null or a client returned by `ryst_client_new` which has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn ryst_client_free(client: *mut RystClient) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+/// Free a string returned by this library.
+///
+/// # Safety
+///
+/// `string` must be null or a string returned by this library which has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn ryst_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+/// Submit a chat completion request given as JSON, returning the response as JSON.
+///
+/// Returns null on failure, setting `*error` to a message if `error` is not null. Both strings
+/// must be freed with `ryst_string_free`.
+///
+/// # Safety
+///
+/// `client` must be a live client, `request_json` a valid NUL-terminated string, and `error`
+/// null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn ryst_submit_chat(
+    client: *const RystClient,
+    request_json: *const c_char,
+    error: *mut *mut c_char,
+) -> *mut c_char {
+    let result = guarded(|| {
+        let (client, request) = prepare(client, request_json)?;
+        let response = client.runtime.block_on(request.submit())?;
+        serde_json::to_string(&response).map_err(json_error)
+    });
+
+    match result {
+        Ok(json) => into_c_string(json),
+        Err(err) => {
+            set_error(error, err);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Submit a chat completion request given as JSON as a stream, calling `callback` with each
+/// response as JSON until the stream ends or the callback returns `false`.
+///
+/// The strings passed to the callback are only valid during the call. Returns `true` on
+/// success, or `false` on failure, setting `*error` to a message as `ryst_submit_chat` does.
+///
+/// # Safety
+///
+/// `client` must be a live client, `request_json` a valid NUL-terminated string, and `error`
+/// null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn ryst_stream_chat(
+    client: *const RystClient,
+    request_json: *const c_char,
+    callback: RystStreamCallback,
+    user_data: *mut c_void,
+    error: *mut *mut c_char,
+) -> bool {
+    let result = guarded(|| {
+        let (client, request) = prepare(client, request_json)?;
+        client.runtime.block_on(async {
+            let mut stream = request.stream().await?;
+            while let Some(response) = stream.next().await? {
+                let json = serde_json::to_string(&response).map_err(json_error)?;
+                let json = CString::new(json).map_err(|err| {
+                    OpenAIError::Internal(InternalError::from_source(Box::new(err)))
+                })?;
+                if !callback(json.as_ptr(), user_data) {
+                    break;
+                }
+            }
+            Ok(())
+        })
+    });
+
+    match result {
+        Ok(()) => true,
+        Err(err) => {
+            set_error(error, err);
+            false
+        }
+    }
+}
+
+/// Resolve the client and parse the request, sending it with the client's key.
+unsafe fn prepare<'a>(
+    client: *const RystClient,
+    request_json: *const c_char,
+) -> Result<(&'a RystClient, ChatCompletionRequest), OpenAIError> {
+    let client = client.as_ref().ok_or_else(|| null_argument("client"))?;
+    if request_json.is_null() {
+        return Err(null_argument("request_json"));
+    }
+
+    let request_json = CStr::from_ptr(request_json).to_string_lossy();
+    let mut request: ChatCompletionRequest =
+        serde_json::from_str(&request_json).map_err(|err| {
+            OpenAIError::InvalidArgument(InvalidArgumentError::new("request_json", err.to_string()))
+        })?;
+    if let Some(key_source) = &client.key_source {
+        request = request.with_key_source(key_source.clone());
+    }
+
+    Ok((client, request))
+}
+
+/// Run the function, converting a panic to an error rather than unwinding into the caller.
+fn guarded<T, F>(f: F) -> Result<T, OpenAIError>
+where
+    F: FnOnce() -> Result<T, OpenAIError>,
+{
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|_| {
+        Err(OpenAIError::Internal(InternalError::with_message(
+            "Panicked while handling the request",
+        )))
+    })
+}
+
+fn null_argument(argument: &str) -> OpenAIError {
+    OpenAIError::InvalidArgument(InvalidArgumentError::new(argument, "Must not be null"))
+}
+
+fn json_error(err: serde_json::Error) -> OpenAIError {
+    OpenAIError::Internal(InternalError::from_source(Box::new(err)))
+}
+
+/// Convert to a C string, replacing any NUL bytes, which JSON and error messages can't contain
+/// unescaped anyway.
+fn into_c_string(string: String) -> *mut c_char {
+    CString::new(string.replace('\0', " "))
+        .map(CString::into_raw)
+        .unwrap_or(ptr::null_mut())
+}
+
+unsafe fn set_error(error: *mut *mut c_char, err: OpenAIError) {
+    if !error.is_null() {
+        *error = into_c_string(err.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn take_string(string: *mut c_char) -> String {
+        assert!(!string.is_null());
+        let value = unsafe { CStr::from_ptr(string) }
+            .to_string_lossy()
+            .into_owned();
+        unsafe { ryst_string_free(string) };
+        value
+    }
+
+    #[test]
+    // Verify that invalid arguments are returned as errors rather than crashing
+    fn test_submit_chat_errors() {
+        let client = unsafe { ryst_client_new(c"sk-test".as_ptr()) };
+        assert!(!client.is_null());
+
+        let mut error = ptr::null_mut();
+        let response = unsafe { ryst_submit_chat(client, c"not json".as_ptr(), &mut error) };
+        assert!(response.is_null());
+        assert!(take_string(error).contains("request_json"));
+
+        let mut error = ptr::null_mut();
+        let response = unsafe { ryst_submit_chat(client, ptr::null(), &mut error) };
+        assert!(response.is_null());
+        assert!(take_string(error).contains("Must not be null"));
+
+        // A null error pointer is allowed
+        let response = unsafe { ryst_submit_chat(ptr::null(), c"{}".as_ptr(), ptr::null_mut()) };
+        assert!(response.is_null());
+
+        unsafe { ryst_client_free(client) };
+        unsafe { ryst_client_free(ptr::null_mut()) };
+    }
+
+    #[test]
+    // Verify that a stream which can't be started reports an error without calling back
+    fn test_stream_chat_errors() {
+        extern "C" fn callback(_: *const c_char, _: *mut c_void) -> bool {
+            panic!("callback should not be called");
+        }
+
+        let client = unsafe { ryst_client_new(ptr::null()) };
+        let mut error = ptr::null_mut();
+        let ok = unsafe {
+            ryst_stream_chat(
+                client,
+                c"{\"model\": \"gpt-4o\", \"messages\": [], \"temperature\": 1, \"top_p\": 1}"
+                    .as_ptr(),
+                callback,
+                ptr::null_mut(),
+                &mut error,
+            )
+        };
+        assert!(!ok);
+        assert!(take_string(error).contains("temperature or top_p"));
+        unsafe { ryst_client_free(client) };
+    }
+}
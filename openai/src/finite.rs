@@ -0,0 +1,79 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing the deserialization of numbers which must be finite, such as log
+//! probabilities.
+
+use std::collections::HashMap;
+
+use serde::de::{Deserialize, Deserializer, Error, Unexpected};
+
+/// Deserialize a number, rejecting NaN and infinity.
+pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    check(f64::deserialize(deserializer)?)
+}
+
+/// Deserialize a sequence of numbers, rejecting NaN and infinity.
+pub(crate) fn deserialize_vec<'de, D>(deserializer: D) -> Result<Vec<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<f64>::deserialize(deserializer)?
+        .into_iter()
+        .map(check)
+        .collect()
+}
+
+/// Returns the values of the map, rejecting NaN and infinity.
+pub(crate) fn check_map<E: Error>(map: HashMap<String, f64>) -> Result<HashMap<String, f64>, E> {
+    map.into_iter()
+        .map(|(key, value)| Ok((key, check(value)?)))
+        .collect()
+}
+
+/// Returns the value if it is finite, and an `invalid_value` error otherwise.
+pub(crate) fn check<E: Error>(value: f64) -> Result<f64, E> {
+    if value.is_finite() {
+        Ok(value)
+    } else {
+        Err(E::invalid_value(
+            Unexpected::Float(value),
+            &"a finite number",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde::de::value::{Error as ValueError, F64Deserializer};
+    use serde::de::IntoDeserializer;
+
+    #[test]
+    // Verify that finite numbers are kept and NaN and infinity are rejected
+    fn test_deserialize_finite() {
+        let deserializer: F64Deserializer<ValueError> = (-0.25).into_deserializer();
+        assert_eq!(deserialize(deserializer).unwrap(), -0.25);
+
+        for value in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let deserializer: F64Deserializer<ValueError> = value.into_deserializer();
+            let err = deserialize(deserializer).unwrap_err();
+            assert!(err.to_string().contains("expected a finite number"));
+        }
+    }
+}
@@ -0,0 +1,610 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing gRPC types mirroring the chat completion types, with a tonic client and a
+//! relay service.
+//!
+//! The proto definitions are in `proto/chat.proto`. Extra request fields, logprobs and Azure
+//! content filter results are not mirrored and are dropped when converting.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures::stream::{self, Stream, StreamExt};
+use ryst_error::{InternalError, InvalidArgumentError};
+use tonic::{Code, Status};
+
+use crate::error::OpenAIError;
+use crate::intern;
+use crate::{
+    ChatChoice, ChatCompletionRequest, ChatCompletionResponse, ChatUsage, ContentPart, FileInput,
+    FunctionCall, ImageUrl, Message, MessageContent, RequestTags, Tool, ToolCall,
+};
+
+/// The types and service generated from the proto definitions.
+#[allow(clippy::all)]
+pub mod proto {
+    tonic::include_proto!("ryst.chat.v1");
+}
+
+use proto::chat_service_client::ChatServiceClient;
+use proto::chat_service_server::{ChatService, ChatServiceServer};
+
+type Handler = dyn Fn(
+        ChatCompletionRequest,
+    ) -> Pin<Box<dyn Future<Output = Result<ChatCompletionResponse, OpenAIError>> + Send>>
+    + Send
+    + Sync;
+type ResponseStream =
+    Pin<Box<dyn Stream<Item = Result<proto::ChatCompletionResponse, Status>> + Send>>;
+
+/// A gRPC `ChatService` which answers requests with a handler, by default relaying them to
+/// OpenAI.
+#[derive(Clone)]
+pub struct ChatRelay {
+    handler: Option<Arc<Handler>>,
+}
+
+impl ChatRelay {
+    /// Create a relay which submits requests to OpenAI, reading the API key as
+    /// `ChatCompletionRequest::submit` does.
+    pub fn openai() -> Self {
+        Self { handler: None }
+    }
+
+    /// Create a relay which answers requests with the handler.
+    ///
+    /// Streamed requests are answered with the handler's response as the only item.
+    pub fn with_handler<F, Fut>(handler: F) -> Self
+    where
+        F: Fn(ChatCompletionRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<ChatCompletionResponse, OpenAIError>> + Send + 'static,
+    {
+        Self {
+            handler: Some(Arc::new(move |request| Box::pin(handler(request)))),
+        }
+    }
+
+    /// Wrap the relay in a tonic server, to add to a `tonic::transport::Server`.
+    pub fn into_server(self) -> ChatServiceServer<Self> {
+        ChatServiceServer::new(self)
+    }
+}
+
+#[tonic::async_trait]
+impl ChatService for ChatRelay {
+    async fn complete(
+        &self,
+        request: tonic::Request<proto::ChatCompletionRequest>,
+    ) -> Result<tonic::Response<proto::ChatCompletionResponse>, Status> {
+        let request = ChatCompletionRequest::try_from(request.into_inner()).map_err(to_status)?;
+        let response = match &self.handler {
+            Some(handler) => handler(request).await,
+            None => request.submit().await,
+        }
+        .map_err(to_status)?;
+
+        Ok(tonic::Response::new((&response).into()))
+    }
+
+    type StreamStream = ResponseStream;
+
+    async fn stream(
+        &self,
+        request: tonic::Request<proto::ChatCompletionRequest>,
+    ) -> Result<tonic::Response<Self::StreamStream>, Status> {
+        let request = ChatCompletionRequest::try_from(request.into_inner()).map_err(to_status)?;
+
+        let responses: ResponseStream = match &self.handler {
+            Some(handler) => {
+                let response = handler(request).await.map_err(to_status)?;
+                Box::pin(stream::once(async move { Ok((&response).into()) }))
+            }
+            None => {
+                let upstream = request.stream().await.map_err(to_status)?;
+                Box::pin(stream::unfold(Some(upstream), |state| async move {
+                    let mut upstream = state?;
+                    match upstream.next().await {
+                        Ok(Some(response)) => Some((Ok((&response).into()), Some(upstream))),
+                        Ok(None) => None,
+                        Err(err) => Some((Err(to_status(err)), None)),
+                    }
+                }))
+            }
+        };
+
+        Ok(tonic::Response::new(responses))
+    }
+}
+
+/// A client for a gRPC `ChatService`, such as an internal gateway in front of OpenAI.
+#[derive(Debug, Clone)]
+pub struct GrpcChatClient {
+    inner: ChatServiceClient<tonic::transport::Channel>,
+}
+
+impl GrpcChatClient {
+    /// Connect to the service at the endpoint, such as `http://gateway:50051`.
+    pub async fn connect(endpoint: &str) -> Result<Self, OpenAIError> {
+        let inner = ChatServiceClient::connect(endpoint.to_string())
+            .await
+            .map_err(|err| {
+                OpenAIError::Internal(InternalError::from_source_with_prefix(
+                    Box::new(err),
+                    format!("Unable to connect to {endpoint}"),
+                ))
+            })?;
+        Ok(Self { inner })
+    }
+
+    /// Create a chat completion.
+    pub async fn complete(
+        &mut self,
+        request: &ChatCompletionRequest,
+    ) -> Result<ChatCompletionResponse, OpenAIError> {
+        let response = self
+            .inner
+            .complete(proto::ChatCompletionRequest::from(request))
+            .await
+            .map_err(from_status)?;
+        ChatCompletionResponse::try_from(response.into_inner())
+    }
+
+    /// Create a chat completion, streaming back the responses.
+    pub async fn stream(
+        &mut self,
+        request: &ChatCompletionRequest,
+    ) -> Result<impl Stream<Item = Result<ChatCompletionResponse, OpenAIError>>, OpenAIError> {
+        let responses = self
+            .inner
+            .stream(proto::ChatCompletionRequest::from(request))
+            .await
+            .map_err(from_status)?
+            .into_inner();
+
+        Ok(responses.map(|response| {
+            response
+                .map_err(from_status)
+                .and_then(ChatCompletionResponse::try_from)
+        }))
+    }
+}
+
+fn to_status(err: OpenAIError) -> Status {
+    match &err {
+        OpenAIError::InvalidArgument(_) => Status::invalid_argument(err.to_string()),
+        OpenAIError::RateLimited { .. } => Status::resource_exhausted(err.to_string()),
+        OpenAIError::Internal(_)
+        | OpenAIError::InvalidState(_)
+        | OpenAIError::StreamInterrupted { .. } => Status::internal(err.to_string()),
+    }
+}
+
+fn from_status(status: Status) -> OpenAIError {
+    match status.code() {
+        Code::InvalidArgument => {
+            OpenAIError::InvalidArgument(InvalidArgumentError::new("request", status.message()))
+        }
+        _ => OpenAIError::Internal(InternalError::from_source(Box::new(status))),
+    }
+}
+
+fn invalid(argument: &str, err: impl ToString) -> OpenAIError {
+    OpenAIError::InvalidArgument(InvalidArgumentError::new(argument, err.to_string()))
+}
+
+impl From<&ChatCompletionRequest> for proto::ChatCompletionRequest {
+    fn from(request: &ChatCompletionRequest) -> Self {
+        Self {
+            model: request.model().to_string(),
+            messages: request.messages().iter().map(Into::into).collect(),
+            temperature: request.temperature(),
+            top_p: request.top_p(),
+            n: request.n().map(i32::from),
+            stop: request.stops().to_vec(),
+            max_tokens: request.max_tokens(),
+            presence_penalty: request.presence_penalty(),
+            frequency_penalty: request.frequency_penalty(),
+            logit_bias: request
+                .logit_bias()
+                .map(|bias| {
+                    bias.iter()
+                        .map(|(token, bias)| (token.clone(), i32::from(*bias)))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            logprobs: request.logprobs(),
+            top_logprobs: request.top_logprobs().map(i32::from),
+            user: request.user().map(str::to_string),
+            tools: request.tools().iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl TryFrom<proto::ChatCompletionRequest> for ChatCompletionRequest {
+    type Error = OpenAIError;
+
+    fn try_from(request: proto::ChatCompletionRequest) -> Result<Self, Self::Error> {
+        let messages = request
+            .messages
+            .into_iter()
+            .map(Message::from)
+            .collect::<Vec<_>>();
+        let mut converted = ChatCompletionRequest::new(&request.model, &messages);
+
+        if let Some(temperature) = request.temperature {
+            converted = converted.with_temperature(temperature);
+        }
+        if let Some(top_p) = request.top_p {
+            converted = converted.with_top_p(top_p);
+        }
+        if let Some(n) = request.n {
+            converted = converted.with_n(i8::try_from(n).map_err(|err| invalid("n", err))?);
+        }
+        if !request.stop.is_empty() {
+            converted = converted.with_stops(&request.stop);
+        }
+        if let Some(max_tokens) = request.max_tokens {
+            converted = converted.with_max_tokens(max_tokens);
+        }
+        if let Some(presence_penalty) = request.presence_penalty {
+            converted = converted.with_presence_penalty(presence_penalty);
+        }
+        if let Some(frequency_penalty) = request.frequency_penalty {
+            converted = converted.with_frequency_penalty(frequency_penalty);
+        }
+        if !request.logit_bias.is_empty() {
+            let logit_bias = request
+                .logit_bias
+                .into_iter()
+                .map(|(token, bias)| Ok((token, i8::try_from(bias)?)))
+                .collect::<Result<HashMap<_, _>, std::num::TryFromIntError>>()
+                .map_err(|err| invalid("logit_bias", err))?;
+            converted = converted.with_logit_bias(&logit_bias);
+        }
+        if let Some(logprobs) = request.logprobs {
+            converted = converted.with_logprobs(logprobs);
+        }
+        if let Some(top_logprobs) = request.top_logprobs {
+            converted = converted.with_top_logprobs(
+                i8::try_from(top_logprobs).map_err(|err| invalid("top_logprobs", err))?,
+            );
+        }
+        if let Some(user) = &request.user {
+            converted = converted.with_user(user);
+        }
+        if !request.tools.is_empty() {
+            let tools = request
+                .tools
+                .into_iter()
+                .map(Tool::try_from)
+                .collect::<Result<Vec<_>, _>>()?;
+            converted = converted.with_tools(&tools);
+        }
+
+        Ok(converted)
+    }
+}
+
+impl From<&Message> for proto::Message {
+    fn from(message: &Message) -> Self {
+        let (text, parts) = match message.content() {
+            MessageContent::Text(text) => (text.clone(), Vec::new()),
+            MessageContent::Parts(parts) => (String::new(), parts.iter().map(Into::into).collect()),
+        };
+
+        Self {
+            role: message.role().to_string(),
+            text,
+            parts,
+            tool_calls: message
+                .tool_calls()
+                .iter()
+                .map(|call| proto::ToolCall {
+                    id: call.id.clone(),
+                    r#type: call.kind.clone(),
+                    name: call.function.name.clone(),
+                    arguments: call.function.arguments.clone(),
+                })
+                .collect(),
+            tool_call_id: message.tool_call_id.clone(),
+        }
+    }
+}
+
+impl From<proto::Message> for Message {
+    fn from(message: proto::Message) -> Self {
+        let content = if message.parts.is_empty() {
+            MessageContent::Text(message.text)
+        } else {
+            MessageContent::Parts(message.parts.into_iter().filter_map(Into::into).collect())
+        };
+        let tool_calls = (!message.tool_calls.is_empty()).then(|| {
+            message
+                .tool_calls
+                .into_iter()
+                .map(|call| ToolCall {
+                    id: call.id,
+                    kind: call.r#type,
+                    function: FunctionCall {
+                        name: call.name,
+                        arguments: call.arguments,
+                    },
+                })
+                .collect()
+        });
+
+        Message {
+            role: intern::intern(&message.role),
+            content,
+            tool_calls,
+            tool_call_id: message.tool_call_id,
+            annotations: None,
+        }
+    }
+}
+
+impl From<&ContentPart> for proto::ContentPart {
+    fn from(part: &ContentPart) -> Self {
+        use proto::content_part::Part;
+
+        let part = match part {
+            ContentPart::Text { text } => Part::Text(text.clone()),
+            ContentPart::ImageUrl { image_url } => Part::ImageUrl(proto::ImageUrl {
+                url: image_url.url.clone(),
+                detail: image_url.detail.clone(),
+            }),
+            ContentPart::File { file } => Part::File(proto::FileInput {
+                file_id: file.file_id.clone(),
+                filename: file.filename.clone(),
+                file_data: file.file_data.clone(),
+            }),
+        };
+        Self { part: Some(part) }
+    }
+}
+
+/// Parts with no content set are dropped.
+impl From<proto::ContentPart> for Option<ContentPart> {
+    fn from(part: proto::ContentPart) -> Self {
+        use proto::content_part::Part;
+
+        Some(match part.part? {
+            Part::Text(text) => ContentPart::Text { text },
+            Part::ImageUrl(image_url) => ContentPart::ImageUrl {
+                image_url: ImageUrl {
+                    url: image_url.url,
+                    detail: image_url.detail,
+                },
+            },
+            Part::File(file) => ContentPart::File {
+                file: FileInput {
+                    file_id: file.file_id,
+                    filename: file.filename,
+                    file_data: file.file_data,
+                },
+            },
+        })
+    }
+}
+
+impl From<&Tool> for proto::Tool {
+    fn from(tool: &Tool) -> Self {
+        Self {
+            r#type: tool.kind.clone(),
+            name: tool.function.name.clone(),
+            description: tool.function.description.clone(),
+            parameters_json: tool
+                .function
+                .parameters
+                .as_ref()
+                .map(|parameters| parameters.to_string()),
+        }
+    }
+}
+
+impl TryFrom<proto::Tool> for Tool {
+    type Error = OpenAIError;
+
+    fn try_from(tool: proto::Tool) -> Result<Self, Self::Error> {
+        let parameters = tool
+            .parameters_json
+            .map(|json| serde_json::from_str(&json))
+            .transpose()
+            .map_err(|err| invalid("parameters_json", err))?;
+
+        Ok(Tool {
+            kind: tool.r#type,
+            function: crate::FunctionDefinition {
+                name: tool.name,
+                description: tool.description,
+                parameters,
+            },
+        })
+    }
+}
+
+impl From<&ChatCompletionResponse> for proto::ChatCompletionResponse {
+    fn from(response: &ChatCompletionResponse) -> Self {
+        Self {
+            id: response.id.clone(),
+            object: response.object.to_string(),
+            created: response.created,
+            model: response.model.to_string(),
+            choices: response
+                .choices
+                .iter()
+                .map(|choice| proto::ChatChoice {
+                    message: Some((&choice.message).into()),
+                    index: choice.index,
+                    finish_reason: choice.finish_reason.to_string(),
+                })
+                .collect(),
+            usage: Some(proto::ChatUsage {
+                prompt_tokens: response.usage.prompt_tokens,
+                completion_tokens: response.usage.completion_tokens,
+                total_tokens: response.usage.total_tokens,
+            }),
+        }
+    }
+}
+
+impl TryFrom<proto::ChatCompletionResponse> for ChatCompletionResponse {
+    type Error = OpenAIError;
+
+    fn try_from(response: proto::ChatCompletionResponse) -> Result<Self, Self::Error> {
+        let usage = response
+            .usage
+            .ok_or_else(|| invalid("usage", "Response is missing usage"))?;
+
+        Ok(ChatCompletionResponse {
+            id: response.id,
+            object: intern::intern(&response.object),
+            created: response.created,
+            model: intern::intern(&response.model),
+            choices: response
+                .choices
+                .into_iter()
+                .map(|choice| ChatChoice {
+                    message: choice.message.map(Message::from).unwrap_or_default(),
+                    index: choice.index,
+                    logprobs: None,
+                    finish_reason: intern::intern(&choice.finish_reason),
+                    content_filter_results: None,
+                })
+                .collect(),
+            usage: ChatUsage {
+                prompt_tokens: usage.prompt_tokens,
+                completion_tokens: usage.completion_tokens,
+                total_tokens: usage.total_tokens,
+            },
+            prompt_filter_results: None,
+            tags: RequestTags::default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    fn response(content: &str) -> ChatCompletionResponse {
+        serde_json::from_value(json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 1700000000,
+            "model": "gpt-4o",
+            "choices": [{
+                "message": {"role": "assistant", "content": content},
+                "index": 0,
+                "finish_reason": "stop"
+            }],
+            "usage": {"prompt_tokens": 1, "completion_tokens": 2, "total_tokens": 3}
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    // Verify that a request survives conversion to the proto type and back
+    fn test_request_round_trip() {
+        let request = ChatCompletionRequest::new(
+            "gpt-4o",
+            &[
+                Message::new("system", "Be brief"),
+                Message::with_parts(
+                    "user",
+                    &[
+                        ContentPart::text("What is this?"),
+                        ContentPart::image_url("https://example.com/cat.png"),
+                        ContentPart::file_id("file-1"),
+                    ],
+                ),
+                Message::tool_result("call_1", "42"),
+            ],
+        )
+        .with_temperature(0.5)
+        .with_n(2)
+        .with_stop("END")
+        .with_logit_bias(&HashMap::from([("50256".to_string(), -100)]))
+        .with_user("user-1")
+        .with_tools(&[Tool::function(
+            "lookup",
+            "Look something up",
+            json!({"type": "object"}),
+        )]);
+
+        let converted =
+            ChatCompletionRequest::try_from(proto::ChatCompletionRequest::from(&request)).unwrap();
+        assert_eq!(converted, request);
+    }
+
+    #[test]
+    // Verify that out of range values are rejected when converting a proto request
+    fn test_request_out_of_range() {
+        let request = proto::ChatCompletionRequest {
+            model: "gpt-4o".to_string(),
+            n: Some(1000),
+            ..Default::default()
+        };
+        assert!(ChatCompletionRequest::try_from(request).is_err());
+    }
+
+    #[test]
+    // Verify that a response survives conversion to the proto type and back
+    fn test_response_round_trip() {
+        let response = response("Hello");
+        let converted =
+            ChatCompletionResponse::try_from(proto::ChatCompletionResponse::from(&response))
+                .unwrap();
+        assert_eq!(converted, response);
+    }
+
+    #[tokio::test]
+    // Verify that the relay answers with its handler and maps errors to statuses
+    async fn test_chat_relay() {
+        let relay = ChatRelay::with_handler(|request: ChatCompletionRequest| async move {
+            match request.model() {
+                "gpt-4o" => Ok(response("Hi")),
+                _ => Err(invalid("model", "unknown model")),
+            }
+        });
+
+        let request = ChatCompletionRequest::new("gpt-4o", &[Message::new("user", "Hello")]);
+        let reply = relay
+            .complete(tonic::Request::new((&request).into()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(reply.choices[0].message.as_ref().unwrap().text, "Hi");
+
+        let mut responses = relay
+            .stream(tonic::Request::new((&request).into()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(&*responses.next().await.unwrap().unwrap().model, "gpt-4o");
+        assert!(responses.next().await.is_none());
+
+        let request = ChatCompletionRequest::new("gpt-0", &[Message::new("user", "Hello")]);
+        let status = relay
+            .complete(tonic::Request::new((&request).into()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+}
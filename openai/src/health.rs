@@ -0,0 +1,194 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing a health check of the API, for readiness probes of services which depend
+//! on it.
+
+use std::time::{Duration, Instant};
+
+use ryst_error::InvalidStateError;
+
+use crate::client::OpenAIClient;
+use crate::credentials::KeySource;
+use crate::error::OpenAIError;
+use crate::http::{self, RequestOptions, ResponseMetadata};
+use crate::{ChatCompletionRequest, Message};
+
+/// The result of a successful health check.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthReport {
+    /// The time from sending the request to receiving the response
+    pub latency: Duration,
+    /// The model which was called, or `None` if the models were listed
+    pub model: Option<String>,
+    /// Details of the HTTP response
+    pub metadata: ResponseMetadata,
+}
+
+/// Checks that the API is reachable and accepts the API key.
+///
+/// By default the models are listed, which uses no tokens. With `with_model`, a chat completion
+/// limited to one token is requested instead, which also checks that the model is available
+/// and warms up the connection to it.
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    model: Option<String>,
+    timeout: Duration,
+    key_source: Option<KeySource>,
+    base_url: Option<String>,
+    client: Option<OpenAIClient>,
+}
+
+impl HealthCheck {
+    /// Create a health check which lists the models, failing after 10 seconds.
+    pub fn new() -> Self {
+        Self {
+            model: None,
+            timeout: Duration::from_secs(10),
+            key_source: None,
+            base_url: None,
+            client: None,
+        }
+    }
+
+    /// Request a one token chat completion from the model instead of listing the models.
+    pub fn with_model(mut self, model: &str) -> Self {
+        self.model = Some(model.to_string());
+        self
+    }
+
+    /// How long to wait for the response before the check fails.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Where to read the API key from, see `ChatCompletionRequest::with_key_source`.
+    pub fn with_key_source(mut self, key_source: KeySource) -> Self {
+        self.key_source = Some(key_source);
+        self
+    }
+
+    /// Check the API at another URL, see `ChatCompletionRequest::with_base_url`.
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = Some(base_url.to_string());
+        self
+    }
+
+    /// Send the check through the client, see `ChatCompletionRequest::with_client`.
+    pub fn with_client(mut self, client: OpenAIClient) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Run the check, returning an error if the API could not be reached, rejected the request
+    /// or did not respond within the timeout.
+    pub async fn check(&self) -> Result<HealthReport, OpenAIError> {
+        let started = Instant::now();
+
+        let metadata = tokio::time::timeout(self.timeout, self.request())
+            .await
+            .map_err(|_| {
+                OpenAIError::InvalidState(InvalidStateError::with_message(format!(
+                    "Health check timed out after {}ms",
+                    self.timeout.as_millis()
+                )))
+            })??;
+
+        Ok(HealthReport {
+            latency: started.elapsed(),
+            model: self.model.clone(),
+            metadata,
+        })
+    }
+
+    async fn request(&self) -> Result<ResponseMetadata, OpenAIError> {
+        match &self.model {
+            Some(model) => {
+                let mut request =
+                    ChatCompletionRequest::new(model, &[Message::new("user", "Reply with OK.")])
+                        .with_max_tokens(1);
+                if let Some(key_source) = &self.key_source {
+                    request = request.with_key_source(key_source.clone());
+                }
+                if let Some(base_url) = &self.base_url {
+                    request = request.with_base_url(base_url);
+                }
+                if let Some(client) = &self.client {
+                    request = request.with_client(client.clone());
+                }
+                request
+                    .submit_with_metadata()
+                    .await
+                    .map(|(_, metadata)| metadata)
+            }
+            None => {
+                let options = RequestOptions {
+                    key_source: self.key_source.clone(),
+                    base_url: self.base_url.clone(),
+                    client: self.client.clone(),
+                    ..Default::default()
+                };
+                let response = http::get("/v1/models", &options).await?;
+                Ok(ResponseMetadata::from_response(&response, &[]))
+            }
+        }
+    }
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The following tests require that OPENAI_API_KEY (optionally OPENAI_API_ORG) is set.
+#[cfg(feature = "integration")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    // Verify that listing the models reports a successful status and latency
+    async fn test_health_check_models() {
+        let report = HealthCheck::new().check().await.unwrap();
+
+        assert_eq!(report.metadata.status, 200);
+        assert!(report.latency > Duration::ZERO);
+    }
+
+    #[tokio::test]
+    // Verify that a one token completion from the model reports the model
+    async fn test_health_check_model() {
+        let report = HealthCheck::new()
+            .with_model("gpt-4o-mini")
+            .check()
+            .await
+            .unwrap();
+
+        assert_eq!(report.model.as_deref(), Some("gpt-4o-mini"));
+    }
+
+    #[tokio::test]
+    // Verify that the check can be sent through a client
+    async fn test_health_check_client() {
+        let report = HealthCheck::new()
+            .with_client(OpenAIClient::default())
+            .check()
+            .await
+            .unwrap();
+
+        assert_eq!(report.metadata.status, 200);
+    }
+}
@@ -0,0 +1,879 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing the HTTP plumbing shared by the API requests.
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, Request, Response, StatusCode};
+use ryst_error::{InternalError, InvalidArgumentError, InvalidStateError};
+use serde::Serialize;
+
+use crate::client::{self, OpenAIClient};
+use crate::credentials::{self, KeySource};
+use crate::deadline;
+use crate::error::{ApiError, OpenAIError};
+use crate::latency::SlowResponsePolicy;
+use crate::rate_limit::{self, RateLimiter};
+use crate::redact::RedactedHeaders;
+use crate::retry::{self, RateLimitRetry, RetryEvent, SharedRetryObserver};
+use crate::tags::RequestTags;
+use crate::trace::{ExchangeTrace, TraceEvent};
+use crate::OPEN_AI_URL;
+
+/// The `User-Agent` sent unless the application sets its own.
+pub(crate) const USER_AGENT: &str = concat!("ryst-openai/", env!("CARGO_PKG_VERSION"));
+
+type HookFn = dyn Fn(&mut Request) -> Result<(), OpenAIError> + Send + Sync;
+
+/// A callback invoked with the final HTTP request immediately before it is sent.
+///
+/// The request has its body and all headers, including authorization, already set, so the hook
+/// can compute signatures over the body and add headers such as timestamps or HMACs required by
+/// a gateway. Returning an error aborts the request.
+#[derive(Clone)]
+pub struct PreSendHook(Arc<HookFn>);
+
+impl PreSendHook {
+    /// Create a new `PreSendHook` from a callback.
+    pub fn new<F>(hook: F) -> Self
+    where
+        F: Fn(&mut Request) -> Result<(), OpenAIError> + Send + Sync + 'static,
+    {
+        Self(Arc::new(hook))
+    }
+}
+
+// Hooks are equal when they share the same callback
+impl PartialEq for PreSendHook {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for PreSendHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("PreSendHook")
+    }
+}
+
+/// Details of the HTTP response a body was parsed from.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ResponseMetadata {
+    /// The HTTP status code
+    pub status: u16,
+    /// The time OpenAI spent processing the request, from `openai-processing-ms`
+    pub processing_ms: Option<u64>,
+    /// The API version which served the request, from `openai-version`
+    pub openai_version: Option<String>,
+    /// The ID OpenAI assigned the request, from `x-request-id`, to quote when contacting support
+    pub request_id: Option<String>,
+    /// The headers requested with `with_captured_header`, keyed by lowercase name. Headers missing
+    /// from the response or which are not valid UTF-8 are left out.
+    pub headers: HashMap<String, String>,
+    /// The rate limits reported by the `x-ratelimit-*` headers
+    pub rate_limit: RateLimitInfo,
+}
+
+/// The state of the account's rate limits, as reported with each response.
+///
+/// Fields are `None` when the header is missing or can't be parsed, such as from servers other
+/// than OpenAI.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RateLimitInfo {
+    /// The requests allowed per window, from `x-ratelimit-limit-requests`
+    pub limit_requests: Option<u64>,
+    /// The tokens allowed per window, from `x-ratelimit-limit-tokens`
+    pub limit_tokens: Option<u64>,
+    /// The requests left in the window, from `x-ratelimit-remaining-requests`
+    pub remaining_requests: Option<u64>,
+    /// The tokens left in the window, from `x-ratelimit-remaining-tokens`
+    pub remaining_tokens: Option<u64>,
+    /// The time until the request limit resets, from `x-ratelimit-reset-requests`
+    pub reset_requests: Option<Duration>,
+    /// The time until the token limit resets, from `x-ratelimit-reset-tokens`
+    pub reset_tokens: Option<Duration>,
+}
+
+impl ResponseMetadata {
+    pub(crate) fn from_response(response: &Response, captured_headers: &[String]) -> Self {
+        Self::from_headers(response.status(), response.headers(), captured_headers)
+    }
+
+    fn from_headers(status: StatusCode, headers: &HeaderMap, captured_headers: &[String]) -> Self {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let count = |name: &str| header(name).and_then(|value| value.trim().parse().ok());
+
+        Self {
+            status: status.as_u16(),
+            processing_ms: header("openai-processing-ms").and_then(|value| value.parse().ok()),
+            openai_version: header("openai-version"),
+            request_id: header("x-request-id"),
+            headers: captured_headers
+                .iter()
+                .filter_map(|name| {
+                    let name = name.to_ascii_lowercase();
+                    header(&name).map(|value| (name, value))
+                })
+                .collect(),
+            rate_limit: RateLimitInfo {
+                limit_requests: count("x-ratelimit-limit-requests"),
+                limit_tokens: count("x-ratelimit-limit-tokens"),
+                remaining_requests: count("x-ratelimit-remaining-requests"),
+                remaining_tokens: count("x-ratelimit-remaining-tokens"),
+                reset_requests: header("x-ratelimit-reset-requests")
+                    .and_then(|value| retry::parse_reset(value.trim())),
+                reset_tokens: header("x-ratelimit-reset-tokens")
+                    .and_then(|value| retry::parse_reset(value.trim())),
+            },
+        }
+    }
+}
+
+/// Settings which control how a request is sent, rather than being part of its body.
+#[derive(Clone, PartialEq, Default)]
+pub(crate) struct RequestOptions {
+    /// The client the request is sent through, providing credentials the request does not set
+    pub client: Option<OpenAIClient>,
+    pub key_source: Option<KeySource>,
+    /// Sent to instead of `OPEN_AI_URL`, such as a gateway or an OpenAI-compatible server
+    pub base_url: Option<String>,
+    pub pre_send_hook: Option<PreSendHook>,
+    /// Query parameters appended to the URL, such as Azure's `api-version`
+    pub query: Vec<(String, String)>,
+    /// Response headers to copy into `ResponseMetadata::headers`
+    pub captured_headers: Vec<String>,
+    pub trace: Option<ExchangeTrace>,
+    pub retry_observer: Option<SharedRetryObserver>,
+    /// Retries requests rejected with a 429
+    pub rate_limit_retry: Option<RateLimitRetry>,
+    /// Waits before sending to stay within the requests and tokens per minute
+    pub rate_limiter: Option<RateLimiter>,
+    /// Headers sent after the client's default headers, replacing any with the same name
+    pub headers: Vec<(String, String)>,
+    /// Sent instead of the default `USER_AGENT`
+    pub user_agent: Option<String>,
+    /// Whether to send the `X-Ryst-*` headers describing the client
+    pub client_metadata: bool,
+    /// Recorded in the trace and tracing span, but never sent
+    pub tags: RequestTags,
+    /// Switches streams to a faster model when their response is slow
+    pub slow_response_policy: Option<SlowResponsePolicy>,
+    /// Sent as `Last-Event-ID` when reconnecting to a stream, to continue after that event
+    pub last_event_id: Option<String>,
+}
+
+impl fmt::Debug for RequestOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RequestOptions")
+            .field("client", &self.client)
+            .field("key_source", &self.key_source)
+            .field("base_url", &self.base_url)
+            .field("pre_send_hook", &self.pre_send_hook)
+            .field("query", &self.query)
+            .field("captured_headers", &self.captured_headers)
+            .field("trace", &self.trace)
+            .field("retry_observer", &self.retry_observer)
+            .field("rate_limit_retry", &self.rate_limit_retry)
+            .field("rate_limiter", &self.rate_limiter)
+            .field("headers", &RedactedHeaders(&self.headers))
+            .field("user_agent", &self.user_agent)
+            .field("client_metadata", &self.client_metadata)
+            .field("tags", &self.tags)
+            .field("slow_response_policy", &self.slow_response_policy)
+            .field("last_event_id", &self.last_event_id)
+            .finish()
+    }
+}
+
+/// Post a JSON body to the given API path, returning the response if it has a 2XX status.
+///
+/// If the key source is a `SharedKey` with a refresh callback or an `ApiKeyProvider` which can
+/// refresh its key and the API rejects the key, the key is refreshed and the request is sent
+/// once more.
+pub(crate) async fn post<T: Serialize + ?Sized>(
+    path: &str,
+    body: &T,
+    options: &RequestOptions,
+) -> Result<Response, OpenAIError> {
+    traced(path, Some(body), options).await
+}
+
+/// Get the given API path, returning the response if it has a 2XX status.
+pub(crate) async fn get(path: &str, options: &RequestOptions) -> Result<Response, OpenAIError> {
+    traced::<()>(path, None, options).await
+}
+
+/// Send a request, recording it in the trace if one is set and, with the `tracing` feature,
+/// within a span carrying the request's tags.
+async fn traced<T: Serialize + ?Sized>(
+    path: &str,
+    body: Option<&T>,
+    options: &RequestOptions,
+) -> Result<Response, OpenAIError> {
+    let record = |event| {
+        if let Some(trace) = &options.trace {
+            trace.record(event);
+        }
+    };
+    if options.trace.is_some() {
+        record(TraceEvent::Request {
+            path: path.to_string(),
+            body: serde_json::to_value(body).unwrap_or_default(),
+            tags: options.tags.clone(),
+        });
+    }
+
+    let sent = send(path, body, options, record);
+    #[cfg(feature = "tracing")]
+    let sent = tracing::Instrument::instrument(
+        sent,
+        tracing::info_span!(
+            target: "ryst_openai::request",
+            "request",
+            path,
+            template_id = options.tags.template_id.as_deref(),
+            template_version = options.tags.template_version.as_deref(),
+            experiment = options.tags.experiment.as_deref(),
+        ),
+    );
+    let result = sent.await;
+    if let (Some(trace), Err(err)) = (&options.trace, &result) {
+        trace.record(TraceEvent::Error {
+            message: err.to_string(),
+        });
+    }
+    result
+}
+
+async fn send<T, F>(
+    path: &str,
+    body: Option<&T>,
+    options: &RequestOptions,
+    record: F,
+) -> Result<Response, OpenAIError>
+where
+    T: Serialize + ?Sized,
+    F: Fn(TraceEvent),
+{
+    let client = request_client(options).http();
+    let mut refreshed = false;
+    let mut rate_limit_retries = 0;
+    let mut attempt = 1;
+
+    loop {
+        if let Some(limiter) = rate_limiter(options) {
+            limiter.acquire(rate_limit::estimate_tokens(body)).await?;
+        }
+
+        let remaining = deadline::remaining();
+        if remaining == Some(Duration::ZERO) {
+            return Err(deadline::exceeded());
+        }
+
+        let mut request = build_request(client, path, body, options).await?;
+        if let Some(remaining) = remaining {
+            let timeout = request
+                .timeout()
+                .map_or(remaining, |timeout| remaining.min(*timeout));
+            *request.timeout_mut() = Some(timeout);
+        }
+
+        let response = client.execute(request).await.map_err(|err| {
+            if err.is_timeout() && deadline::remaining() == Some(Duration::ZERO) {
+                deadline::exceeded()
+            } else {
+                OpenAIError::Internal(InternalError::from_source(Box::new(err)))
+            }
+        })?;
+
+        // Check if the status is a 2XX code.
+        let status = response.status();
+        let request_id = response
+            .headers()
+            .get("x-request-id")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        record(TraceEvent::Response {
+            status: status.as_u16(),
+            request_id: request_id.clone(),
+        });
+        if status.is_success() {
+            return Ok(response);
+        }
+
+        if status == StatusCode::UNAUTHORIZED && !refreshed {
+            if let Some(source) = key_source(options) {
+                if source.refresh().await? {
+                    refreshed = true;
+                    let reason = "API key was rejected and has been refreshed".to_string();
+                    record(TraceEvent::Retry {
+                        reason: reason.clone(),
+                    });
+                    retry::report(
+                        options.retry_observer.as_ref(),
+                        RetryEvent {
+                            path: path.to_string(),
+                            attempt: attempt + 1,
+                            delay: Duration::ZERO,
+                            reason,
+                            status: Some(status.as_u16()),
+                            retry_after: None,
+                        },
+                    );
+                    attempt += 1;
+                    continue;
+                }
+            }
+        }
+
+        let retry_after = retry::retry_after(response.headers(), SystemTime::now());
+        let text = response.text().await.map_err(|err| {
+            OpenAIError::InvalidState(InvalidStateError::with_message(err.to_string()))
+        })?;
+
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            let policy = rate_limit_retry(options);
+            // Don't wait to retry if the deadline would pass first
+            let delay = policy
+                .and_then(|policy| policy.delay(rate_limit_retries, retry_after, &text))
+                .filter(|delay| deadline::remaining().is_none_or(|remaining| *delay < remaining));
+            if let (Some(policy), Some(delay)) = (policy, delay) {
+                let reason = "Rate limited".to_string();
+                record(TraceEvent::Retry {
+                    reason: reason.clone(),
+                });
+                retry::report(
+                    options.retry_observer.as_ref(),
+                    RetryEvent {
+                        path: path.to_string(),
+                        attempt: attempt + 1,
+                        delay,
+                        reason,
+                        status: Some(status.as_u16()),
+                        retry_after,
+                    },
+                );
+                policy.sleep(delay).await;
+                rate_limit_retries += 1;
+                attempt += 1;
+                continue;
+            }
+
+            return Err(OpenAIError::RateLimited {
+                message: text,
+                retry_after,
+                request_id,
+            });
+        }
+
+        return Err(status_error(status, text, request_id));
+    }
+}
+
+/// The error for a response with an unsuccessful status other than a 429, keeping the response
+/// as its source.
+fn status_error(status: StatusCode, text: String, request_id: Option<String>) -> OpenAIError {
+    let api_error = ApiError {
+        status: status.as_u16(),
+        message: text.clone(),
+        request_id,
+    };
+    if status.is_client_error() {
+        OpenAIError::InvalidArgument(
+            InvalidArgumentError::new("request", text).with_source(Box::new(api_error)),
+        )
+    } else {
+        OpenAIError::Internal(InternalError::from_source(Box::new(api_error)))
+    }
+}
+
+/// Build the final request, running the pre-send hook if one is set.
+///
+/// Requests with a body are sent as a POST, and requests without as a GET.
+async fn build_request<T: Serialize + ?Sized>(
+    client: &Client,
+    path: &str,
+    body: Option<&T>,
+    options: &RequestOptions,
+) -> Result<Request, OpenAIError> {
+    let api_key = credentials::api_key(key_source(options)).await?;
+
+    let url = format!("{}{path}", base_url(options).trim_end_matches('/'));
+    let mut builder = match body {
+        Some(body) => client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .json(body),
+        None => client.get(url),
+    }
+    .query(&query(options))
+    .header("Authorization", format!("Bearer {api_key}"))
+    .header(
+        "User-Agent",
+        options.user_agent.as_deref().unwrap_or(USER_AGENT),
+    );
+
+    if let Some(last_event_id) = &options.last_event_id {
+        builder = builder.header("Last-Event-ID", last_event_id);
+    }
+
+    if options.client_metadata {
+        for (name, value) in client_metadata() {
+            builder = builder.header(name, value);
+        }
+    }
+
+    let org = match request_client(options).org() {
+        Some(org) => Some(org.to_string()),
+        None => env::var("OPENAI_API_ORG").ok(),
+    };
+    if let Some(org) = org {
+        builder = builder.header("OpenAI-Organization", org)
+    };
+
+    let mut request = builder.build().map_err(|err| {
+        OpenAIError::Internal(InternalError::from_source_with_prefix(
+            Box::new(err),
+            "Unable to build request",
+        ))
+    })?;
+
+    let client_headers = request_client(options).default_headers();
+    for (name, value) in client_headers.iter().chain(&options.headers) {
+        let invalid = |err: &dyn fmt::Display| {
+            OpenAIError::InvalidArgument(InvalidArgumentError::new(
+                "header",
+                format!("Invalid header {name}: {err}"),
+            ))
+        };
+        let name = HeaderName::try_from(name).map_err(|err| invalid(&err))?;
+        let value = HeaderValue::try_from(value).map_err(|err| invalid(&err))?;
+        request.headers_mut().insert(name, value);
+    }
+
+    if let Some(PreSendHook(hook)) = &options.pre_send_hook {
+        hook(&mut request)?;
+    }
+
+    Ok(request)
+}
+
+/// The client the request is sent through, or else the global client, so requests share one
+/// connection pool and reuse TLS sessions instead of connecting afresh every time.
+fn request_client(options: &RequestOptions) -> &OpenAIClient {
+    options.client.as_ref().unwrap_or_else(|| client::global())
+}
+
+/// The query parameters of the client the request is sent through, followed by the request's,
+/// which replace any of the client's with the same key.
+pub(crate) fn query(options: &RequestOptions) -> Vec<(&str, &str)> {
+    request_client(options)
+        .default_query()
+        .iter()
+        .filter(|(key, _)| !options.query.iter().any(|(set, _)| set == key))
+        .chain(&options.query)
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect()
+}
+
+/// The key source set on the request, or else on the client it is sent through.
+fn key_source(options: &RequestOptions) -> Option<&KeySource> {
+    options
+        .key_source
+        .as_ref()
+        .or_else(|| request_client(options).key_source())
+}
+
+/// The rate limit retry policy set on the request, or else on the client it is sent through.
+fn rate_limit_retry(options: &RequestOptions) -> Option<&RateLimitRetry> {
+    options
+        .rate_limit_retry
+        .as_ref()
+        .or_else(|| request_client(options).rate_limit_retry())
+}
+
+/// The rate limiter set on the request, or else on the client it is sent through.
+fn rate_limiter(options: &RequestOptions) -> Option<&RateLimiter> {
+    options
+        .rate_limiter
+        .as_ref()
+        .or_else(|| request_client(options).rate_limiter())
+}
+
+/// The base URL set on the request, or else on the client it is sent through.
+pub(crate) fn base_url(options: &RequestOptions) -> &str {
+    options
+        .base_url
+        .as_deref()
+        .or_else(|| request_client(options).base_url())
+        .unwrap_or(OPEN_AI_URL)
+}
+
+/// The headers describing the client, so providers and gateways can attribute traffic.
+fn client_metadata() -> [(&'static str, &'static str); 4] {
+    [
+        ("X-Ryst-Lang", "rust"),
+        ("X-Ryst-Package-Version", env!("CARGO_PKG_VERSION")),
+        ("X-Ryst-OS", env::consts::OS),
+        ("X-Ryst-Arch", env::consts::ARCH),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    // Verify that the pre-send hook sees the final body and headers and can add headers
+    async fn test_build_request_pre_send_hook() {
+        let options = RequestOptions {
+            key_source: Some(KeySource::Static("sk-test".to_string())),
+            pre_send_hook: Some(PreSendHook::new(|request| {
+                let body = request.body().and_then(|body| body.as_bytes()).unwrap();
+                let auth = request.headers()["Authorization"].clone();
+                let signature = format!("{}:{}", body.len(), auth.to_str().unwrap());
+                request
+                    .headers_mut()
+                    .insert("X-Signature", HeaderValue::from_str(&signature).unwrap());
+                Ok(())
+            })),
+            ..Default::default()
+        };
+
+        let request = build_request(&Client::new(), "/v1/test", Some(&[1, 2, 3]), &options)
+            .await
+            .unwrap();
+
+        assert_eq!(request.url().as_str(), format!("{OPEN_AI_URL}/v1/test"));
+        assert_eq!(request.headers()["X-Signature"], "7:Bearer sk-test");
+    }
+
+    #[tokio::test]
+    // Verify that an error returned by the pre-send hook aborts the request
+    async fn test_build_request_pre_send_hook_error() {
+        let options = RequestOptions {
+            key_source: Some(KeySource::Static("sk-test".to_string())),
+            pre_send_hook: Some(PreSendHook::new(|_| {
+                Err(OpenAIError::InvalidState(InvalidStateError::with_message(
+                    "signing key unavailable".to_string(),
+                )))
+            })),
+            ..Default::default()
+        };
+
+        assert!(
+            build_request(&Client::new(), "/v1/test", Some("body"), &options)
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    // Verify that the OpenAI headers and the captured headers are read into the metadata
+    fn test_response_metadata() {
+        let mut headers = HeaderMap::new();
+        headers.insert("openai-processing-ms", HeaderValue::from_static("412"));
+        headers.insert("openai-version", HeaderValue::from_static("2020-10-01"));
+        headers.insert("x-request-id", HeaderValue::from_static("req_123"));
+        headers.insert("x-trace-id", HeaderValue::from_static("abc123"));
+        headers.insert("x-other", HeaderValue::from_static("ignored"));
+
+        let metadata = ResponseMetadata::from_headers(
+            StatusCode::OK,
+            &headers,
+            &["X-Trace-Id".to_string(), "x-missing".to_string()],
+        );
+
+        assert_eq!(
+            metadata,
+            ResponseMetadata {
+                status: 200,
+                processing_ms: Some(412),
+                openai_version: Some("2020-10-01".to_string()),
+                request_id: Some("req_123".to_string()),
+                headers: HashMap::from([("x-trace-id".to_string(), "abc123".to_string())]),
+                rate_limit: RateLimitInfo::default(),
+            }
+        );
+    }
+
+    #[test]
+    // Verify that the rate limit headers are parsed, leaving out missing or malformed values
+    fn test_response_metadata_rate_limit() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-ratelimit-limit-requests",
+            HeaderValue::from_static("5000"),
+        );
+        headers.insert(
+            "x-ratelimit-limit-tokens",
+            HeaderValue::from_static("160000"),
+        );
+        headers.insert(
+            "x-ratelimit-remaining-requests",
+            HeaderValue::from_static("4999"),
+        );
+        headers.insert(
+            "x-ratelimit-remaining-tokens",
+            HeaderValue::from_static("many"),
+        );
+        headers.insert(
+            "x-ratelimit-reset-requests",
+            HeaderValue::from_static("12ms"),
+        );
+        headers.insert("x-ratelimit-reset-tokens", HeaderValue::from_static("6m0s"));
+
+        let metadata = ResponseMetadata::from_headers(StatusCode::OK, &headers, &[]);
+        let mut overflowing = headers.clone();
+        overflowing.insert(
+            "x-ratelimit-reset-tokens",
+            HeaderValue::from_static("10000000000000000000s10000000000000000000s"),
+        );
+        let overflowing = ResponseMetadata::from_headers(StatusCode::OK, &overflowing, &[]);
+        assert_eq!(overflowing.rate_limit.reset_tokens, None);
+
+        assert_eq!(
+            metadata.rate_limit,
+            RateLimitInfo {
+                limit_requests: Some(5000),
+                limit_tokens: Some(160000),
+                remaining_requests: Some(4999),
+                remaining_tokens: None,
+                reset_requests: Some(Duration::from_millis(12)),
+                reset_tokens: Some(Duration::from_secs(360)),
+            }
+        );
+    }
+
+    #[test]
+    // Verify that the request ID of an error response can be read back from the error
+    fn test_status_error_request_id() {
+        let invalid = status_error(
+            StatusCode::BAD_REQUEST,
+            "Bad model".to_string(),
+            Some("req_400".to_string()),
+        );
+        assert!(matches!(invalid, OpenAIError::InvalidArgument(_)));
+        assert_eq!(invalid.to_string(), "Bad model (request)");
+        assert_eq!(invalid.request_id(), Some("req_400"));
+
+        let internal = status_error(
+            StatusCode::BAD_GATEWAY,
+            "Upstream failed".to_string(),
+            Some("req_502".to_string()),
+        );
+        assert!(matches!(internal, OpenAIError::Internal(_)));
+        assert_eq!(internal.to_string(), "Upstream failed");
+        assert_eq!(internal.request_id(), Some("req_502"));
+
+        let interrupted = OpenAIError::StreamInterrupted {
+            partial: String::new(),
+            source: Box::new(internal),
+        };
+        assert_eq!(interrupted.request_id(), Some("req_502"));
+        assert_eq!(
+            status_error(StatusCode::NOT_FOUND, String::new(), None).request_id(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    // Verify that a request without a body is sent as a GET
+    async fn test_build_request_get() {
+        let options = RequestOptions {
+            key_source: Some(KeySource::Static("sk-test".to_string())),
+            ..Default::default()
+        };
+
+        let request = build_request::<()>(&Client::new(), "/v1/models", None, &options)
+            .await
+            .unwrap();
+
+        assert_eq!(request.method(), reqwest::Method::GET);
+        assert!(request.body().is_none());
+        assert_eq!(request.headers()["Authorization"], "Bearer sk-test");
+    }
+
+    #[tokio::test]
+    // Verify that the default user agent can be replaced and client metadata sent on request
+    async fn test_build_request_user_agent() {
+        let mut options = RequestOptions {
+            key_source: Some(KeySource::Static("sk-test".to_string())),
+            ..Default::default()
+        };
+
+        let request = build_request(&Client::new(), "/v1/test", Some("body"), &options)
+            .await
+            .unwrap();
+        assert_eq!(request.headers()["User-Agent"], USER_AGENT);
+        assert!(request.headers().get("X-Ryst-Lang").is_none());
+
+        options.user_agent = Some("my-app/1.0".to_string());
+        options.client_metadata = true;
+        let request = build_request(&Client::new(), "/v1/test", Some("body"), &options)
+            .await
+            .unwrap();
+        assert_eq!(request.headers()["User-Agent"], "my-app/1.0");
+        assert_eq!(request.headers()["X-Ryst-Lang"], "rust");
+        assert_eq!(
+            request.headers()["X-Ryst-Package-Version"],
+            env!("CARGO_PKG_VERSION")
+        );
+        assert_eq!(request.headers()["X-Ryst-OS"], env::consts::OS);
+        assert!(request.headers().get("Last-Event-ID").is_none());
+
+        options.last_event_id = Some("42".to_string());
+        let request = build_request(&Client::new(), "/v1/test", Some("body"), &options)
+            .await
+            .unwrap();
+        assert_eq!(request.headers()["Last-Event-ID"], "42");
+    }
+
+    #[tokio::test]
+    // Verify that query parameters are appended to the URL
+    async fn test_build_request_query() {
+        let options = RequestOptions {
+            key_source: Some(KeySource::Static("sk-test".to_string())),
+            query: vec![
+                ("api-version".to_string(), "2024-10-21".to_string()),
+                ("route".to_string(), "a b".to_string()),
+            ],
+            ..Default::default()
+        };
+
+        let request = build_request(&Client::new(), "/v1/test", Some("body"), &options)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            request.url().as_str(),
+            format!("{OPEN_AI_URL}/v1/test?api-version=2024-10-21&route=a+b")
+        );
+    }
+
+    #[tokio::test]
+    // Verify that the client's query parameters are sent, with the request's replacing them
+    async fn test_build_request_default_query() {
+        let client = OpenAIClient::new("sk-test")
+            .with_default_query("api-version", "2024-06-01")
+            .with_default_query("deployment", "east");
+        let options = RequestOptions {
+            client: Some(client),
+            query: vec![("api-version".to_string(), "2024-10-21".to_string())],
+            ..Default::default()
+        };
+
+        let request = build_request(&Client::new(), "/v1/test", Some("body"), &options)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            request.url().as_str(),
+            format!("{OPEN_AI_URL}/v1/test?deployment=east&api-version=2024-10-21")
+        );
+    }
+
+    #[tokio::test]
+    // Verify that the client's key and org are sent unless the request sets its own key
+    async fn test_build_request_client() {
+        let mut options = RequestOptions {
+            client: Some(OpenAIClient::new("sk-client").with_org("org-test")),
+            ..Default::default()
+        };
+
+        let request = build_request(&Client::new(), "/v1/test", Some("body"), &options)
+            .await
+            .unwrap();
+        assert_eq!(request.headers()["Authorization"], "Bearer sk-client");
+        assert_eq!(request.headers()["OpenAI-Organization"], "org-test");
+
+        options.key_source = Some(KeySource::Static("sk-request".to_string()));
+        let request = build_request(&Client::new(), "/v1/test", Some("body"), &options)
+            .await
+            .unwrap();
+        assert_eq!(request.headers()["Authorization"], "Bearer sk-request");
+    }
+
+    #[tokio::test]
+    // Verify that custom headers are sent, with the request's replacing the client's and built-ins,
+    // and that their values are kept out of Debug
+    async fn test_build_request_headers() {
+        let client = OpenAIClient::new("sk-test")
+            .with_default_header("OpenAI-Beta", "assistants=v1")
+            .with_default_header("X-Gateway-Token", "client");
+        let mut options = RequestOptions {
+            client: Some(client),
+            headers: vec![
+                ("X-Gateway-Token".to_string(), "request".to_string()),
+                ("User-Agent".to_string(), "custom".to_string()),
+            ],
+            ..Default::default()
+        };
+
+        let request = build_request(&Client::new(), "/v1/test", Some("body"), &options)
+            .await
+            .unwrap();
+        assert_eq!(request.headers()["OpenAI-Beta"], "assistants=v1");
+        assert_eq!(request.headers()["X-Gateway-Token"], "request");
+        assert_eq!(request.headers()["User-Agent"], "custom");
+        assert_eq!(
+            request.headers().get_all("X-Gateway-Token").iter().count(),
+            1
+        );
+
+        let debug = format!("{options:?}");
+        assert!(debug.contains("X-Gateway-Token"));
+        assert!(!debug.contains("assistants=v1") && !debug.contains("custom"));
+
+        options.headers = vec![("Bad Name".to_string(), "value".to_string())];
+        let err = build_request(&Client::new(), "/v1/test", Some("body"), &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, OpenAIError::InvalidArgument(_)));
+    }
+
+    #[tokio::test]
+    // Verify that the base URL can be replaced by the client or the request
+    async fn test_build_request_base_url() {
+        let mut options = RequestOptions {
+            client: Some(OpenAIClient::new("sk-test").with_base_url("http://localhost:4000/")),
+            ..Default::default()
+        };
+
+        let request = build_request(&Client::new(), "/v1/test", Some("body"), &options)
+            .await
+            .unwrap();
+        assert_eq!(request.url().as_str(), "http://localhost:4000/v1/test");
+
+        options.base_url = Some("https://gateway.example.com/openai".to_string());
+        let request = build_request(&Client::new(), "/v1/test", Some("body"), &options)
+            .await
+            .unwrap();
+        assert_eq!(
+            request.url().as_str(),
+            "https://gateway.example.com/openai/v1/test"
+        );
+    }
+}
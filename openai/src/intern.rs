@@ -0,0 +1,107 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing the interning of strings which repeat across responses, such as model
+//! names, roles and finish reasons.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use serde::de::{Deserializer, Error, Visitor};
+
+/// Strings longer than this are not worth interning and are allocated as usual.
+const MAX_INTERNED_LEN: usize = 64;
+
+/// The most strings kept in the table, so unexpected values can't grow it without bound.
+const MAX_INTERNED: usize = 1024;
+
+fn table() -> &'static Mutex<HashSet<Arc<str>>> {
+    static TABLE: OnceLock<Mutex<HashSet<Arc<str>>>> = OnceLock::new();
+    TABLE.get_or_init(Default::default)
+}
+
+/// Returns a shared copy of the string, allocating it only the first time it is seen.
+pub(crate) fn intern(value: &str) -> Arc<str> {
+    if value.len() > MAX_INTERNED_LEN {
+        return Arc::from(value);
+    }
+
+    let mut table = match table().lock() {
+        Ok(table) => table,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    if let Some(interned) = table.get(value) {
+        return Arc::clone(interned);
+    }
+
+    let interned: Arc<str> = Arc::from(value);
+    if table.len() < MAX_INTERNED {
+        table.insert(Arc::clone(&interned));
+    }
+    interned
+}
+
+/// Deserialize a string into its interned copy, without allocating it when already interned.
+pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<Arc<str>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct InternVisitor;
+
+    impl Visitor<'_> for InternVisitor {
+        type Value = Arc<str>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a string")
+        }
+
+        fn visit_str<E: Error>(self, value: &str) -> Result<Self::Value, E> {
+            Ok(intern(value))
+        }
+    }
+
+    deserializer.deserialize_str(InternVisitor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Verify that the same string is shared, and long strings are not kept in the table
+    fn test_intern() {
+        let first = intern("assistant");
+        let second = intern("assistant");
+        assert!(Arc::ptr_eq(&first, &second));
+
+        let long = "x".repeat(MAX_INTERNED_LEN + 1);
+        assert!(!Arc::ptr_eq(&intern(&long), &intern(&long)));
+    }
+
+    #[test]
+    // Verify that deserialized strings are interned
+    fn test_deserialize() {
+        #[derive(serde::Deserialize)]
+        struct Choice {
+            #[serde(deserialize_with = "deserialize")]
+            finish_reason: Arc<str>,
+        }
+
+        let first: Choice = serde_json::from_str(r#"{"finish_reason":"stop"}"#).unwrap();
+        let second: Choice = serde_json::from_str(r#"{"finish_reason":"stop"}"#).unwrap();
+        assert_eq!(&*second.finish_reason, "stop");
+        assert!(Arc::ptr_eq(&first.finish_reason, &second.finish_reason));
+    }
+}
@@ -0,0 +1,615 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing a `Judge`, which scores replies against a rubric by asking a model, for
+//! evaluations and for picking the best of several choices.
+//!
+//! The judge is asked for JSON matching a schema, with its reasoning before its verdict, and is
+//! told to ignore the length of replies. Pairwise comparisons show the two replies in a random
+//! order, and can be asked in both orders to cancel out a preference for either position.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), ryst_openai::OpenAIError> {
+//! use ryst_openai::judge::Judge;
+//!
+//! let judge = Judge::new("gpt-4o", "The reply answers the question correctly and concisely.")
+//!     .with_scale(1, 10);
+//! let score = judge.score("What is 2 + 2?", "4").await?;
+//! println!("{} of 10: {}", score.score, score.reasoning);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::future::Future;
+
+use futures::stream::{FuturesUnordered, TryStreamExt};
+use ryst_error::InvalidStateError;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::client::OpenAIClient;
+use crate::clock::Rng;
+use crate::credentials::KeySource;
+use crate::error::OpenAIError;
+use crate::structured::{self, JsonRetry};
+use crate::{ChatChoice, ChatCompletionRequest, ChatCompletionResponse, Message};
+
+/// A judge's score of a reply.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Score {
+    /// The score on the judge's scale
+    pub score: u32,
+    /// The score scaled to between 0 and 1
+    pub normalized: f64,
+    /// The judge's explanation of the score
+    pub reasoning: String,
+}
+
+/// Which of two replies a judge preferred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preference {
+    First,
+    Second,
+    /// Neither reply is better, or the judge's preference changed with the order they were
+    /// shown in
+    Tie,
+}
+
+/// A judge's comparison of two replies.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comparison {
+    pub preferred: Preference,
+    /// The judge's explanation, one for each order the replies were shown in
+    pub reasoning: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ScoreReply {
+    reasoning: String,
+    score: i64,
+}
+
+#[derive(Deserialize)]
+enum Winner {
+    A,
+    B,
+    #[serde(rename = "tie")]
+    Tie,
+}
+
+#[derive(Deserialize)]
+struct ComparisonReply {
+    reasoning: String,
+    winner: Winner,
+}
+
+/// Scores replies against a rubric by asking a model.
+///
+/// Clones share the same random sequence for ordering comparisons.
+#[derive(Debug, Clone)]
+pub struct Judge {
+    model: String,
+    rubric: String,
+    min: u32,
+    max: u32,
+    both_orders: bool,
+    retry: JsonRetry,
+    key_source: Option<KeySource>,
+    client: Option<OpenAIClient>,
+    rng: Rng,
+}
+
+impl PartialEq for Judge {
+    fn eq(&self, other: &Self) -> bool {
+        self.model == other.model
+            && self.rubric == other.rubric
+            && self.min == other.min
+            && self.max == other.max
+            && self.both_orders == other.both_orders
+            && self.retry == other.retry
+            && self.key_source == other.key_source
+            && self.client == other.client
+            && self.rng == other.rng
+    }
+}
+
+impl Judge {
+    /// Create a judge which asks the model to score replies from 1 to 5 against the rubric.
+    pub fn new(model: &str, rubric: &str) -> Self {
+        Self {
+            model: model.to_string(),
+            rubric: rubric.to_string(),
+            min: 1,
+            max: 5,
+            both_orders: false,
+            retry: JsonRetry::new(),
+            key_source: None,
+            client: None,
+            rng: Rng::from_time(),
+        }
+    }
+
+    /// The lowest and highest scores, the highest being best.
+    ///
+    /// The scale always has at least two scores, so a highest score which is not above the
+    /// lowest is raised to one above it, lowering the lowest if it is `u32::MAX`.
+    pub fn with_scale(mut self, min: u32, max: u32) -> Self {
+        self.min = min.min(u32::MAX - 1);
+        self.max = max.max(self.min + 1);
+        self
+    }
+
+    /// Whether comparisons are asked in both orders, returning a tie when the judge's preference
+    /// follows the position rather than the reply. Doubles the requests made.
+    pub fn with_both_orders(mut self, both_orders: bool) -> Self {
+        self.both_orders = both_orders;
+        self
+    }
+
+    /// How to recover when the judge's reply is not the expected JSON.
+    pub fn with_json_retry(mut self, retry: JsonRetry) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Where to read the API key from, see `ChatCompletionRequest::with_key_source`.
+    pub fn with_key_source(mut self, key_source: KeySource) -> Self {
+        self.key_source = Some(key_source);
+        self
+    }
+
+    /// Send the judge's requests through the client, see `ChatCompletionRequest::with_client`.
+    pub fn with_client(mut self, client: OpenAIClient) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Order comparisons the same way for the same seed, for reproducible evaluations.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = Rng::seeded(seed);
+        self
+    }
+
+    /// Score the reply to the input against the rubric.
+    ///
+    /// Returns an `InvalidState` error if the judge's score is outside the scale.
+    pub async fn score(&self, input: &str, output: &str) -> Result<Score, OpenAIError> {
+        self.score_with(input, output, ChatCompletionRequest::submit)
+            .await
+    }
+
+    /// Compare two replies to the input against the rubric.
+    pub async fn compare(
+        &self,
+        input: &str,
+        first: &str,
+        second: &str,
+    ) -> Result<Comparison, OpenAIError> {
+        self.compare_with(input, first, second, ChatCompletionRequest::submit)
+            .await
+    }
+
+    /// Score the text of each choice of the response concurrently and return the best, for use
+    /// as a best-of strategy.
+    ///
+    /// Choices without text are skipped and ties are resolved in favor of the choice that comes
+    /// first, as with `ChatCompletionResponse::best_choice_by`. Requests sent with
+    /// `ChatCompletionRequest::with_client_best_of_by` are reduced to this choice.
+    pub async fn best_choice<'a>(
+        &self,
+        input: &str,
+        response: &'a ChatCompletionResponse,
+    ) -> Result<Option<&'a ChatChoice>, OpenAIError> {
+        self.best_choice_with(input, response, ChatCompletionRequest::submit)
+            .await
+    }
+
+    /// Keep only the best choice of the response, as index 0, falling back to the first choice
+    /// when none can be scored.
+    pub(crate) async fn keep_best_choice(
+        &self,
+        input: &str,
+        response: ChatCompletionResponse,
+    ) -> Result<ChatCompletionResponse, OpenAIError> {
+        self.keep_best_choice_with(input, response, ChatCompletionRequest::submit)
+            .await
+    }
+
+    async fn score_with<F, Fut>(
+        &self,
+        input: &str,
+        output: &str,
+        submit: F,
+    ) -> Result<Score, OpenAIError>
+    where
+        F: Fn(ChatCompletionRequest) -> Fut,
+        Fut: Future<Output = Result<ChatCompletionResponse, OpenAIError>>,
+    {
+        let (min, max) = (self.min, self.max);
+        let system = format!(
+            "You are an impartial judge. Evaluate the response to the input against this \
+             rubric:\n\n{}\n\nScore the response with a whole number from {min} to {max}, where \
+             {max} is best. Explain your reasoning before giving the score. The length of the \
+             response must not affect the score.",
+            self.rubric
+        );
+        let user = format!("Input:\n{input}\n\nResponse:\n{output}");
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "reasoning": {"type": "string"},
+                "score": {"type": "integer"}
+            },
+            "required": ["reasoning", "score"],
+            "additionalProperties": false
+        });
+
+        let reply: ScoreReply = structured::run(
+            self.request(&system, &user, "score", schema),
+            self.retry,
+            submit,
+        )
+        .await?;
+        let score = u32::try_from(reply.score)
+            .ok()
+            .filter(|score| (min..=max).contains(score))
+            .ok_or_else(|| {
+                OpenAIError::InvalidState(InvalidStateError::with_message(format!(
+                    "The judge's score of {} is outside the scale of {min} to {max}",
+                    reply.score
+                )))
+            })?;
+
+        Ok(Score {
+            score,
+            normalized: f64::from(score - min) / f64::from(max - min),
+            reasoning: reply.reasoning,
+        })
+    }
+
+    async fn compare_with<F, Fut>(
+        &self,
+        input: &str,
+        first: &str,
+        second: &str,
+        submit: F,
+    ) -> Result<Comparison, OpenAIError>
+    where
+        F: Fn(ChatCompletionRequest) -> Fut,
+        Fut: Future<Output = Result<ChatCompletionResponse, OpenAIError>>,
+    {
+        let swapped = self.rng.below(2) == 1;
+        let orders = if self.both_orders {
+            vec![swapped, !swapped]
+        } else {
+            vec![swapped]
+        };
+
+        let mut preferences = Vec::new();
+        let mut reasoning = Vec::new();
+        for swapped in orders {
+            let (a, b) = if swapped {
+                (second, first)
+            } else {
+                (first, second)
+            };
+            let reply = self.compare_once(input, a, b, &submit).await?;
+            preferences.push(match (reply.winner, swapped) {
+                (Winner::A, false) | (Winner::B, true) => Preference::First,
+                (Winner::B, false) | (Winner::A, true) => Preference::Second,
+                (Winner::Tie, _) => Preference::Tie,
+            });
+            reasoning.push(reply.reasoning);
+        }
+
+        let preferred = match preferences.as_slice() {
+            [preferred] => *preferred,
+            [a, b] if a == b => *a,
+            _ => Preference::Tie,
+        };
+        Ok(Comparison {
+            preferred,
+            reasoning,
+        })
+    }
+
+    async fn compare_once<F, Fut>(
+        &self,
+        input: &str,
+        a: &str,
+        b: &str,
+        submit: F,
+    ) -> Result<ComparisonReply, OpenAIError>
+    where
+        F: Fn(ChatCompletionRequest) -> Fut,
+        Fut: Future<Output = Result<ChatCompletionResponse, OpenAIError>>,
+    {
+        let system = format!(
+            "You are an impartial judge. Decide which of two responses to the input better \
+             satisfies this rubric:\n\n{}\n\nThe order of the responses is random and must not \
+             affect your decision, and neither must their length. Explain your reasoning before \
+             naming the winner as \"A\" or \"B\", or \"tie\" if they are equally good.",
+            self.rubric
+        );
+        let user = format!("Input:\n{input}\n\nResponse A:\n{a}\n\nResponse B:\n{b}");
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "reasoning": {"type": "string"},
+                "winner": {"type": "string", "enum": ["A", "B", "tie"]}
+            },
+            "required": ["reasoning", "winner"],
+            "additionalProperties": false
+        });
+
+        structured::run(
+            self.request(&system, &user, "comparison", schema),
+            self.retry,
+            submit,
+        )
+        .await
+    }
+
+    async fn best_choice_with<'a, F, Fut>(
+        &self,
+        input: &str,
+        response: &'a ChatCompletionResponse,
+        submit: F,
+    ) -> Result<Option<&'a ChatChoice>, OpenAIError>
+    where
+        F: Fn(ChatCompletionRequest) -> Fut,
+        Fut: Future<Output = Result<ChatCompletionResponse, OpenAIError>>,
+    {
+        let scoring = response.choices.iter().filter_map(|choice| {
+            let text = choice.message.content().as_text()?;
+            let submit = &submit;
+            Some(async move {
+                let score = self.score_with(input, text, submit).await?;
+                Ok::<_, OpenAIError>((choice.index, score.normalized))
+            })
+        });
+
+        // Unlike joining, this never holds a finished error while other scores are awaited, so
+        // the future stays `Send` for requests sent with `with_client_best_of_by`
+        let scores: HashMap<i32, f64> = scoring
+            .collect::<FuturesUnordered<_>>()
+            .try_collect()
+            .await?;
+        Ok(response.best_choice_by(|choice| scores.get(&choice.index).copied()))
+    }
+
+    async fn keep_best_choice_with<F, Fut>(
+        &self,
+        input: &str,
+        response: ChatCompletionResponse,
+        submit: F,
+    ) -> Result<ChatCompletionResponse, OpenAIError>
+    where
+        F: Fn(ChatCompletionRequest) -> Fut,
+        Fut: Future<Output = Result<ChatCompletionResponse, OpenAIError>>,
+    {
+        let best = self
+            .best_choice_with(input, &response, submit)
+            .await?
+            .map(|choice| choice.index);
+        Ok(response.into_choice(best))
+    }
+
+    /// The settings which change the scores, for fingerprints.
+    pub(crate) fn settings(&self) -> Value {
+        json!({
+            "model": self.model,
+            "rubric": self.rubric,
+            "min": self.min,
+            "max": self.max,
+            "both_orders": self.both_orders,
+        })
+    }
+
+    /// The request asking the judge for JSON matching the schema.
+    fn request(
+        &self,
+        system: &str,
+        user: &str,
+        name: &str,
+        schema: Value,
+    ) -> ChatCompletionRequest {
+        let mut request = ChatCompletionRequest::new(
+            &self.model,
+            &[Message::new("system", system), Message::new("user", user)],
+        )
+        .with_temperature(0.0)
+        .with_extra(
+            "response_format",
+            json!({
+                "type": "json_schema",
+                "json_schema": {"name": name, "strict": true, "schema": schema}
+            }),
+        );
+        if let Some(key_source) = &self.key_source {
+            request = request.with_key_source(key_source.clone());
+        }
+        if let Some(client) = &self.client {
+            request = request.with_client(client.clone());
+        }
+        request
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(contents: &[&str]) -> ChatCompletionResponse {
+        let choices: Vec<Value> = contents
+            .iter()
+            .enumerate()
+            .map(|(index, content)| {
+                json!({
+                    "index": index,
+                    "message": {"role": "assistant", "content": content},
+                    "finish_reason": "stop"
+                })
+            })
+            .collect();
+        serde_json::from_value(json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "gpt-4o",
+            "choices": choices,
+            "usage": {"prompt_tokens": 1, "completion_tokens": 1, "total_tokens": 2}
+        }))
+        .unwrap()
+    }
+
+    fn user_message(request: &ChatCompletionRequest) -> String {
+        request.messages()[1]
+            .content()
+            .as_text()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    // Verify that the scale always has at least two scores, without overflowing
+    fn test_scale() {
+        let judge = Judge::new("gpt-4o", "Be correct.").with_scale(3, 3);
+        assert_eq!((judge.min, judge.max), (3, 4));
+
+        let judge = judge.with_scale(u32::MAX, u32::MAX);
+        assert_eq!((judge.min, judge.max), (u32::MAX - 1, u32::MAX));
+    }
+
+    #[tokio::test]
+    // Verify that the rubric and schema are sent and the score is checked against the scale
+    async fn test_score() {
+        let judge = Judge::new("gpt-4o", "Be correct.").with_scale(1, 5);
+        let submit = |request: ChatCompletionRequest| async move {
+            let body = serde_json::to_value(&request).unwrap();
+            assert_eq!(body["response_format"]["json_schema"]["name"], "score");
+            assert!(body["messages"][0]["content"]
+                .as_str()
+                .unwrap()
+                .contains("Be correct."));
+
+            let score = if user_message(&request).contains("wrong") {
+                9
+            } else {
+                4
+            };
+            Ok(response(&[
+                &json!({"reasoning": "Fine", "score": score}).to_string()
+            ]))
+        };
+
+        let score = judge.score_with("2 + 2?", "4", submit).await.unwrap();
+        assert_eq!(
+            score,
+            Score {
+                score: 4,
+                normalized: 0.75,
+                reasoning: "Fine".to_string(),
+            }
+        );
+
+        let result = judge.score_with("2 + 2?", "wrong", submit).await;
+        assert!(matches!(result, Err(OpenAIError::InvalidState(_))));
+    }
+
+    #[tokio::test]
+    // Verify that comparisons are shown in a random order which is mapped back to the replies
+    async fn test_compare_randomized() {
+        // The judge always prefers the better reply, wherever it is shown
+        let fair = |request: ChatCompletionRequest| async move {
+            let user = user_message(&request);
+            let winner = if user.find("better") < user.find("worse") {
+                "A"
+            } else {
+                "B"
+            };
+            Ok(response(&[
+                &json!({"reasoning": "Clearer", "winner": winner}).to_string(),
+            ]))
+        };
+        // The judge always prefers the first reply it is shown
+        let biased = |_| async {
+            Ok(response(&[
+                &json!({"reasoning": "First", "winner": "A"}).to_string()
+            ]))
+        };
+
+        let judge = Judge::new("gpt-4o", "Be clear.").with_seed(1);
+        let mut positions = Vec::new();
+        for _ in 0..16 {
+            let comparison = judge
+                .compare_with("Explain", "better", "worse", fair)
+                .await
+                .unwrap();
+            assert_eq!(comparison.preferred, Preference::First);
+
+            let comparison = judge
+                .compare_with("Explain", "x", "y", biased)
+                .await
+                .unwrap();
+            positions.push(comparison.preferred);
+        }
+        assert!(positions.contains(&Preference::First));
+        assert!(positions.contains(&Preference::Second));
+
+        let both = judge.with_both_orders(true);
+        let comparison = both
+            .compare_with("Explain", "x", "y", biased)
+            .await
+            .unwrap();
+        assert_eq!(comparison.preferred, Preference::Tie);
+        assert_eq!(comparison.reasoning.len(), 2);
+
+        let comparison = both
+            .compare_with("Explain", "worse", "better", fair)
+            .await
+            .unwrap();
+        assert_eq!(comparison.preferred, Preference::Second);
+    }
+
+    #[tokio::test]
+    // Verify that the best choice is the one the judge scores highest
+    async fn test_best_choice() {
+        let judge = Judge::new("gpt-4o", "Be detailed.").with_scale(0, 10);
+        // The judge scores the reply by its length
+        let submit = |request: ChatCompletionRequest| async move {
+            let user = user_message(&request);
+            let reply = user.rsplit("Response:\n").next().unwrap();
+            Ok(response(&[
+                &json!({"reasoning": "", "score": reply.len()}).to_string()
+            ]))
+        };
+        let candidates = response(&["a", "bbb", "cc"]);
+
+        let best = judge
+            .best_choice_with("Topic", &candidates, submit)
+            .await
+            .unwrap();
+        assert_eq!(best.map(|choice| choice.index), Some(1));
+
+        let best = judge
+            .keep_best_choice_with("Topic", candidates, submit)
+            .await
+            .unwrap();
+        assert_eq!(best.choices.len(), 1);
+        assert_eq!(best.choices[0].index, 0);
+        assert_eq!(best.choices[0].message.content(), "bbb");
+    }
+}
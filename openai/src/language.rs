@@ -0,0 +1,92 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing language detection for generated text.
+
+use crate::ChatChoice;
+
+/// The language a piece of text is written in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DetectedLanguage {
+    /// The ISO 639-3 code of the language, e.g. `eng`
+    pub code: &'static str,
+    /// The English name of the language, e.g. `English`
+    pub name: &'static str,
+    /// How confident the detection is, between 0 and 1
+    pub confidence: f64,
+    /// Whether there was enough text for the detection to be trusted
+    pub reliable: bool,
+}
+
+impl DetectedLanguage {
+    /// Check whether this is the language with the given ISO 639-3 code or English name, ignoring
+    /// case.
+    pub fn is(&self, language: &str) -> bool {
+        self.code.eq_ignore_ascii_case(language) || self.name.eq_ignore_ascii_case(language)
+    }
+}
+
+/// Detect the language of the text using a trigram model.
+///
+/// Returns `None` if the text contains no letters to detect a language from. Short text is
+/// detected with low confidence, so check `reliable` before acting on the result.
+pub fn detect_language(text: &str) -> Option<DetectedLanguage> {
+    whatlang::detect(text).map(|info| DetectedLanguage {
+        code: info.lang().code(),
+        name: info.lang().eng_name(),
+        confidence: info.confidence(),
+        reliable: info.is_reliable(),
+    })
+}
+
+impl ChatChoice {
+    /// Detect the language of the message text, see `detect_language`.
+    ///
+    /// Returns `None` if the message is not plain text.
+    pub fn detect_language(&self) -> Option<DetectedLanguage> {
+        self.message.content().as_text().and_then(detect_language)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Verify that the language of reasonably long text is detected
+    fn test_detect_language() {
+        let german = detect_language(
+            "Das Wetter ist heute sehr schön, deshalb gehen wir am Nachmittag im Park spazieren.",
+        )
+        .unwrap();
+        assert_eq!(german.code, "deu");
+        assert!(german.is("German"));
+        assert!(german.is("DEU"));
+        assert!(!german.is("eng"));
+        assert!(german.reliable);
+
+        let english = detect_language(
+            "The weather is lovely today, so we are going for a walk in the park this afternoon.",
+        )
+        .unwrap();
+        assert!(english.is("eng"));
+    }
+
+    #[test]
+    // Verify that text without letters has no language
+    fn test_detect_language_none() {
+        assert_eq!(detect_language("12345 !?"), None);
+        assert_eq!(detect_language(""), None);
+    }
+}
@@ -0,0 +1,206 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Module containing a `SlowResponsePolicy`, which switches a streamed chat request to a faster
+//! model when its response is slow to arrive.
+//!
+//! The policy is set with `ChatCompletionRequest::with_slow_response_policy`. When no chunk of the
+//! body arrives within the threshold, the request is cancelled and sent again with the fallback
+//! model. The body is a single JSON document, which the API only starts sending once the whole
+//! reply has been generated, so the threshold is in effect a deadline for the whole response
+//! rather than for its first token. The switch is reported like a retry: to the request's retry
+//! observer, to its trace, and with the `tracing` feature as a `tracing` event.
+
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::clock::{Clock, TokioClock};
+use crate::error::OpenAIError;
+use crate::ChatCompletionResponseStream;
+
+/// Sends a streamed request again with a faster model when its response takes too long to start
+/// arriving, which for a complete JSON body means when the whole response takes too long.
+#[derive(Clone)]
+pub struct SlowResponsePolicy {
+    threshold: Duration,
+    fallback_model: String,
+    clock: Arc<dyn Clock>,
+}
+
+impl SlowResponsePolicy {
+    /// Create a policy which switches to the fallback model if no part of the response arrives
+    /// within the threshold, measured from when the request is sent.
+    pub fn new(threshold: Duration, fallback_model: &str) -> Self {
+        Self {
+            threshold,
+            fallback_model: fallback_model.to_string(),
+            clock: Arc::new(TokioClock),
+        }
+    }
+
+    /// The clock used to measure the threshold.
+    ///
+    /// Defaults to `TokioClock`, which follows `tokio::time::pause`.
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    pub fn threshold(&self) -> Duration {
+        self.threshold
+    }
+
+    pub fn fallback_model(&self) -> &str {
+        &self.fallback_model
+    }
+
+    /// Wait for the stream to be opened and the first chunk of its body to arrive, returning
+    /// `None` if the threshold passes first. The stream is dropped, cancelling the request, when
+    /// it is too slow.
+    ///
+    /// Errors which arrive in time, whether opening the stream or reading its first chunk, are
+    /// returned rather than triggering the fallback.
+    pub(crate) async fn response_started_within<F>(
+        &self,
+        open: F,
+    ) -> Option<Result<ChatCompletionResponseStream, OpenAIError>>
+    where
+        F: Future<Output = Result<ChatCompletionResponseStream, OpenAIError>>,
+    {
+        let first_chunk = async {
+            let mut stream = open.await?;
+            stream.wait_for_first_chunk().await;
+            Ok(stream)
+        };
+
+        tokio::select! {
+            biased;
+            result = first_chunk => Some(result),
+            _ = self.clock.sleep(self.threshold) => None,
+        }
+    }
+
+    /// Describes the switch to the fallback model, for telemetry.
+    pub(crate) fn reason(&self, model: &str) -> String {
+        format!(
+            "No response from {model} within {}ms, retrying with {}",
+            self.threshold.as_millis(),
+            self.fallback_model
+        )
+    }
+}
+
+// Policies are equal when they have the same settings and share a clock
+impl PartialEq for SlowResponsePolicy {
+    fn eq(&self, other: &Self) -> bool {
+        self.threshold == other.threshold
+            && self.fallback_model == other.fallback_model
+            && Arc::ptr_eq(&self.clock, &other.clock)
+    }
+}
+
+impl fmt::Debug for SlowResponsePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SlowResponsePolicy")
+            .field("threshold", &self.threshold)
+            .field("fallback_model", &self.fallback_model)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+    use futures::stream::{self, StreamExt};
+
+    use crate::clock::ManualClock;
+
+    const FIRST_CHUNK: &str = r#"{"id":"chatcmpl-1","object":"chat.completion","created":0,"#;
+    const LAST_CHUNK: &str = r#""model":"gpt-4o","choices":[{"index":0,"message":{"role":"assistant","content":"Hi"},"finish_reason":"stop"}],"usage":{"prompt_tokens":1,"completion_tokens":1,"total_tokens":2}}"#;
+
+    /// A stream whose first chunk arrives after the delay.
+    fn delayed_stream(delay: Duration) -> ChatCompletionResponseStream {
+        let chunks = stream::once(async move {
+            tokio::time::sleep(delay).await;
+            Ok(Bytes::from(FIRST_CHUNK))
+        })
+        .chain(stream::iter([Ok(Bytes::from(LAST_CHUNK))]));
+        ChatCompletionResponseStream::new(Box::pin(chunks))
+    }
+
+    #[tokio::test(start_paused = true)]
+    // Verify that a stream whose first chunk arrives in time is returned with the chunk intact
+    async fn test_first_chunk_in_time() {
+        let policy = SlowResponsePolicy::new(Duration::from_millis(500), "gpt-4o-mini");
+
+        let mut stream = policy
+            .response_started_within(async { Ok(delayed_stream(Duration::from_millis(200))) })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let response = stream.next().await.unwrap().unwrap();
+        assert_eq!(response.choices[0].message.content(), "Hi");
+    }
+
+    #[tokio::test(start_paused = true)]
+    // Verify that a slow first chunk or a slow request gives up once the threshold passes
+    async fn test_first_chunk_too_slow() {
+        let policy = SlowResponsePolicy::new(Duration::from_millis(500), "gpt-4o-mini");
+        let started = tokio::time::Instant::now();
+
+        assert!(policy
+            .response_started_within(async { Ok(delayed_stream(Duration::from_secs(5))) })
+            .await
+            .is_none());
+        assert_eq!(started.elapsed(), Duration::from_millis(500));
+
+        assert!(policy
+            .response_started_within(async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                Ok(delayed_stream(Duration::ZERO))
+            })
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    // Verify that errors arriving in time are returned rather than triggering the fallback, and
+    // that the threshold follows the policy's clock
+    async fn test_error_and_clock() {
+        let policy = SlowResponsePolicy::new(Duration::from_secs(3600), "gpt-4o-mini")
+            .with_clock(ManualClock::new());
+
+        let result = policy
+            .response_started_within(async {
+                Err(OpenAIError::InvalidArgument(
+                    ryst_error::InvalidArgumentError::new("model", "unknown"),
+                ))
+            })
+            .await;
+        assert!(matches!(result, Some(Err(OpenAIError::InvalidArgument(_)))));
+
+        // The manual clock completes the hour long sleep at once
+        assert!(policy
+            .response_started_within(std::future::pending())
+            .await
+            .is_none());
+        assert!(policy
+            .reason("gpt-4o")
+            .contains("gpt-4o within 3600000ms, retrying with gpt-4o-mini"));
+    }
+}
@@ -13,21 +13,110 @@
 // limitations under the License.
 
 //! SDK for the OpenAI API
+//!
+//! Malformed data from the API or a model never panics: parsing responses, repairing JSON,
+//! validating tool arguments and tracking markdown return errors or best-effort results
+//! instead. These paths are fuzzed by the cargo-fuzz targets in `openai/fuzz`.
 
 extern crate serde;
 
+pub mod canonical;
 mod chat_completion;
+mod choice;
+mod client;
+pub mod clock;
+#[cfg(feature = "async-openai")]
+mod compat;
 mod completion;
+pub mod compress;
+#[cfg(feature = "config")]
+pub mod config;
+pub mod conversation;
+mod credentials;
+pub mod deadline;
+pub mod dedup;
+pub mod diff;
+#[cfg(feature = "encryption")]
+pub mod encryption;
 mod error;
+pub mod explore;
+pub mod fewshot;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod finite;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "guard")]
+pub mod guard;
+pub mod health;
+mod http;
+mod intern;
+pub mod judge;
+#[cfg(feature = "language")]
+pub mod language;
+pub mod latency;
+pub mod markdown;
+pub mod model_router;
+pub mod models;
+pub mod patch;
+#[cfg(feature = "pii")]
+pub mod pii;
+pub mod pipeline;
+pub mod poll;
+#[cfg(feature = "queue")]
+pub mod queue;
+pub mod rate_limit;
+pub mod redact;
+#[cfg(feature = "reload")]
+pub mod reload;
+pub mod retry;
+mod rolling;
+pub mod sanitize;
+#[cfg(feature = "schema")]
+pub mod schema;
+#[cfg(feature = "server")]
+pub mod server;
+mod simulated;
+mod sse;
+pub mod stitch;
+#[cfg(feature = "storage")]
+pub mod storage;
+mod stream_stats;
+pub mod strict;
+pub mod structured;
+pub mod system_prompt;
+mod tags;
+pub mod template;
+#[cfg(feature = "tokens")]
+pub mod tokens;
+pub mod trace;
+mod transcript;
+pub mod translate;
+#[cfg(feature = "web")]
+pub mod web;
+#[cfg(feature = "websocket")]
+pub mod websocket;
 
 const OPEN_AI_URL: &str = "https://api.openai.com";
 
 pub use chat_completion::{
-    ChatChoice, ChatCompletionRequest, ChatCompletionResponse, ChatCompletionResponseStream,
-    ChatUsage, Message,
+    Annotation, ArgumentViolation, AssistantMessage, ChatChoice, ChatCompletionRequest,
+    ChatCompletionResponse, ChatCompletionResponseStream, ChatLogprobs, ChatUsage, Citation,
+    ContentFilterError, ContentFilterResults, ContentPart, DetectionResult, FileInput,
+    FilterSeverity, FunctionCall, FunctionDefinition, ImageUrl, Message, MessageContent,
+    MultiStream, PromptFilterResult, SeverityResult, SystemMessage, TokenLogprob, Tool,
+    ToolArgumentError, ToolCall, ToolMessage, TopLogprob, UrlCitation, UserMessage,
 };
+pub use choice::ChoiceStrategy;
+pub use client::{global, set_global, OpenAIClient, Proxy};
 pub use completion::{
     CompletionChoice, CompletionRequest, CompletionResponse, CompletionResponseStream,
-    CompletionUsage,
+    CompletionUsage, Logprobs,
 };
-pub use error::OpenAIError;
+pub use credentials::{ApiKeyProvider, KeyFuture, KeySource, SharedKey, SharedKeyProvider};
+pub use error::{ApiError, OpenAIError};
+pub use http::{PreSendHook, RateLimitInfo, ResponseMetadata};
+pub use reqwest;
+pub use simulated::SimulatedStream;
+pub use stream_stats::StreamStats;
+pub use tags::RequestTags;
@@ -0,0 +1,473 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing tracking of markdown structure in text that arrives in pieces.
+
+use ryst_error::InvalidStateError;
+
+use crate::error::OpenAIError;
+use crate::{ChatChoice, ChatCompletionResponse};
+
+/// An event produced while tracking markdown across text deltas.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MarkdownEvent {
+    /// Text outside of code blocks
+    Text(String),
+    /// A fenced code block was opened, with the language from its info string if one was given
+    CodeBlockStarted(Option<String>),
+    /// Text inside a code block, excluding the fences
+    Code(String),
+    /// The current code block was closed, or the text ended inside it
+    CodeBlockEnded,
+    /// A list item starts on this line. The text of the item, including its marker, follows as
+    /// `Text`.
+    ListItemStarted { ordered: bool, indent: usize },
+}
+
+/// How much of the current line has been classified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineState {
+    /// The start of the line is held back until it is known whether it begins a fence or list
+    Start,
+    /// The line is a fence, held back until the end of the line
+    Fence,
+    /// The line is ordinary text or code, emitted as it arrives
+    Body,
+}
+
+/// Tracks open code fences and list items across text deltas.
+///
+/// Text is emitted as soon as it arrives, except for the start of each line, which is held back
+/// until it is known whether the line opens or closes a code fence or starts a list item. A fence
+/// line is held back until its end so that the language can be read from it.
+#[derive(Debug, Clone)]
+pub struct MarkdownTracker {
+    line: String,
+    state: LineState,
+    fence: Option<String>,
+}
+
+impl MarkdownTracker {
+    /// Create a new `MarkdownTracker` at the start of a document.
+    pub fn new() -> Self {
+        Self {
+            line: String::new(),
+            state: LineState::Start,
+            fence: None,
+        }
+    }
+
+    /// Returns whether the text so far ends inside a code block.
+    pub fn in_code_block(&self) -> bool {
+        self.fence.is_some()
+    }
+
+    /// Add the next piece of text, returning the events it completes.
+    pub fn push(&mut self, delta: &str) -> Vec<MarkdownEvent> {
+        let mut events = Vec::new();
+
+        for c in delta.chars() {
+            match self.state {
+                LineState::Body => {
+                    self.emit_body(&c.to_string(), &mut events);
+                    if c == '\n' {
+                        self.state = LineState::Start;
+                    }
+                }
+                LineState::Fence => {
+                    self.line.push(c);
+                    if c == '\n' {
+                        self.finish_fence(&mut events);
+                    }
+                }
+                LineState::Start => {
+                    self.line.push(c);
+                    self.classify(c == '\n', &mut events);
+                }
+            }
+        }
+
+        events
+    }
+
+    /// End the text, returning any held back text and closing an unterminated code block.
+    pub fn finish(mut self) -> Vec<MarkdownEvent> {
+        let mut events = Vec::new();
+
+        match self.state {
+            LineState::Start => self.classify(true, &mut events),
+            LineState::Fence => self.finish_fence(&mut events),
+            LineState::Body => (),
+        }
+
+        if self.fence.take().is_some() {
+            events.push(MarkdownEvent::CodeBlockEnded);
+        }
+
+        events
+    }
+
+    /// Decide what kind of line the held back start of the line begins, if possible yet.
+    fn classify(&mut self, complete: bool, events: &mut Vec<MarkdownEvent>) {
+        let trimmed = self.line.trim_start();
+        let indent = self.line.len() - trimmed.len();
+
+        let fence_marker = ["```", "~~~"]
+            .into_iter()
+            .find(|marker| trimmed.starts_with(marker));
+        if fence_marker.is_some() {
+            self.state = LineState::Fence;
+            if complete {
+                self.finish_fence(events);
+            }
+            return;
+        }
+
+        let could_be_fence = !trimmed.is_empty()
+            && ["```", "~~~"]
+                .into_iter()
+                .any(|marker| marker.starts_with(trimmed));
+        if !complete && (trimmed.is_empty() || could_be_fence) {
+            return;
+        }
+
+        if self.fence.is_none() {
+            match list_marker(trimmed) {
+                Some(ordered) => events.push(MarkdownEvent::ListItemStarted { ordered, indent }),
+                // The marker may be incomplete
+                None if !complete && possible_list_marker(trimmed) => return,
+                None => (),
+            }
+        }
+
+        let line = std::mem::take(&mut self.line);
+        self.emit_body(&line, events);
+        self.state = if complete {
+            LineState::Start
+        } else {
+            LineState::Body
+        };
+    }
+
+    /// Handle a complete fence line, opening or closing a code block.
+    fn finish_fence(&mut self, events: &mut Vec<MarkdownEvent>) {
+        let line = std::mem::take(&mut self.line);
+        let trimmed = line.trim();
+        self.state = LineState::Start;
+
+        match &self.fence {
+            None => {
+                let marker_char = trimmed.chars().next().unwrap_or('`');
+                // Measured in bytes so slicing stays on a character boundary
+                let marker_len = trimmed.len() - trimmed.trim_start_matches(marker_char).len();
+                let info = trimmed[marker_len..].trim();
+                let language = info
+                    .split_whitespace()
+                    .next()
+                    .map(|language| language.to_string());
+
+                self.fence = Some(trimmed[..marker_len].to_string());
+                events.push(MarkdownEvent::CodeBlockStarted(language));
+            }
+            Some(fence) => {
+                // A closing fence uses the same character, is at least as long and has no info
+                let fence_char = fence.chars().next().unwrap_or('`');
+                let closes = trimmed.chars().all(|c| c == fence_char)
+                    && trimmed.chars().count() >= fence.chars().count();
+                if closes {
+                    self.fence = None;
+                    events.push(MarkdownEvent::CodeBlockEnded);
+                } else {
+                    self.emit_body(&line, events);
+                }
+            }
+        }
+    }
+
+    /// Emit text as code or text depending on whether a code block is open, merging it with the
+    /// previous event where possible.
+    fn emit_body(&self, text: &str, events: &mut Vec<MarkdownEvent>) {
+        if text.is_empty() {
+            return;
+        }
+
+        match (events.last_mut(), self.fence.is_some()) {
+            (Some(MarkdownEvent::Code(code)), true) => code.push_str(text),
+            (Some(MarkdownEvent::Text(existing)), false) => existing.push_str(text),
+            (_, true) => events.push(MarkdownEvent::Code(text.to_string())),
+            (_, false) => events.push(MarkdownEvent::Text(text.to_string())),
+        }
+    }
+}
+
+impl Default for MarkdownTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Extract the fenced code blocks from markdown text as `(language, code)` pairs.
+///
+/// A code block left open at the end of the text, such as from a truncated response, is
+/// included.
+pub fn code_blocks(text: &str) -> Vec<(Option<String>, String)> {
+    let mut tracker = MarkdownTracker::new();
+    let mut events = tracker.push(text);
+    events.extend(tracker.finish());
+
+    let mut blocks = Vec::new();
+    for event in events {
+        match event {
+            MarkdownEvent::CodeBlockStarted(language) => blocks.push((language, String::new())),
+            MarkdownEvent::Code(code) => {
+                if let Some((_, block)) = blocks.last_mut() {
+                    block.push_str(&code);
+                }
+            }
+            _ => (),
+        }
+    }
+    blocks
+}
+
+impl ChatChoice {
+    /// Extract the fenced code blocks from the message text, see `code_blocks`.
+    pub fn code_blocks(&self) -> Vec<(Option<String>, String)> {
+        self.message
+            .content()
+            .as_text()
+            .map(code_blocks)
+            .unwrap_or_default()
+    }
+}
+
+impl ChatCompletionResponse {
+    /// Extract the fenced code blocks from the first choice, see `code_blocks`.
+    pub fn code_blocks(&self) -> Vec<(Option<String>, String)> {
+        self.choices
+            .first()
+            .map(ChatChoice::code_blocks)
+            .unwrap_or_default()
+    }
+
+    /// Extract the fenced code blocks from the first choice, returning an error if there are
+    /// none.
+    pub fn code_blocks_strict(&self) -> Result<Vec<(Option<String>, String)>, OpenAIError> {
+        let blocks = self.code_blocks();
+        if blocks.is_empty() {
+            return Err(OpenAIError::InvalidState(InvalidStateError::with_message(
+                "Response does not contain a code block".to_string(),
+            )));
+        }
+        Ok(blocks)
+    }
+}
+
+/// Returns whether the line starts with a list marker, and if so whether the list is ordered.
+fn list_marker(line: &str) -> Option<bool> {
+    let mut chars = line.chars();
+    match chars.next()? {
+        '-' | '*' | '+' => chars.next().filter(|c| *c == ' ').map(|_| false),
+        c if c.is_ascii_digit() => {
+            let rest = line.trim_start_matches(|c: char| c.is_ascii_digit());
+            let mut rest = rest.chars();
+            match (rest.next(), rest.next()) {
+                (Some('.' | ')'), Some(' ')) => Some(true),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Returns whether more text could turn the start of the line into a list marker.
+fn possible_list_marker(line: &str) -> bool {
+    match line {
+        "-" | "*" | "+" => true,
+        _ => {
+            let rest = line.trim_start_matches(|c: char| c.is_ascii_digit());
+            rest.len() < line.len() && (rest.is_empty() || rest == "." || rest == ")")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    /// Feed the text to a tracker one character at a time, merging adjacent text events.
+    fn track_chars(text: &str) -> Vec<MarkdownEvent> {
+        let mut tracker = MarkdownTracker::new();
+        let mut events = Vec::new();
+        for c in text.chars() {
+            events.extend(tracker.push(&c.to_string()));
+        }
+        events.extend(tracker.finish());
+        merge(events)
+    }
+
+    /// Merge adjacent text and code events, which depend on how the text was split.
+    fn merge(events: Vec<MarkdownEvent>) -> Vec<MarkdownEvent> {
+        let mut merged: Vec<MarkdownEvent> = Vec::new();
+        for event in events {
+            match (merged.last_mut(), event) {
+                (Some(MarkdownEvent::Text(existing)), MarkdownEvent::Text(text)) => {
+                    existing.push_str(&text)
+                }
+                (Some(MarkdownEvent::Code(existing)), MarkdownEvent::Code(code)) => {
+                    existing.push_str(&code)
+                }
+                (_, event) => merged.push(event),
+            }
+        }
+        merged
+    }
+
+    #[test]
+    // Verify that code blocks and list items produce events however the text is split
+    fn test_tracker_events() {
+        let text = "Steps:\n\n1. Install\n- item\n\n```rust\nfn main() {}\n```\nDone";
+        let expected = vec![
+            MarkdownEvent::Text("Steps:\n\n".to_string()),
+            MarkdownEvent::ListItemStarted {
+                ordered: true,
+                indent: 0,
+            },
+            MarkdownEvent::Text("1. Install\n".to_string()),
+            MarkdownEvent::ListItemStarted {
+                ordered: false,
+                indent: 0,
+            },
+            MarkdownEvent::Text("- item\n\n".to_string()),
+            MarkdownEvent::CodeBlockStarted(Some("rust".to_string())),
+            MarkdownEvent::Code("fn main() {}\n".to_string()),
+            MarkdownEvent::CodeBlockEnded,
+            MarkdownEvent::Text("Done".to_string()),
+        ];
+
+        assert_eq!(track_chars(text), expected);
+
+        let mut tracker = MarkdownTracker::new();
+        let mut events = tracker.push(text);
+        events.extend(tracker.finish());
+        assert_eq!(events, expected);
+    }
+
+    #[test]
+    // Verify that fences inside a code block only close it when they match
+    fn test_tracker_nested_fences() {
+        let text = "````md\n```js\nx\n```\n````\n";
+        assert_eq!(
+            track_chars(text),
+            vec![
+                MarkdownEvent::CodeBlockStarted(Some("md".to_string())),
+                MarkdownEvent::Code("```js\nx\n```\n".to_string()),
+                MarkdownEvent::CodeBlockEnded,
+            ]
+        );
+    }
+
+    #[test]
+    // Verify that an unterminated code block is ended by finish and that text streams eagerly
+    fn test_tracker_unterminated() {
+        let mut tracker = MarkdownTracker::new();
+        assert_eq!(
+            tracker.push("```\nlet"),
+            vec![
+                MarkdownEvent::CodeBlockStarted(None),
+                MarkdownEvent::Code("let".to_string())
+            ]
+        );
+        assert!(tracker.in_code_block());
+        assert_eq!(
+            tracker.push(" x"),
+            vec![MarkdownEvent::Code(" x".to_string())]
+        );
+        assert_eq!(tracker.finish(), vec![MarkdownEvent::CodeBlockEnded]);
+    }
+
+    #[test]
+    // Verify that code blocks are extracted with their languages
+    fn test_code_blocks() {
+        let text = "Here:\n```python\nprint(1)\n```\nand\n\n```\nplain\n```\n```sh\nls";
+        assert_eq!(
+            code_blocks(text),
+            vec![
+                (Some("python".to_string()), "print(1)\n".to_string()),
+                (None, "plain\n".to_string()),
+                (Some("sh".to_string()), "ls".to_string()),
+            ]
+        );
+        assert!(code_blocks("no code here").is_empty());
+    }
+
+    #[test]
+    // Verify that strict extraction fails when the response has no code block
+    fn test_code_blocks_strict() {
+        let response = |content: &str| -> ChatCompletionResponse {
+            serde_json::from_value(serde_json::json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion",
+                "created": 0,
+                "model": "gpt-4o",
+                "choices": [{
+                    "message": {"role": "assistant", "content": content},
+                    "index": 0,
+                    "finish_reason": "stop"
+                }],
+                "usage": {"prompt_tokens": 1, "completion_tokens": 1, "total_tokens": 2}
+            }))
+            .unwrap()
+        };
+
+        assert!(response("Sorry, no code.").code_blocks_strict().is_err());
+        assert_eq!(
+            response("```rust\nfn main() {}\n```")
+                .code_blocks_strict()
+                .unwrap(),
+            vec![(Some("rust".to_string()), "fn main() {}\n".to_string())]
+        );
+    }
+
+    #[test]
+    // Verify that text resembling markers is not mistaken for lists
+    fn test_tracker_not_lists() {
+        assert_eq!(
+            track_chars("---\n2024 was\n-5 degrees"),
+            vec![MarkdownEvent::Text("---\n2024 was\n-5 degrees".to_string())]
+        );
+    }
+
+    proptest! {
+        #[test]
+        // Verify that arbitrary text split at arbitrary points never panics, and produces the
+        // same events as when it is pushed one character at a time
+        fn test_tracker_arbitrary_splits(
+            text in "[`~ \n0-9a-z.*+-é]{0,64}",
+            piece_len in 1usize..8,
+        ) {
+            let chars = text.chars().collect::<Vec<_>>();
+            let mut tracker = MarkdownTracker::new();
+            let mut events = Vec::new();
+            for piece in chars.chunks(piece_len) {
+                events.extend(tracker.push(&piece.iter().collect::<String>()));
+            }
+            events.extend(tracker.finish());
+
+            prop_assert_eq!(merge(events), track_chars(&text));
+        }
+    }
+}
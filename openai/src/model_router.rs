@@ -0,0 +1,269 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing a `ModelRouter`, which picks the model and parameters for a request from
+//! the prompt and the user, so that model names are configured in one place.
+
+use std::fmt;
+use std::sync::Arc;
+
+use crate::markdown::code_blocks;
+use crate::{ChatCompletionRequest, ContentPart, Message, MessageContent};
+
+/// The approximate number of characters per token of English text, used to estimate the size of
+/// prompts without a tokenizer.
+const CHARS_PER_TOKEN: usize = 4;
+
+/// Facts about a prompt which routes are matched against.
+#[derive(Debug, Clone, Copy)]
+pub struct RouteContext<'a> {
+    /// The messages of the prompt
+    pub messages: &'a [Message],
+    /// The tier of the user making the request, if known
+    pub tier: Option<&'a str>,
+    /// The estimated number of prompt tokens, at about 4 characters of text per token
+    pub estimated_tokens: usize,
+    /// Whether any message contains a fenced code block
+    pub has_code: bool,
+}
+
+impl<'a> RouteContext<'a> {
+    /// Collect the facts about the messages.
+    pub fn new(messages: &'a [Message], tier: Option<&'a str>) -> Self {
+        let texts = messages.iter().flat_map(|message| match message.content() {
+            MessageContent::Text(text) => vec![text.as_str()],
+            MessageContent::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ContentPart::Text { text } => Some(text.as_str()),
+                    ContentPart::ImageUrl { .. } | ContentPart::File { .. } => None,
+                })
+                .collect(),
+        });
+
+        let mut chars = 0;
+        let mut has_code = false;
+        for text in texts {
+            chars += text.chars().count();
+            has_code = has_code || !code_blocks(text).is_empty();
+        }
+
+        Self {
+            messages,
+            tier,
+            estimated_tokens: chars.div_ceil(CHARS_PER_TOKEN),
+            has_code,
+        }
+    }
+}
+
+type Predicate = dyn Fn(&RouteContext) -> bool + Send + Sync;
+
+/// A condition which selects a route.
+#[derive(Clone)]
+pub enum RouteRule {
+    /// The prompt is estimated to use at least this many tokens
+    MinPromptTokens(usize),
+    /// A message contains a fenced code block
+    ContainsCode,
+    /// The user is in the tier
+    Tier(String),
+    /// Every one of the rules matches
+    All(Vec<RouteRule>),
+    /// Any one of the rules matches
+    Any(Vec<RouteRule>),
+    /// The callback returns true
+    Custom(Arc<Predicate>),
+}
+
+impl RouteRule {
+    /// Create a rule from a callback.
+    pub fn custom<F>(predicate: F) -> Self
+    where
+        F: Fn(&RouteContext) -> bool + Send + Sync + 'static,
+    {
+        RouteRule::Custom(Arc::new(predicate))
+    }
+
+    /// Returns whether the rule matches the context.
+    pub fn matches(&self, context: &RouteContext) -> bool {
+        match self {
+            RouteRule::MinPromptTokens(tokens) => context.estimated_tokens >= *tokens,
+            RouteRule::ContainsCode => context.has_code,
+            RouteRule::Tier(tier) => context.tier == Some(tier.as_str()),
+            RouteRule::All(rules) => rules.iter().all(|rule| rule.matches(context)),
+            RouteRule::Any(rules) => rules.iter().any(|rule| rule.matches(context)),
+            RouteRule::Custom(predicate) => predicate(context),
+        }
+    }
+}
+
+impl fmt::Debug for RouteRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RouteRule::MinPromptTokens(tokens) => {
+                f.debug_tuple("MinPromptTokens").field(tokens).finish()
+            }
+            RouteRule::ContainsCode => f.write_str("ContainsCode"),
+            RouteRule::Tier(tier) => f.debug_tuple("Tier").field(tier).finish(),
+            RouteRule::All(rules) => f.debug_tuple("All").field(rules).finish(),
+            RouteRule::Any(rules) => f.debug_tuple("Any").field(rules).finish(),
+            RouteRule::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+/// A model and the parameters to use with it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelPreset {
+    model: String,
+    temperature: Option<f32>,
+    max_tokens: Option<i32>,
+}
+
+impl ModelPreset {
+    pub fn new(model: &str) -> Self {
+        Self {
+            model: model.to_string(),
+            temperature: None,
+            max_tokens: None,
+        }
+    }
+
+    pub fn with_temperature(mut self, temperature: f32) -> Self {
+        self.temperature = Some(temperature);
+        self
+    }
+
+    pub fn with_max_tokens(mut self, max_tokens: i32) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// Create a request for the messages with the model and parameters of the preset.
+    pub fn request(&self, messages: &[Message]) -> ChatCompletionRequest {
+        let mut request = ChatCompletionRequest::new(&self.model, messages);
+        if let Some(temperature) = self.temperature {
+            request = request.with_temperature(temperature);
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            request = request.with_max_tokens(max_tokens);
+        }
+        request
+    }
+}
+
+/// Picks a `ModelPreset` for a prompt from an ordered list of routes.
+///
+/// Routes are checked in the order they were added and the first whose rule matches is used,
+/// falling back to the default preset.
+#[derive(Debug, Clone)]
+pub struct ModelRouter {
+    default: ModelPreset,
+    routes: Vec<(RouteRule, ModelPreset)>,
+}
+
+impl ModelRouter {
+    /// Create a router which uses the preset when no route matches.
+    pub fn new(default: ModelPreset) -> Self {
+        Self {
+            default,
+            routes: Vec::new(),
+        }
+    }
+
+    /// Add a route, checked after the routes already added.
+    pub fn with_route(mut self, rule: RouteRule, preset: ModelPreset) -> Self {
+        self.routes.push((rule, preset));
+        self
+    }
+
+    /// Returns the preset for the messages, sent by a user in the tier if known.
+    pub fn route(&self, messages: &[Message], tier: Option<&str>) -> &ModelPreset {
+        let context = RouteContext::new(messages, tier);
+        self.routes
+            .iter()
+            .find(|(rule, _)| rule.matches(&context))
+            .map(|(_, preset)| preset)
+            .unwrap_or(&self.default)
+    }
+
+    /// Create a request for the messages using the preset they are routed to.
+    pub fn request(&self, messages: &[Message], tier: Option<&str>) -> ChatCompletionRequest {
+        self.route(messages, tier).request(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router() -> ModelRouter {
+        ModelRouter::new(ModelPreset::new("gpt-4o-mini"))
+            .with_route(
+                RouteRule::Tier("free".to_string()),
+                ModelPreset::new("gpt-4o-mini").with_max_tokens(256),
+            )
+            .with_route(
+                RouteRule::Any(vec![
+                    RouteRule::ContainsCode,
+                    RouteRule::MinPromptTokens(1000),
+                ]),
+                ModelPreset::new("gpt-4o").with_temperature(0.2),
+            )
+            .with_route(
+                RouteRule::custom(|context| context.messages.len() > 10),
+                ModelPreset::new("gpt-4.1"),
+            )
+    }
+
+    #[test]
+    // Verify that the first matching route is used, falling back to the default
+    fn test_route() {
+        let router = router();
+        let question = [Message::new("user", "What is the capital of France?")];
+        let code = [Message::new(
+            "user",
+            "Why does this fail?\n```rust\nlet x: u8 = 256;\n```",
+        )];
+        let long = [Message::new("user", &"word ".repeat(1000))];
+
+        assert_eq!(router.route(&question, None).model(), "gpt-4o-mini");
+        assert_eq!(router.route(&code, None).model(), "gpt-4o");
+        assert_eq!(router.route(&long, Some("pro")).model(), "gpt-4o");
+        assert_eq!(
+            router.route(&code, Some("free")),
+            &ModelPreset::new("gpt-4o-mini").with_max_tokens(256)
+        );
+        assert_eq!(
+            router.route(&vec![question[0].clone(); 11], None).model(),
+            "gpt-4.1"
+        );
+    }
+
+    #[test]
+    // Verify that requests are built with the model and parameters of the preset
+    fn test_request() {
+        let code = [Message::new("user", "```\nfn main() {}\n```")];
+        let request = router().request(&code, None);
+
+        assert_eq!(request.model(), "gpt-4o");
+        assert_eq!(request.temperature(), Some(0.2));
+        assert_eq!(request.messages(), &code);
+    }
+}
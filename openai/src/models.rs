@@ -0,0 +1,130 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing the listing of the models available to an API key.
+
+use ryst_error::InvalidStateError;
+use serde::{Deserialize, Serialize};
+
+use crate::client::OpenAIClient;
+use crate::credentials::KeySource;
+use crate::error::OpenAIError;
+use crate::http::{self, RequestOptions};
+
+/// A model available to the API key, as listed by the API.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Model {
+    pub id: String,
+    /// When the model was created, in seconds since the Unix epoch
+    #[serde(default)]
+    pub created: i64,
+    /// The organization which owns the model
+    #[serde(default)]
+    pub owned_by: String,
+}
+
+impl Model {
+    /// Returns the number of tokens the model can read and write in a single request, if the
+    /// model is known.
+    #[cfg(feature = "tokens")]
+    pub fn context_window(&self) -> Option<usize> {
+        crate::tokens::context_window(&self.id)
+    }
+}
+
+/// The body returned when listing the models.
+#[derive(Deserialize)]
+struct ModelList {
+    data: Vec<Model>,
+}
+
+/// Lists the models available to the API key.
+///
+/// ```no_run
+/// # async fn example() -> Result<(), ryst_openai::OpenAIError> {
+/// use ryst_openai::models::ListModels;
+///
+/// for model in ListModels::new().send().await? {
+///     println!("{}", model.id);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ListModels {
+    options: RequestOptions,
+}
+
+impl ListModels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Where to read the API key from, see `ChatCompletionRequest::with_key_source`.
+    pub fn with_key_source(mut self, key_source: KeySource) -> Self {
+        self.options.key_source = Some(key_source);
+        self
+    }
+
+    /// List the models of another URL, see `ChatCompletionRequest::with_base_url`.
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.options.base_url = Some(base_url.to_string());
+        self
+    }
+
+    /// Send the request through the client, using its credentials.
+    pub fn with_client(mut self, client: OpenAIClient) -> Self {
+        self.options.client = Some(client);
+        self
+    }
+
+    /// List the models, sorted by id.
+    pub async fn send(self) -> Result<Vec<Model>, OpenAIError> {
+        let response = http::get("/v1/models", &self.options).await?;
+        let mut models = response
+            .json::<ModelList>()
+            .await
+            .map_err(|err| {
+                OpenAIError::InvalidState(InvalidStateError::with_message(err.to_string()))
+            })?
+            .data;
+
+        models.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(models)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    // Verify that the list returned by the API is parsed, tolerating missing optional fields
+    fn test_model_list() {
+        let list: ModelList = serde_json::from_value(json!({
+            "object": "list",
+            "data": [
+                {"id": "gpt-4o", "object": "model", "created": 1715367049, "owned_by": "system"},
+                {"id": "local-model", "object": "model"}
+            ]
+        }))
+        .unwrap();
+
+        assert_eq!(list.data[0].owned_by, "system");
+        assert_eq!(list.data[1].id, "local-model");
+        assert_eq!(list.data[1].created, 0);
+    }
+}
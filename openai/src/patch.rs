@@ -0,0 +1,380 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing application of unified diffs, and requesting them from a model.
+
+use std::error::Error;
+use std::fmt;
+
+use crate::client::OpenAIClient;
+use crate::credentials::KeySource;
+use crate::error::OpenAIError;
+use crate::markdown::code_blocks;
+use crate::{ChatCompletionRequest, Message};
+
+const SYSTEM_PROMPT: &str = "You edit files by replying with a unified diff.\n\
+    - Reply with a single ```diff code block and no other text.\n\
+    - Write the diff against the file exactly as given, with --- and +++ headers and @@ hunk \
+    headers.\n\
+    - Include three lines of unchanged context around every change and copy context lines \
+    exactly, including whitespace.\n\
+    - Do not reformat or change lines that the request does not require changing.";
+
+/// Returned when a diff cannot be requested or applied.
+#[derive(Debug)]
+pub enum PatchError {
+    /// The request for the diff failed
+    Request(OpenAIError),
+    /// The diff could not be parsed
+    Malformed(String),
+    /// A hunk does not match the contents it is applied to
+    Conflict(PatchConflict),
+}
+
+/// Describes a hunk that does not apply cleanly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchConflict {
+    /// The position of the hunk in the diff, starting at 1
+    pub hunk: usize,
+    /// The line the hunk header says the hunk starts at in the original, starting at 1
+    pub line: usize,
+    /// The context and removed lines the hunk expected to find
+    pub expected: Vec<String>,
+}
+
+impl Error for PatchError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PatchError::Request(err) => Some(err),
+            PatchError::Malformed(_) | PatchError::Conflict(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::Request(err) => err.fmt(f),
+            PatchError::Malformed(msg) => write!(f, "Malformed diff: {msg}"),
+            PatchError::Conflict(conflict) => write!(
+                f,
+                "Hunk {} at line {} does not apply: expected lines not found",
+                conflict.hunk, conflict.line
+            ),
+        }
+    }
+}
+
+impl From<OpenAIError> for PatchError {
+    fn from(err: OpenAIError) -> Self {
+        PatchError::Request(err)
+    }
+}
+
+/// Asks a model for a unified diff implementing a change, and applies it.
+#[derive(Debug, Clone)]
+pub struct Patcher {
+    model: String,
+    key_source: Option<KeySource>,
+    client: Option<OpenAIClient>,
+}
+
+impl Patcher {
+    /// Create a new `Patcher` using the given model.
+    pub fn new(model: &str) -> Self {
+        Self {
+            model: model.to_string(),
+            key_source: None,
+            client: None,
+        }
+    }
+
+    /// Where to read the API key from, see `ChatCompletionRequest::with_key_source`.
+    pub fn with_key_source(mut self, key_source: KeySource) -> Self {
+        self.key_source = Some(key_source);
+        self
+    }
+
+    /// Send the requests through the client, see `ChatCompletionRequest::with_client`.
+    pub fn with_client(mut self, client: OpenAIClient) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Ask the model for a unified diff making the requested change to the file.
+    pub async fn request_diff(
+        &self,
+        path: &str,
+        contents: &str,
+        instruction: &str,
+    ) -> Result<String, OpenAIError> {
+        let mut request = ChatCompletionRequest::new(
+            &self.model,
+            &[
+                Message::new("system", SYSTEM_PROMPT),
+                Message::new(
+                    "user",
+                    &format!("File: {path}\n```\n{contents}\n```\n\nChange: {instruction}"),
+                ),
+            ],
+        )
+        .with_temperature(0.0);
+
+        if let Some(key_source) = &self.key_source {
+            request = request.with_key_source(key_source.clone());
+        }
+
+        if let Some(client) = &self.client {
+            request = request.with_client(client.clone());
+        }
+
+        let response = request.submit().await?;
+        Ok(extract_diff(
+            response
+                .choices
+                .first()
+                .and_then(|choice| choice.message.content().as_text())
+                .unwrap_or_default(),
+        ))
+    }
+
+    /// Ask the model for a diff making the requested change to the file and apply it, returning
+    /// the patched contents.
+    pub async fn edit(
+        &self,
+        path: &str,
+        contents: &str,
+        instruction: &str,
+    ) -> Result<String, PatchError> {
+        let diff = self.request_diff(path, contents, instruction).await?;
+        apply_patch(contents, &diff)
+    }
+}
+
+/// Take the diff from a model reply, preferring a `diff` or `patch` code block.
+fn extract_diff(reply: &str) -> String {
+    let blocks = code_blocks(reply);
+    blocks
+        .iter()
+        .find(|(language, _)| matches!(language.as_deref(), Some("diff" | "patch")))
+        .or_else(|| blocks.first())
+        .map(|(_, code)| code.clone())
+        .unwrap_or_else(|| reply.to_string())
+}
+
+/// A parsed hunk of a unified diff.
+#[derive(Debug)]
+struct Hunk<'a> {
+    old_start: usize,
+    old: Vec<&'a str>,
+    new: Vec<&'a str>,
+}
+
+/// Apply a unified diff for a single file to its contents, returning the patched contents.
+///
+/// Hunks are located by their context and removed lines, which must match exactly. A hunk may be
+/// found away from the line in its header, which models often get wrong, but hunks must appear
+/// in order and must not overlap.
+pub fn apply_patch(original: &str, diff: &str) -> Result<String, PatchError> {
+    let hunks = parse_hunks(diff)?;
+    if hunks.is_empty() {
+        return Err(PatchError::Malformed("no hunks found".to_string()));
+    }
+
+    let lines: Vec<&str> = original.lines().collect();
+    let mut patched: Vec<&str> = Vec::with_capacity(lines.len());
+    let mut next = 0;
+
+    for (index, hunk) in hunks.iter().enumerate() {
+        let position = locate(&lines, next, hunk).ok_or_else(|| {
+            PatchError::Conflict(PatchConflict {
+                hunk: index + 1,
+                line: hunk.old_start,
+                expected: hunk.old.iter().map(|line| line.to_string()).collect(),
+            })
+        })?;
+
+        patched.extend_from_slice(&lines[next..position]);
+        patched.extend_from_slice(&hunk.new);
+        next = position + hunk.old.len();
+    }
+    patched.extend_from_slice(&lines[next..]);
+
+    let mut result = patched.join("\n");
+    if !result.is_empty() && (original.ends_with('\n') || original.is_empty()) {
+        result.push('\n');
+    }
+    Ok(result)
+}
+
+/// Find where the hunk applies at or after `from`, choosing the match closest to its header.
+fn locate(lines: &[&str], from: usize, hunk: &Hunk) -> Option<usize> {
+    // A header line of 0 means the hunk inserts at the start of the file
+    let stated = if hunk.old.is_empty() {
+        hunk.old_start
+    } else {
+        hunk.old_start.saturating_sub(1)
+    };
+
+    if hunk.old.is_empty() {
+        return (stated >= from && stated <= lines.len()).then_some(stated);
+    }
+
+    (from..=lines.len().checked_sub(hunk.old.len())?)
+        .filter(|start| lines[*start..*start + hunk.old.len()] == hunk.old[..])
+        .min_by_key(|start| start.abs_diff(stated))
+}
+
+fn parse_hunks(diff: &str) -> Result<Vec<Hunk<'_>>, PatchError> {
+    let mut hunks: Vec<Hunk> = Vec::new();
+    let mut files = 0;
+    let lines: Vec<&str> = diff.lines().collect();
+
+    let mut index = 0;
+    while index < lines.len() {
+        let line = lines[index];
+        index += 1;
+
+        // A file header is a --- line followed by a +++ line, which would otherwise be read as a
+        // removed and an added line
+        let is_header = line.starts_with("--- ")
+            && lines
+                .get(index)
+                .is_some_and(|next| next.starts_with("+++ "));
+        if is_header {
+            index += 1;
+            files += 1;
+            if files > 1 {
+                return Err(PatchError::Malformed(
+                    "diff changes more than one file".to_string(),
+                ));
+            }
+        } else if let Some(header) = line.strip_prefix("@@") {
+            hunks.push(Hunk {
+                old_start: parse_old_start(header)?,
+                old: Vec::new(),
+                new: Vec::new(),
+            });
+        } else if let Some(hunk) = hunks.last_mut() {
+            if let Some(removed) = line.strip_prefix('-') {
+                hunk.old.push(removed);
+            } else if let Some(added) = line.strip_prefix('+') {
+                hunk.new.push(added);
+            } else if line.starts_with('\\') {
+                // "\ No newline at end of file"
+            } else {
+                // Models often drop the leading space from blank context lines
+                let context = line.strip_prefix(' ').unwrap_or(line);
+                hunk.old.push(context);
+                hunk.new.push(context);
+            }
+        } else if line.starts_with("diff ") || line.starts_with("index ") || line.is_empty() {
+            // Git headers precede the file headers
+        } else {
+            return Err(PatchError::Malformed(format!(
+                "unexpected line before the first hunk: {line}"
+            )));
+        }
+    }
+
+    Ok(hunks)
+}
+
+/// Parse the old start line from a hunk header such as ` -12,5 +12,6 @@`.
+fn parse_old_start(header: &str) -> Result<usize, PatchError> {
+    header
+        .split_whitespace()
+        .find_map(|range| range.strip_prefix('-'))
+        .and_then(|range| range.split(',').next())
+        .and_then(|start| start.parse().ok())
+        .ok_or_else(|| PatchError::Malformed(format!("invalid hunk header: @@{header}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGINAL: &str =
+        "fn main() {\n    let x = 1;\n    println!(\"{x}\");\n}\n\nfn other() {}\n";
+
+    #[test]
+    // Verify that a diff is applied, even when the hunk header line is wrong
+    fn test_apply_patch() {
+        let diff = "--- a/src/main.rs\n+++ b/src/main.rs\n@@ -5,4 +5,4 @@\n fn main() {\n\
+                    -    let x = 1;\n+    let x = 2;\n     println!(\"{x}\");\n }\n";
+
+        assert_eq!(
+            apply_patch(ORIGINAL, diff).unwrap(),
+            "fn main() {\n    let x = 2;\n    println!(\"{x}\");\n}\n\nfn other() {}\n"
+        );
+    }
+
+    #[test]
+    // Verify that blank context lines without a leading space and insertions are handled
+    fn test_apply_patch_blank_context_and_insert() {
+        let diff = "@@ -0,0 +1,1 @@\n+// header\n@@ -4,3 +5,4 @@\n }\n\n fn other() {}\n\
+                    +fn more() {}\n";
+
+        assert_eq!(
+            apply_patch(ORIGINAL, diff).unwrap(),
+            "// header\nfn main() {\n    let x = 1;\n    println!(\"{x}\");\n}\n\nfn other() {}\n\
+             fn more() {}\n"
+        );
+    }
+
+    #[test]
+    // Verify that a hunk whose lines are not found is reported as a conflict
+    fn test_apply_patch_conflict() {
+        let diff = "@@ -1,2 +1,2 @@\n fn main() {\n-    let y = 1;\n+    let y = 2;\n";
+
+        match apply_patch(ORIGINAL, diff) {
+            Err(PatchError::Conflict(conflict)) => {
+                assert_eq!(conflict.hunk, 1);
+                assert_eq!(conflict.line, 1);
+                assert_eq!(conflict.expected, vec!["fn main() {", "    let y = 1;"]);
+            }
+            other => panic!("expected a conflict, got {other:?}"),
+        }
+    }
+
+    #[test]
+    // Verify that malformed diffs are rejected
+    fn test_apply_patch_malformed() {
+        assert!(matches!(
+            apply_patch(ORIGINAL, "just some text"),
+            Err(PatchError::Malformed(_))
+        ));
+        assert!(matches!(
+            apply_patch(ORIGINAL, "@@ bad @@\n"),
+            Err(PatchError::Malformed(_))
+        ));
+        assert!(matches!(
+            apply_patch(ORIGINAL, "--- a\n+++ a\n@@ -1 +1 @@\n--- b\n+++ b\n"),
+            Err(PatchError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    // Verify that the diff is taken from a code block in the reply when there is one
+    fn test_extract_diff() {
+        assert_eq!(
+            extract_diff("Here you go:\n```diff\n@@ -1 +1 @@\n-a\n+b\n```\n"),
+            "@@ -1 +1 @@\n-a\n+b\n"
+        );
+        assert_eq!(
+            extract_diff("@@ -1 +1 @@\n-a\n+b\n"),
+            "@@ -1 +1 @@\n-a\n+b\n"
+        );
+    }
+}
@@ -0,0 +1,423 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing detection and masking of personally identifiable information in generated
+//! text.
+
+use regex::Regex;
+use ryst_error::InvalidStateError;
+
+use crate::error::OpenAIError;
+use crate::{ChatCompletionResponse, CompletionResponse, ContentPart, MessageContent};
+
+const EMAIL_PATTERN: &str = r"[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,}";
+const CREDIT_CARD_PATTERN: &str = r"\b\d(?:[ -]?\d){12,18}\b";
+const PHONE_PATTERN: &str =
+    r"(?:\+\d{1,3}[\s.-]?)?(?:\(\d{2,4}\)[\s.-]?|\b\d{2,4}[\s.-])\d{3,4}[\s.-]?\d{3,4}\b";
+
+/// The amount of text held back by `PiiStreamFilter` in case a match continues in the next
+/// delta. This is longer than any phone or card number, including separators.
+const STREAM_HOLDBACK: usize = 32;
+
+/// The kind of personally identifiable information detected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PiiKind {
+    Email,
+    Phone,
+    /// A 13 to 19 digit number passing the Luhn check
+    CreditCard,
+}
+
+impl PiiKind {
+    fn mask(&self) -> &'static str {
+        match self {
+            PiiKind::Email => "[EMAIL]",
+            PiiKind::Phone => "[PHONE]",
+            PiiKind::CreditCard => "[CREDIT_CARD]",
+        }
+    }
+}
+
+/// A detected piece of personally identifiable information.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PiiMatch {
+    pub kind: PiiKind,
+    /// The byte offset of the start of the match
+    pub start: usize,
+    /// The byte offset of the end of the match
+    pub end: usize,
+}
+
+/// What to do when personally identifiable information is found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PiiPolicy {
+    /// Replace each match with a placeholder such as `[EMAIL]`
+    Mask,
+    /// Return an error
+    Reject,
+}
+
+/// Detects emails, phone numbers and credit card numbers in text, masking or rejecting them.
+pub struct PiiFilter {
+    policy: PiiPolicy,
+    kinds: Vec<PiiKind>,
+    email: Regex,
+    phone: Regex,
+    credit_card: Regex,
+}
+
+impl PiiFilter {
+    /// Create a new `PiiFilter` which masks every kind of PII.
+    pub fn new() -> Self {
+        Self {
+            policy: PiiPolicy::Mask,
+            kinds: vec![PiiKind::Email, PiiKind::Phone, PiiKind::CreditCard],
+            email: Regex::new(EMAIL_PATTERN).expect("email pattern is valid"),
+            phone: Regex::new(PHONE_PATTERN).expect("phone pattern is valid"),
+            credit_card: Regex::new(CREDIT_CARD_PATTERN).expect("credit card pattern is valid"),
+        }
+    }
+
+    /// Set what happens when PII is found.
+    pub fn with_policy(mut self, policy: PiiPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Only detect the given kinds of PII.
+    pub fn with_kinds(mut self, kinds: &[PiiKind]) -> Self {
+        self.kinds = kinds.to_vec();
+        self
+    }
+
+    /// Find the PII in the text, ordered by position and without overlaps.
+    pub fn detect(&self, text: &str) -> Vec<PiiMatch> {
+        let mut matches = Vec::new();
+
+        // Card numbers are found first so that their digit groups are not reported as phones
+        if self.kinds.contains(&PiiKind::CreditCard) {
+            matches.extend(
+                self.credit_card
+                    .find_iter(text)
+                    .filter(|found| passes_luhn(found.as_str()))
+                    .map(|found| PiiMatch {
+                        kind: PiiKind::CreditCard,
+                        start: found.start(),
+                        end: found.end(),
+                    }),
+            );
+        }
+
+        for (kind, regex) in [(PiiKind::Email, &self.email), (PiiKind::Phone, &self.phone)] {
+            if !self.kinds.contains(&kind) {
+                continue;
+            }
+
+            for found in regex.find_iter(text) {
+                if kind == PiiKind::Phone && in_digit_run(text, found.start(), found.end()) {
+                    continue;
+                }
+
+                let overlaps = matches
+                    .iter()
+                    .any(|other: &PiiMatch| found.start() < other.end && other.start < found.end());
+                if !overlaps {
+                    matches.push(PiiMatch {
+                        kind,
+                        start: found.start(),
+                        end: found.end(),
+                    });
+                }
+            }
+        }
+
+        matches.sort_by_key(|found| found.start);
+        matches
+    }
+
+    /// Apply the policy to the text, returning the masked text or an error if PII is rejected.
+    pub fn filter(&self, text: &str) -> Result<String, OpenAIError> {
+        let matches = self.detect(text);
+        self.apply(text, &matches)
+    }
+
+    /// Apply the policy to the text of every choice in a chat completion response.
+    pub fn filter_chat_response(
+        &self,
+        response: &mut ChatCompletionResponse,
+    ) -> Result<(), OpenAIError> {
+        for choice in response.choices.iter_mut() {
+            match &mut choice.message.content {
+                MessageContent::Text(text) => *text = self.filter(text)?,
+                MessageContent::Parts(parts) => {
+                    for part in parts.iter_mut() {
+                        if let ContentPart::Text { text } = part {
+                            *text = self.filter(text)?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the policy to the text of every choice in a completion response.
+    pub fn filter_completion_response(
+        &self,
+        response: &mut CompletionResponse,
+    ) -> Result<(), OpenAIError> {
+        for choice in response.choices.iter_mut() {
+            choice.text = self.filter(&choice.text)?;
+        }
+        Ok(())
+    }
+
+    /// Create a filter for text that arrives in pieces.
+    pub fn stream(&self) -> PiiStreamFilter<'_> {
+        PiiStreamFilter {
+            filter: self,
+            pending: String::new(),
+        }
+    }
+
+    fn apply(&self, text: &str, matches: &[PiiMatch]) -> Result<String, OpenAIError> {
+        if matches.is_empty() {
+            return Ok(text.to_string());
+        }
+
+        if self.policy == PiiPolicy::Reject {
+            let kinds = matches
+                .iter()
+                .map(|found| format!("{:?}", found.kind))
+                .collect::<Vec<_>>()
+                .join(", ");
+            return Err(OpenAIError::InvalidState(InvalidStateError::with_message(
+                format!("Generated text contains PII: {kinds}"),
+            )));
+        }
+
+        let mut masked = String::with_capacity(text.len());
+        let mut last = 0;
+        for found in matches {
+            masked.push_str(&text[last..found.start]);
+            masked.push_str(found.kind.mask());
+            last = found.end;
+        }
+        masked.push_str(&text[last..]);
+
+        Ok(masked)
+    }
+}
+
+impl Default for PiiFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Applies a `PiiFilter` to text that arrives in pieces, such as streamed deltas.
+///
+/// PII can be split across pieces, so the end of the text is held back until enough of the
+/// following text has arrived to rule out a match continuing into it. Call `finish` once the
+/// stream ends to get the rest of the text.
+pub struct PiiStreamFilter<'a> {
+    filter: &'a PiiFilter,
+    pending: String,
+}
+
+impl<'a> PiiStreamFilter<'a> {
+    /// Add the next piece of text, returning the filtered text that is now safe to emit.
+    pub fn push(&mut self, delta: &str) -> Result<String, OpenAIError> {
+        self.pending.push_str(delta);
+
+        let mut cut = self.pending.len().saturating_sub(STREAM_HOLDBACK);
+        while !self.pending.is_char_boundary(cut) {
+            cut -= 1;
+        }
+
+        // Emails have no upper bound on length, so never split a run of non-whitespace
+        cut -= self.pending[..cut]
+            .rsplit(char::is_whitespace)
+            .next()
+            .map_or(0, str::len);
+
+        let matches = self.filter.detect(&self.pending);
+        if let Some(straddling) = matches
+            .iter()
+            .find(|found| found.start < cut && cut < found.end)
+        {
+            cut = straddling.start;
+        }
+
+        let ready: Vec<PiiMatch> = matches
+            .into_iter()
+            .filter(|found| found.end <= cut)
+            .collect();
+        let output = self.filter.apply(&self.pending[..cut], &ready)?;
+        self.pending.drain(..cut);
+
+        Ok(output)
+    }
+
+    /// Returns the filtered text that was held back.
+    pub fn finish(self) -> Result<String, OpenAIError> {
+        self.filter.filter(&self.pending)
+    }
+}
+
+/// Check whether the match is part of a longer sequence of digit groups, such as an account
+/// number, rather than a phone number on its own.
+fn in_digit_run(text: &str, start: usize, end: usize) -> bool {
+    let is_separator = |c: char| c == ' ' || c == '.' || c == '-';
+
+    let mut after = text[end..].chars();
+    let followed = match after.next() {
+        Some(c) if c.is_ascii_digit() => true,
+        Some(c) if is_separator(c) => after.next().is_some_and(|c| c.is_ascii_digit()),
+        _ => false,
+    };
+
+    let mut before = text[..start].chars().rev();
+    let preceded = match before.next() {
+        Some(c) if c.is_ascii_digit() => true,
+        Some(c) if is_separator(c) => before.next().is_some_and(|c| c.is_ascii_digit()),
+        _ => false,
+    };
+
+    followed || preceded
+}
+
+/// Check whether the digits in the text form a valid card number using the Luhn algorithm.
+fn passes_luhn(text: &str) -> bool {
+    let digits: Vec<u32> = text.chars().filter_map(|c| c.to_digit(10)).collect();
+    if !(13..=19).contains(&digits.len()) {
+        return false;
+    }
+
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, digit)| {
+            if i % 2 == 1 {
+                let doubled = digit * 2;
+                if doubled > 9 {
+                    doubled - 9
+                } else {
+                    doubled
+                }
+            } else {
+                *digit
+            }
+        })
+        .sum();
+
+    sum.is_multiple_of(10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Verify that the Luhn check accepts valid card numbers and rejects others
+    fn test_passes_luhn() {
+        assert!(passes_luhn("4111 1111 1111 1111"));
+        assert!(passes_luhn("5500-0000-0000-0004"));
+        assert!(!passes_luhn("4111 1111 1111 1112"));
+        assert!(!passes_luhn("4111"));
+    }
+
+    #[test]
+    // Verify that each kind of PII is masked
+    fn test_filter_mask() {
+        let filter = PiiFilter::new();
+        let text = "Mail jane.doe@example.co.uk or call (555) 123-4567 / +44 20 7946 0958, \
+                    card 4111 1111 1111 1111 but not order 4111 1111 1111 1112.";
+
+        assert_eq!(
+            filter.filter(text).unwrap(),
+            "Mail [EMAIL] or call [PHONE] / [PHONE], card [CREDIT_CARD] but not order \
+             4111 1111 1111 1112."
+        );
+    }
+
+    #[test]
+    // Verify that dates and short numbers are not reported
+    fn test_detect_false_positives() {
+        let filter = PiiFilter::new();
+        assert!(filter
+            .detect("On 2023-10-16 we shipped 1234 5678 units, version 1.2.3")
+            .is_empty());
+    }
+
+    #[test]
+    // Verify that the reject policy returns an error and that kinds can be limited
+    fn test_filter_reject_and_kinds() {
+        let filter = PiiFilter::new().with_policy(PiiPolicy::Reject);
+        assert!(filter.filter("write to a@b.io").is_err());
+        assert_eq!(filter.filter("nothing here").unwrap(), "nothing here");
+
+        let emails_only = PiiFilter::new().with_kinds(&[PiiKind::Email]);
+        assert_eq!(
+            emails_only.filter("a@b.io 555-123-4567").unwrap(),
+            "[EMAIL] 555-123-4567"
+        );
+    }
+
+    #[test]
+    // Verify that PII split across streamed deltas is masked
+    fn test_stream_filter() {
+        let filter = PiiFilter::new();
+        let mut stream = filter.stream();
+
+        let deltas = [
+            "Sure! You can reach ",
+            "our support team at supp",
+            "ort@example.com or by phone at 555-",
+            "123-4567. Your card ending ",
+            "4111 1111 11",
+            "11 1111 is on file. ",
+            "Have a nice day and a long tail of text that is emitted.",
+        ];
+
+        let mut output = String::new();
+        for delta in deltas {
+            output.push_str(&stream.push(delta).unwrap());
+        }
+        output.push_str(&stream.finish().unwrap());
+
+        assert_eq!(output, filter.filter(&deltas.concat()).unwrap(),);
+        assert!(output.contains("[EMAIL]"));
+        assert!(output.contains("[PHONE]"));
+        assert!(output.contains("[CREDIT_CARD]"));
+    }
+
+    #[test]
+    // Verify that filtering a response masks the text of every choice
+    fn test_filter_completion_response() {
+        let mut response: CompletionResponse = serde_json::from_str(
+            r#"{"id":"cmpl-1","object":"text_completion","created":0,"model":"babbage-002",
+            "choices":[{"text":"email me at x@y.com","index":0,"logprobs":null,
+            "finish_reason":"stop"}],
+            "usage":{"prompt_tokens":1,"completion_tokens":1,"total_tokens":2}}"#,
+        )
+        .unwrap();
+
+        PiiFilter::new()
+            .filter_completion_response(&mut response)
+            .unwrap();
+        assert_eq!(response.choices[0].text, "email me at [EMAIL]");
+    }
+}
@@ -0,0 +1,459 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing a pipeline of steps which checkpoints its results to disk.
+//!
+//! Each step reads named values produced by the pipeline's inputs or earlier steps and produces
+//! one named value. After every step the values are written to a checkpoint file, so a pipeline
+//! which is interrupted resumes from the last completed step instead of repeating requests.
+
+use std::collections::HashMap;
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use ryst_error::{InternalError, InvalidArgumentError, InvalidStateError};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::client::OpenAIClient;
+use crate::credentials::KeySource;
+#[cfg(feature = "encryption")]
+use crate::encryption::EncryptionKey;
+use crate::error::OpenAIError;
+use crate::{ChatCompletionRequest, ChatCompletionResponse};
+
+type BuildRequest = dyn Fn(&[Value]) -> Result<ChatCompletionRequest, OpenAIError> + Send + Sync;
+type Transform = dyn Fn(&[Value]) -> Result<Value, OpenAIError> + Send + Sync;
+type Handler = dyn Fn(
+        ChatCompletionRequest,
+    ) -> Pin<Box<dyn Future<Output = Result<ChatCompletionResponse, OpenAIError>> + Send>>
+    + Send
+    + Sync;
+
+enum Action {
+    Chat(Box<BuildRequest>),
+    Transform(Box<Transform>),
+}
+
+struct Step {
+    name: String,
+    inputs: Vec<String>,
+    output: String,
+    action: Action,
+}
+
+/// The completed steps and values of a pipeline, as written to the checkpoint file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Checkpoint {
+    completed: Vec<String>,
+    values: HashMap<String, Value>,
+}
+
+/// An ordered list of steps which checkpoints the value produced by each step.
+///
+/// The checkpoint records the names of the completed steps. Resuming with a pipeline whose steps
+/// do not start with the completed ones is an error; call `reset` to start over.
+pub struct Pipeline {
+    checkpoint: PathBuf,
+    steps: Vec<Step>,
+    key_source: Option<KeySource>,
+    client: Option<OpenAIClient>,
+    handler: Option<Arc<Handler>>,
+    #[cfg(feature = "encryption")]
+    encryption: Option<EncryptionKey>,
+}
+
+impl Pipeline {
+    /// Create an empty pipeline which checkpoints to the file at the path.
+    pub fn new<P: AsRef<Path>>(checkpoint: P) -> Self {
+        Self {
+            checkpoint: checkpoint.as_ref().to_path_buf(),
+            steps: Vec::new(),
+            key_source: None,
+            client: None,
+            handler: None,
+            #[cfg(feature = "encryption")]
+            encryption: None,
+        }
+    }
+
+    /// Add a step which builds a chat request from its inputs and outputs the text of the first
+    /// choice of the response.
+    pub fn with_chat_step<F>(mut self, name: &str, inputs: &[&str], output: &str, build: F) -> Self
+    where
+        F: Fn(&[Value]) -> Result<ChatCompletionRequest, OpenAIError> + Send + Sync + 'static,
+    {
+        self.steps.push(Step {
+            name: name.to_string(),
+            inputs: inputs.iter().map(|input| input.to_string()).collect(),
+            output: output.to_string(),
+            action: Action::Chat(Box::new(build)),
+        });
+        self
+    }
+
+    /// Add a step which computes its output from its inputs without making a request.
+    pub fn with_transform_step<F>(
+        mut self,
+        name: &str,
+        inputs: &[&str],
+        output: &str,
+        transform: F,
+    ) -> Self
+    where
+        F: Fn(&[Value]) -> Result<Value, OpenAIError> + Send + Sync + 'static,
+    {
+        self.steps.push(Step {
+            name: name.to_string(),
+            inputs: inputs.iter().map(|input| input.to_string()).collect(),
+            output: output.to_string(),
+            action: Action::Transform(Box::new(transform)),
+        });
+        self
+    }
+
+    /// Set the source of the API key used to submit chat steps.
+    pub fn with_key_source(mut self, key_source: KeySource) -> Self {
+        self.key_source = Some(key_source);
+        self
+    }
+
+    /// Set the client used to submit chat steps, see `ChatCompletionRequest::with_client`.
+    pub fn with_client(mut self, client: OpenAIClient) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Encrypt the checkpoint with the key.
+    ///
+    /// An unencrypted checkpoint left by an earlier run is still resumed from.
+    #[cfg(feature = "encryption")]
+    pub fn with_encryption(mut self, key: EncryptionKey) -> Self {
+        self.encryption = Some(key);
+        self
+    }
+
+    /// Run chat steps with the handler instead of submitting them to OpenAI.
+    pub fn with_handler<F, Fut>(mut self, handler: F) -> Self
+    where
+        F: Fn(ChatCompletionRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<ChatCompletionResponse, OpenAIError>> + Send + 'static,
+    {
+        self.handler = Some(Arc::new(move |request| Box::pin(handler(request))));
+        self
+    }
+
+    /// Run the steps which have not completed yet, returning every value once all steps have
+    /// completed.
+    ///
+    /// Values restored from the checkpoint take precedence over the inputs.
+    pub async fn run(
+        &self,
+        inputs: HashMap<String, Value>,
+    ) -> Result<HashMap<String, Value>, OpenAIError> {
+        self.check(&inputs)?;
+
+        let mut checkpoint = self.load()?;
+        for (step, completed) in self.steps.iter().zip(&checkpoint.completed) {
+            if &step.name != completed {
+                return Err(OpenAIError::InvalidState(InvalidStateError::with_message(
+                    format!(
+                        "Checkpoint completed step {completed} where the pipeline has {}",
+                        step.name
+                    ),
+                )));
+            }
+        }
+        if checkpoint.completed.len() > self.steps.len() {
+            return Err(OpenAIError::InvalidState(InvalidStateError::with_message(
+                "Checkpoint has more completed steps than the pipeline".to_string(),
+            )));
+        }
+
+        for (name, value) in inputs {
+            checkpoint.values.entry(name).or_insert(value);
+        }
+
+        for step in &self.steps[checkpoint.completed.len()..] {
+            let values = step
+                .inputs
+                .iter()
+                .map(|input| {
+                    checkpoint.values.get(input).cloned().ok_or_else(|| {
+                        OpenAIError::InvalidState(InvalidStateError::with_message(format!(
+                            "Checkpoint is missing value {input} read by step {}",
+                            step.name
+                        )))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let output = match &step.action {
+                Action::Chat(build) => {
+                    let response = self.submit(build(&values)?).await?;
+                    let text = response
+                        .choices
+                        .first()
+                        .and_then(|choice| choice.message.content().as_text())
+                        .ok_or_else(|| {
+                            OpenAIError::InvalidState(InvalidStateError::with_message(format!(
+                                "Step {} received a response without text",
+                                step.name
+                            )))
+                        })?;
+                    Value::String(text.to_string())
+                }
+                Action::Transform(transform) => transform(&values)?,
+            };
+
+            checkpoint.values.insert(step.output.clone(), output);
+            checkpoint.completed.push(step.name.clone());
+            self.save(&checkpoint)?;
+        }
+
+        Ok(checkpoint.values)
+    }
+
+    /// Delete the checkpoint so that the next run starts from the first step.
+    pub fn reset(&self) -> Result<(), OpenAIError> {
+        match fs::remove_file(&self.checkpoint) {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(io_error(err, &self.checkpoint)),
+        }
+    }
+
+    /// Check that every step's inputs are produced before it runs.
+    fn check(&self, inputs: &HashMap<String, Value>) -> Result<(), OpenAIError> {
+        let mut available = inputs.keys().collect::<Vec<_>>();
+        for step in &self.steps {
+            if let Some(missing) = step.inputs.iter().find(|input| !available.contains(input)) {
+                return Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
+                    "inputs",
+                    format!(
+                        "Step {} reads {missing}, which is not produced before it",
+                        step.name
+                    ),
+                )));
+            }
+            available.push(&step.output);
+        }
+        Ok(())
+    }
+
+    async fn submit(
+        &self,
+        mut request: ChatCompletionRequest,
+    ) -> Result<ChatCompletionResponse, OpenAIError> {
+        if let Some(handler) = &self.handler {
+            return handler(request).await;
+        }
+        if let Some(key_source) = &self.key_source {
+            request = request.with_key_source(key_source.clone());
+        }
+        if let Some(client) = &self.client {
+            request = request.with_client(client.clone());
+        }
+        request.submit().await
+    }
+
+    fn load(&self) -> Result<Checkpoint, OpenAIError> {
+        match fs::read(&self.checkpoint) {
+            Ok(bytes) => {
+                #[cfg(feature = "encryption")]
+                let bytes = match &self.encryption {
+                    Some(key) => key.decrypt_if_encrypted(bytes)?,
+                    None => bytes,
+                };
+
+                serde_json::from_slice(&bytes).map_err(|err| {
+                    OpenAIError::InvalidState(InvalidStateError::with_message(format!(
+                        "Checkpoint {} is invalid: {err}",
+                        self.checkpoint.display()
+                    )))
+                })
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Checkpoint::default()),
+            Err(err) => Err(io_error(err, &self.checkpoint)),
+        }
+    }
+
+    /// Write the checkpoint to a temporary file and rename it over the previous one, so an
+    /// interruption never leaves a partially written checkpoint.
+    fn save(&self, checkpoint: &Checkpoint) -> Result<(), OpenAIError> {
+        let bytes = serde_json::to_vec(checkpoint)
+            .map_err(|err| OpenAIError::Internal(InternalError::from_source(Box::new(err))))?;
+        #[cfg(feature = "encryption")]
+        let bytes = match &self.encryption {
+            Some(key) => key.encrypt(&bytes),
+            None => bytes,
+        };
+
+        let mut temporary = self.checkpoint.clone().into_os_string();
+        temporary.push(".tmp");
+        fs::write(&temporary, bytes).map_err(|err| io_error(err, Path::new(&temporary)))?;
+        fs::rename(&temporary, &self.checkpoint).map_err(|err| io_error(err, &self.checkpoint))
+    }
+}
+
+fn io_error(err: std::io::Error, path: &Path) -> OpenAIError {
+    OpenAIError::Internal(InternalError::from_source_with_prefix(
+        Box::new(err),
+        format!("Unable to access checkpoint {}", path.display()),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use serde_json::json;
+
+    use crate::Message;
+
+    fn response(content: &str) -> ChatCompletionResponse {
+        serde_json::from_value(json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 1700000000,
+            "model": "gpt-4o",
+            "choices": [{
+                "message": {"role": "assistant", "content": content},
+                "index": 0,
+                "finish_reason": "stop"
+            }],
+            "usage": {"prompt_tokens": 1, "completion_tokens": 2, "total_tokens": 3}
+        }))
+        .unwrap()
+    }
+
+    fn pipeline(path: &Path, calls: Arc<AtomicU32>, fail_on: &'static str) -> Pipeline {
+        Pipeline::new(path)
+            .with_chat_step("summarize", &["document"], "summary", |values| {
+                let document = values[0].as_str().unwrap_or_default();
+                Ok(ChatCompletionRequest::new(
+                    "gpt-4o",
+                    &[Message::new("user", &format!("Summarize: {document}"))],
+                ))
+            })
+            .with_transform_step("shout", &["summary"], "title", move |values| {
+                if fail_on == "shout" {
+                    return Err(OpenAIError::InvalidState(InvalidStateError::with_message(
+                        "interrupted".to_string(),
+                    )));
+                }
+                Ok(json!(values[0].as_str().unwrap_or_default().to_uppercase()))
+            })
+            .with_handler(move |_| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                async { Ok(response("a short summary")) }
+            })
+    }
+
+    #[tokio::test]
+    // Verify that an interrupted pipeline resumes after the last completed step
+    async fn test_resume() {
+        let path = std::env::temp_dir().join("ryst_test_pipeline_resume.json");
+        let calls = Arc::new(AtomicU32::new(0));
+        let inputs = HashMap::from([("document".to_string(), json!("A long document"))]);
+
+        let interrupted = pipeline(&path, calls.clone(), "shout");
+        interrupted.reset().unwrap();
+        assert!(interrupted.run(inputs.clone()).await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let values = pipeline(&path, calls.clone(), "")
+            .run(inputs)
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(values["summary"], json!("a short summary"));
+        assert_eq!(values["title"], json!("A SHORT SUMMARY"));
+
+        interrupted.reset().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    // Verify that steps reading values which are not produced before them are rejected
+    async fn test_missing_input() {
+        let path = std::env::temp_dir().join("ryst_test_pipeline_missing_input.json");
+        let pipeline = Pipeline::new(&path)
+            .with_transform_step("first", &["later"], "early", |values| Ok(values[0].clone()))
+            .with_transform_step("second", &[], "later", |_| Ok(json!(1)));
+
+        assert!(matches!(
+            pipeline.run(HashMap::new()).await,
+            Err(OpenAIError::InvalidArgument(_))
+        ));
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    // Verify that resuming from a checkpoint which lacks a completed step's value is rejected
+    async fn test_checkpoint_missing_value() {
+        let path = std::env::temp_dir().join("ryst_test_pipeline_missing_value.json");
+        let calls = Arc::new(AtomicU32::new(0));
+        let inputs = HashMap::from([("document".to_string(), json!("A long document"))]);
+        fs::write(&path, r#"{"completed": ["summarize"], "values": {}}"#).unwrap();
+
+        let pipeline = pipeline(&path, calls.clone(), "");
+        match pipeline.run(inputs).await {
+            Err(OpenAIError::InvalidState(err)) => assert!(err.to_string().contains("summary")),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        pipeline.reset().unwrap();
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "encryption")]
+    // Verify that an encrypted checkpoint hides its values and is resumed only with its key
+    async fn test_encrypted_checkpoint() {
+        let path = std::env::temp_dir().join("ryst_test_pipeline_encrypted.json");
+        let calls = Arc::new(AtomicU32::new(0));
+        let inputs = HashMap::from([("document".to_string(), json!("A long document"))]);
+        let key = EncryptionKey::new([6; 32]);
+
+        let interrupted = pipeline(&path, calls.clone(), "shout").with_encryption(key.clone());
+        interrupted.reset().unwrap();
+        assert!(interrupted.run(inputs.clone()).await.is_err());
+
+        let bytes = fs::read(&path).unwrap();
+        assert!(crate::encryption::is_encrypted(&bytes));
+        assert!(!String::from_utf8_lossy(&bytes).contains("summary"));
+
+        assert!(pipeline(&path, calls.clone(), "")
+            .with_encryption(EncryptionKey::new([7; 32]))
+            .run(inputs.clone())
+            .await
+            .is_err());
+
+        let values = pipeline(&path, calls.clone(), "")
+            .with_encryption(key)
+            .run(inputs)
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(values["title"], json!("A SHORT SUMMARY"));
+
+        interrupted.reset().unwrap();
+    }
+}
@@ -0,0 +1,386 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing a `Poller` for waiting on slow operations which are checked by polling.
+
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use ryst_error::InvalidStateError;
+use tokio::sync::Notify;
+
+use crate::clock::{Clock, Jitter, TokioClock};
+use crate::error::OpenAIError;
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_MAX_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_BACKOFF: f64 = 1.5;
+
+/// The result of checking an operation once.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PollStatus<T> {
+    /// The operation is still running, with its progress as a fraction from 0 to 1 if known
+    Pending { progress: Option<f64> },
+    /// The operation finished with the value
+    Ready(T),
+}
+
+/// Reported to the progress callback after each check which found the operation pending.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PollProgress {
+    /// The number of checks made so far
+    pub attempt: u32,
+    /// The time since polling started
+    pub elapsed: Duration,
+    /// The progress reported by the operation, if any
+    pub progress: Option<f64>,
+}
+
+/// Cancels polling from another task; clones cancel the same `Poller`.
+#[derive(Debug, Clone, Default)]
+pub struct PollCancel {
+    cancelled: Arc<AtomicBool>,
+    notify: Arc<Notify>,
+}
+
+impl PollCancel {
+    /// Stop polling. The `Poller` returns an error at its next check or during its current wait.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    /// Returns whether polling has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+type ProgressFn = dyn Fn(PollProgress) + Send + Sync;
+
+/// Repeatedly checks an operation until it is ready, waiting longer between each check.
+///
+/// The wait starts at the interval and is multiplied by the backoff after every check, up to the
+/// maximum interval.
+#[derive(Clone)]
+pub struct Poller {
+    interval: Duration,
+    max_interval: Duration,
+    backoff: f64,
+    timeout: Option<Duration>,
+    progress: Option<Arc<ProgressFn>>,
+    cancel: PollCancel,
+    clock: Arc<dyn Clock>,
+    jitter: Option<Jitter>,
+}
+
+impl Default for Poller {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Poller {
+    /// Create a `Poller` which waits 1 second after the first check, backing off by 1.5 times up
+    /// to 30 seconds, with no timeout.
+    pub fn new() -> Self {
+        Self {
+            interval: DEFAULT_INTERVAL,
+            max_interval: DEFAULT_MAX_INTERVAL,
+            backoff: DEFAULT_BACKOFF,
+            timeout: None,
+            progress: None,
+            cancel: PollCancel::default(),
+            clock: Arc::new(TokioClock),
+            jitter: None,
+        }
+    }
+
+    /// The wait after the first check.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// The longest wait between checks.
+    pub fn with_max_interval(mut self, max_interval: Duration) -> Self {
+        self.max_interval = max_interval;
+        self
+    }
+
+    /// The factor the wait is multiplied by after each check. Values below 1 and NaN are treated
+    /// as 1, and infinity as the largest finite factor.
+    pub fn with_backoff(mut self, backoff: f64) -> Self {
+        self.backoff = if backoff.is_nan() {
+            1.0
+        } else {
+            backoff.clamp(1.0, f64::MAX)
+        };
+        self
+    }
+
+    /// Give up once this much time has passed since polling started.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Call the callback after each check which found the operation pending.
+    pub fn with_progress<F>(mut self, progress: F) -> Self
+    where
+        F: Fn(PollProgress) + Send + Sync + 'static,
+    {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    /// The clock used to measure the timeout and to wait between checks.
+    ///
+    /// Defaults to `TokioClock`, which follows `tokio::time::pause`.
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Randomize each wait, which is seeded with `Jitter::seeded` for reproducible tests.
+    pub fn with_jitter(mut self, jitter: Jitter) -> Self {
+        self.jitter = Some(jitter);
+        self
+    }
+
+    /// Returns a handle which cancels this `Poller`.
+    pub fn cancel_handle(&self) -> PollCancel {
+        self.cancel.clone()
+    }
+
+    /// Check the operation until it is ready, returning its value.
+    ///
+    /// Errors returned by the check are returned immediately. Returns an `InvalidState` error if
+    /// the timeout passes or polling is cancelled.
+    pub async fn run<T, F, Fut>(&self, mut check: F) -> Result<T, OpenAIError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<PollStatus<T>, OpenAIError>>,
+    {
+        let start = self.clock.now();
+        let mut wait = self.interval;
+
+        for attempt in 1.. {
+            if self.cancel.is_cancelled() {
+                return Err(cancelled());
+            }
+
+            let progress = match check().await? {
+                PollStatus::Ready(value) => return Ok(value),
+                PollStatus::Pending { progress } => progress,
+            };
+
+            let elapsed = self.clock.now().saturating_duration_since(start);
+            if let Some(callback) = &self.progress {
+                callback(PollProgress {
+                    attempt,
+                    elapsed,
+                    progress,
+                });
+            }
+
+            let mut sleep = match &self.jitter {
+                Some(jitter) => jitter.apply(wait),
+                None => wait,
+            };
+            if let Some(timeout) = self.timeout {
+                let remaining = timeout.saturating_sub(elapsed);
+                if remaining.is_zero() {
+                    return Err(OpenAIError::InvalidState(InvalidStateError::with_message(
+                        format!("Operation was not ready after {timeout:?}"),
+                    )));
+                }
+                sleep = sleep.min(remaining);
+            }
+
+            // Registered before checking the flag, so a cancel between the two is not missed
+            let notified = self.cancel.notify.notified();
+            if self.cancel.is_cancelled() {
+                return Err(cancelled());
+            }
+            tokio::select! {
+                _ = self.clock.sleep(sleep) => {}
+                _ = notified => return Err(cancelled()),
+            }
+
+            // A wait too long to represent is capped at the longest wait rather than panicking
+            wait = Duration::try_from_secs_f64(wait.as_secs_f64() * self.backoff)
+                .unwrap_or(self.max_interval)
+                .min(self.max_interval);
+        }
+
+        unreachable!("polling only stops by returning")
+    }
+}
+
+impl fmt::Debug for Poller {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Poller")
+            .field("interval", &self.interval)
+            .field("max_interval", &self.max_interval)
+            .field("backoff", &self.backoff)
+            .field("timeout", &self.timeout)
+            .field("cancel", &self.cancel)
+            .field("jitter", &self.jitter)
+            .finish()
+    }
+}
+
+fn cancelled() -> OpenAIError {
+    OpenAIError::InvalidState(InvalidStateError::with_message(
+        "Polling was cancelled".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use crate::clock::ManualClock;
+
+    #[tokio::test]
+    // Verify that the operation is checked until ready, reporting progress in between
+    async fn test_run() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let recorded = reports.clone();
+        let poller = Poller::new()
+            .with_interval(Duration::from_millis(1))
+            .with_progress(move |progress| recorded.lock().unwrap().push(progress.progress));
+
+        let mut checks = 0;
+        let value = poller
+            .run(|| {
+                checks += 1;
+                let status = match checks {
+                    3 => PollStatus::Ready("done"),
+                    _ => PollStatus::Pending {
+                        progress: Some(f64::from(checks) / 3.0),
+                    },
+                };
+                async move { Ok(status) }
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(value, "done");
+        assert_eq!(
+            *reports.lock().unwrap(),
+            vec![Some(1.0 / 3.0), Some(2.0 / 3.0)]
+        );
+    }
+
+    #[tokio::test]
+    // Verify that polling stops when the timeout passes
+    async fn test_timeout() {
+        let poller = Poller::new()
+            .with_interval(Duration::from_millis(5))
+            .with_timeout(Duration::from_millis(20));
+
+        let result = poller
+            .run(|| async { Ok(PollStatus::<()>::Pending { progress: None }) })
+            .await;
+        assert!(matches!(result, Err(OpenAIError::InvalidState(_))));
+    }
+
+    #[tokio::test]
+    // Verify that waits and the timeout follow the clock, so no real time passes
+    async fn test_manual_clock() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let poller = Poller::new()
+            .with_interval(Duration::from_secs(60))
+            .with_timeout(Duration::from_secs(3600))
+            .with_jitter(Jitter::seeded(0.1, 1))
+            .with_clock(clock.clone());
+
+        let result = poller
+            .run(|| async { Ok(PollStatus::<()>::Pending { progress: None }) })
+            .await;
+
+        assert!(matches!(result, Err(OpenAIError::InvalidState(_))));
+        assert_eq!(clock.now() - start, Duration::from_secs(3600));
+    }
+
+    #[tokio::test]
+    // Verify that an infinite backoff or unbounded longest wait caps the wait without panicking
+    async fn test_extreme_backoff() {
+        let pending = || async { Ok(PollStatus::<()>::Pending { progress: None }) };
+
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let result = Poller::new()
+            .with_backoff(f64::INFINITY)
+            .with_timeout(Duration::from_secs(3600))
+            .with_clock(clock.clone())
+            .run(pending)
+            .await;
+        assert!(matches!(result, Err(OpenAIError::InvalidState(_))));
+        assert_eq!(clock.now() - start, Duration::from_secs(3600));
+
+        // The second wait is too long to represent, so it is cut short by the timeout
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let result = Poller::new()
+            .with_backoff(1e300)
+            .with_max_interval(Duration::MAX)
+            .with_timeout(Duration::from_secs(3600))
+            .with_clock(clock.clone())
+            .run(pending)
+            .await;
+        assert!(matches!(result, Err(OpenAIError::InvalidState(_))));
+        assert_eq!(clock.now() - start, Duration::from_secs(3600));
+
+        assert_eq!(Poller::new().with_backoff(f64::INFINITY).backoff, f64::MAX);
+        assert_eq!(Poller::new().with_backoff(f64::NAN).backoff, 1.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    // Verify that the default clock follows a paused tokio runtime
+    async fn test_paused_runtime() {
+        let start = tokio::time::Instant::now();
+        let poller = Poller::new().with_timeout(Duration::from_secs(600));
+
+        let result = poller
+            .run(|| async { Ok(PollStatus::<()>::Pending { progress: None }) })
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(start.elapsed(), Duration::from_secs(600));
+    }
+
+    #[tokio::test]
+    // Verify that cancelling interrupts a wait
+    async fn test_cancel() {
+        let poller = Poller::new().with_interval(Duration::from_secs(60));
+        let cancel = poller.cancel_handle();
+
+        let result = poller
+            .run(|| {
+                cancel.cancel();
+                async { Ok(PollStatus::<()>::Pending { progress: None }) }
+            })
+            .await;
+        assert!(matches!(result, Err(OpenAIError::InvalidState(_))));
+    }
+}
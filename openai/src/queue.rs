@@ -0,0 +1,782 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing a durable queue of chat completion requests.
+//!
+//! Requests are persisted when enqueued and their responses are stored once they complete, so
+//! work survives process restarts. Delivery is at least once: a request which was running when
+//! the process stopped is run again when the store is reopened.
+
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rusqlite::{params, Connection, OptionalExtension};
+use ryst_error::{InternalError, InvalidStateError};
+
+use crate::client::OpenAIClient;
+use crate::clock::{Clock, TokioClock};
+use crate::credentials::KeySource;
+#[cfg(feature = "encryption")]
+use crate::encryption::EncryptionKey;
+use crate::error::OpenAIError;
+use crate::{ChatCompletionRequest, ChatCompletionResponse};
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(1);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// The identifier of an enqueued request.
+pub type JobId = i64;
+
+/// The future returned by the methods of a `QueueStore`.
+pub type StoreFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, OpenAIError>> + Send + 'a>>;
+
+/// The state of a job in a `QueueStore`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    /// Waiting to be run
+    Pending,
+    /// Claimed by a worker
+    Running,
+    /// Run successfully, with the response stored
+    Completed,
+    /// Given up on after the maximum number of attempts, or after an error which retrying
+    /// cannot fix
+    Failed,
+}
+
+impl JobState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Pending => "pending",
+            JobState::Running => "running",
+            JobState::Completed => "completed",
+            JobState::Failed => "failed",
+        }
+    }
+
+    fn parse(state: &str) -> Result<Self, OpenAIError> {
+        match state {
+            "pending" => Ok(JobState::Pending),
+            "running" => Ok(JobState::Running),
+            "completed" => Ok(JobState::Completed),
+            "failed" => Ok(JobState::Failed),
+            _ => Err(OpenAIError::InvalidState(InvalidStateError::with_message(
+                format!("Unknown job state {state}"),
+            ))),
+        }
+    }
+}
+
+/// A job as persisted by a `QueueStore`, with the request and response as JSON.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredJob {
+    pub id: JobId,
+    pub state: JobState,
+    /// The number of times the job has been claimed
+    pub attempts: u32,
+    pub request: String,
+    pub response: Option<String>,
+    /// The error from the most recent failed attempt
+    pub error: Option<String>,
+}
+
+/// Storage backend for a `DurableQueue`.
+///
+/// Implementations must persist each change before their future completes, and should not block
+/// the runtime while doing so.
+pub trait QueueStore: Send + Sync {
+    /// Persist a new pending job.
+    fn push<'a>(&'a self, request: &'a str) -> StoreFuture<'a, JobId>;
+
+    /// Mark the oldest pending job as running, incrementing its attempts, and return it.
+    fn claim(&self) -> StoreFuture<'_, Option<StoredJob>>;
+
+    /// Store the response of a running job and mark it completed.
+    fn complete<'a>(&'a self, id: JobId, response: &'a str) -> StoreFuture<'a, ()>;
+
+    /// Record the error of a running job, returning it to pending if `retry` is set or marking it
+    /// failed otherwise.
+    fn fail<'a>(&'a self, id: JobId, error: &'a str, retry: bool) -> StoreFuture<'a, ()>;
+
+    /// Return every running job to pending, returning how many were recovered.
+    fn recover(&self) -> StoreFuture<'_, usize>;
+
+    /// Look up a job.
+    fn get(&self, id: JobId) -> StoreFuture<'_, Option<StoredJob>>;
+}
+
+/// A `QueueStore` backed by a SQLite database.
+///
+/// Queries run on tokio's blocking thread pool, so they do not stall other tasks.
+pub struct SqliteQueueStore {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteQueueStore {
+    /// Open or create the database at the path.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, OpenAIError> {
+        Self::init(Connection::open(path).map_err(sqlite_error)?)
+    }
+
+    /// Create a database which only lives as long as the store, mainly for tests.
+    pub fn in_memory() -> Result<Self, OpenAIError> {
+        Self::init(Connection::open_in_memory().map_err(sqlite_error)?)
+    }
+
+    fn init(connection: Connection) -> Result<Self, OpenAIError> {
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS ryst_queue (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    state TEXT NOT NULL,
+                    attempts INTEGER NOT NULL DEFAULT 0,
+                    request TEXT NOT NULL,
+                    response TEXT,
+                    error TEXT
+                )",
+            )
+            .map_err(sqlite_error)?;
+
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Run the queries on the blocking thread pool with the connection.
+    fn blocking<'a, T, F>(&self, queries: F) -> StoreFuture<'a, T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || {
+                // A panic part way through a transaction rolls it back, leaving the database
+                // consistent
+                let mut connection = connection
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+                queries(&mut connection)
+            })
+            .await
+            .map_err(|err| {
+                OpenAIError::Internal(InternalError::from_source_with_prefix(
+                    Box::new(err),
+                    "Queue store task failed".to_string(),
+                ))
+            })?
+            .map_err(sqlite_error)
+        })
+    }
+}
+
+impl QueueStore for SqliteQueueStore {
+    fn push<'a>(&'a self, request: &'a str) -> StoreFuture<'a, JobId> {
+        let request = request.to_string();
+        self.blocking(move |connection| {
+            connection.execute(
+                "INSERT INTO ryst_queue (state, request) VALUES (?1, ?2)",
+                params![JobState::Pending.as_str(), request],
+            )?;
+            Ok(connection.last_insert_rowid())
+        })
+    }
+
+    fn claim(&self) -> StoreFuture<'_, Option<StoredJob>> {
+        let row = self.blocking(|connection| {
+            let transaction = connection.transaction()?;
+
+            let id: Option<JobId> = transaction
+                .query_row(
+                    "SELECT id FROM ryst_queue WHERE state = ?1 ORDER BY id LIMIT 1",
+                    params![JobState::Pending.as_str()],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            let Some(id) = id else {
+                return Ok(None);
+            };
+
+            transaction.execute(
+                "UPDATE ryst_queue SET state = ?1, attempts = attempts + 1 WHERE id = ?2",
+                params![JobState::Running.as_str(), id],
+            )?;
+            let row = select(&transaction, id)?;
+            transaction.commit()?;
+            Ok(row)
+        });
+        Box::pin(async move { row.await?.map(StoredRow::into_job).transpose() })
+    }
+
+    fn complete<'a>(&'a self, id: JobId, response: &'a str) -> StoreFuture<'a, ()> {
+        let response = response.to_string();
+        self.blocking(move |connection| {
+            connection.execute(
+                "UPDATE ryst_queue SET state = ?1, response = ?2, error = NULL WHERE id = ?3",
+                params![JobState::Completed.as_str(), response, id],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn fail<'a>(&'a self, id: JobId, error: &'a str, retry: bool) -> StoreFuture<'a, ()> {
+        let state = if retry {
+            JobState::Pending
+        } else {
+            JobState::Failed
+        };
+        let error = error.to_string();
+        self.blocking(move |connection| {
+            connection.execute(
+                "UPDATE ryst_queue SET state = ?1, error = ?2 WHERE id = ?3",
+                params![state.as_str(), error, id],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn recover(&self) -> StoreFuture<'_, usize> {
+        self.blocking(|connection| {
+            connection.execute(
+                "UPDATE ryst_queue SET state = ?1 WHERE state = ?2",
+                params![JobState::Pending.as_str(), JobState::Running.as_str()],
+            )
+        })
+    }
+
+    fn get(&self, id: JobId) -> StoreFuture<'_, Option<StoredJob>> {
+        let row = self.blocking(move |connection| select(connection, id));
+        Box::pin(async move { row.await?.map(StoredRow::into_job).transpose() })
+    }
+}
+
+/// A job as read from the database, before its state is parsed.
+struct StoredRow {
+    id: JobId,
+    state: String,
+    attempts: u32,
+    request: String,
+    response: Option<String>,
+    error: Option<String>,
+}
+
+impl StoredRow {
+    fn into_job(self) -> Result<StoredJob, OpenAIError> {
+        Ok(StoredJob {
+            id: self.id,
+            state: JobState::parse(&self.state)?,
+            attempts: self.attempts,
+            request: self.request,
+            response: self.response,
+            error: self.error,
+        })
+    }
+}
+
+fn select(connection: &Connection, id: JobId) -> rusqlite::Result<Option<StoredRow>> {
+    connection
+        .query_row(
+            "SELECT state, attempts, request, response, error FROM ryst_queue WHERE id = ?1",
+            params![id],
+            |row| {
+                Ok(StoredRow {
+                    id,
+                    state: row.get(0)?,
+                    attempts: row.get(1)?,
+                    request: row.get(2)?,
+                    response: row.get(3)?,
+                    error: row.get(4)?,
+                })
+            },
+        )
+        .optional()
+}
+
+fn sqlite_error(err: rusqlite::Error) -> OpenAIError {
+    OpenAIError::Internal(InternalError::from_source_with_prefix(
+        Box::new(err),
+        "Queue store error".to_string(),
+    ))
+}
+
+/// The status of an enqueued request.
+#[derive(Debug, PartialEq)]
+pub enum JobStatus {
+    /// Waiting to be run, possibly after failed attempts
+    Pending { attempts: u32 },
+    /// Currently being run
+    Running { attempts: u32 },
+    /// Run successfully
+    Completed(ChatCompletionResponse),
+    /// Given up on, with the error from the last attempt
+    Failed { attempts: u32, error: String },
+}
+
+type Handler = dyn Fn(
+        ChatCompletionRequest,
+    ) -> Pin<Box<dyn Future<Output = Result<ChatCompletionResponse, OpenAIError>> + Send>>
+    + Send
+    + Sync;
+
+/// A queue of chat completion requests which are persisted to a `QueueStore` and retried until
+/// they succeed or run out of attempts.
+///
+/// Failed attempts are retried after an exponential backoff, except for `InvalidArgument` errors
+/// such as a rejected request, which fail the job at once. Requests are serialized without their
+/// key source or pre-send hook; the queue's key source is used when they run.
+pub struct DurableQueue<S: QueueStore> {
+    store: S,
+    max_attempts: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+    clock: Arc<dyn Clock>,
+    key_source: Option<KeySource>,
+    client: Option<OpenAIClient>,
+    handler: Option<Arc<Handler>>,
+    #[cfg(feature = "encryption")]
+    encryption: Option<EncryptionKey>,
+}
+
+impl<S: QueueStore> DurableQueue<S> {
+    /// Create a queue over the store, returning any requests left running by a previous process
+    /// to pending. Only one queue should run against a store at a time.
+    pub async fn new(store: S) -> Result<Self, OpenAIError> {
+        store.recover().await?;
+        Ok(Self {
+            store,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            backoff: DEFAULT_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            clock: Arc::new(TokioClock),
+            key_source: None,
+            client: None,
+            handler: None,
+            #[cfg(feature = "encryption")]
+            encryption: None,
+        })
+    }
+
+    /// Set how many times a request is attempted before it is marked failed. Defaults to 3.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Set the wait before the first retry, which doubles with each further attempt. Defaults to
+    /// 1 second.
+    pub fn with_backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Set the longest wait before a retry. Defaults to 60 seconds.
+    pub fn with_max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// The clock used to wait before retrying.
+    ///
+    /// Defaults to `TokioClock`, which follows `tokio::time::pause`.
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Set the source of the API key used to submit requests.
+    pub fn with_key_source(mut self, key_source: KeySource) -> Self {
+        self.key_source = Some(key_source);
+        self
+    }
+
+    /// Set the client used to submit requests, see `ChatCompletionRequest::with_client`.
+    pub fn with_client(mut self, client: OpenAIClient) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Run requests with the handler instead of submitting them to OpenAI.
+    pub fn with_handler<F, Fut>(mut self, handler: F) -> Self
+    where
+        F: Fn(ChatCompletionRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<ChatCompletionResponse, OpenAIError>> + Send + 'static,
+    {
+        self.handler = Some(Arc::new(move |request| Box::pin(handler(request))));
+        self
+    }
+
+    /// Encrypt the requests and responses persisted from now on with the key.
+    ///
+    /// Jobs enqueued before encryption was turned on are still run.
+    #[cfg(feature = "encryption")]
+    pub fn with_encryption(mut self, key: EncryptionKey) -> Self {
+        self.encryption = Some(key);
+        self
+    }
+
+    /// Persist the request, returning its id.
+    pub async fn enqueue(&self, request: &ChatCompletionRequest) -> Result<JobId, OpenAIError> {
+        request.validate()?;
+        let request = self.to_json(request)?;
+        self.store.push(&request).await
+    }
+
+    /// Run pending requests until none are left, returning how many were attempted.
+    ///
+    /// A failed attempt is retried after waiting for the backoff, or for longer if the API asked
+    /// for a longer wait, until the request runs out of attempts.
+    pub async fn run(&self) -> Result<usize, OpenAIError> {
+        let mut attempted = 0;
+
+        loop {
+            // Claimed outside of a `while let`, whose scrutinee would live across the awaits below
+            let job = self.store.claim().await?;
+            let Some(job) = job else {
+                break;
+            };
+            attempted += 1;
+
+            // Errors are kept as text, as the error type cannot be held across an await in a
+            // future which is sent between threads
+            let request = self.read_request(&job).map_err(|err| err.to_string());
+            let outcome = match request {
+                Ok(request) => self
+                    .execute(request)
+                    .await
+                    .and_then(|response| self.to_json(&response))
+                    .map_err(|err| {
+                        let wait = err.retry_after().unwrap_or_default();
+                        let retryable = !matches!(err, OpenAIError::InvalidArgument(_));
+                        (err.to_string(), retryable.then_some(wait))
+                    }),
+                // A request which cannot be read will never succeed
+                Err(err) => Err((err, None)),
+            };
+
+            match outcome {
+                Ok(response) => self.store.complete(job.id, &response).await?,
+                Err((error, Some(wait))) if job.attempts < self.max_attempts => {
+                    self.store.fail(job.id, &error, true).await?;
+                    let wait = wait.max(self.backoff_for(job.attempts));
+                    self.clock.sleep(wait).await;
+                }
+                Err((error, _)) => self.store.fail(job.id, &error, false).await?,
+            }
+        }
+
+        Ok(attempted)
+    }
+
+    /// Look up the status of an enqueued request.
+    pub async fn status(&self, id: JobId) -> Result<Option<JobStatus>, OpenAIError> {
+        let Some(job) = self.store.get(id).await? else {
+            return Ok(None);
+        };
+
+        let status = match job.state {
+            JobState::Pending => JobStatus::Pending {
+                attempts: job.attempts,
+            },
+            JobState::Running => JobStatus::Running {
+                attempts: job.attempts,
+            },
+            JobState::Completed => {
+                let response = self.unseal(job.response.unwrap_or_default())?;
+                JobStatus::Completed(serde_json::from_str(&response).map_err(|err| {
+                    OpenAIError::InvalidState(InvalidStateError::with_message(format!(
+                        "Stored response for job {id} is invalid: {err}"
+                    )))
+                })?)
+            }
+            JobState::Failed => JobStatus::Failed {
+                attempts: job.attempts,
+                error: job.error.unwrap_or_default(),
+            },
+        };
+
+        Ok(Some(status))
+    }
+
+    /// The wait before the retry following the given number of attempts.
+    fn backoff_for(&self, attempts: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+        self.backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+
+    fn read_request(&self, job: &StoredJob) -> Result<ChatCompletionRequest, OpenAIError> {
+        let request = self.unseal(job.request.clone())?;
+        serde_json::from_str(&request).map_err(|err| {
+            OpenAIError::InvalidState(InvalidStateError::with_message(format!(
+                "Stored request for job {} is invalid: {err}",
+                job.id
+            )))
+        })
+    }
+
+    fn to_json<T: serde::Serialize>(&self, value: &T) -> Result<String, OpenAIError> {
+        let json = serde_json::to_string(value)
+            .map_err(|err| OpenAIError::Internal(InternalError::from_source(Box::new(err))))?;
+
+        #[cfg(feature = "encryption")]
+        if let Some(key) = &self.encryption {
+            return Ok(key.encrypt_text(&json));
+        }
+        Ok(json)
+    }
+
+    fn unseal(&self, stored: String) -> Result<String, OpenAIError> {
+        #[cfg(feature = "encryption")]
+        if let Some(key) = &self.encryption {
+            return key.decrypt_text(stored);
+        }
+        Ok(stored)
+    }
+
+    async fn execute(
+        &self,
+        mut request: ChatCompletionRequest,
+    ) -> Result<ChatCompletionResponse, OpenAIError> {
+        if let Some(handler) = &self.handler {
+            return handler(request).await;
+        }
+        if let Some(key_source) = &self.key_source {
+            request = request.with_key_source(key_source.clone());
+        }
+        if let Some(client) = &self.client {
+            request = request.with_client(client.clone());
+        }
+        request.submit().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use ryst_error::InvalidArgumentError;
+    use serde_json::json;
+
+    use crate::clock::ManualClock;
+    use crate::Message;
+
+    fn response(content: &str) -> ChatCompletionResponse {
+        serde_json::from_value(json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 1700000000,
+            "model": "gpt-4o",
+            "choices": [{
+                "message": {"role": "assistant", "content": content},
+                "index": 0,
+                "finish_reason": "stop"
+            }],
+            "usage": {"prompt_tokens": 1, "completion_tokens": 2, "total_tokens": 3}
+        }))
+        .unwrap()
+    }
+
+    fn request(content: &str) -> ChatCompletionRequest {
+        ChatCompletionRequest::new("gpt-4o", &[Message::new("user", content)])
+    }
+
+    #[tokio::test]
+    // Verify that enqueued requests are run and their responses stored
+    async fn test_run() {
+        let queue = DurableQueue::new(SqliteQueueStore::in_memory().unwrap())
+            .await
+            .unwrap()
+            .with_handler(|request: ChatCompletionRequest| async move {
+                let content = request.messages()[0]
+                    .content()
+                    .as_text()
+                    .unwrap_or_default();
+                Ok(response(&content.to_uppercase()))
+            });
+
+        let first = queue.enqueue(&request("hello")).await.unwrap();
+        let second = queue.enqueue(&request("world")).await.unwrap();
+        assert_eq!(
+            queue.status(first).await.unwrap(),
+            Some(JobStatus::Pending { attempts: 0 })
+        );
+
+        fn assert_send<T: Send>(_: &T) {}
+        let run = queue.run();
+        assert_send(&run);
+        assert_eq!(run.await.unwrap(), 2);
+        assert_eq!(
+            queue.status(first).await.unwrap(),
+            Some(JobStatus::Completed(response("HELLO")))
+        );
+        assert_eq!(
+            queue.status(second).await.unwrap(),
+            Some(JobStatus::Completed(response("WORLD")))
+        );
+        assert_eq!(queue.status(100).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    // Verify that failed requests are retried with exponential backoff until they run out of
+    // attempts
+    async fn test_retry() {
+        let clock = ManualClock::new();
+        let started = clock.now();
+        let calls = Arc::new(AtomicU32::new(0));
+        let counter = calls.clone();
+        let queue = DurableQueue::new(SqliteQueueStore::in_memory().unwrap())
+            .await
+            .unwrap()
+            .with_max_attempts(4)
+            .with_backoff(Duration::from_secs(1))
+            .with_max_backoff(Duration::from_secs(3))
+            .with_clock(clock.clone())
+            .with_handler(move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                async {
+                    Err(OpenAIError::InvalidState(InvalidStateError::with_message(
+                        "overloaded".to_string(),
+                    )))
+                }
+            });
+
+        let id = queue.enqueue(&request("hello")).await.unwrap();
+        assert_eq!(queue.run().await.unwrap(), 4);
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+        // Waits of 1, 2 and then 3 rather than 4 seconds, capped by the maximum
+        assert_eq!(clock.now() - started, Duration::from_secs(6));
+
+        match queue.status(id).await.unwrap() {
+            Some(JobStatus::Failed { attempts, error }) => {
+                assert_eq!(attempts, 4);
+                assert!(error.contains("overloaded"));
+            }
+            status => panic!("Unexpected status {status:?}"),
+        }
+    }
+
+    #[tokio::test]
+    // Verify that requests rejected as invalid fail without being retried
+    async fn test_no_retry_invalid_argument() {
+        let clock = ManualClock::new();
+        let started = clock.now();
+        let queue = DurableQueue::new(SqliteQueueStore::in_memory().unwrap())
+            .await
+            .unwrap()
+            .with_clock(clock.clone())
+            .with_handler(|_| async {
+                Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
+                    "model",
+                    "does not exist",
+                )))
+            });
+
+        let id = queue.enqueue(&request("hello")).await.unwrap();
+        assert_eq!(queue.run().await.unwrap(), 1);
+        assert_eq!(clock.now(), started);
+        assert!(matches!(
+            queue.status(id).await.unwrap(),
+            Some(JobStatus::Failed { attempts: 1, .. })
+        ));
+    }
+
+    #[tokio::test]
+    // Verify that requests survive reopening the store, including ones left running
+    async fn test_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queue.sqlite");
+
+        let (pending, running) = {
+            let queue = DurableQueue::new(SqliteQueueStore::open(&path).unwrap())
+                .await
+                .unwrap();
+            let pending = queue.enqueue(&request("one")).await.unwrap();
+            let running = queue.enqueue(&request("two")).await.unwrap();
+            // Simulate a process stopping while a request is running
+            queue.store.claim().await.unwrap();
+            queue.store.claim().await.unwrap();
+            queue
+                .store
+                .fail(pending, "interrupted", true)
+                .await
+                .unwrap();
+            (pending, running)
+        };
+
+        let queue = DurableQueue::new(SqliteQueueStore::open(&path).unwrap())
+            .await
+            .unwrap()
+            .with_handler(|_| async { Ok(response("done")) });
+        assert_eq!(
+            queue.status(running).await.unwrap(),
+            Some(JobStatus::Pending { attempts: 1 })
+        );
+
+        assert_eq!(queue.run().await.unwrap(), 2);
+        assert_eq!(
+            queue.status(pending).await.unwrap(),
+            Some(JobStatus::Completed(response("done")))
+        );
+        assert_eq!(
+            queue.status(running).await.unwrap(),
+            Some(JobStatus::Completed(response("done")))
+        );
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "encryption")]
+    // Verify that requests and responses are stored encrypted, and that jobs enqueued before
+    // encryption was turned on still run
+    async fn test_encryption() {
+        let queue = DurableQueue::new(SqliteQueueStore::in_memory().unwrap())
+            .await
+            .unwrap();
+        let before = queue.enqueue(&request("plain")).await.unwrap();
+
+        let queue = queue
+            .with_encryption(EncryptionKey::new([5; 32]))
+            .with_handler(|_| async { Ok(response("secret answer")) });
+        let after = queue.enqueue(&request("secret question")).await.unwrap();
+        assert!(!queue
+            .store
+            .get(after)
+            .await
+            .unwrap()
+            .unwrap()
+            .request
+            .contains("secret"));
+
+        assert_eq!(queue.run().await.unwrap(), 2);
+        for id in [before, after] {
+            assert!(!queue
+                .store
+                .get(id)
+                .await
+                .unwrap()
+                .unwrap()
+                .response
+                .unwrap()
+                .contains("secret"));
+            assert_eq!(
+                queue.status(id).await.unwrap(),
+                Some(JobStatus::Completed(response("secret answer")))
+            );
+        }
+    }
+}
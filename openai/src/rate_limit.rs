@@ -0,0 +1,342 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing a client-side rate limiter, which spaces out requests to stay within the
+//! account's requests and tokens per minute instead of being rejected with a 429.
+//!
+//! The limiter is set on a client with `OpenAIClient::with_rate_limiter`, or on a request with
+//! `with_rate_limiter`. Clones share the same budget, so one limiter can be given to every
+//! request of a batch. Each request waits until both budgets have room for it, and is charged
+//! one request and an estimate of its tokens: the length of its body divided by four, plus the
+//! tokens it allows for its reply with `max_tokens`, for each choice.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), ryst_openai::OpenAIError> {
+//! use ryst_openai::rate_limit::RateLimiter;
+//! use ryst_openai::OpenAIClient;
+//!
+//! let limiter = RateLimiter::new()
+//!     .with_requests_per_minute(500)?
+//!     .with_tokens_per_minute(200_000)?;
+//! let client = OpenAIClient::new("sk-...").with_rate_limiter(limiter);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ryst_error::InvalidArgumentError;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::clock::{Clock, TokioClock};
+use crate::deadline;
+use crate::error::OpenAIError;
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+/// A token bucket which refills continuously up to a per-minute capacity.
+#[derive(Debug)]
+struct Bucket {
+    per_minute: f64,
+    available: f64,
+}
+
+impl Bucket {
+    fn new(name: &str, per_minute: u32) -> Result<Self, OpenAIError> {
+        if per_minute == 0 {
+            return Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
+                name,
+                "The limit must be at least 1 per minute",
+            )));
+        }
+        Ok(Self {
+            per_minute: f64::from(per_minute),
+            available: f64::from(per_minute),
+        })
+    }
+
+    fn refill(&mut self, elapsed: Duration) {
+        let refilled = self.per_minute * elapsed.as_secs_f64() / MINUTE.as_secs_f64();
+        self.available = (self.available + refilled).min(self.per_minute);
+    }
+
+    /// The cost taken from the bucket, as a cost larger than the capacity could never be paid
+    fn cost(&self, cost: f64) -> f64 {
+        cost.min(self.per_minute)
+    }
+
+    /// How long until the bucket has room for the cost.
+    fn wait(&self, cost: f64) -> Duration {
+        let missing = self.cost(cost) - self.available;
+        if missing <= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(missing / self.per_minute * MINUTE.as_secs_f64())
+    }
+}
+
+#[derive(Debug)]
+struct LimiterState {
+    requests: Option<Bucket>,
+    tokens: Option<Bucket>,
+    refilled_at: Option<Instant>,
+}
+
+/// Limits the requests and tokens sent per minute, waiting before requests which would exceed
+/// either.
+///
+/// A limiter without limits lets every request through.
+#[derive(Clone)]
+pub struct RateLimiter {
+    state: Arc<Mutex<LimiterState>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl RateLimiter {
+    /// Create a limiter without limits.
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(LimiterState {
+                requests: None,
+                tokens: None,
+                refilled_at: None,
+            })),
+            clock: Arc::new(TokioClock),
+        }
+    }
+
+    /// Allow up to the given number of requests per minute.
+    ///
+    /// Returns an `InvalidArgument` error for a limit of 0, which could never be met.
+    pub fn with_requests_per_minute(self, requests: u32) -> Result<Self, OpenAIError> {
+        self.lock().requests = Some(Bucket::new("requests_per_minute", requests)?);
+        Ok(self)
+    }
+
+    /// Allow up to the given number of estimated tokens per minute.
+    ///
+    /// Returns an `InvalidArgument` error for a limit of 0, which could never be met.
+    pub fn with_tokens_per_minute(self, tokens: u32) -> Result<Self, OpenAIError> {
+        self.lock().tokens = Some(Bucket::new("tokens_per_minute", tokens)?);
+        Ok(self)
+    }
+
+    /// The clock used to refill the budgets and to wait for them.
+    ///
+    /// Defaults to `TokioClock`, which follows `tokio::time::pause`.
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Wait until there is room for one request using the given number of tokens, and take it
+    /// from the budgets.
+    ///
+    /// Requests costing more tokens than a minute's budget wait for the full budget. Returns an
+    /// error without waiting if the current task's deadline, see [`crate::deadline`], would pass
+    /// first.
+    pub async fn acquire(&self, tokens: u64) -> Result<(), OpenAIError> {
+        // Token counts are far below where f64 loses precision
+        let tokens = tokens as f64;
+        loop {
+            let wait = {
+                let mut state = self.lock();
+                let now = self.clock.now();
+                let elapsed = state
+                    .refilled_at
+                    .map(|refilled_at| now.saturating_duration_since(refilled_at))
+                    .unwrap_or_default();
+                state.refilled_at = Some(now);
+
+                let LimiterState {
+                    requests,
+                    tokens: token_bucket,
+                    ..
+                } = &mut *state;
+                let mut buckets = [(requests.as_mut(), 1.0), (token_bucket.as_mut(), tokens)];
+                for bucket in buckets.iter_mut().filter_map(|(bucket, _)| bucket.as_mut()) {
+                    bucket.refill(elapsed);
+                }
+
+                let wait = buckets
+                    .iter()
+                    .filter_map(|(bucket, cost)| bucket.as_ref().map(|bucket| bucket.wait(*cost)))
+                    .max()
+                    .unwrap_or_default();
+                if wait.is_zero() {
+                    for (bucket, cost) in buckets {
+                        if let Some(bucket) = bucket {
+                            bucket.available -= bucket.cost(cost);
+                        }
+                    }
+                }
+                wait
+            };
+            if wait.is_zero() {
+                return Ok(());
+            }
+
+            if deadline::remaining().is_some_and(|remaining| remaining <= wait) {
+                return Err(deadline::exceeded());
+            }
+            self.clock.sleep(wait).await;
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, LimiterState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Limiters are equal when they share the same budgets
+impl PartialEq for RateLimiter {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.state, &other.state)
+    }
+}
+
+impl fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.lock();
+        f.debug_struct("RateLimiter")
+            .field(
+                "requests_per_minute",
+                &state.requests.as_ref().map(|bucket| bucket.per_minute),
+            )
+            .field(
+                "tokens_per_minute",
+                &state.tokens.as_ref().map(|bucket| bucket.per_minute),
+            )
+            .finish()
+    }
+}
+
+/// Estimate the tokens a request body will use: its length divided by four, plus the tokens
+/// allowed for the reply for each choice.
+pub(crate) fn estimate_tokens<T: Serialize + ?Sized>(body: Option<&T>) -> u64 {
+    let Some(Ok(body)) = body.map(serde_json::to_value) else {
+        return 0;
+    };
+
+    let prompt = body.to_string().len().div_ceil(4) as u64;
+    let field = |name: &str| body.get(name).and_then(Value::as_u64);
+    let reply = field("max_tokens")
+        .or_else(|| field("max_completion_tokens"))
+        .unwrap_or(0);
+    prompt + reply * field("n").unwrap_or(1).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::clock::ManualClock;
+
+    #[test]
+    // Verify that requests wait for the budget to refill, taking the longest wait of the two
+    fn test_acquire_waits() {
+        let clock = ManualClock::new();
+        let started = clock.now();
+        let limiter = RateLimiter::new()
+            .with_requests_per_minute(2)
+            .unwrap()
+            .with_tokens_per_minute(1000)
+            .unwrap()
+            .with_clock(clock.clone());
+
+        futures::executor::block_on(async {
+            limiter.acquire(100).await.unwrap();
+            limiter.acquire(100).await.unwrap();
+            assert_eq!(clock.now(), started);
+
+            // The third request waits for half a minute to refill one request
+            limiter.acquire(100).await.unwrap();
+            assert_eq!(clock.now() - started, Duration::from_secs(30));
+
+            // Both budgets are short, and the request budget takes longer to refill
+            limiter.acquire(1000).await.unwrap();
+            assert_eq!(clock.now() - started, Duration::from_secs(60));
+        });
+
+        let tokens = RateLimiter::new()
+            .with_tokens_per_minute(600)
+            .unwrap()
+            .with_clock(clock.clone());
+        futures::executor::block_on(async {
+            tokens.acquire(600).await.unwrap();
+            tokens.acquire(300).await.unwrap();
+        });
+        assert_eq!(clock.now() - started, Duration::from_secs(90));
+    }
+
+    #[test]
+    // Verify that a limiter without limits never waits and clones share a budget
+    fn test_unlimited_and_shared() {
+        let clock = ManualClock::new();
+        let started = clock.now();
+        let unlimited = RateLimiter::new().with_clock(clock.clone());
+        let limited = RateLimiter::new()
+            .with_requests_per_minute(1)
+            .unwrap()
+            .with_clock(clock.clone());
+        let shared = limited.clone();
+        assert_eq!(limited, shared);
+        assert_ne!(limited, unlimited);
+
+        futures::executor::block_on(async {
+            for _ in 0..10 {
+                unlimited.acquire(u64::MAX).await.unwrap();
+            }
+            limited.acquire(0).await.unwrap();
+            assert_eq!(clock.now(), started);
+
+            shared.acquire(0).await.unwrap();
+            assert_eq!(clock.now() - started, Duration::from_secs(60));
+        });
+    }
+
+    #[test]
+    // Verify that a limit of 0 is rejected rather than letting every request through
+    fn test_zero_limit() {
+        assert!(matches!(
+            RateLimiter::new().with_requests_per_minute(0),
+            Err(OpenAIError::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            RateLimiter::new().with_tokens_per_minute(0),
+            Err(OpenAIError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    // Verify that the estimate counts the body and the reply of each choice
+    fn test_estimate_tokens() {
+        let body = serde_json::json!({"model": "gpt-4o", "max_tokens": 100, "n": 2});
+        let len = body.to_string().len() as u64;
+
+        assert_eq!(estimate_tokens(Some(&body)), len.div_ceil(4) + 200);
+        assert_eq!(estimate_tokens::<Value>(None), 0);
+    }
+}
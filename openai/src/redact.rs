@@ -0,0 +1,205 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Module containing the redaction applied to prompts and other user content when request,
+//! message and conversation types are formatted with `Debug`.
+//!
+//! Errors and logs often include the `Debug` output of a request, so by default the text a user
+//! sent is replaced with its length and a short hash. The hash lets two log lines be matched up
+//! without revealing what was sent.
+//!
+//! ```
+//! use ryst_openai::redact::{self, Redaction};
+//! use ryst_openai::Message;
+//!
+//! redact::set_debug_redaction(Redaction::Truncated(5));
+//! let message = Message::new("user", "What is the capital of France?");
+//! assert!(format!("{message:?}").contains("\"What \"… (30 chars)"));
+//! # redact::set_debug_redaction(Redaction::default());
+//! ```
+
+use std::fmt::{self, Write};
+use std::sync::RwLock;
+
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+/// The number of hex characters of the hash shown by `Redaction::Hashed`.
+const HASH_LEN: usize = 12;
+
+static REDACTION: RwLock<Redaction> = RwLock::new(Redaction::Hashed);
+
+/// How user content is shown in `Debug` output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Redaction {
+    /// Show the content unchanged
+    Full,
+    /// Show at most this many characters of the content, followed by its length
+    Truncated(usize),
+    /// Show only the length of the content and the start of its SHA-256 hash
+    #[default]
+    Hashed,
+}
+
+/// Set how user content is shown in `Debug` output for the whole process.
+pub fn set_debug_redaction(redaction: Redaction) {
+    *REDACTION.write().unwrap_or_else(|err| err.into_inner()) = redaction;
+}
+
+/// Returns how user content is currently shown in `Debug` output.
+pub fn debug_redaction() -> Redaction {
+    *REDACTION.read().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Formats user content with the process wide redaction when debug printed.
+pub(crate) struct Redacted<'a>(pub &'a str);
+
+impl fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_redacted(f, self.0, debug_redaction())
+    }
+}
+
+/// Formats optional user content with the process wide redaction when debug printed.
+pub(crate) struct RedactedOption<'a>(pub Option<&'a str>);
+
+impl fmt::Debug for RedactedOption<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(text) => f.debug_tuple("Some").field(&Redacted(text)).finish(),
+            None => f.write_str("None"),
+        }
+    }
+}
+
+/// Formats extra request fields with their values redacted when debug printed.
+pub(crate) struct RedactedValues<'a>(pub &'a Map<String, Value>);
+
+impl fmt::Debug for RedactedValues<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.0.iter().map(|(key, value)| (key, RedactedJson(value))))
+            .finish()
+    }
+}
+
+/// Formats headers with only their names when debug printed, as values often hold credentials.
+pub(crate) struct RedactedHeaders<'a>(pub &'a [(String, String)]);
+
+impl fmt::Debug for RedactedHeaders<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|(name, _)| (name, "<redacted>")))
+            .finish()
+    }
+}
+
+struct RedactedJson<'a>(&'a Value);
+
+impl fmt::Debug for RedactedJson<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_redacted(f, &self.0.to_string(), debug_redaction())
+    }
+}
+
+fn write_redacted(f: &mut fmt::Formatter<'_>, text: &str, redaction: Redaction) -> fmt::Result {
+    match redaction {
+        Redaction::Full => fmt::Debug::fmt(text, f),
+        Redaction::Truncated(max_chars) => {
+            let chars = text.chars().count();
+            if chars <= max_chars {
+                return fmt::Debug::fmt(text, f);
+            }
+
+            let end = text
+                .char_indices()
+                .nth(max_chars)
+                .map_or(text.len(), |(index, _)| index);
+            write!(f, "{:?}… ({chars} chars)", &text[..end])
+        }
+        Redaction::Hashed => {
+            let digest = Sha256::digest(text.as_bytes());
+            let mut hex = String::with_capacity(HASH_LEN);
+            for byte in digest.iter().take(HASH_LEN / 2) {
+                let _ = write!(hex, "{byte:02x}");
+            }
+            write!(f, "<{} chars, sha256:{hex}>", text.chars().count())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Explicit<'a>(&'a str, Redaction);
+
+    impl fmt::Debug for Explicit<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write_redacted(f, self.0, self.1)
+        }
+    }
+
+    fn redact(text: &str, redaction: Redaction) -> String {
+        format!("{:?}", Explicit(text, redaction))
+    }
+
+    #[test]
+    // Verify that the full mode debug prints the text unchanged
+    fn test_full() {
+        assert_eq!(redact("say \"hi\"", Redaction::Full), "\"say \\\"hi\\\"\"");
+    }
+
+    #[test]
+    // Verify that the truncated mode keeps whole characters and only marks text it shortened
+    fn test_truncated() {
+        assert_eq!(redact("hello", Redaction::Truncated(5)), "\"hello\"");
+        assert_eq!(
+            redact("héllo wörld", Redaction::Truncated(4)),
+            "\"héll\"… (11 chars)"
+        );
+        assert_eq!(redact("secret", Redaction::Truncated(0)), "\"\"… (6 chars)");
+    }
+
+    #[test]
+    // Verify that the hashed mode hides the text but is stable for the same text
+    fn test_hashed() {
+        let hashed = redact("my secret prompt", Redaction::Hashed);
+        assert!(hashed.starts_with("<16 chars, sha256:"));
+        assert!(!hashed.contains("secret"));
+        assert_eq!(hashed, redact("my secret prompt", Redaction::Hashed));
+        assert_ne!(hashed, redact("my other prompt!", Redaction::Hashed));
+
+        // sha256("") begins with e3b0c442
+        assert_eq!(
+            redact("", Redaction::Hashed),
+            "<0 chars, sha256:e3b0c44298fc>"
+        );
+    }
+
+    #[test]
+    // Verify that optional content shows `None` without redaction
+    fn test_redacted_option() {
+        assert_eq!(format!("{:?}", RedactedOption(None)), "None");
+    }
+
+    #[test]
+    // Verify that header names are shown but their values are not
+    fn test_redacted_headers() {
+        let headers = [("X-Gateway-Token".to_string(), "secret".to_string())];
+        assert_eq!(
+            format!("{:?}", RedactedHeaders(&headers)),
+            "[(\"X-Gateway-Token\", \"<redacted>\")]"
+        );
+    }
+}
@@ -0,0 +1,433 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Module containing a `ConfigWatcher`, which reloads a `PipelineConfig` when its file changes.
+//!
+//! Each config which loads successfully is given the next version number and swapped in
+//! atomically, so a pipeline built from `current` always sees one complete config. A config
+//! which fails to load is rejected and the previous one stays current. Every reload is recorded
+//! in the watcher's audit log, which keeps the most recent records, and with the `tracing`
+//! feature is also emitted as a `tracing` event with the `ryst_openai::reload` target.
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use arc_swap::ArcSwap;
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use ryst_error::InternalError;
+use sha2::{Digest, Sha256};
+
+use crate::config::PipelineConfig;
+use crate::error::OpenAIError;
+use crate::pipeline::Pipeline;
+
+const DEFAULT_AUDIT_CAPACITY: usize = 1000;
+
+/// A config along with the version it was loaded as.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VersionedConfig {
+    /// Starts at 1 and increases with each config which loads successfully
+    pub version: u64,
+    /// The hex encoded SHA-256 hash of the file the config was read from
+    pub fingerprint: String,
+    pub config: PipelineConfig,
+}
+
+impl VersionedConfig {
+    /// Build a pipeline of the config's steps, see `PipelineConfig::pipeline`.
+    ///
+    /// Requests are also tagged with the version of the config as their template version.
+    pub fn pipeline<P: AsRef<Path>>(&self, checkpoint: P) -> Result<Pipeline, OpenAIError> {
+        self.config
+            .versioned_pipeline(checkpoint, Some(&self.version.to_string()))
+    }
+}
+
+/// The result of loading a config file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReloadOutcome {
+    /// The config was loaded and became current
+    Loaded { version: u64, fingerprint: String },
+    /// The file was unchanged since the current config was loaded
+    Unchanged { version: u64 },
+    /// The config could not be loaded and the previous config stayed current
+    Rejected { error: String },
+}
+
+/// An entry of the audit log, recording a reload.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReloadRecord {
+    /// The Unix time in milliseconds of the reload
+    pub at_ms: u64,
+    pub outcome: ReloadOutcome,
+}
+
+struct Shared {
+    path: PathBuf,
+    current: ArcSwap<VersionedConfig>,
+    audit: Mutex<AuditLog>,
+}
+
+/// The most recent reload records, up to a capacity.
+struct AuditLog {
+    capacity: usize,
+    records: VecDeque<ReloadRecord>,
+}
+
+impl AuditLog {
+    /// Append the record, dropping the oldest records beyond the capacity.
+    fn push(&mut self, record: ReloadRecord) {
+        self.records.push_back(record);
+        self.truncate();
+    }
+
+    fn truncate(&mut self) {
+        let overflow = self.records.len().saturating_sub(self.capacity);
+        self.records.drain(..overflow);
+    }
+}
+
+/// Keeps the config in a file current, reloading it whenever the file's directory changes.
+///
+/// The directory rather than the file is watched, so editors which save by replacing the file
+/// are picked up. A reload which sees a partly written file rejects it, so configs are best
+/// deployed by writing a temporary file and renaming it over the config.
+pub struct ConfigWatcher {
+    shared: Arc<Shared>,
+    _watcher: RecommendedWatcher,
+}
+
+impl ConfigWatcher {
+    /// Load the config at the path and start watching it.
+    ///
+    /// Returns an error if the config cannot be loaded or the directory cannot be watched.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, OpenAIError> {
+        let path = path.as_ref().to_path_buf();
+        let shared = Arc::new(Shared::new(path.clone())?);
+
+        let handler_shared = Arc::downgrade(&shared);
+        let file_name = path.file_name().map(|name| name.to_os_string());
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            let Ok(event) = event else {
+                return;
+            };
+            if event.kind.is_access() {
+                return;
+            }
+            let touches_file = event
+                .paths
+                .iter()
+                .any(|changed| changed.file_name().map(|name| name.to_os_string()) == file_name);
+            if let (true, Some(shared)) = (touches_file, handler_shared.upgrade()) {
+                shared.reload();
+            }
+        })
+        .map_err(watch_error)?;
+
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        watcher
+            .watch(directory, RecursiveMode::NonRecursive)
+            .map_err(watch_error)?;
+
+        Ok(Self {
+            shared,
+            _watcher: watcher,
+        })
+    }
+
+    /// Returns the current config.
+    pub fn current(&self) -> Arc<VersionedConfig> {
+        self.shared.current.load_full()
+    }
+
+    /// Load the file now rather than waiting for it to change.
+    pub fn reload(&self) -> ReloadOutcome {
+        self.shared.reload()
+    }
+
+    /// Set the number of records the audit log keeps, defaults to 1000.
+    ///
+    /// Once the log is full, the oldest record is dropped for each new one.
+    pub fn with_audit_capacity(self, capacity: usize) -> Self {
+        {
+            let mut audit = self.shared.audit();
+            audit.capacity = capacity;
+            audit.truncate();
+        }
+        self
+    }
+
+    /// Returns the records of the most recent reloads, oldest first.
+    pub fn audit_log(&self) -> Vec<ReloadRecord> {
+        self.shared.audit().records.iter().cloned().collect()
+    }
+}
+
+impl Shared {
+    fn new(path: PathBuf) -> Result<Self, OpenAIError> {
+        let (config, fingerprint) = load(&path)?;
+        let outcome = ReloadOutcome::Loaded {
+            version: 1,
+            fingerprint: fingerprint.clone(),
+        };
+        report(&path, &outcome);
+
+        Ok(Self {
+            current: ArcSwap::from_pointee(VersionedConfig {
+                version: 1,
+                fingerprint,
+                config,
+            }),
+            audit: Mutex::new(AuditLog {
+                capacity: DEFAULT_AUDIT_CAPACITY,
+                records: VecDeque::from([ReloadRecord {
+                    at_ms: now_ms(),
+                    outcome,
+                }]),
+            }),
+            path,
+        })
+    }
+
+    fn reload(&self) -> ReloadOutcome {
+        // Holding the audit lock keeps concurrent reloads from assigning the same version
+        let mut audit = self.audit();
+
+        let outcome = match load(&self.path) {
+            Ok((config, fingerprint)) => {
+                let current = self.current.load();
+                if current.fingerprint == fingerprint {
+                    ReloadOutcome::Unchanged {
+                        version: current.version,
+                    }
+                } else {
+                    let version = current.version + 1;
+                    self.current.store(Arc::new(VersionedConfig {
+                        version,
+                        fingerprint: fingerprint.clone(),
+                        config,
+                    }));
+                    ReloadOutcome::Loaded {
+                        version,
+                        fingerprint,
+                    }
+                }
+            }
+            Err(err) => ReloadOutcome::Rejected {
+                error: err.to_string(),
+            },
+        };
+
+        report(&self.path, &outcome);
+        audit.push(ReloadRecord {
+            at_ms: now_ms(),
+            outcome: outcome.clone(),
+        });
+        outcome
+    }
+
+    // A panic while holding the lock cannot leave the log inconsistent, so a poisoned lock is
+    // still used
+    fn audit(&self) -> MutexGuard<'_, AuditLog> {
+        self.audit
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn load(path: &Path) -> Result<(PipelineConfig, String), OpenAIError> {
+    // The file is read once, so the fingerprint is always of the contents the config was parsed
+    // from even if the file is written concurrently
+    let contents = fs::read_to_string(path).map_err(|err| {
+        OpenAIError::Internal(InternalError::from_source_with_prefix(
+            Box::new(err),
+            format!("Unable to read config {}", path.display()),
+        ))
+    })?;
+    let config = PipelineConfig::parse(path, &contents)?;
+
+    let mut fingerprint = String::with_capacity(64);
+    for byte in Sha256::digest(contents.as_bytes()) {
+        let _ = write!(fingerprint, "{byte:02x}");
+    }
+
+    Ok((config, fingerprint))
+}
+
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+fn report(path: &Path, outcome: &ReloadOutcome) {
+    #[cfg(feature = "tracing")]
+    match outcome {
+        ReloadOutcome::Loaded {
+            version,
+            fingerprint,
+        } => tracing::info!(
+            target: "ryst_openai::reload",
+            path = %path.display(),
+            version,
+            fingerprint = %fingerprint,
+            "loaded config"
+        ),
+        ReloadOutcome::Unchanged { .. } => (),
+        ReloadOutcome::Rejected { error } => tracing::warn!(
+            target: "ryst_openai::reload",
+            path = %path.display(),
+            error = %error,
+            "rejected config"
+        ),
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn watch_error(err: notify::Error) -> OpenAIError {
+    OpenAIError::Internal(InternalError::from_source_with_prefix(
+        Box::new(err),
+        "Unable to watch config".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn config(model: &str) -> String {
+        format!(
+            "[[steps]]\nname = \"answer\"\ninputs = [\"question\"]\noutput = \"answer\"\n\
+             prompt = \"{{{{question}}}}\"\nmodel = \"{model}\"\n"
+        )
+    }
+
+    fn directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    // Verify that reloads swap in new versions, skip unchanged files and reject invalid configs
+    fn test_reload() {
+        let directory = directory("ryst_test_reload");
+        let path = directory.join("pipeline.toml");
+        fs::write(&path, config("gpt-4o-mini")).unwrap();
+
+        // Reloads are driven by hand, without a watcher racing them
+        let watcher = Shared::new(path.clone()).unwrap();
+        let first = watcher.current.load_full();
+        assert_eq!(first.version, 1);
+        assert_eq!(first.config.steps[0].model.as_deref(), Some("gpt-4o-mini"));
+
+        assert_eq!(watcher.reload(), ReloadOutcome::Unchanged { version: 1 });
+
+        fs::write(&path, config("gpt-4o")).unwrap();
+        let outcome = watcher.reload();
+        assert!(matches!(outcome, ReloadOutcome::Loaded { version: 2, .. }));
+        let second = watcher.current.load_full();
+        assert_eq!(second.config.steps[0].model.as_deref(), Some("gpt-4o"));
+        assert_ne!(second.fingerprint, first.fingerprint);
+        // A config taken before the reload is unaffected by it
+        assert_eq!(first.config.steps[0].model.as_deref(), Some("gpt-4o-mini"));
+
+        fs::write(&path, "[[steps]]\nname = 1").unwrap();
+        assert!(matches!(watcher.reload(), ReloadOutcome::Rejected { .. }));
+        assert_eq!(watcher.current.load().version, 2);
+
+        let log = Vec::from(watcher.audit().records.clone());
+        assert_eq!(log.len(), 4);
+        assert!(matches!(
+            log[0].outcome,
+            ReloadOutcome::Loaded { version: 1, .. }
+        ));
+        assert!(matches!(log[3].outcome, ReloadOutcome::Rejected { .. }));
+        assert!(log.windows(2).all(|pair| pair[0].at_ms <= pair[1].at_ms));
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    // Verify that writing the file is picked up without calling reload
+    fn test_watch() {
+        let directory = directory("ryst_test_reload_watch");
+        let path = directory.join("pipeline.toml");
+        fs::write(&path, config("gpt-4o-mini")).unwrap();
+        let watcher = ConfigWatcher::new(&path).unwrap();
+
+        // Save the way editors do, by replacing the file
+        let temporary = directory.join("pipeline.toml.tmp");
+        fs::write(&temporary, config("gpt-4o")).unwrap();
+        fs::rename(&temporary, &path).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while watcher.current().version == 1 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(
+            watcher.current().config.steps[0].model.as_deref(),
+            Some("gpt-4o")
+        );
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    // Verify that the audit log keeps only the most recent records
+    fn test_audit_capacity() {
+        let directory = directory("ryst_test_reload_audit");
+        let path = directory.join("pipeline.toml");
+        fs::write(&path, config("gpt-4o-mini")).unwrap();
+
+        let watcher = ConfigWatcher::new(&path).unwrap().with_audit_capacity(3);
+        fs::write(&path, config("gpt-4o")).unwrap();
+        watcher.reload();
+        for _ in 0..4 {
+            watcher.reload();
+        }
+
+        let log = watcher.audit_log();
+        assert_eq!(log.len(), 3);
+        assert!(log
+            .iter()
+            .all(|record| matches!(record.outcome, ReloadOutcome::Unchanged { version: 2 })));
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    // Verify that a watcher is not created for a config which does not load
+    fn test_invalid_initial_config() {
+        let directory = directory("ryst_test_reload_invalid");
+        let path = directory.join("pipeline.toml");
+        fs::write(&path, "steps = 1").unwrap();
+
+        assert!(ConfigWatcher::new(&path).is_err());
+        assert!(ConfigWatcher::new(directory.join("missing.toml")).is_err());
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
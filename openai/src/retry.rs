@@ -0,0 +1,397 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing the events reported when a request is sent again, for seeing why requests
+//! took longer than expected.
+//!
+//! Events are passed to the `RetryObserver` set with `with_retry_observer`, and with the
+//! `tracing` feature are also emitted as `tracing` events with the `ryst_openai::retry` target.
+//!
+//! Requests rejected with a 429 can be retried automatically after the wait the API recommends,
+//! by setting a `RateLimitRetry` with `with_rate_limit_retry`. Without one, the recommended wait
+//! is returned in `OpenAIError::RateLimited` for callers to back off themselves.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use reqwest::header::HeaderMap;
+
+use crate::clock::{Clock, Jitter, TokioClock};
+
+/// Describes a request which is about to be sent again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryEvent {
+    /// The API path of the request
+    pub path: String,
+    /// The attempt about to be made, where the first retry is attempt 2
+    pub attempt: u32,
+    /// How long is waited before the attempt
+    pub delay: Duration,
+    /// Why the request is retried
+    pub reason: String,
+    /// The HTTP status of the failed attempt, if a response was received
+    pub status: Option<u16>,
+    /// The wait requested by the `Retry-After` header of the failed attempt, if any
+    pub retry_after: Option<Duration>,
+}
+
+/// Receives an event each time a request is retried, for custom telemetry.
+pub trait RetryObserver: Send + Sync {
+    fn on_retry(&self, event: &RetryEvent);
+}
+
+impl<F> RetryObserver for F
+where
+    F: Fn(&RetryEvent) + Send + Sync,
+{
+    fn on_retry(&self, event: &RetryEvent) {
+        self(event)
+    }
+}
+
+/// A shared observer, which lets the options of a request stay cheap to clone.
+#[derive(Clone)]
+pub(crate) struct SharedRetryObserver(Arc<dyn RetryObserver>);
+
+impl SharedRetryObserver {
+    pub fn new<O: RetryObserver + 'static>(observer: O) -> Self {
+        Self(Arc::new(observer))
+    }
+}
+
+// Observers are equal when they are the same observer
+impl PartialEq for SharedRetryObserver {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for SharedRetryObserver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("RetryObserver")
+    }
+}
+
+/// The wait before retrying when a 429 response does not recommend one.
+const DEFAULT_RATE_LIMIT_DELAY: Duration = Duration::from_secs(1);
+
+/// The longest recommended wait which is retried after, when not set with `with_max_delay`.
+const DEFAULT_MAX_RATE_LIMIT_DELAY: Duration = Duration::from_secs(60);
+
+/// Retries requests rejected with a 429 after the wait recommended by the response's
+/// `retry-after-ms`, `Retry-After` or `x-ratelimit-reset-*` headers.
+///
+/// Responses reporting that the quota is used up, rather than a rate limit reached, are not
+/// retried, as waiting does not help.
+#[derive(Clone)]
+pub struct RateLimitRetry {
+    max_retries: u32,
+    default_delay: Duration,
+    max_delay: Duration,
+    clock: Arc<dyn Clock>,
+    jitter: Option<Jitter>,
+}
+
+impl RateLimitRetry {
+    /// Create a policy which retries a request up to `max_retries` times, waiting up to a minute
+    /// each time.
+    pub fn new(max_retries: u32) -> Self {
+        Self {
+            max_retries,
+            default_delay: DEFAULT_RATE_LIMIT_DELAY,
+            max_delay: DEFAULT_MAX_RATE_LIMIT_DELAY,
+            clock: Arc::new(TokioClock),
+            jitter: None,
+        }
+    }
+
+    /// The wait when the response does not recommend one, which defaults to 1 second.
+    pub fn with_default_delay(mut self, default_delay: Duration) -> Self {
+        self.default_delay = default_delay;
+        self
+    }
+
+    /// The longest wait to retry after. When a longer wait is recommended, the error is returned
+    /// instead.
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// The clock used to wait before retrying.
+    ///
+    /// Defaults to `TokioClock`, which follows `tokio::time::pause`.
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Randomize each wait, which is seeded with `Jitter::seeded` for reproducible tests.
+    pub fn with_jitter(mut self, jitter: Jitter) -> Self {
+        self.jitter = Some(jitter);
+        self
+    }
+
+    /// Returns how long to wait before the retry after `retries` earlier retries, or `None` if
+    /// the request should not be retried.
+    pub(crate) fn delay(
+        &self,
+        retries: u32,
+        retry_after: Option<Duration>,
+        body: &str,
+    ) -> Option<Duration> {
+        if retries >= self.max_retries || body.contains("insufficient_quota") {
+            return None;
+        }
+
+        let delay = retry_after.unwrap_or(self.default_delay);
+        if delay > self.max_delay {
+            return None;
+        }
+        let delay = match &self.jitter {
+            // Only lengthen the recommended wait, so the retry is not rejected for being early
+            Some(jitter) if retry_after.is_some() => delay.max(jitter.apply(delay)),
+            Some(jitter) => jitter.apply(delay),
+            None => delay,
+        };
+        Some(delay.min(self.max_delay))
+    }
+
+    pub(crate) async fn sleep(&self, delay: Duration) {
+        self.clock.sleep(delay).await
+    }
+}
+
+// Policies are equal when they have the same settings and share a clock
+impl PartialEq for RateLimitRetry {
+    fn eq(&self, other: &Self) -> bool {
+        self.max_retries == other.max_retries
+            && self.default_delay == other.default_delay
+            && self.max_delay == other.max_delay
+            && Arc::ptr_eq(&self.clock, &other.clock)
+    }
+}
+
+impl fmt::Debug for RateLimitRetry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RateLimitRetry")
+            .field("max_retries", &self.max_retries)
+            .field("default_delay", &self.default_delay)
+            .field("max_delay", &self.max_delay)
+            .field("jitter", &self.jitter)
+            .finish()
+    }
+}
+
+/// Returns the wait recommended by a response's headers, if any.
+///
+/// OpenAI's `retry-after-ms` is preferred for its precision, then the standard `Retry-After` in
+/// seconds or as a date. Otherwise the longest of the `x-ratelimit-reset-requests` and
+/// `x-ratelimit-reset-tokens` durations, such as `6m0s`, is used.
+pub(crate) fn retry_after(headers: &HeaderMap, now: SystemTime) -> Option<Duration> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+    };
+
+    if let Some(ms) = header("retry-after-ms").and_then(|value| value.parse::<f64>().ok()) {
+        return Duration::try_from_secs_f64(ms / 1000.0).ok();
+    }
+
+    if let Some(value) = header("retry-after") {
+        return match value.parse::<f64>() {
+            Ok(seconds) => Duration::try_from_secs_f64(seconds).ok(),
+            Err(_) => httpdate::parse_http_date(value)
+                .ok()
+                .map(|date| date.duration_since(now).unwrap_or_default()),
+        };
+    }
+
+    ["x-ratelimit-reset-requests", "x-ratelimit-reset-tokens"]
+        .into_iter()
+        .filter_map(|name| header(name).and_then(parse_reset))
+        .max()
+}
+
+/// Parse a duration such as `1s`, `6m0s` or `20ms`, as used by the `x-ratelimit-reset-*`
+/// headers.
+pub(crate) fn parse_reset(value: &str) -> Option<Duration> {
+    let mut total = Duration::ZERO;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let split = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .filter(|split| *split > 0)?;
+        let (number, units) = rest.split_at(split);
+        let number = number.parse::<f64>().ok()?;
+
+        let unit_len = units
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(units.len());
+        let seconds = match &units[..unit_len] {
+            "ms" => number / 1000.0,
+            "s" => number,
+            "m" => number * 60.0,
+            "h" => number * 3600.0,
+            _ => return None,
+        };
+        total = total.checked_add(Duration::try_from_secs_f64(seconds).ok()?)?;
+        rest = &units[unit_len..];
+    }
+    (!value.is_empty()).then_some(total)
+}
+
+/// Report a retry to the observer, if there is one, and as a `tracing` event.
+pub(crate) fn report(observer: Option<&SharedRetryObserver>, event: RetryEvent) {
+    #[cfg(feature = "tracing")]
+    tracing::warn!(
+        target: "ryst_openai::retry",
+        path = %event.path,
+        attempt = event.attempt,
+        delay_ms = event.delay.as_millis() as u64,
+        reason = %event.reason,
+        status = event.status,
+        retry_after_ms = event.retry_after.map(|retry_after| retry_after.as_millis() as u64),
+        "retrying request"
+    );
+
+    if let Some(SharedRetryObserver(observer)) = observer {
+        observer.on_retry(&event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    #[test]
+    // Verify that a closure observer receives reported events
+    fn test_report() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        let observer = SharedRetryObserver::new(move |event: &RetryEvent| {
+            recorded.lock().unwrap().push(event.clone());
+        });
+
+        let event = RetryEvent {
+            path: "/v1/chat/completions".to_string(),
+            attempt: 2,
+            delay: Duration::ZERO,
+            reason: "API key refreshed".to_string(),
+            status: Some(401),
+            retry_after: None,
+        };
+        report(Some(&observer), event.clone());
+        report(None, event.clone());
+
+        assert_eq!(*events.lock().unwrap(), vec![event]);
+    }
+
+    #[test]
+    // Verify that the recommended wait is read from each of the headers, in order of preference
+    fn test_retry_after() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let headers = |pairs: &[(&'static str, &str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(*name, value.parse().unwrap());
+            }
+            headers
+        };
+
+        assert_eq!(retry_after(&headers(&[]), now), None);
+        assert_eq!(
+            retry_after(
+                &headers(&[("retry-after-ms", "1500"), ("retry-after", "2")]),
+                now
+            ),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(
+            retry_after(&headers(&[("retry-after", "2")]), now),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            retry_after(
+                &headers(&[(
+                    "retry-after",
+                    &httpdate::fmt_http_date(now + Duration::from_secs(30))
+                )]),
+                now
+            ),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            retry_after(
+                &headers(&[
+                    ("x-ratelimit-reset-requests", "1s"),
+                    ("x-ratelimit-reset-tokens", "6m0.5s")
+                ]),
+                now
+            ),
+            Some(Duration::from_millis(360_500))
+        );
+        assert_eq!(parse_reset("20ms"), Some(Duration::from_millis(20)));
+        assert_eq!(parse_reset("1h2m"), Some(Duration::from_secs(3720)));
+        assert_eq!(parse_reset("soon"), None);
+        assert_eq!(parse_reset("5"), None);
+        assert_eq!(parse_reset(""), None);
+        assert_eq!(
+            parse_reset("10000000000000000000s10000000000000000000s"),
+            None
+        );
+    }
+
+    #[test]
+    // Verify that the retry delay follows the recommendation within the policy's limits
+    fn test_rate_limit_retry_delay() {
+        let policy = RateLimitRetry::new(2).with_max_delay(Duration::from_secs(10));
+
+        assert_eq!(
+            policy.delay(0, Some(Duration::from_secs(3)), ""),
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(policy.delay(1, None, ""), Some(DEFAULT_RATE_LIMIT_DELAY));
+        assert_eq!(policy.delay(2, None, ""), None);
+        assert_eq!(policy.delay(0, Some(Duration::from_secs(11)), ""), None);
+        assert_eq!(
+            policy.delay(0, None, r#"{"error":{"code":"insufficient_quota"}}"#),
+            None
+        );
+
+        let jittered = policy.with_jitter(Jitter::seeded(0.5, 3));
+        for _ in 0..10 {
+            let delay = jittered.delay(0, Some(Duration::from_secs(2)), "").unwrap();
+            assert!(delay >= Duration::from_secs(2));
+        }
+
+        // Jitter neither pushes a wait past the maximum nor overflows on a huge recommendation
+        for _ in 0..10 {
+            let delay = jittered.delay(0, Some(Duration::from_secs(9)), "").unwrap();
+            assert!(delay <= Duration::from_secs(10));
+        }
+        assert_eq!(jittered.delay(0, Some(Duration::MAX), ""), None);
+        let unbounded = RateLimitRetry::new(1)
+            .with_max_delay(Duration::MAX)
+            .with_jitter(Jitter::seeded(1.0, 3));
+        for _ in 0..10 {
+            assert!(unbounded.delay(0, Some(Duration::MAX), "").is_some());
+        }
+    }
+}
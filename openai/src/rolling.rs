@@ -0,0 +1,67 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing the rolling window of recent bytes kept while reading a stream chunk by
+//! chunk.
+
+/// The most recent bytes read from a stream, up to a fixed capacity.
+#[derive(Debug, Clone)]
+pub(crate) struct RollingWindow {
+    capacity: usize,
+    bytes: Vec<u8>,
+}
+
+impl RollingWindow {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            bytes: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Append the bytes, dropping the oldest bytes beyond the capacity.
+    pub fn push(&mut self, bytes: &[u8]) {
+        let bytes = &bytes[bytes.len().saturating_sub(self.capacity)..];
+        let overflow = (self.bytes.len() + bytes.len()).saturating_sub(self.capacity);
+        self.bytes.drain(..overflow);
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Verify that only the most recent bytes up to the capacity are kept
+    fn test_rolling_window() {
+        let mut window = RollingWindow::new(5);
+        window.push(b"abc");
+        assert_eq!(window.as_bytes(), b"abc");
+
+        window.push(b"def");
+        assert_eq!(window.as_bytes(), b"bcdef");
+
+        window.push(b"0123456789");
+        assert_eq!(window.as_bytes(), b"56789");
+
+        let mut empty = RollingWindow::new(0);
+        empty.push(b"abc");
+        assert!(empty.as_bytes().is_empty());
+    }
+}
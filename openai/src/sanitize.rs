@@ -0,0 +1,264 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing utilities for cleaning up user-provided content before it is submitted.
+
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{ContentPart, Message, MessageContent};
+
+/// The Unicode normalization form applied by a `Sanitizer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Normalization {
+    /// Canonical composition, which only merges equivalent representations of the same text
+    Nfc,
+    /// Compatibility composition, which also folds look-alike characters such as full-width
+    /// letters and ligatures into their plain forms
+    Nfkc,
+}
+
+/// Cleans up text before it is sent to the model.
+///
+/// By default, control characters and invisible formatting characters (zero-width spaces and
+/// bidirectional overrides) are removed, text is NFC normalized, runs of whitespace are
+/// collapsed and the result is trimmed. Each step can be turned off.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sanitizer {
+    strip_control_chars: bool,
+    strip_invisible_chars: bool,
+    normalization: Option<Normalization>,
+    collapse_whitespace: bool,
+    trim: bool,
+}
+
+impl Default for Sanitizer {
+    fn default() -> Self {
+        Self {
+            strip_control_chars: true,
+            strip_invisible_chars: true,
+            normalization: Some(Normalization::Nfc),
+            collapse_whitespace: true,
+            trim: true,
+        }
+    }
+}
+
+impl Sanitizer {
+    /// Create a new `Sanitizer` with every step enabled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remove control characters other than newlines and tabs.
+    pub fn with_strip_control_chars(mut self, strip_control_chars: bool) -> Self {
+        self.strip_control_chars = strip_control_chars;
+        self
+    }
+
+    /// Remove zero-width spaces, byte order marks and bidirectional overrides and isolates, which
+    /// can be used to hide instructions from anyone reviewing the text. Zero-width joiners and
+    /// non-joiners are kept.
+    pub fn with_strip_invisible_chars(mut self, strip_invisible_chars: bool) -> Self {
+        self.strip_invisible_chars = strip_invisible_chars;
+        self
+    }
+
+    /// The Unicode normalization to apply, or `None` to leave the text as is.
+    pub fn with_normalization(mut self, normalization: Option<Normalization>) -> Self {
+        self.normalization = normalization;
+        self
+    }
+
+    /// Collapse runs of spaces and tabs into a single space, remove trailing whitespace from
+    /// each line and allow at most one blank line in a row.
+    pub fn with_collapse_whitespace(mut self, collapse_whitespace: bool) -> Self {
+        self.collapse_whitespace = collapse_whitespace;
+        self
+    }
+
+    /// Remove leading and trailing whitespace.
+    pub fn with_trim(mut self, trim: bool) -> Self {
+        self.trim = trim;
+        self
+    }
+
+    /// Returns the sanitized text.
+    pub fn sanitize(&self, text: &str) -> String {
+        let mut result: String = text
+            .chars()
+            .filter(|c| !(self.strip_control_chars && is_stripped_control(*c)))
+            .filter(|c| !(self.strip_invisible_chars && is_invisible(*c)))
+            .collect();
+
+        result = match self.normalization {
+            Some(Normalization::Nfc) => result.nfc().collect(),
+            Some(Normalization::Nfkc) => result.nfkc().collect(),
+            None => result,
+        };
+
+        if self.collapse_whitespace {
+            result = collapse_whitespace(&result);
+        }
+
+        if self.trim {
+            result = result.trim().to_string();
+        }
+
+        result
+    }
+
+    /// Returns a copy of the message with its text, including any text parts, sanitized.
+    pub fn sanitize_message(&self, message: &Message) -> Message {
+        let content = match &message.content {
+            MessageContent::Text(text) => MessageContent::Text(self.sanitize(text)),
+            MessageContent::Parts(parts) => MessageContent::Parts(
+                parts
+                    .iter()
+                    .map(|part| match part {
+                        ContentPart::Text { text } => ContentPart::Text {
+                            text: self.sanitize(text),
+                        },
+                        part => part.clone(),
+                    })
+                    .collect(),
+            ),
+        };
+
+        Message {
+            content,
+            ..message.clone()
+        }
+    }
+}
+
+fn is_stripped_control(c: char) -> bool {
+    c.is_control() && c != '\n' && c != '\t'
+}
+
+// Zero-width joiners and non-joiners and the left-to-right and right-to-left marks are kept, as
+// emoji sequences and scripts such as Persian need them to render correctly
+fn is_invisible(c: char) -> bool {
+    matches!(
+        c,
+        '\u{200B}' | '\u{202A}'..='\u{202E}' | '\u{2060}'..='\u{2064}' | '\u{2066}'..='\u{2069}'
+            | '\u{FEFF}'
+    )
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut blank_lines = 0;
+
+    for (i, line) in text.split('\n').enumerate() {
+        let collapsed = line.split_whitespace().collect::<Vec<_>>().join(" ");
+
+        if collapsed.is_empty() {
+            blank_lines += 1;
+            if blank_lines > 1 {
+                continue;
+            }
+        } else {
+            blank_lines = 0;
+        }
+
+        if i > 0 {
+            result.push('\n');
+        }
+        result.push_str(&collapsed);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Verify that the default sanitizer applies every step
+    fn test_sanitize_default() {
+        let text = "  Hello\u{0007}\u{200B} \t world \r\n\n\n\nIgnore\u{202E}  previous\u{0000}  ";
+        assert_eq!(
+            Sanitizer::new().sanitize(text),
+            "Hello world\n\nIgnore previous"
+        );
+    }
+
+    #[test]
+    // Verify that joiners needed by emoji sequences and Persian words are kept
+    fn test_sanitize_keeps_joiners() {
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        assert_eq!(Sanitizer::new().sanitize(family), family);
+
+        let persian = "\u{0645}\u{06CC}\u{200C}\u{062E}\u{0648}\u{0627}\u{0647}\u{0645}";
+        assert_eq!(Sanitizer::new().sanitize(persian), persian);
+        assert_eq!(
+            Sanitizer::new().sanitize(&format!("\u{202E}{persian}\u{200B}")),
+            persian
+        );
+    }
+
+    #[test]
+    // Verify that text is normalized to the configured form
+    fn test_sanitize_normalization() {
+        let decomposed = "e\u{0301}";
+        assert_eq!(Sanitizer::new().sanitize(decomposed), "\u{00E9}");
+
+        let full_width = "\u{FF21}\u{FF22}";
+        assert_eq!(Sanitizer::new().sanitize(full_width), full_width);
+        assert_eq!(
+            Sanitizer::new()
+                .with_normalization(Some(Normalization::Nfkc))
+                .sanitize(full_width),
+            "AB"
+        );
+    }
+
+    #[test]
+    // Verify that each step can be disabled
+    fn test_sanitize_disabled_steps() {
+        let sanitizer = Sanitizer::new()
+            .with_strip_control_chars(false)
+            .with_strip_invisible_chars(false)
+            .with_normalization(None)
+            .with_collapse_whitespace(false)
+            .with_trim(false);
+
+        let text = " a\u{0007}\u{200B}  b ";
+        assert_eq!(sanitizer.sanitize(text), text);
+    }
+
+    #[test]
+    // Verify that only the text of a message is sanitized
+    fn test_sanitize_message() {
+        let message = Message::with_parts(
+            "user",
+            &[
+                ContentPart::text("  what   is this?\u{200B}"),
+                ContentPart::image_url("https://example.com/a  b.png"),
+            ],
+        );
+
+        assert_eq!(
+            Sanitizer::new().sanitize_message(&message),
+            Message::with_parts(
+                "user",
+                &[
+                    ContentPart::text("what is this?"),
+                    ContentPart::image_url("https://example.com/a  b.png"),
+                ],
+            )
+        );
+    }
+}
@@ -0,0 +1,70 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! JSON Schemas of the types sent to and received from the API.
+//!
+//! The schemas describe the JSON produced and accepted by this crate, allowing payloads to be
+//! validated by gateways and clients written in other languages.
+
+use std::collections::BTreeMap;
+
+use schemars::schema_for;
+use serde_json::Value;
+
+use crate::{ChatCompletionRequest, ChatCompletionResponse, CompletionRequest, CompletionResponse};
+
+/// Returns the JSON Schema of each request and response type, keyed by type name.
+pub fn json_schemas() -> BTreeMap<&'static str, Value> {
+    // schema_for! produces a RootSchema, which always serializes to a JSON object
+    let to_value = |schema| serde_json::to_value(schema).unwrap_or(Value::Null);
+
+    BTreeMap::from([
+        (
+            "ChatCompletionRequest",
+            to_value(schema_for!(ChatCompletionRequest)),
+        ),
+        (
+            "ChatCompletionResponse",
+            to_value(schema_for!(ChatCompletionResponse)),
+        ),
+        (
+            "CompletionRequest",
+            to_value(schema_for!(CompletionRequest)),
+        ),
+        (
+            "CompletionResponse",
+            to_value(schema_for!(CompletionResponse)),
+        ),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Verify that a schema is generated for each type and describes its required fields
+    fn test_json_schemas() {
+        let schemas = json_schemas();
+        assert_eq!(schemas.len(), 4);
+
+        let required = schemas["ChatCompletionRequest"]["required"]
+            .as_array()
+            .unwrap();
+        assert!(required.contains(&Value::from("model")));
+        assert!(required.contains(&Value::from("messages")));
+
+        assert!(schemas["CompletionResponse"]["definitions"]["Logprobs"].is_object());
+    }
+}
@@ -0,0 +1,247 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing an OpenAI-compatible HTTP server layer built on axum, for gateways, mock
+//! servers and local shims which use the same types as the client.
+//!
+//! The router only handles routing and (de)serialization. Serving it requires enabling the
+//! `tokio` and `http1` features of axum in the application and calling `axum::serve`.
+
+use std::future::Future;
+
+use axum::body::Bytes;
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::Router;
+use serde_json::json;
+
+use crate::error::OpenAIError;
+use crate::{ChatCompletionRequest, ChatCompletionResponse};
+
+/// Create a router serving `POST /v1/chat/completions` with the given handler.
+///
+/// Requests are validated the same way the client validates them before they reach the handler.
+/// Invalid requests and handler errors are returned in the OpenAI error format, with
+/// `InvalidArgument` errors as 400 responses and other errors as 500 responses. Streaming is not
+/// supported, so requests with `stream` set are rejected.
+pub fn chat_completions_router<F, Fut>(handler: F) -> Router
+where
+    F: Fn(ChatCompletionRequest) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Result<ChatCompletionResponse, OpenAIError>> + Send + 'static,
+{
+    Router::new().route(
+        "/v1/chat/completions",
+        post(move |body: Bytes| async move {
+            let request = match serde_json::from_slice::<ChatCompletionRequest>(&body) {
+                Ok(request) => request,
+                Err(err) => {
+                    return error_response(StatusCode::BAD_REQUEST, &err.to_string(), None);
+                }
+            };
+
+            if request.is_stream() {
+                return error_response(
+                    StatusCode::BAD_REQUEST,
+                    "Streaming is not supported by this server",
+                    Some("stream"),
+                );
+            }
+
+            if let Err(err) = request.validate() {
+                return openai_error_response(err);
+            }
+
+            match handler(request).await {
+                Ok(response) => match serde_json::to_string(&response) {
+                    Ok(json) => (
+                        StatusCode::OK,
+                        [(header::CONTENT_TYPE, "application/json")],
+                        json,
+                    )
+                        .into_response(),
+                    Err(err) => {
+                        error_response(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string(), None)
+                    }
+                },
+                Err(err) => openai_error_response(err),
+            }
+        }),
+    )
+}
+
+fn openai_error_response(err: OpenAIError) -> Response {
+    match &err {
+        OpenAIError::InvalidArgument(invalid) => error_response(
+            StatusCode::BAD_REQUEST,
+            &invalid.message(),
+            Some(&invalid.argument()),
+        ),
+        OpenAIError::RateLimited {
+            message,
+            retry_after,
+            ..
+        } => {
+            let mut response = error_response(StatusCode::TOO_MANY_REQUESTS, message, None);
+            if let Some(retry_after) = retry_after {
+                // Retry-After is in whole seconds, so round up to not invite an early retry
+                let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+            }
+            response
+        }
+        OpenAIError::Internal(_)
+        | OpenAIError::InvalidState(_)
+        | OpenAIError::StreamInterrupted { .. } => {
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string(), None)
+        }
+    }
+}
+
+/// Build a response in the error format used by the OpenAI API.
+fn error_response(status: StatusCode, message: &str, param: Option<&str>) -> Response {
+    let kind = if status.is_client_error() {
+        "invalid_request_error"
+    } else {
+        "server_error"
+    };
+    let body = json!({
+        "error": {
+            "message": message,
+            "type": kind,
+            "param": param,
+            "code": null,
+        }
+    });
+
+    (
+        status,
+        [(header::CONTENT_TYPE, "application/json")],
+        body.to_string(),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::body::Body;
+    use axum::http::Request;
+    use ryst_error::InternalError;
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    use crate::Message;
+
+    fn router() -> Router {
+        chat_completions_router(|request: ChatCompletionRequest| async move {
+            if request.model() == "broken" {
+                return Err(OpenAIError::Internal(InternalError::with_message(
+                    "model unavailable".to_string(),
+                )));
+            }
+
+            let reply = format!(
+                "echo: {}",
+                request.messages()[0].content().as_text().unwrap()
+            );
+            Ok(serde_json::from_value(json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion",
+                "created": 0,
+                "model": request.model(),
+                "choices": [{
+                    "message": {"role": "assistant", "content": reply},
+                    "index": 0,
+                    "finish_reason": "stop"
+                }],
+                "usage": {"prompt_tokens": 1, "completion_tokens": 1, "total_tokens": 2}
+            }))
+            .unwrap())
+        })
+    }
+
+    async fn send(body: String) -> (StatusCode, Value) {
+        let response = router()
+            .oneshot(
+                Request::post("/v1/chat/completions")
+                    .header("content-type", "application/json")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    // Verify that a request built by the client is answered by the handler
+    async fn test_chat_completions_router() {
+        let request = ChatCompletionRequest::new("gpt-4o", &[Message::new("user", "hello")]);
+        let (status, body) = send(serde_json::to_string(&request).unwrap()).await;
+
+        assert_eq!(status, StatusCode::OK);
+        let response: ChatCompletionResponse = serde_json::from_value(body).unwrap();
+        assert_eq!(response.choices[0].message.content(), "echo: hello");
+    }
+
+    #[tokio::test]
+    // Verify that invalid requests and handler errors use the OpenAI error format
+    async fn test_chat_completions_router_errors() {
+        let (status, body) = send("{".to_string()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+
+        let request = ChatCompletionRequest::new("gpt-4o", &[Message::new("user", "hi")])
+            .with_temperature(0.5)
+            .with_top_p(0.5);
+        let (status, body) = send(serde_json::to_string(&request).unwrap()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["param"], "temperature");
+
+        let (status, body) = send(
+            r#"{"model":"gpt-4o","messages":[{"role":"user","content":"hi"}],"stream":true}"#
+                .to_string(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["param"], "stream");
+
+        let request = ChatCompletionRequest::new("broken", &[Message::new("user", "hi")]);
+        let (status, body) = send(serde_json::to_string(&request).unwrap()).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body["error"]["type"], "server_error");
+    }
+
+    #[test]
+    // Verify that a rate limited request is relayed as a 429 with the wait rounded up
+    fn test_rate_limited_error_response() {
+        let response = openai_error_response(OpenAIError::RateLimited {
+            message: "Slow down".to_string(),
+            retry_after: Some(std::time::Duration::from_millis(1500)),
+            request_id: None,
+        });
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "2");
+    }
+}
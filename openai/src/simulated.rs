@@ -0,0 +1,203 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing a stream which replays a complete response a chunk of text at a time, so
+//! responses which arrive all at once, such as from a cache or a batch, can be shown the same
+//! way as streamed ones.
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::Stream;
+
+use crate::clock::{Clock, TokioClock};
+
+/// Yields a response with its text cut off after one more chunk each time, ending with the full
+/// response.
+///
+/// Created with `ChatCompletionResponse::into_simulated_stream` or
+/// `CompletionResponse::into_simulated_stream`. Only the text of each choice is cut, so the other
+/// fields of every item, such as the usage and finish reason, are those of the full response.
+pub struct SimulatedStream<T> {
+    response: Option<T>,
+    truncate: fn(&T, usize) -> T,
+    /// The number of characters of the longest choice
+    len: usize,
+    chunk_size: usize,
+    /// The number of characters of text in the last item yielded
+    position: usize,
+    delay: Duration,
+    clock: Arc<dyn Clock>,
+    sleep: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+}
+
+impl<T> SimulatedStream<T> {
+    /// Create a stream over the response, whose longest choice has `len` characters of text,
+    /// which `truncate` cuts off after the given number of characters.
+    pub(crate) fn new(
+        response: T,
+        len: usize,
+        truncate: fn(&T, usize) -> T,
+        chunk_size: usize,
+        delay: Duration,
+    ) -> Self {
+        Self {
+            response: Some(response),
+            truncate,
+            len,
+            chunk_size: chunk_size.max(1),
+            position: 0,
+            delay,
+            clock: Arc::new(TokioClock),
+            sleep: None,
+        }
+    }
+
+    /// The clock used to wait between chunks.
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+}
+
+impl<T: Unpin> Stream for SimulatedStream<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(sleep) = &mut self.sleep {
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.sleep = None;
+        }
+
+        self.position = self.position.saturating_add(self.chunk_size);
+        if self.position >= self.len {
+            return Poll::Ready(self.response.take());
+        }
+
+        let item = match &self.response {
+            Some(response) => (self.truncate)(response, self.position),
+            None => return Poll::Ready(None),
+        };
+        if !self.delay.is_zero() {
+            self.sleep = Some(self.clock.sleep(self.delay));
+        }
+        Poll::Ready(Some(item))
+    }
+}
+
+impl<T> fmt::Debug for SimulatedStream<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SimulatedStream")
+            .field("len", &self.len)
+            .field("chunk_size", &self.chunk_size)
+            .field("position", &self.position)
+            .field("delay", &self.delay)
+            .finish()
+    }
+}
+
+/// Cut the text off after the given number of characters.
+pub(crate) fn truncate_chars(text: &mut String, chars: usize) {
+    if let Some((index, _)) = text.char_indices().nth(chars) {
+        text.truncate(index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Instant;
+
+    use futures::StreamExt;
+
+    use crate::clock::ManualClock;
+    use crate::{ChatCompletionResponse, CompletionResponse};
+
+    #[test]
+    // Verify that the chat text grows a chunk at a time, waiting between chunks on the clock
+    fn test_chat_simulated_stream() {
+        let response: ChatCompletionResponse = serde_json::from_str(
+            r#"{"id":"chatcmpl-1","object":"chat.completion","created":0,"model":"gpt-4o",
+            "choices":[{"message":{"role":"assistant","content":"Héllo world"},"index":0,
+            "finish_reason":"stop"}],"usage":{"prompt_tokens":1,"completion_tokens":2,
+            "total_tokens":3}}"#,
+        )
+        .unwrap();
+        let clock = ManualClock::new();
+        let started = clock.now();
+
+        let stream = response
+            .clone()
+            .into_simulated_stream(4, Duration::from_millis(50))
+            .with_clock(clock.clone());
+        let items = futures::executor::block_on(stream.collect::<Vec<_>>());
+
+        let texts: Vec<_> = items
+            .iter()
+            .map(|item| item.choices[0].message.content.as_text().unwrap())
+            .collect();
+        assert_eq!(texts, ["Héll", "Héllo wo", "Héllo world"]);
+        assert_eq!(items.last(), Some(&response));
+        assert_eq!(clock.now() - started, Duration::from_millis(100));
+    }
+
+    #[test]
+    // Verify that every completion choice is cut, and an empty response is yielded once
+    fn test_completion_simulated_stream() {
+        let response: CompletionResponse = serde_json::from_str(
+            r#"{"id":"cmpl-1","object":"text_completion","created":0,"model":"babbage-002",
+            "choices":[{"text":"abcdef","index":0,"logprobs":null,"finish_reason":"stop"},
+            {"text":"abc","index":1,"logprobs":null,"finish_reason":"stop"}],
+            "usage":{"prompt_tokens":1,"completion_tokens":2,"total_tokens":3}}"#,
+        )
+        .unwrap();
+
+        let items = futures::executor::block_on(
+            response
+                .into_simulated_stream(2, Duration::ZERO)
+                .collect::<Vec<_>>(),
+        );
+
+        let texts: Vec<Vec<_>> = items
+            .iter()
+            .map(|item| {
+                item.choices
+                    .iter()
+                    .map(|choice| choice.text.as_str())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            texts,
+            [vec!["ab", "ab"], vec!["abcd", "abc"], vec!["abcdef", "abc"]]
+        );
+
+        let started = Instant::now();
+        let mut empty = CompletionResponse {
+            choices: vec![],
+            ..items[0].clone()
+        }
+        .into_simulated_stream(2, Duration::from_secs(60));
+        assert!(futures::executor::block_on(empty.next()).is_some());
+        assert!(futures::executor::block_on(empty.next()).is_none());
+        assert!(started.elapsed() < Duration::from_secs(60));
+    }
+}
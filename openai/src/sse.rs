@@ -0,0 +1,137 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing the tracking of server-sent event IDs, so a stream can be reconnected with
+//! `Last-Event-ID`.
+
+/// Reads the `id` fields of server-sent events from a body which arrives in chunks.
+///
+/// As in the EventSource specification, an `id` takes effect once the event it belongs to is
+/// complete, so the last ID never names an event which was only partly read.
+#[derive(Debug, Default)]
+pub(crate) struct EventIds {
+    /// The start of a line which has not been ended yet
+    line: Vec<u8>,
+    /// Whether the last line ended with `\r`, so a following `\n` is part of the same line end
+    after_cr: bool,
+    /// The ID set by the fields read so far, which takes effect when the event is complete
+    pending: Option<String>,
+    last: Option<String>,
+}
+
+impl EventIds {
+    /// Read the event IDs from the next chunk of the body.
+    ///
+    /// Returns the length of the start of the chunk up to the end of the last event completed
+    /// in it, or 0 if no event was completed.
+    pub fn push(&mut self, chunk: &[u8]) -> usize {
+        let mut complete = 0;
+        for (index, byte) in chunk.iter().enumerate() {
+            match byte {
+                b'\n' if self.after_cr => {
+                    self.after_cr = false;
+                    // Keep the whole line end of the blank line with the event it ends
+                    if complete == index && index > 0 {
+                        complete = index + 1;
+                    }
+                }
+                b'\n' | b'\r' => {
+                    self.after_cr = *byte == b'\r';
+                    // A blank line ends the event
+                    if self.line.is_empty() {
+                        self.last.clone_from(&self.pending);
+                        complete = index + 1;
+                    }
+                    self.read_line();
+                    self.line.clear();
+                }
+                byte => {
+                    self.after_cr = false;
+                    self.line.push(*byte);
+                }
+            }
+        }
+        complete
+    }
+
+    /// The ID of the last event read, if any event had one.
+    pub fn last(&self) -> Option<&str> {
+        self.last.as_deref()
+    }
+
+    /// Forget the event which was being read, as its connection has been closed.
+    pub fn discard_partial(&mut self) {
+        self.line.clear();
+        self.after_cr = false;
+        self.pending.clone_from(&self.last);
+    }
+
+    fn read_line(&mut self) {
+        let Some(value) = self.line.strip_prefix(b"id:") else {
+            return;
+        };
+        let value = value.strip_prefix(b" ").unwrap_or(value);
+
+        // As in the EventSource specification, IDs containing NULL are ignored and an empty ID
+        // clears the last one
+        if let Ok(id) = std::str::from_utf8(value) {
+            if !id.contains('\0') {
+                self.pending = (!id.is_empty()).then(|| id.to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Verify that the last event ID is read, including IDs split across chunks
+    fn test_event_ids() {
+        let mut ids = EventIds::default();
+        assert_eq!(ids.last(), None);
+
+        assert_eq!(ids.push(b"id: 1\ndata: {}\n\n"), 16);
+        assert_eq!(ids.last(), Some("1"));
+
+        assert_eq!(ids.push(b"id:4"), 0);
+        assert_eq!(ids.last(), Some("1"));
+        assert_eq!(ids.push(b"2\r\ndata: {}\r\n\r\n"), 15);
+        assert_eq!(ids.last(), Some("42"));
+
+        ids.push(b"id: bad\0id\n: id: 7\ndata: id: 8\n\n");
+        assert_eq!(ids.last(), Some("42"));
+
+        ids.push(b"id:\n\n");
+        assert_eq!(ids.last(), None);
+    }
+
+    #[test]
+    // Verify that an ID takes effect only once its event is complete
+    fn test_partial_event() {
+        let mut ids = EventIds::default();
+        assert_eq!(ids.push(b"id: 1\ndata: a\n\nid: 2\ndata: b"), 15);
+        assert_eq!(ids.last(), Some("1"));
+
+        // The blank line which would have completed the discarded event completes one without
+        // its ID instead
+        ids.discard_partial();
+        assert_eq!(ids.push(b"\n"), 1);
+        assert_eq!(ids.last(), Some("1"));
+
+        assert_eq!(ids.push(b"id: 2\rdata: b\r\r"), 15);
+        assert_eq!(ids.last(), Some("2"));
+    }
+}
@@ -0,0 +1,101 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing the joining of a truncated generation and its continuation.
+//!
+//! Asked to continue, a model often starts by repeating the end of what it had already written.
+//! The repeated text is found by matching a suffix of the original against a prefix of the
+//! continuation, and trimmed so the joined text reads as one reply.
+
+/// The fewest characters treated as repeated text, so a continuation which happens to start
+/// with the letter or short word the original ends with is kept whole.
+const MIN_OVERLAP: usize = 8;
+
+/// Join the original text and its continuation, trimming any text the continuation repeats from
+/// the end of the original.
+///
+/// Whitespace between the two is ignored when matching. Without a repeat of at least 8
+/// characters, the continuation is appended as it is.
+pub fn stitch(original: &str, continuation: &str) -> String {
+    match overlap(original, continuation) {
+        0 => format!("{original}{continuation}"),
+        len => format!("{}{}", original.trim_end(), &continuation[len..]),
+    }
+}
+
+/// Returns the length in bytes of the start of the continuation which repeats the end of the
+/// original, including any whitespace before it, or 0 if there is no repeat to trim.
+pub fn overlap(original: &str, continuation: &str) -> usize {
+    let original = original.trim_end();
+    let head = continuation.trim_start();
+    let skipped = continuation.len() - head.len();
+
+    // Prefer the longest repeat, as a short one may occur inside it
+    let repeat = head
+        .char_indices()
+        .map(|(i, c)| i + c.len_utf8())
+        .skip(MIN_OVERLAP - 1)
+        .filter(|end| *end <= original.len())
+        .filter(|end| original.ends_with(&head[..*end]))
+        .last();
+
+    repeat.map(|end| skipped + end).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Verify that a repeated sentence is trimmed from the continuation
+    fn test_stitch_repeated_sentence() {
+        assert_eq!(
+            stitch(
+                "The sky is blue. Grass is green.",
+                " Grass is green. Snow is white."
+            ),
+            "The sky is blue. Grass is green. Snow is white."
+        );
+    }
+
+    #[test]
+    // Verify that a word cut off in the original is completed by the continuation
+    fn test_stitch_cut_off_word() {
+        assert_eq!(
+            stitch("Rust has a borrow chec", "has a borrow checker which"),
+            "Rust has a borrow checker which"
+        );
+    }
+
+    #[test]
+    // Verify that a continuation without a long enough repeat is appended as it is
+    fn test_stitch_without_overlap() {
+        assert_eq!(stitch("It is a", " cat."), "It is a cat.");
+        assert_eq!(stitch("one and", "and two"), "one andand two");
+        assert_eq!(stitch("", "Hello"), "Hello");
+        assert_eq!(stitch("Hello", ""), "Hello");
+    }
+
+    #[test]
+    // Verify that the overlap is measured on character boundaries
+    fn test_overlap_multibyte() {
+        let original = "Le café est fermé";
+        let continuation = "café est fermé le dimanche";
+        assert_eq!(overlap(original, continuation), "café est fermé".len());
+        assert_eq!(
+            stitch(original, continuation),
+            "Le café est fermé le dimanche"
+        );
+    }
+}
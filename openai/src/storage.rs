@@ -0,0 +1,777 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing a SQLite-backed store of conversations, their messages and responses, and
+//! the tokens used by each response.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use ryst_error::{InternalError, InvalidArgumentError, InvalidStateError};
+
+#[cfg(feature = "encryption")]
+use crate::encryption::EncryptionKey;
+use crate::error::OpenAIError;
+use crate::{ChatCompletionResponse, Message, RequestTags};
+
+/// The identifier of a stored conversation.
+pub type ConversationId = i64;
+
+/// A stored conversation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conversation {
+    pub id: ConversationId,
+    pub user: String,
+    /// Unix timestamp in seconds of when the conversation was created
+    pub created: i64,
+}
+
+/// The tokens used by one stored response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageRecord {
+    pub conversation: ConversationId,
+    pub user: String,
+    pub model: String,
+    pub prompt_tokens: i32,
+    pub completion_tokens: i32,
+    /// Unix timestamp in seconds of when the response was created
+    pub created: i64,
+    /// The tags of the request, set with `with_tags`
+    pub tags: RequestTags,
+}
+
+impl UsageRecord {
+    /// Returns the cost of the record using the price of the model, or `None` if no price
+    /// matches the model.
+    ///
+    /// Prices are matched by the longest model name which prefixes the record's model, so a
+    /// price for `gpt-4o` applies to `gpt-4o-2024-08-06`.
+    pub fn cost(&self, prices: &HashMap<String, ModelPrice>) -> Option<f64> {
+        let price = prices
+            .iter()
+            .filter(|(model, _)| self.model.starts_with(model.as_str()))
+            .max_by_key(|(model, _)| model.len())
+            .map(|(_, price)| price)?;
+
+        Some(
+            f64::from(self.prompt_tokens) * price.prompt_per_million / 1_000_000.0
+                + f64::from(self.completion_tokens) * price.completion_per_million / 1_000_000.0,
+        )
+    }
+}
+
+/// The price of a model per million tokens.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModelPrice {
+    pub prompt_per_million: f64,
+    pub completion_per_million: f64,
+}
+
+impl ModelPrice {
+    pub fn new(prompt_per_million: f64, completion_per_million: f64) -> Self {
+        Self {
+            prompt_per_million,
+            completion_per_million,
+        }
+    }
+}
+
+/// Selects usage records by user, creation time and tags.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UsageQuery {
+    user: Option<String>,
+    start: Option<i64>,
+    end: Option<i64>,
+    template_id: Option<String>,
+    experiment: Option<String>,
+}
+
+impl UsageQuery {
+    /// Create a query matching every record.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only match records of the user.
+    pub fn with_user(mut self, user: &str) -> Self {
+        self.user = Some(user.to_string());
+        self
+    }
+
+    /// Only match records created from `start` and before `end`, as Unix timestamps in seconds.
+    pub fn with_range(mut self, start: i64, end: i64) -> Self {
+        self.start = Some(start);
+        self.end = Some(end);
+        self
+    }
+
+    /// Only match records of requests built from the template, in any version.
+    pub fn with_template_id(mut self, template_id: &str) -> Self {
+        self.template_id = Some(template_id.to_string());
+        self
+    }
+
+    /// Only match records of requests in the experiment.
+    pub fn with_experiment(mut self, experiment: &str) -> Self {
+        self.experiment = Some(experiment.to_string());
+        self
+    }
+}
+
+/// A store of conversations, messages, responses and usage backed by a SQLite database.
+pub struct ConversationStore {
+    connection: Mutex<Connection>,
+    #[cfg(feature = "encryption")]
+    encryption: Option<EncryptionKey>,
+}
+
+impl ConversationStore {
+    /// Open or create the database at the path.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, OpenAIError> {
+        Self::init(Connection::open(path).map_err(sqlite_error)?)
+    }
+
+    /// Create a database which only lives as long as the store, mainly for tests.
+    pub fn in_memory() -> Result<Self, OpenAIError> {
+        Self::init(Connection::open_in_memory().map_err(sqlite_error)?)
+    }
+
+    fn init(connection: Connection) -> Result<Self, OpenAIError> {
+        // SQLite only enforces the references between the tables when asked to, on every
+        // connection
+        connection
+            .execute_batch(
+                "PRAGMA foreign_keys = ON;
+                CREATE TABLE IF NOT EXISTS ryst_conversations (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    user TEXT NOT NULL,
+                    created INTEGER NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS ryst_conversations_user
+                    ON ryst_conversations (user);
+                CREATE TABLE IF NOT EXISTS ryst_messages (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    conversation INTEGER NOT NULL REFERENCES ryst_conversations (id),
+                    message TEXT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS ryst_responses (
+                    id TEXT PRIMARY KEY,
+                    conversation INTEGER NOT NULL REFERENCES ryst_conversations (id),
+                    response TEXT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS ryst_usage (
+                    response TEXT PRIMARY KEY REFERENCES ryst_responses (id),
+                    conversation INTEGER NOT NULL REFERENCES ryst_conversations (id),
+                    model TEXT NOT NULL,
+                    prompt_tokens INTEGER NOT NULL,
+                    completion_tokens INTEGER NOT NULL,
+                    created INTEGER NOT NULL,
+                    template_id TEXT,
+                    template_version TEXT,
+                    experiment TEXT
+                );
+                CREATE INDEX IF NOT EXISTS ryst_usage_created ON ryst_usage (created);",
+            )
+            .map_err(sqlite_error)?;
+
+        // Databases created before requests could be tagged lack the tag columns
+        let columns = connection
+            .prepare("SELECT name FROM pragma_table_info('ryst_usage')")
+            .and_then(|mut statement| {
+                statement
+                    .query_map([], |row| row.get::<_, String>(0))?
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .map_err(sqlite_error)?;
+        for column in ["template_id", "template_version", "experiment"] {
+            if !columns.iter().any(|existing| existing == column) {
+                connection
+                    .execute(
+                        &format!("ALTER TABLE ryst_usage ADD COLUMN {column} TEXT"),
+                        [],
+                    )
+                    .map_err(sqlite_error)?;
+            }
+        }
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+            #[cfg(feature = "encryption")]
+            encryption: None,
+        })
+    }
+
+    /// Encrypt the messages and responses written from now on with the key.
+    ///
+    /// Each record is bound to its conversation, and a response to its id, so a record copied
+    /// elsewhere in the database cannot be read. Records which are not encrypted are rejected, so
+    /// a store must be encrypted from when it is created. Users, models and usage are stored
+    /// unencrypted so that they can be queried.
+    #[cfg(feature = "encryption")]
+    pub fn with_encryption(mut self, key: EncryptionKey) -> Self {
+        self.encryption = Some(key);
+        self
+    }
+
+    /// Create a conversation for the user.
+    pub fn create_conversation(&self, user: &str) -> Result<ConversationId, OpenAIError> {
+        let connection = self.connection()?;
+        connection
+            .execute(
+                "INSERT INTO ryst_conversations (user, created) VALUES (?1, ?2)",
+                params![user, now()],
+            )
+            .map_err(sqlite_error)?;
+        Ok(connection.last_insert_rowid())
+    }
+
+    /// Look up a conversation.
+    pub fn conversation(&self, id: ConversationId) -> Result<Option<Conversation>, OpenAIError> {
+        self.connection()?
+            .query_row(
+                "SELECT id, user, created FROM ryst_conversations WHERE id = ?1",
+                params![id],
+                |row| {
+                    Ok(Conversation {
+                        id: row.get(0)?,
+                        user: row.get(1)?,
+                        created: row.get(2)?,
+                    })
+                },
+            )
+            .optional()
+            .map_err(sqlite_error)
+    }
+
+    /// Returns the user's conversations, oldest first.
+    pub fn conversations_for_user(&self, user: &str) -> Result<Vec<Conversation>, OpenAIError> {
+        let connection = self.connection()?;
+        let mut statement = connection
+            .prepare("SELECT id, user, created FROM ryst_conversations WHERE user = ?1 ORDER BY id")
+            .map_err(sqlite_error)?;
+        let conversations = statement
+            .query_map(params![user], |row| {
+                Ok(Conversation {
+                    id: row.get(0)?,
+                    user: row.get(1)?,
+                    created: row.get(2)?,
+                })
+            })
+            .map_err(sqlite_error)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(sqlite_error)?;
+        Ok(conversations)
+    }
+
+    /// Append a message to a conversation.
+    pub fn add_message(
+        &self,
+        conversation: ConversationId,
+        message: &Message,
+    ) -> Result<(), OpenAIError> {
+        let message = self.to_record(message, &message_data(conversation))?;
+        self.connection()?
+            .execute(
+                "INSERT INTO ryst_messages (conversation, message) VALUES (?1, ?2)",
+                params![conversation, message],
+            )
+            .map_err(sqlite_error)?;
+        Ok(())
+    }
+
+    /// Returns the messages of a conversation in the order they were added.
+    pub fn messages(&self, conversation: ConversationId) -> Result<Vec<Message>, OpenAIError> {
+        let connection = self.connection()?;
+        let mut statement = connection
+            .prepare("SELECT message FROM ryst_messages WHERE conversation = ?1 ORDER BY id")
+            .map_err(sqlite_error)?;
+        let messages = statement
+            .query_map(params![conversation], |row| row.get::<_, String>(0))
+            .map_err(sqlite_error)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(sqlite_error)?;
+
+        messages
+            .into_iter()
+            .map(|message| self.parse_record(message, &message_data(conversation)))
+            .collect()
+    }
+
+    /// Store a response to a conversation along with its usage and the tags of its request, and
+    /// append the message of its first choice to the conversation.
+    pub fn record_response(
+        &self,
+        conversation: ConversationId,
+        response: &ChatCompletionResponse,
+    ) -> Result<(), OpenAIError> {
+        self.record_response_with_tags(conversation, response, &response.tags)
+    }
+
+    /// Store a response like `record_response`, recording the tags with its usage in place of
+    /// the tags carried by the response.
+    pub fn record_response_with_tags(
+        &self,
+        conversation: ConversationId,
+        response: &ChatCompletionResponse,
+        tags: &RequestTags,
+    ) -> Result<(), OpenAIError> {
+        let json = self.to_record(response, &response_data(&response.id, conversation))?;
+        let message = response
+            .choices
+            .first()
+            .map(|choice| self.to_record(&choice.message, &message_data(conversation)))
+            .transpose()?;
+
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction().map_err(sqlite_error)?;
+        transaction
+            .execute(
+                "INSERT INTO ryst_responses (id, conversation, response) VALUES (?1, ?2, ?3)",
+                params![response.id, conversation, json],
+            )
+            .map_err(sqlite_error)?;
+        transaction
+            .execute(
+                "INSERT INTO ryst_usage
+                    (response, conversation, model, prompt_tokens, completion_tokens, created,
+                        template_id, template_version, experiment)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    response.id,
+                    conversation,
+                    response.model,
+                    response.usage.prompt_tokens,
+                    response.usage.completion_tokens,
+                    response.created,
+                    tags.template_id,
+                    tags.template_version,
+                    tags.experiment,
+                ],
+            )
+            .map_err(sqlite_error)?;
+        if let Some(message) = message {
+            transaction
+                .execute(
+                    "INSERT INTO ryst_messages (conversation, message) VALUES (?1, ?2)",
+                    params![conversation, message],
+                )
+                .map_err(sqlite_error)?;
+        }
+        transaction.commit().map_err(sqlite_error)
+    }
+
+    /// Look up a stored response by its id.
+    pub fn response(&self, id: &str) -> Result<Option<ChatCompletionResponse>, OpenAIError> {
+        let response = self
+            .connection()?
+            .query_row(
+                "SELECT response, conversation FROM ryst_responses WHERE id = ?1",
+                params![id],
+                |row| Ok((row.get::<_, String>(0)?, row.get(1)?)),
+            )
+            .optional()
+            .map_err(sqlite_error)?;
+
+        response
+            .map(|(response, conversation)| {
+                self.parse_record(response, &response_data(id, conversation))
+            })
+            .transpose()
+    }
+
+    /// Returns the usage records matching the query, oldest first.
+    pub fn usage(&self, query: &UsageQuery) -> Result<Vec<UsageRecord>, OpenAIError> {
+        let connection = self.connection()?;
+        let mut statement = connection
+            .prepare(
+                "SELECT u.conversation, c.user, u.model, u.prompt_tokens, u.completion_tokens,
+                    u.created, u.template_id, u.template_version, u.experiment
+                FROM ryst_usage u JOIN ryst_conversations c ON c.id = u.conversation
+                WHERE (?1 IS NULL OR c.user = ?1)
+                    AND (?2 IS NULL OR u.created >= ?2)
+                    AND (?3 IS NULL OR u.created < ?3)
+                    AND (?4 IS NULL OR u.template_id = ?4)
+                    AND (?5 IS NULL OR u.experiment = ?5)
+                ORDER BY u.created, u.rowid",
+            )
+            .map_err(sqlite_error)?;
+        let records = statement
+            .query_map(
+                params![
+                    query.user,
+                    query.start,
+                    query.end,
+                    query.template_id,
+                    query.experiment
+                ],
+                |row| {
+                    Ok(UsageRecord {
+                        conversation: row.get(0)?,
+                        user: row.get(1)?,
+                        model: row.get(2)?,
+                        prompt_tokens: row.get(3)?,
+                        completion_tokens: row.get(4)?,
+                        created: row.get(5)?,
+                        tags: RequestTags {
+                            template_id: row.get(6)?,
+                            template_version: row.get(7)?,
+                            experiment: row.get(8)?,
+                        },
+                    })
+                },
+            )
+            .map_err(sqlite_error)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(sqlite_error)?;
+        Ok(records)
+    }
+
+    /// Returns the total cost of the usage records matching the query.
+    ///
+    /// Fails if a record's model has no price; see `UsageRecord::cost`.
+    pub fn total_cost(
+        &self,
+        query: &UsageQuery,
+        prices: &HashMap<String, ModelPrice>,
+    ) -> Result<f64, OpenAIError> {
+        self.usage(query)?
+            .iter()
+            .map(|record| {
+                record.cost(prices).ok_or_else(|| {
+                    OpenAIError::InvalidArgument(InvalidArgumentError::new(
+                        "prices",
+                        format!("No price for model {}", record.model),
+                    ))
+                })
+            })
+            .sum()
+    }
+
+    /// Serialize a record, encrypting it bound to the data identifying where it is stored if a
+    /// key is set.
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    fn to_record<T: serde::Serialize>(&self, value: &T, data: &str) -> Result<String, OpenAIError> {
+        let json = serde_json::to_string(value)
+            .map_err(|err| OpenAIError::Internal(InternalError::from_source(Box::new(err))))?;
+
+        #[cfg(feature = "encryption")]
+        if let Some(key) = &self.encryption {
+            return Ok(key.seal_text(&json, data.as_bytes()));
+        }
+        Ok(json)
+    }
+
+    /// Parse a record written by `to_record` with the same data.
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    fn parse_record<T: serde::de::DeserializeOwned>(
+        &self,
+        stored: String,
+        data: &str,
+    ) -> Result<T, OpenAIError> {
+        #[cfg(feature = "encryption")]
+        let stored = match &self.encryption {
+            Some(key) => key.open_text(&stored, data.as_bytes())?,
+            None => stored,
+        };
+
+        serde_json::from_str(&stored).map_err(|err| {
+            OpenAIError::InvalidState(InvalidStateError::with_message(format!(
+                "Stored record is invalid: {err}"
+            )))
+        })
+    }
+
+    fn connection(&self) -> Result<MutexGuard<'_, Connection>, OpenAIError> {
+        self.connection.lock().map_err(|_| {
+            OpenAIError::Internal(InternalError::with_message(
+                "Store connection lock was poisoned".to_string(),
+            ))
+        })
+    }
+}
+
+/// The data a message is bound to when encrypted, so it can only be read in its conversation.
+fn message_data(conversation: ConversationId) -> String {
+    format!("ryst_messages:{conversation}")
+}
+
+/// The data a response is bound to when encrypted, so it can only be read under its id in its
+/// conversation.
+fn response_data(id: &str, conversation: ConversationId) -> String {
+    format!("ryst_responses:{conversation}:{id}")
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+fn sqlite_error(err: rusqlite::Error) -> OpenAIError {
+    OpenAIError::Internal(InternalError::from_source_with_prefix(
+        Box::new(err),
+        "Conversation store error".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    fn response(id: &str, model: &str, created: i64, content: &str) -> ChatCompletionResponse {
+        serde_json::from_value(json!({
+            "id": id,
+            "object": "chat.completion",
+            "created": created,
+            "model": model,
+            "choices": [{
+                "message": {"role": "assistant", "content": content},
+                "index": 0,
+                "finish_reason": "stop"
+            }],
+            "usage": {"prompt_tokens": 1000, "completion_tokens": 500, "total_tokens": 1500}
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    // Verify that messages and responses are stored in the order they were added
+    fn test_conversation() {
+        let store = ConversationStore::in_memory().unwrap();
+        let id = store.create_conversation("alice").unwrap();
+
+        let question = Message::new("user", "Hello");
+        store.add_message(id, &question).unwrap();
+        let reply = response("chatcmpl-1", "gpt-4o", 100, "Hi there");
+        store.record_response(id, &reply).unwrap();
+
+        assert_eq!(
+            store.messages(id).unwrap(),
+            vec![question, Message::new("assistant", "Hi there")]
+        );
+        assert_eq!(store.response("chatcmpl-1").unwrap(), Some(reply));
+        assert_eq!(store.response("chatcmpl-2").unwrap(), None);
+        assert_eq!(store.conversation(id).unwrap().unwrap().user, "alice");
+        assert_eq!(store.conversations_for_user("alice").unwrap().len(), 1);
+        assert!(store.conversations_for_user("bob").unwrap().is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    // Verify that encrypted records are unreadable in the database and only read back in the row
+    // they were written to, and that unencrypted records are rejected
+    fn test_encryption() {
+        let store = ConversationStore::in_memory()
+            .unwrap()
+            .with_encryption(EncryptionKey::new([3; 32]));
+        let id = store.create_conversation("alice").unwrap();
+        let question = Message::new("user", "Secret question");
+        store.add_message(id, &question).unwrap();
+        let reply = response("chatcmpl-1", "gpt-4o", 100, "Secret answer");
+        store.record_response(id, &reply).unwrap();
+
+        let raw = store
+            .connection()
+            .unwrap()
+            .query_row(
+                "SELECT group_concat(message) FROM ryst_messages",
+                [],
+                |row| row.get::<_, String>(0),
+            )
+            .unwrap();
+        assert!(!raw.contains("Secret"));
+
+        assert_eq!(
+            store.messages(id).unwrap(),
+            vec![question, Message::new("assistant", "Secret answer")]
+        );
+        assert_eq!(store.response("chatcmpl-1").unwrap(), Some(reply));
+
+        // A message copied to another conversation cannot be read there
+        let other = store.create_conversation("mallory").unwrap();
+        store
+            .connection()
+            .unwrap()
+            .execute(
+                "INSERT INTO ryst_messages (conversation, message)
+                    SELECT ?2, message FROM ryst_messages WHERE conversation = ?1",
+                params![id, other],
+            )
+            .unwrap();
+        assert!(store.messages(other).is_err());
+
+        let plain = store.create_conversation("bob").unwrap();
+        store
+            .connection()
+            .unwrap()
+            .execute(
+                "INSERT INTO ryst_messages (conversation, message) VALUES (?1, ?2)",
+                params![
+                    plain,
+                    serde_json::to_string(&Message::new("user", "hi")).unwrap()
+                ],
+            )
+            .unwrap();
+        assert!(store.messages(plain).is_err());
+
+        let other_key = ConversationStore {
+            connection: Mutex::new(Connection::open_in_memory().unwrap()),
+            encryption: Some(EncryptionKey::new([4; 32])),
+        };
+        let sealed = store
+            .to_record(&Message::new("user", "hi"), &message_data(id))
+            .unwrap();
+        assert!(other_key
+            .parse_record::<Message>(sealed, &message_data(id))
+            .is_err());
+    }
+
+    #[test]
+    // Verify that messages and responses cannot be stored against a missing conversation
+    fn test_foreign_keys() {
+        let store = ConversationStore::in_memory().unwrap();
+        assert!(store
+            .add_message(100, &Message::new("user", "Hello"))
+            .is_err());
+        assert!(store
+            .record_response(100, &response("1", "gpt-4o", 100, "a"))
+            .is_err());
+        assert!(store.response("1").unwrap().is_none());
+    }
+
+    #[test]
+    // Verify that usage is filtered by user and date range and priced by model prefix
+    fn test_usage() {
+        let store = ConversationStore::in_memory().unwrap();
+        let alice = store.create_conversation("alice").unwrap();
+        let bob = store.create_conversation("bob").unwrap();
+        store
+            .record_response(alice, &response("1", "gpt-4o-2024-08-06", 100, "a"))
+            .unwrap();
+        store
+            .record_response(alice, &response("2", "gpt-4o-mini", 200, "b"))
+            .unwrap();
+        store
+            .record_response(bob, &response("3", "gpt-4o", 300, "c"))
+            .unwrap();
+
+        let query = UsageQuery::new().with_user("alice");
+        assert_eq!(store.usage(&query).unwrap().len(), 2);
+        let records = store
+            .usage(&UsageQuery::new().with_range(150, 300))
+            .unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].model, "gpt-4o-mini");
+
+        let prices = HashMap::from([
+            ("gpt-4o".to_string(), ModelPrice::new(2.0, 8.0)),
+            ("gpt-4o-mini".to_string(), ModelPrice::new(0.2, 0.8)),
+        ]);
+        let cost = store.total_cost(&query, &prices).unwrap();
+        assert!((cost - (0.006 + 0.0006)).abs() < 1e-9);
+
+        let prices = HashMap::from([("gpt-4o-mini".to_string(), ModelPrice::new(0.2, 0.8))]);
+        assert!(store.total_cost(&query, &prices).is_err());
+    }
+
+    #[test]
+    // Verify that request tags are stored with usage and can be queried
+    fn test_usage_tags() {
+        let store = ConversationStore::in_memory().unwrap();
+        let id = store.create_conversation("alice").unwrap();
+        let control = RequestTags::new()
+            .with_template("summarize", "1")
+            .with_experiment("short-prompts");
+        let variant = RequestTags::new()
+            .with_template("summarize", "2")
+            .with_experiment("short-prompts");
+        store
+            .record_response_with_tags(id, &response("1", "gpt-4o", 100, "a"), &control)
+            .unwrap();
+        store
+            .record_response_with_tags(id, &response("2", "gpt-4o", 200, "b"), &variant)
+            .unwrap();
+        store
+            .record_response(id, &response("3", "gpt-4o", 300, "c"))
+            .unwrap();
+        // Responses carry the tags of their requests
+        let mut tagged = response("4", "gpt-4o", 400, "d");
+        tagged.tags = RequestTags::new().with_experiment("long-prompts");
+        store.record_response(id, &tagged).unwrap();
+
+        let records = store
+            .usage(&UsageQuery::new().with_experiment("short-prompts"))
+            .unwrap();
+        assert_eq!(
+            records
+                .iter()
+                .map(|record| record.tags.clone())
+                .collect::<Vec<_>>(),
+            vec![control, variant]
+        );
+        assert_eq!(
+            store
+                .usage(&UsageQuery::new().with_template_id("summarize"))
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(store.usage(&UsageQuery::new()).unwrap()[2].tags.is_empty());
+        assert_eq!(
+            store
+                .usage(&UsageQuery::new().with_experiment("long-prompts"))
+                .unwrap()[0]
+                .tags,
+            tagged.tags
+        );
+    }
+
+    #[test]
+    // Verify that a database created before usage was tagged gains the tag columns
+    fn test_usage_tags_migration() {
+        let path = std::env::temp_dir().join("ryst_test_storage_migration.sqlite");
+        let _ = std::fs::remove_file(&path);
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE ryst_usage (
+                    response TEXT PRIMARY KEY,
+                    conversation INTEGER NOT NULL,
+                    model TEXT NOT NULL,
+                    prompt_tokens INTEGER NOT NULL,
+                    completion_tokens INTEGER NOT NULL,
+                    created INTEGER NOT NULL
+                );",
+            )
+            .unwrap();
+
+        let store = ConversationStore::open(&path).unwrap();
+        let id = store.create_conversation("alice").unwrap();
+        let tags = RequestTags::new().with_experiment("migrated");
+        store
+            .record_response_with_tags(id, &response("1", "gpt-4o", 100, "a"), &tags)
+            .unwrap();
+        assert_eq!(store.usage(&UsageQuery::new()).unwrap()[0].tags, tags);
+
+        drop(store);
+        assert!(ConversationStore::open(&path).is_ok());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,163 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing the timing statistics recorded while reading a streamed response.
+
+use std::time::{Duration, Instant};
+
+/// Timings of a streamed response, available once the stream has been read.
+///
+/// Times are measured from when the request was sent. Chunks are the pieces of the body as they
+/// arrive over the network, not tokens: the body is one JSON document which the API sends once
+/// the reply has been generated, so the chunk timings reflect the network rather than the model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamStats {
+    /// The time until the first chunk arrived
+    pub time_to_first_chunk: Option<Duration>,
+    /// The time between each chunk and the one before it
+    pub inter_chunk_latencies: Vec<Duration>,
+    /// The time until the stream ended
+    pub total: Duration,
+    /// The number of chunks received
+    pub chunks: usize,
+    /// The completion tokens reported by the response, if it reported usage
+    pub completion_tokens: Option<i32>,
+}
+
+impl StreamStats {
+    /// Returns the completion tokens per second over the whole request, from when it was sent to
+    /// the end of the stream.
+    ///
+    /// Returns `None` if the response did not report usage.
+    pub fn tokens_per_second(&self) -> Option<f64> {
+        let tokens = self.completion_tokens? as f64;
+        let total = self.total.as_secs_f64();
+
+        (total > 0.0).then(|| tokens / total)
+    }
+
+    /// Returns the latency between chunks at the percentile, from 0 to 100, using the nearest
+    /// rank.
+    pub fn latency_percentile(&self, percentile: f64) -> Option<Duration> {
+        if self.inter_chunk_latencies.is_empty() {
+            return None;
+        }
+
+        let mut latencies = self.inter_chunk_latencies.clone();
+        latencies.sort();
+        let rank = (percentile.clamp(0.0, 100.0) / 100.0 * latencies.len() as f64).ceil() as usize;
+        Some(latencies[rank.saturating_sub(1)])
+    }
+
+    /// Returns the mean latency between chunks.
+    pub fn mean_latency(&self) -> Option<Duration> {
+        let count = u32::try_from(self.inter_chunk_latencies.len()).ok()?;
+        (count > 0).then(|| self.inter_chunk_latencies.iter().sum::<Duration>() / count)
+    }
+}
+
+/// Records chunk arrival times for a stream.
+#[derive(Debug, Clone)]
+pub(crate) struct StatsRecorder {
+    started: Instant,
+    chunks: Vec<Instant>,
+    stats: Option<StreamStats>,
+}
+
+impl StatsRecorder {
+    pub fn new(started: Instant) -> Self {
+        Self {
+            started,
+            chunks: Vec::new(),
+            stats: None,
+        }
+    }
+
+    pub fn chunk(&mut self) {
+        self.chunks.push(Instant::now());
+    }
+
+    pub fn finish(&mut self, completion_tokens: Option<i32>) {
+        self.finish_at(Instant::now(), completion_tokens);
+    }
+
+    fn finish_at(&mut self, ended: Instant, completion_tokens: Option<i32>) {
+        self.stats = Some(StreamStats {
+            time_to_first_chunk: self
+                .chunks
+                .first()
+                .map(|first| first.duration_since(self.started)),
+            inter_chunk_latencies: self
+                .chunks
+                .windows(2)
+                .map(|pair| pair[1].duration_since(pair[0]))
+                .collect(),
+            total: ended.duration_since(self.started),
+            chunks: self.chunks.len(),
+            completion_tokens,
+        });
+    }
+
+    pub fn stats(&self) -> Option<&StreamStats> {
+        self.stats.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Verify that the timings are derived from the chunk arrival times
+    fn test_stream_stats() {
+        let started = Instant::now();
+        let at = |ms| started + Duration::from_millis(ms);
+
+        let mut recorder = StatsRecorder::new(started);
+        recorder.chunks = vec![at(200), at(210), at(240), at(250)];
+        recorder.finish_at(at(400), Some(20));
+
+        let stats = recorder.stats().unwrap();
+        assert_eq!(stats.time_to_first_chunk, Some(Duration::from_millis(200)));
+        assert_eq!(stats.chunks, 4);
+        assert_eq!(stats.tokens_per_second(), Some(50.0));
+        assert_eq!(stats.mean_latency(), Some(Duration::from_millis(50) / 3));
+        assert_eq!(
+            stats.latency_percentile(50.0),
+            Some(Duration::from_millis(10))
+        );
+        assert_eq!(
+            stats.latency_percentile(100.0),
+            Some(Duration::from_millis(30))
+        );
+    }
+
+    #[test]
+    // Verify that a stream with no chunks has no chunk timings, and no throughput without usage
+    fn test_stream_stats_empty() {
+        let started = Instant::now();
+        let mut recorder = StatsRecorder::new(started);
+        recorder.chunks = vec![started + Duration::from_millis(100)];
+        recorder.finish_at(started + Duration::from_millis(200), None);
+        assert_eq!(recorder.stats().unwrap().tokens_per_second(), None);
+
+        let mut recorder = StatsRecorder::new(Instant::now());
+        recorder.finish(None);
+
+        let stats = recorder.stats().unwrap();
+        assert_eq!(stats.time_to_first_chunk, None);
+        assert_eq!(stats.tokens_per_second(), None);
+        assert_eq!(stats.latency_percentile(99.0), None);
+    }
+}
@@ -0,0 +1,244 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing strict checking of finish reasons, which turns responses that were cut
+//! short into errors instead of letting truncated output pass silently.
+
+use std::error::Error;
+use std::fmt;
+
+use crate::error::OpenAIError;
+use crate::{ChatCompletionRequest, ChatCompletionResponse, CompletionRequest, CompletionResponse};
+
+/// The finish reasons of a choice which stopped before the model finished its reply.
+const INCOMPLETE_FINISH_REASONS: &[&str] = &["length", "content_filter"];
+
+/// Returned by strict submission when the request fails or a choice is incomplete.
+#[derive(Debug)]
+pub enum StrictError<R> {
+    /// The request failed
+    Request(OpenAIError),
+    /// A choice finished with `length` or `content_filter`
+    Incomplete(Box<Incomplete<R>>),
+}
+
+/// A response with a choice which stopped before the model finished its reply.
+#[derive(Debug, PartialEq)]
+pub struct Incomplete<R> {
+    /// The index of the first incomplete choice
+    pub index: i32,
+    /// The finish reason of the incomplete choice
+    pub finish_reason: String,
+    /// The full response, including the partial content of the choice
+    pub response: R,
+}
+
+impl Incomplete<ChatCompletionResponse> {
+    /// Returns the partial text of the incomplete choice.
+    pub fn partial_content(&self) -> Option<&str> {
+        self.response
+            .choices
+            .iter()
+            .find(|choice| choice.index == self.index)
+            .and_then(|choice| choice.message.content().as_text())
+    }
+}
+
+impl Incomplete<CompletionResponse> {
+    /// Returns the partial text of the incomplete choice.
+    pub fn partial_content(&self) -> Option<&str> {
+        self.response
+            .choices
+            .iter()
+            .find(|choice| choice.index == self.index)
+            .map(|choice| choice.text.as_str())
+    }
+}
+
+impl<R: fmt::Debug> Error for StrictError<R> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StrictError::Request(err) => Some(err),
+            StrictError::Incomplete(_) => None,
+        }
+    }
+}
+
+impl<R> fmt::Display for StrictError<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StrictError::Request(err) => err.fmt(f),
+            StrictError::Incomplete(incomplete) => write!(
+                f,
+                "Choice {} is incomplete, finishing with {}",
+                incomplete.index, incomplete.finish_reason
+            ),
+        }
+    }
+}
+
+impl<R> From<OpenAIError> for StrictError<R> {
+    fn from(err: OpenAIError) -> Self {
+        StrictError::Request(err)
+    }
+}
+
+/// Returns the index and finish reason of the first incomplete choice.
+fn first_incomplete<'a>(
+    finish_reasons: impl IntoIterator<Item = (i32, &'a str)>,
+) -> Option<(i32, String)> {
+    finish_reasons
+        .into_iter()
+        .find(|(_, reason)| INCOMPLETE_FINISH_REASONS.contains(reason))
+        .map(|(index, reason)| (index, reason.to_string()))
+}
+
+impl ChatCompletionResponse {
+    /// Returns an error carrying the response if any choice finished with `length` or
+    /// `content_filter`.
+    pub fn into_strict(self) -> Result<Self, StrictError<Self>> {
+        let incomplete = first_incomplete(
+            self.choices
+                .iter()
+                .map(|choice| (choice.index, &*choice.finish_reason)),
+        );
+
+        match incomplete {
+            Some((index, finish_reason)) => Err(StrictError::Incomplete(Box::new(Incomplete {
+                index,
+                finish_reason,
+                response: self,
+            }))),
+            None => Ok(self),
+        }
+    }
+}
+
+impl CompletionResponse {
+    /// Returns an error carrying the response if any choice finished with `length` or
+    /// `content_filter`.
+    pub fn into_strict(self) -> Result<Self, StrictError<Self>> {
+        let incomplete = first_incomplete(
+            self.choices
+                .iter()
+                .map(|choice| (choice.index, &*choice.finish_reason)),
+        );
+
+        match incomplete {
+            Some((index, finish_reason)) => Err(StrictError::Incomplete(Box::new(Incomplete {
+                index,
+                finish_reason,
+                response: self,
+            }))),
+            None => Ok(self),
+        }
+    }
+}
+
+impl ChatCompletionRequest {
+    /// Submit the request as `submit` does, returning `StrictError::Incomplete` if any choice was
+    /// cut short by the token limit or the content filter.
+    pub async fn submit_strict(
+        self,
+    ) -> Result<ChatCompletionResponse, StrictError<ChatCompletionResponse>> {
+        self.submit().await?.into_strict()
+    }
+}
+
+impl CompletionRequest {
+    /// Submit the request as `submit` does, returning `StrictError::Incomplete` if any choice was
+    /// cut short by the token limit or the content filter.
+    pub async fn submit_strict(
+        self,
+    ) -> Result<CompletionResponse, StrictError<CompletionResponse>> {
+        self.submit().await?.into_strict()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    fn chat_response(finish_reasons: &[&str]) -> ChatCompletionResponse {
+        let choices = finish_reasons
+            .iter()
+            .enumerate()
+            .map(|(index, finish_reason)| {
+                json!({
+                    "message": {"role": "assistant", "content": format!("Partial {index}")},
+                    "index": index,
+                    "finish_reason": finish_reason
+                })
+            })
+            .collect::<Vec<_>>();
+
+        serde_json::from_value(json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 1700000000,
+            "model": "gpt-4o",
+            "choices": choices,
+            "usage": {"prompt_tokens": 1, "completion_tokens": 2, "total_tokens": 3}
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    // Verify that chat responses with an incomplete choice become errors carrying the content
+    fn test_chat_into_strict() {
+        assert!(chat_response(&["stop", "tool_calls"]).into_strict().is_ok());
+
+        match chat_response(&["stop", "length"]).into_strict() {
+            Err(StrictError::Incomplete(incomplete)) => {
+                assert_eq!(incomplete.index, 1);
+                assert_eq!(incomplete.finish_reason, "length");
+                assert_eq!(incomplete.partial_content(), Some("Partial 1"));
+            }
+            result => panic!("Unexpected result {result:?}"),
+        }
+    }
+
+    #[test]
+    // Verify that completion responses filtered by the content filter become errors
+    fn test_completion_into_strict() {
+        let response: CompletionResponse = serde_json::from_value(json!({
+            "id": "cmpl-1",
+            "object": "text_completion",
+            "created": 1700000000,
+            "model": "davinci-002",
+            "choices": [{
+                "text": "Partial",
+                "index": 0,
+                "logprobs": null,
+                "finish_reason": "content_filter"
+            }],
+            "usage": {"prompt_tokens": 1, "completion_tokens": 2, "total_tokens": 3}
+        }))
+        .unwrap();
+
+        let err = response.into_strict().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Choice 0 is incomplete, finishing with content_filter"
+        );
+        match err {
+            StrictError::Incomplete(incomplete) => {
+                assert_eq!(incomplete.partial_content(), Some("Partial"))
+            }
+            StrictError::Request(err) => panic!("Unexpected error {err}"),
+        }
+    }
+}
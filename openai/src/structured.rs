@@ -0,0 +1,300 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing parsing of JSON replies, with local repair of common mistakes and
+//! re-prompting the model with the parse error when a reply cannot be parsed.
+
+use std::future::Future;
+
+use ryst_error::InvalidStateError;
+use serde::de::DeserializeOwned;
+
+use crate::error::OpenAIError;
+use crate::{ChatCompletionRequest, ChatCompletionResponse, Message};
+
+/// How to recover when a reply is not the expected JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JsonRetry {
+    max_attempts: usize,
+    repair: bool,
+}
+
+impl JsonRetry {
+    /// Create a policy which sends the request up to twice and repairs replies locally.
+    pub fn new() -> Self {
+        Self {
+            max_attempts: 2,
+            repair: true,
+        }
+    }
+
+    /// The number of times the request is sent, including the first.
+    pub fn with_max_attempts(mut self, max_attempts: usize) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Whether to try `repair_json` on a reply before re-prompting.
+    pub fn with_local_repair(mut self, repair: bool) -> Self {
+        self.repair = repair;
+        self
+    }
+}
+
+impl Default for JsonRetry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChatCompletionRequest {
+    /// Submit the request and parse the text of the first choice as JSON.
+    ///
+    /// When the reply cannot be parsed, it is repaired locally if enabled. If it still cannot be
+    /// parsed, the reply and the parse error are added to the conversation and the model is asked
+    /// to correct it, until the attempts run out.
+    pub async fn submit_json<T: DeserializeOwned>(
+        self,
+        retry: JsonRetry,
+    ) -> Result<T, OpenAIError> {
+        run(self, retry, ChatCompletionRequest::submit).await
+    }
+}
+
+/// Send the request with `submit` until its reply parses as `T`, see `submit_json`.
+pub(crate) async fn run<T, F, Fut>(
+    mut request: ChatCompletionRequest,
+    retry: JsonRetry,
+    submit: F,
+) -> Result<T, OpenAIError>
+where
+    T: DeserializeOwned,
+    F: Fn(ChatCompletionRequest) -> Fut,
+    Fut: Future<Output = Result<ChatCompletionResponse, OpenAIError>>,
+{
+    let mut last_error = String::new();
+
+    for _ in 0..retry.max_attempts {
+        let response = submit(request.clone()).await?;
+        let content = response
+            .choices
+            .first()
+            .and_then(|choice| choice.message.content().as_text())
+            .unwrap_or_default()
+            .to_string();
+
+        let error = match serde_json::from_str::<T>(&content) {
+            Ok(value) => return Ok(value),
+            Err(err) => err,
+        };
+        if retry.repair {
+            if let Ok(value) = serde_json::from_str::<T>(&repair_json(&content)) {
+                return Ok(value);
+            }
+        }
+
+        last_error = error.to_string();
+        request.push_message(Message::new("assistant", &content));
+        request.push_message(Message::new(
+            "user",
+            &format!(
+                "Your reply could not be parsed as JSON: {error}. Reply with only the corrected \
+                 JSON."
+            ),
+        ));
+    }
+
+    Err(OpenAIError::InvalidState(InvalidStateError::with_message(
+        format!(
+            "Reply was not valid JSON after {} attempts: {last_error}",
+            retry.max_attempts
+        ),
+    )))
+}
+
+/// Fix common mistakes models make when writing JSON.
+///
+/// Text around the outermost object or array, such as a markdown code fence or an explanation,
+/// is removed. Trailing commas are removed and unquoted object keys are quoted. Strings are
+/// never changed.
+pub fn repair_json(text: &str) -> String {
+    let start = text.find(['{', '[']);
+    let end = text.rfind(['}', ']']);
+    let text = match (start, end) {
+        (Some(start), Some(end)) if start < end => &text[start..=end],
+        _ => text,
+    };
+
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    let mut in_string = false;
+
+    while let Some(c) = chars.next() {
+        if in_string {
+            out.push(c);
+            match c {
+                '\\' => out.extend(chars.next()),
+                '"' => in_string = false,
+                _ => (),
+            }
+            continue;
+        }
+
+        match c {
+            '"' => {
+                in_string = true;
+                out.push(c);
+            }
+            '}' | ']' => {
+                // Drop a comma left before the closing bracket
+                let trimmed = out.trim_end().len();
+                if out[..trimmed].ends_with(',') {
+                    out.truncate(trimmed - 1);
+                }
+                out.push(c);
+            }
+            c if (c.is_alphabetic() || c == '_' || c == '$')
+                && matches!(out.trim_end().chars().last(), Some('{' | ',')) =>
+            {
+                let mut word = c.to_string();
+                while let Some(next) =
+                    chars.next_if(|c| c.is_alphanumeric() || *c == '_' || *c == '$')
+                {
+                    word.push(next);
+                }
+
+                let mut whitespace = String::new();
+                while let Some(next) = chars.next_if(|c| c.is_whitespace()) {
+                    whitespace.push(next);
+                }
+
+                if chars.peek() == Some(&':') {
+                    out.push('"');
+                    out.push_str(&word);
+                    out.push('"');
+                } else {
+                    out.push_str(&word);
+                }
+                out.push_str(&whitespace);
+            }
+            c => out.push(c),
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use proptest::prelude::*;
+    use serde::Deserialize;
+    use serde_json::{json, Value};
+
+    fn response(content: &str) -> ChatCompletionResponse {
+        serde_json::from_value(json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "gpt-4o",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": content},
+                "finish_reason": "stop"
+            }],
+            "usage": {"prompt_tokens": 1, "completion_tokens": 1, "total_tokens": 2}
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    // Verify that fences, trailing commas and unquoted keys are repaired but strings are not
+    fn test_repair_json() {
+        let text =
+            "Here you go:\n```json\n{name: \"a, }\", tags: [1, 2,], nested: {ok: true,},}\n```";
+        let repaired: Value = serde_json::from_str(&repair_json(text)).unwrap();
+
+        assert_eq!(
+            repaired,
+            json!({"name": "a, }", "tags": [1, 2], "nested": {"ok": true}})
+        );
+        assert_eq!(repair_json("[true, null]"), "[true, null]");
+    }
+
+    #[tokio::test]
+    // Verify that an unparseable reply is sent back with its error until a valid reply arrives
+    async fn test_submit_json_reprompts() {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Answer {
+            value: i32,
+        }
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let submit = {
+            let calls = calls.clone();
+            move |request: ChatCompletionRequest| {
+                let call = calls.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    if call == 0 {
+                        Ok(response("The value is seven"))
+                    } else {
+                        assert_eq!(request.messages().len(), 3);
+                        assert!(request.messages()[2]
+                            .content()
+                            .as_text()
+                            .unwrap()
+                            .starts_with("Your reply could not be parsed as JSON"));
+                        Ok(response("{value: 7,}"))
+                    }
+                }
+            }
+        };
+
+        let request = ChatCompletionRequest::new("gpt-4o", &[Message::new("user", "Value?")]);
+        let answer: Answer = run(request.clone(), JsonRetry::new(), &submit)
+            .await
+            .unwrap();
+        assert_eq!(answer, Answer { value: 7 });
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let result = run::<Answer, _, _>(
+            request,
+            JsonRetry::new()
+                .with_max_attempts(1)
+                .with_local_repair(false),
+            |_| async { Ok(response("{value: 7}")) },
+        )
+        .await;
+        assert!(result.is_err());
+    }
+
+    proptest! {
+        #[test]
+        // Verify that repairing arbitrary text never panics
+        fn test_repair_json_arbitrary(text in "[{}\\[\\],:\"\\\\ a-z0-9_$é]{0,64}") {
+            repair_json(&text);
+        }
+
+        #[test]
+        // Verify that repairing valid JSON leaves it unchanged
+        fn test_repair_json_valid(value in any::<(String, Vec<u32>, Option<bool>)>()) {
+            let json = json!({"text": value.0, "numbers": value.1, "flag": value.2}).to_string();
+            prop_assert_eq!(repair_json(&json), json);
+        }
+    }
+}
@@ -0,0 +1,61 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Module containing the tags which identify the prompt template and experiment a request
+//! belongs to.
+
+use serde::{Deserialize, Serialize};
+
+/// Identifies the prompt template and experiment behind a request, so results can be compared
+/// downstream.
+///
+/// Tags are never sent to the API. They are recorded in the request's `ExchangeTrace`, on the
+/// `tracing` span of the request with the `tracing` feature, and are carried by the request's
+/// `ChatCompletionResponse` into the usage records of the conversation store.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct RequestTags {
+    /// The id of the prompt template the request was built from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template_id: Option<String>,
+    /// The version of the template
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template_version: Option<String>,
+    /// The name of the experiment the request is part of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub experiment: Option<String>,
+}
+
+impl RequestTags {
+    /// Create empty tags.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the id and version of the prompt template.
+    pub fn with_template(mut self, id: &str, version: &str) -> Self {
+        self.template_id = Some(id.to_string());
+        self.template_version = Some(version.to_string());
+        self
+    }
+
+    /// Set the name of the experiment.
+    pub fn with_experiment(mut self, experiment: &str) -> Self {
+        self.experiment = Some(experiment.to_string());
+        self
+    }
+
+    /// Returns whether no tag is set.
+    pub fn is_empty(&self) -> bool {
+        self.template_id.is_none() && self.template_version.is_none() && self.experiment.is_none()
+    }
+}
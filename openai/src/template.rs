@@ -0,0 +1,97 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing the `{{name}}` placeholders prompt templates are rendered with.
+//!
+//! Names may be surrounded by spaces, as in `{{ name }}`. Text values are inserted as they are
+//! and other values as JSON.
+
+use serde_json::Value;
+
+/// Returns the names of the `{{name}}` placeholders in a template.
+pub fn placeholders(template: &str) -> impl Iterator<Item = &str> {
+    let mut rest = template;
+    std::iter::from_fn(move || {
+        let start = rest.find("{{")?;
+        let end = rest[start..].find("}}")?;
+        let name = rest[start + 2..start + end].trim();
+        rest = &rest[start + end + 2..];
+        Some(name)
+    })
+}
+
+/// Replace the `{{name}}` placeholders in a template with the values looked up by name.
+///
+/// Placeholders without a value and an unclosed `{{` are left as they are, so callers which
+/// require every value check the template's `placeholders` first.
+pub fn render<'a, F>(template: &str, value: F) -> String
+where
+    F: Fn(&str) -> Option<&'a Value>,
+{
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        rendered.push_str(&rest[..start]);
+
+        let name = rest[start + 2..start + end].trim();
+        match value(name) {
+            Some(value) => rendered.push_str(&value_text(value)),
+            None => rendered.push_str(&rest[start..start + end + 2]),
+        }
+        rest = &rest[start + end + 2..];
+    }
+
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Returns the text a value is inserted into a template as.
+pub(crate) fn value_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+
+    use serde_json::json;
+
+    #[test]
+    // Verify that placeholders are replaced and unknown or unclosed ones are left as they are
+    fn test_render() {
+        let value = json!({"a": 1});
+        let text = json!("text");
+        let values = BTreeMap::from([("json", &value), ("text", &text)]);
+
+        assert_eq!(
+            render("{{text}} and {{ json }} but {{other}} {{", |name| values
+                .get(name)
+                .copied()),
+            "text and {\"a\":1} but {{other}} {{"
+        );
+        assert_eq!(
+            placeholders("{{a}} {{ b }} {{c").collect::<Vec<_>>(),
+            vec!["a", "b"]
+        );
+    }
+}
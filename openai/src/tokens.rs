@@ -0,0 +1,225 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing token encoding with the tokenizers of OpenAI models, and the token limits
+//! of those models.
+
+use std::collections::HashMap;
+
+use ryst_error::InvalidArgumentError;
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer};
+use tiktoken_rs::CoreBPE;
+
+use crate::error::OpenAIError;
+use crate::{ContentPart, Message, MessageContent};
+
+/// The tokens added to each chat message for its role and separators.
+const TOKENS_PER_MESSAGE: usize = 3;
+/// The tokens which prime the assistant's reply after the messages.
+const REPLY_PRIMING_TOKENS: usize = 3;
+/// The tokens left unused by `auto_max_tokens`, for content which is not counted.
+const AUTO_MAX_TOKENS_MARGIN: usize = 32;
+
+/// The context window and maximum output tokens of models, matched by the longest prefix.
+/// Models without a separate output limit can use their whole context window for output.
+const MODEL_LIMITS: &[(&str, usize, Option<usize>)] = &[
+    ("gpt-4.1", 1_047_576, Some(32_768)),
+    ("gpt-4o", 128_000, Some(16_384)),
+    ("gpt-4-turbo", 128_000, Some(4_096)),
+    ("gpt-4-1106", 128_000, Some(4_096)),
+    ("gpt-4-0125", 128_000, Some(4_096)),
+    ("gpt-4-32k", 32_768, None),
+    ("gpt-4", 8_192, None),
+    ("gpt-3.5-turbo-instruct", 4_096, None),
+    ("gpt-3.5-turbo", 16_385, Some(4_096)),
+    ("davinci-002", 16_384, None),
+    ("babbage-002", 16_384, None),
+];
+
+/// Encode the text into the token ids of the model's tokenizer.
+///
+/// Special tokens such as `<|endoftext|>` are encoded as ordinary text.
+pub fn encode(model: &str, text: &str) -> Result<Vec<u32>, OpenAIError> {
+    Ok(bpe(model)?.encode_ordinary(text))
+}
+
+/// Returns the number of tokens the text encodes to with the model's tokenizer.
+pub fn count_tokens(model: &str, text: &str) -> Result<usize, OpenAIError> {
+    encode(model, text).map(|tokens| tokens.len())
+}
+
+/// Returns the number of prompt tokens the chat messages use with the model's tokenizer.
+///
+/// Only text is counted; image and file parts use additional tokens which depend on their
+/// contents, as do tool definitions.
+pub fn count_message_tokens(model: &str, messages: &[Message]) -> Result<usize, OpenAIError> {
+    let bpe = bpe(model)?;
+
+    let mut total = REPLY_PRIMING_TOKENS;
+    for message in messages {
+        total += TOKENS_PER_MESSAGE + bpe.encode_ordinary(message.role()).len();
+        total += match message.content() {
+            MessageContent::Text(text) => bpe.encode_ordinary(text).len(),
+            MessageContent::Parts(parts) => parts
+                .iter()
+                .map(|part| match part {
+                    ContentPart::Text { text } => bpe.encode_ordinary(text).len(),
+                    ContentPart::ImageUrl { .. } | ContentPart::File { .. } => 0,
+                })
+                .sum(),
+        };
+    }
+    Ok(total)
+}
+
+/// Returns the number of tokens the model can read and write in a single request.
+pub fn context_window(model: &str) -> Option<usize> {
+    limits(model).map(|(context_window, _)| context_window)
+}
+
+/// Returns the most tokens the model can generate in a single response.
+pub fn max_output_tokens(model: &str) -> Option<usize> {
+    limits(model).map(|(context_window, max_output)| max_output.unwrap_or(context_window))
+}
+
+fn limits(model: &str) -> Option<(usize, Option<usize>)> {
+    MODEL_LIMITS
+        .iter()
+        .filter(|(prefix, _, _)| model.starts_with(prefix))
+        .max_by_key(|(prefix, _, _)| prefix.len())
+        .map(|(_, context_window, max_output)| (*context_window, *max_output))
+}
+
+/// Returns the largest `max_tokens` which fits in the model's context window after the prompt,
+/// less a small margin, and within its output limit.
+pub(crate) fn auto_max_tokens(model: &str, prompt_tokens: usize) -> Result<i32, OpenAIError> {
+    let context_window = context_window(model).ok_or_else(|| {
+        OpenAIError::InvalidArgument(InvalidArgumentError::new(
+            "model",
+            format!("No context window is known for model {model}"),
+        ))
+    })?;
+
+    let available = context_window
+        .saturating_sub(prompt_tokens + AUTO_MAX_TOKENS_MARGIN)
+        .min(max_output_tokens(model).unwrap_or(context_window));
+    if available == 0 {
+        return Err(OpenAIError::InvalidArgument(InvalidArgumentError::new(
+            "max_tokens",
+            format!(
+                "The prompt uses {prompt_tokens} tokens, leaving no room for a reply in the \
+                {context_window} token context window of {model}"
+            ),
+        )));
+    }
+
+    Ok(i32::try_from(available).unwrap_or(i32::MAX))
+}
+
+/// Expand words into a `logit_bias` map of token ids.
+///
+/// Each word is encoded both on its own and with a leading space, as words in the middle of a
+/// sentence are tokenized with the space before them. Every token of a word which encodes to
+/// several tokens is biased, which also affects other words sharing those tokens.
+pub(crate) fn logit_bias_words(
+    model: &str,
+    words: &[(&str, i8)],
+) -> Result<HashMap<String, i8>, OpenAIError> {
+    let bpe = bpe(model)?;
+
+    let mut logit_bias = HashMap::new();
+    for (word, bias) in words {
+        let word = word.trim();
+        for variant in [word.to_string(), format!(" {word}")] {
+            for token in bpe.encode_ordinary(&variant) {
+                logit_bias.insert(token.to_string(), *bias);
+            }
+        }
+    }
+    Ok(logit_bias)
+}
+
+fn bpe(model: &str) -> Result<&'static CoreBPE, OpenAIError> {
+    let tokenizer = get_tokenizer(model).ok_or_else(|| {
+        OpenAIError::InvalidArgument(InvalidArgumentError::new(
+            "model",
+            format!("No tokenizer is known for model {model}"),
+        ))
+    })?;
+
+    Ok(match tokenizer {
+        Tokenizer::O200kBase => tiktoken_rs::o200k_base_singleton(),
+        Tokenizer::Cl100kBase => tiktoken_rs::cl100k_base_singleton(),
+        Tokenizer::P50kBase => tiktoken_rs::p50k_base_singleton(),
+        Tokenizer::P50kEdit => tiktoken_rs::p50k_edit_singleton(),
+        Tokenizer::R50kBase | Tokenizer::Gpt2 => tiktoken_rs::r50k_base_singleton(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Verify that text is encoded with the tokenizer of the model
+    fn test_encode() {
+        assert_eq!(encode("gpt-4", "hello world").unwrap(), vec![15339, 1917]);
+        assert_eq!(count_tokens("gpt-4o-mini", "hello world").unwrap(), 2);
+        assert!(encode("not-a-model", "hello").is_err());
+    }
+
+    #[test]
+    // Verify that chat messages are counted with the per-message overhead
+    fn test_count_message_tokens() {
+        let messages = [
+            Message::new("user", "hello world"),
+            Message::with_parts(
+                "user",
+                &[
+                    ContentPart::text("hello"),
+                    ContentPart::image_url("https://example.com/cat.png"),
+                ],
+            ),
+        ];
+
+        // 3 for the reply, then 3 + 1 for the role and the content of each message
+        assert_eq!(
+            count_message_tokens("gpt-4", &messages).unwrap(),
+            3 + (4 + 2) + (4 + 1)
+        );
+    }
+
+    #[test]
+    // Verify that max_tokens fills the context window and is capped by the output limit
+    fn test_auto_max_tokens() {
+        assert_eq!(
+            auto_max_tokens("gpt-4-0613", 1000).unwrap(),
+            8192 - 1000 - 32
+        );
+        assert_eq!(auto_max_tokens("gpt-4o-2024-08-06", 1000).unwrap(), 16_384);
+        assert!(auto_max_tokens("gpt-4", 8192).is_err());
+        assert!(auto_max_tokens("not-a-model", 10).is_err());
+    }
+
+    #[test]
+    // Verify that words are expanded into the tokens of the word with and without a leading space
+    fn test_logit_bias_words() {
+        let logit_bias = logit_bias_words("gpt-4", &[("world", -100)]).unwrap();
+
+        assert_eq!(
+            logit_bias,
+            HashMap::from([("14957".to_string(), -100), ("1917".to_string(), -100)])
+        );
+    }
+}
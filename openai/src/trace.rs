@@ -0,0 +1,283 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing a recorder of the requests, responses, streamed chunks, retries and errors
+//! which make up a logical operation, for debugging it after the fact.
+//!
+//! An `ExchangeTrace` is shared by cloning it and passing it to each request with `with_trace`.
+//! The recorded entries are in the order they happened and can be exported to JSON.
+
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use ryst_error::InternalError;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::OpenAIError;
+use crate::tags::RequestTags;
+
+/// Something which happened while sending a request or reading its response.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TraceEvent {
+    /// A request body was sent to an API path
+    Request {
+        path: String,
+        body: Value,
+        /// The tags set with `with_tags`
+        #[serde(default, skip_serializing_if = "RequestTags::is_empty")]
+        tags: RequestTags,
+    },
+    /// The request was sent again
+    Retry { reason: String },
+    /// The response status was received
+    Response {
+        status: u16,
+        /// The `x-request-id` of the response
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
+    /// Bytes were read from a streamed response, lossily decoded as UTF-8
+    Chunk { data: String },
+    /// The response body was parsed
+    Body { body: Value },
+    /// The request or reading its response failed
+    Error { message: String },
+}
+
+/// A recorded event and when it happened.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TraceEntry {
+    /// The milliseconds since the trace was created
+    pub elapsed_ms: f64,
+    #[serde(flatten)]
+    pub event: TraceEvent,
+}
+
+#[derive(Serialize)]
+struct TraceExport<'a> {
+    started_at_ms: u64,
+    entries: &'a [TraceEntry],
+}
+
+struct TraceState {
+    started: Instant,
+    started_at_ms: u64,
+    entries: Vec<TraceEntry>,
+}
+
+/// Records the exchanges of one logical operation, such as an agent run.
+///
+/// Clones share the same entries, so one trace can be given to every request in the operation.
+#[derive(Clone)]
+pub struct ExchangeTrace {
+    state: Arc<Mutex<TraceState>>,
+}
+
+impl ExchangeTrace {
+    /// Create an empty trace, with times measured from now.
+    pub fn new() -> Self {
+        let started_at_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or(0);
+
+        Self {
+            state: Arc::new(Mutex::new(TraceState {
+                started: Instant::now(),
+                started_at_ms,
+                entries: Vec::new(),
+            })),
+        }
+    }
+
+    /// Record an event, such as a step of the operation between requests.
+    pub fn record(&self, event: TraceEvent) {
+        let mut state = self.lock();
+        let elapsed_ms = state.started.elapsed().as_secs_f64() * 1000.0;
+        state.entries.push(TraceEntry { elapsed_ms, event });
+    }
+
+    /// Returns the entries recorded so far.
+    pub fn entries(&self) -> Vec<TraceEntry> {
+        self.lock().entries.clone()
+    }
+
+    /// Remove all recorded entries.
+    pub fn clear(&self) {
+        self.lock().entries.clear();
+    }
+
+    /// Export the trace as JSON, with the Unix time in milliseconds it started at and its
+    /// entries.
+    pub fn to_json(&self) -> Result<String, OpenAIError> {
+        let state = self.lock();
+        serde_json::to_string_pretty(&TraceExport {
+            started_at_ms: state.started_at_ms,
+            entries: &state.entries,
+        })
+        .map_err(|err| OpenAIError::Internal(InternalError::from_source(Box::new(err))))
+    }
+
+    /// Write the trace as JSON to a file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), OpenAIError> {
+        fs::write(path, self.to_json()?).map_err(|err| {
+            OpenAIError::Internal(InternalError::from_source_with_prefix(
+                Box::new(err),
+                "Unable to write trace",
+            ))
+        })
+    }
+
+    /// Write the trace as JSON encrypted with the key to a file, to be read back with
+    /// `EncryptionKey::decrypt`.
+    #[cfg(feature = "encryption")]
+    pub fn save_encrypted<P: AsRef<Path>>(
+        &self,
+        path: P,
+        key: &crate::encryption::EncryptionKey,
+    ) -> Result<(), OpenAIError> {
+        fs::write(path, key.encrypt(self.to_json()?.as_bytes())).map_err(|err| {
+            OpenAIError::Internal(InternalError::from_source_with_prefix(
+                Box::new(err),
+                "Unable to write trace",
+            ))
+        })
+    }
+
+    pub(crate) fn record_result<T: Serialize>(&self, result: &Result<T, OpenAIError>) {
+        match result {
+            Ok(body) => self.record(TraceEvent::Body {
+                body: serde_json::to_value(body).unwrap_or(Value::Null),
+            }),
+            Err(err) => self.record(TraceEvent::Error {
+                message: err.to_string(),
+            }),
+        }
+    }
+
+    // A panic while holding the lock cannot leave the entries inconsistent, so a poisoned lock
+    // is still used
+    fn lock(&self) -> MutexGuard<'_, TraceState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Default for ExchangeTrace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Traces are equal when they share the same entries
+impl PartialEq for ExchangeTrace {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.state, &other.state)
+    }
+}
+
+impl std::fmt::Debug for ExchangeTrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ExchangeTrace")
+            .field("entries", &self.lock().entries.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+    use futures::stream;
+    use serde_json::json;
+
+    use crate::ChatCompletionResponseStream;
+
+    #[test]
+    // Verify that entries are recorded in order, shared between clones and exported to JSON
+    fn test_trace_export() {
+        let trace = ExchangeTrace::new();
+        trace.clone().record(TraceEvent::Request {
+            path: "/v1/chat/completions".to_string(),
+            body: json!({"model": "gpt-4o"}),
+            tags: RequestTags::new().with_experiment("short-prompts"),
+        });
+        trace.record(TraceEvent::Response {
+            status: 200,
+            request_id: None,
+        });
+
+        let entries = trace.entries();
+        assert_eq!(entries.len(), 2);
+        assert!(entries[0].elapsed_ms <= entries[1].elapsed_ms);
+
+        let json: Value = serde_json::from_str(&trace.to_json().unwrap()).unwrap();
+        assert_eq!(json["entries"][0]["event"], "request");
+        assert_eq!(json["entries"][0]["body"]["model"], "gpt-4o");
+        assert_eq!(json["entries"][0]["tags"]["experiment"], "short-prompts");
+        assert_eq!(json["entries"][1]["status"], 200);
+
+        trace.clear();
+        assert!(trace.entries().is_empty());
+    }
+
+    #[tokio::test]
+    // Verify that a traced stream records each chunk and the parsed response
+    async fn test_trace_stream() {
+        let response = json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "gpt-4o",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": "Hi"},
+                "finish_reason": "stop"
+            }],
+            "usage": {"prompt_tokens": 1, "completion_tokens": 1, "total_tokens": 2}
+        })
+        .to_string();
+        let (first, second) = response.split_at(10);
+        let chunks = vec![
+            Ok(Bytes::from(first.to_string())),
+            Ok(Bytes::from(second.to_string())),
+        ];
+
+        let trace = ExchangeTrace::new();
+        let mut stream = ChatCompletionResponseStream::new(Box::pin(stream::iter(chunks)))
+            .with_trace(trace.clone());
+        stream.next().await.unwrap().unwrap();
+        assert_eq!(stream.stats().unwrap().chunks, 2);
+
+        let events = trace
+            .entries()
+            .into_iter()
+            .map(|entry| entry.event)
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 3);
+        assert_eq!(
+            events[0],
+            TraceEvent::Chunk {
+                data: first.to_string()
+            }
+        );
+        assert!(matches!(&events[2], TraceEvent::Body { body } if body["model"] == "gpt-4o"));
+    }
+}
@@ -0,0 +1,261 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing the readable transcript format chat messages, requests and responses are
+//! displayed in, and the writing of streamed text to the transcripts of `tee_to`.
+
+use std::fmt;
+use std::pin::Pin;
+
+use ryst_error::InternalError;
+use serde_json::Value;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::error::OpenAIError;
+use crate::{
+    ChatCompletionRequest, ChatCompletionResponse, CompletionResponse, ContentPart, Message,
+    MessageContent,
+};
+
+/// Data URLs longer than this are summarized by their length.
+const MAX_DATA_URL_CHARS: usize = 64;
+
+/// Writes text after a label, indenting any following lines so each message stands out.
+fn write_indented(f: &mut fmt::Formatter, label: &str, text: &str) -> fmt::Result {
+    let mut lines = text.lines();
+    write!(f, "{label}: {}", lines.next().unwrap_or_default())?;
+    for line in lines {
+        write!(f, "\n  {line}")?;
+    }
+    Ok(())
+}
+
+fn url_summary(url: &str) -> String {
+    if url.starts_with("data:") && url.len() > MAX_DATA_URL_CHARS {
+        let media_type = url[5..].split([';', ',']).next().unwrap_or_default();
+        format!("{media_type} data, {} bytes", url.len())
+    } else {
+        url.to_string()
+    }
+}
+
+impl fmt::Display for ContentPart {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ContentPart::Text { text } => f.write_str(text),
+            ContentPart::ImageUrl { image_url } => {
+                write!(f, "[image: {}]", url_summary(&image_url.url))
+            }
+            ContentPart::File { file } => {
+                let name = file
+                    .filename
+                    .as_deref()
+                    .or(file.file_id.as_deref())
+                    .unwrap_or("unnamed");
+                write!(f, "[file: {name}]")
+            }
+        }
+    }
+}
+
+impl fmt::Display for Message {
+    /// Writes the message as `role: content`, with tool calls on their own lines.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let label = match &self.tool_call_id {
+            Some(id) => format!("{} ({id})", self.role),
+            None => self.role.to_string(),
+        };
+
+        let mut text = match &self.content {
+            MessageContent::Text(text) => text.clone(),
+            MessageContent::Parts(parts) => parts
+                .iter()
+                .map(ContentPart::to_string)
+                .collect::<Vec<_>>()
+                .join("\n"),
+        };
+        for call in self.tool_calls() {
+            if !text.is_empty() {
+                text.push('\n');
+            }
+            text.push_str(&format!(
+                "-> {}({}) [{}]",
+                call.function.name, call.function.arguments, call.id
+            ));
+        }
+
+        write_indented(f, &label, &text)
+    }
+}
+
+impl fmt::Display for ChatCompletionRequest {
+    /// Writes the model and parameters on the first line, then the tools and messages.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "model: {}", self.model())?;
+
+        if let Ok(Value::Object(fields)) = serde_json::to_value(self) {
+            for (key, value) in fields {
+                if !matches!(key.as_str(), "model" | "messages" | "tools") {
+                    write!(f, ", {key}: {value}")?;
+                }
+            }
+        }
+
+        if !self.tools().is_empty() {
+            let names = self
+                .tools()
+                .iter()
+                .map(|tool| tool.function.name.as_str())
+                .collect::<Vec<_>>();
+            write!(f, "\ntools: {}", names.join(", "))?;
+        }
+
+        for message in self.messages() {
+            write!(f, "\n\n{message}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ChatCompletionResponse {
+    /// Writes the model and token usage on the first line, then each choice.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}), {} prompt + {} completion tokens",
+            self.model, self.id, self.usage.prompt_tokens, self.usage.completion_tokens
+        )?;
+
+        for choice in &self.choices {
+            write!(
+                f,
+                "\n\n[choice {}, {}]\n{}",
+                choice.index, choice.finish_reason, choice.message
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for CompletionResponse {
+    /// Writes the model and token usage on the first line, then each choice.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}), {} prompt + {} completion tokens",
+            self.model, self.id, self.usage.prompt_tokens, self.usage.completion_tokens
+        )?;
+
+        for choice in &self.choices {
+            write!(
+                f,
+                "\n\n[choice {}, {}]\n{}",
+                choice.index, choice.finish_reason, choice.text
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Write each text followed by a newline, then flush the writer.
+pub(crate) async fn write_transcript(
+    mut tee: Pin<&mut (dyn AsyncWrite + Send)>,
+    texts: &[&str],
+) -> Result<(), OpenAIError> {
+    let result = async {
+        for text in texts {
+            tee.write_all(text.as_bytes()).await?;
+            tee.write_all(b"\n").await?;
+        }
+        tee.flush().await
+    };
+
+    result.await.map_err(|err| {
+        OpenAIError::Internal(InternalError::from_source_with_prefix(
+            Box::new(err),
+            "Unable to write transcript",
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    // Verify that a request is displayed as its parameters followed by a transcript
+    fn test_display_request() {
+        let mut assistant = Message::new("assistant", "");
+        assistant.tool_calls = Some(vec![serde_json::from_value(json!({
+            "id": "call_1",
+            "type": "function",
+            "function": {"name": "lookup", "arguments": "{\"q\":\"rust\"}"}
+        }))
+        .unwrap()]);
+        let request = ChatCompletionRequest::new(
+            "gpt-4o",
+            &[
+                Message::new("system", "Be brief.\nUse lists."),
+                Message::with_parts(
+                    "user",
+                    &[
+                        ContentPart::text("What is this?"),
+                        ContentPart::image_url(&format!(
+                            "data:image/png;base64,{}",
+                            "A".repeat(100)
+                        )),
+                    ],
+                ),
+                assistant,
+                Message::tool_result("call_1", "A language"),
+            ],
+        )
+        .with_max_tokens(100);
+
+        assert_eq!(
+            request.to_string(),
+            "model: gpt-4o, max_tokens: 100\n\n\
+             system: Be brief.\n  Use lists.\n\n\
+             user: What is this?\n  [image: image/png data, 122 bytes]\n\n\
+             assistant: -> lookup({\"q\":\"rust\"}) [call_1]\n\n\
+             tool (call_1): A language"
+        );
+    }
+
+    #[test]
+    // Verify that a response is displayed with its usage and each choice
+    fn test_display_response() {
+        let response: ChatCompletionResponse = serde_json::from_value(json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "gpt-4o",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": "Hello"},
+                "finish_reason": "stop"
+            }],
+            "usage": {"prompt_tokens": 12, "completion_tokens": 1, "total_tokens": 13}
+        }))
+        .unwrap();
+
+        assert_eq!(
+            response.to_string(),
+            "gpt-4o (chatcmpl-1), 12 prompt + 1 completion tokens\n\n\
+             [choice 0, stop]\nassistant: Hello"
+        );
+    }
+}
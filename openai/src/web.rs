@@ -0,0 +1,199 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing adapters which relay a `ChatCompletionResponseStream` to a browser as
+//! server-sent events, for axum and actix-web.
+//!
+//! Each response is sent as a `data` event containing its JSON, followed by `data: [DONE]`
+//! matching the OpenAI wire format. An error ends the stream with an `error` event containing
+//! its message. The upstream request is owned by the event stream, so when the client
+//! disconnects and the server drops the response body, the request to OpenAI is cancelled too.
+
+use std::convert::Infallible;
+
+use bytes::Bytes;
+use futures::stream::{self, Stream, StreamExt};
+
+use crate::ChatCompletionResponseStream;
+
+const DONE: &str = "[DONE]";
+
+/// A server-sent event produced from the stream.
+#[derive(Debug, Clone, PartialEq)]
+enum RelayEvent {
+    Data(String),
+    Error(String),
+}
+
+/// Convert the response stream into events, ending with `[DONE]` or an error.
+fn relay_events(stream: ChatCompletionResponseStream) -> impl Stream<Item = RelayEvent> + Send {
+    stream::unfold(Some(stream), |state| async move {
+        let mut stream = state?;
+        match stream.next().await {
+            Ok(Some(response)) => match serde_json::to_string(&response) {
+                Ok(json) => Some((RelayEvent::Data(json), Some(stream))),
+                Err(err) => Some((RelayEvent::Error(err.to_string()), None)),
+            },
+            Ok(None) => Some((RelayEvent::Data(DONE.to_string()), None)),
+            Err(err) => Some((RelayEvent::Error(err.to_string()), None)),
+        }
+    })
+}
+
+/// Relay the stream as an axum server-sent events response, with keep-alive comments so that
+/// proxies do not close the connection while waiting for the model.
+pub fn into_axum_sse(
+    stream: ChatCompletionResponseStream,
+) -> axum::response::Sse<impl Stream<Item = Result<axum::response::sse::Event, Infallible>>> {
+    use axum::response::sse::{Event, KeepAlive, Sse};
+
+    let events = relay_events(stream).map(|event| {
+        Ok(match event {
+            RelayEvent::Data(data) => Event::default().data(data),
+            RelayEvent::Error(message) => Event::default().event("error").data(message),
+        })
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// Relay the stream as an actix-web server-sent events response.
+pub fn into_actix_sse(stream: ChatCompletionResponseStream) -> actix_web::HttpResponse {
+    let body = relay_events(stream).map(|event| Ok::<_, Infallible>(actix_event(event)));
+
+    actix_web::HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(body)
+}
+
+/// Frame the event as it is written by axum's `Event`, with one `data` line for each line of the
+/// data so that a line break cannot end the event early.
+fn actix_event(event: RelayEvent) -> Bytes {
+    let (name, data) = match event {
+        RelayEvent::Data(data) => (None, data),
+        RelayEvent::Error(message) => (Some("error"), message),
+    };
+
+    let mut frame = String::new();
+    if let Some(name) = name {
+        frame.push_str(&format!("event: {name}\n"));
+    }
+    for line in data.split("\r\n").flat_map(|line| line.split(['\r', '\n'])) {
+        frame.push_str(&format!("data: {line}\n"));
+    }
+    frame.push('\n');
+    Bytes::from(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+
+    use axum::response::IntoResponse;
+
+    const RESPONSE: &str = r#"{"id":"chatcmpl-1","object":"chat.completion","created":0,"model":"gpt-4o","choices":[{"message":{"role":"assistant","content":"Hi"},"index":0,"finish_reason":"stop"}],"usage":{"prompt_tokens":1,"completion_tokens":1,"total_tokens":2}}"#;
+
+    fn response_stream(body: &'static str) -> ChatCompletionResponseStream {
+        ChatCompletionResponseStream::new(Box::pin(stream::iter(vec![Ok(Bytes::from(body))])))
+    }
+
+    #[tokio::test]
+    // Verify that the axum relay sends the response followed by [DONE]
+    async fn test_into_axum_sse() {
+        let response = into_axum_sse(response_stream(RESPONSE)).into_response();
+        assert_eq!(
+            response.headers()["content-type"].to_str().unwrap(),
+            "text/event-stream"
+        );
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::str::from_utf8(&body).unwrap(),
+            format!("data: {RESPONSE}\n\ndata: [DONE]\n\n")
+        );
+    }
+
+    #[tokio::test]
+    // Verify that the actix relay sends an error event when the response is invalid
+    async fn test_into_actix_sse_error() {
+        let response = into_actix_sse(response_stream("not json"));
+        assert_eq!(
+            response
+                .headers()
+                .get("content-type")
+                .unwrap()
+                .to_str()
+                .unwrap(),
+            "text/event-stream"
+        );
+
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.starts_with("event: error\ndata: "), "{body}");
+        assert!(!body.contains(DONE));
+    }
+
+    #[test]
+    // Verify that each line of a multi-line message is sent as its own data line
+    fn test_actix_event_lines() {
+        assert_eq!(
+            actix_event(RelayEvent::Error("first\nsecond\r\nthird".to_string())),
+            "event: error\ndata: first\ndata: second\ndata: third\n\n"
+        );
+        assert_eq!(
+            actix_event(RelayEvent::Data(DONE.to_string())),
+            "data: [DONE]\n\n"
+        );
+    }
+
+    /// A stream which never produces an item and records when it is dropped.
+    struct Pending(Arc<AtomicBool>);
+
+    impl Stream for Pending {
+        type Item = reqwest::Result<Bytes>;
+
+        fn poll_next(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            Poll::Pending
+        }
+    }
+
+    impl Drop for Pending {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    // Verify that dropping the response, as happens when the client disconnects, drops the
+    // upstream stream
+    fn test_disconnect_drops_upstream() {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let upstream = ChatCompletionResponseStream::new(Box::pin(Pending(dropped.clone())));
+
+        let response = into_actix_sse(upstream);
+        assert!(!dropped.load(Ordering::SeqCst));
+        drop(response);
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+}
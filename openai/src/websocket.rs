@@ -0,0 +1,173 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Module containing a relay from a `ChatCompletionResponseStream` to a WebSocket.
+//!
+//! Each frame is a JSON text message with a `type` of `delta`, `done` or `error`:
+//!
+//! - `{"type": "delta", "index": 0, "content": "..."}` for the content of each choice
+//! - `{"type": "done", "usage": {...}}` once the response is complete
+//! - `{"type": "error", "message": "..."}` if the request fails, which ends the relay
+
+use std::pin::pin;
+
+use futures::future::{self, Either};
+use futures::stream::{self, Stream, StreamExt};
+use futures::{Sink, SinkExt};
+use ryst_error::InternalError;
+use serde_json::{json, Value};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message as WsMessage};
+
+use crate::error::OpenAIError;
+use crate::ChatCompletionResponseStream;
+
+/// Relay the responses from the stream to the WebSocket as JSON frames.
+///
+/// Frames are sent with `SinkExt::send`, which waits for the socket to accept each frame, so a
+/// slow client applies backpressure to the relay rather than frames being buffered without
+/// limit. While waiting for the model, messages from the client are read so that pings are
+/// answered. Returns once the `done` or `error` frame has been sent, or as soon as the client
+/// closes the socket, which drops the upstream request. The socket is not closed, so it may be
+/// used for further requests.
+pub async fn relay_to_websocket<S>(
+    stream: ChatCompletionResponseStream,
+    socket: &mut S,
+) -> Result<(), OpenAIError>
+where
+    S: Sink<WsMessage, Error = WsError> + Stream<Item = Result<WsMessage, WsError>> + Unpin,
+{
+    let mut frames = pin!(frames(stream));
+
+    loop {
+        // The futures are dropped before the socket is written to; the frame stream keeps its
+        // progress when the client wins the race
+        let next = match future::select(frames.next(), socket.next()).await {
+            Either::Left((frame, _)) => Either::Left(frame),
+            Either::Right((message, _)) => Either::Right(message),
+        };
+
+        match next {
+            Either::Left(Some((frame, last))) => {
+                socket
+                    .send(WsMessage::text(frame.to_string()))
+                    .await
+                    .map_err(socket_error)?;
+                if last {
+                    return Ok(());
+                }
+            }
+            Either::Left(None) => return Ok(()),
+            // tungstenite queues a pong when a ping is read, which is sent on flush
+            Either::Right(Some(Ok(WsMessage::Ping(_)))) => {
+                socket.flush().await.map_err(socket_error)?
+            }
+            Either::Right(Some(Ok(WsMessage::Close(_)))) | Either::Right(None) => return Ok(()),
+            Either::Right(Some(Ok(_))) => (),
+            Either::Right(Some(Err(err))) => return Err(socket_error(err)),
+        }
+    }
+}
+
+/// Convert the response stream into frames, each with whether it is the last.
+fn frames(stream: ChatCompletionResponseStream) -> impl Stream<Item = (Value, bool)> {
+    stream::unfold(Some(stream), |state| async move {
+        let mut stream = state?;
+        let frames = match stream.next().await {
+            Ok(Some(response)) => {
+                let mut frames: Vec<(Value, bool)> = response
+                    .choices
+                    .iter()
+                    .map(|choice| {
+                        let frame = json!({
+                            "type": "delta",
+                            "index": choice.index,
+                            "content": choice.message.content(),
+                        });
+                        (frame, false)
+                    })
+                    .collect();
+                frames.push((json!({"type": "done", "usage": response.usage}), true));
+                frames
+            }
+            Ok(None) => vec![(json!({"type": "done", "usage": null}), true)],
+            Err(err) => vec![(json!({"type": "error", "message": err.to_string()}), true)],
+        };
+        Some((stream::iter(frames), None))
+    })
+    .flatten()
+}
+
+fn socket_error(err: WsError) -> OpenAIError {
+    OpenAIError::Internal(InternalError::from_source_with_prefix(
+        Box::new(err),
+        "WebSocket error".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+    use tokio_tungstenite::tungstenite::protocol::Role;
+    use tokio_tungstenite::WebSocketStream;
+
+    const RESPONSE: &str = r#"{"id":"chatcmpl-1","object":"chat.completion","created":0,"model":"gpt-4o","choices":[{"message":{"role":"assistant","content":"Hi"},"index":0,"finish_reason":"stop"}],"usage":{"prompt_tokens":1,"completion_tokens":1,"total_tokens":2}}"#;
+
+    fn response_stream(body: &'static str) -> ChatCompletionResponseStream {
+        ChatCompletionResponseStream::new(Box::pin(stream::iter(vec![Ok(Bytes::from(body))])))
+    }
+
+    async fn relay(body: &'static str) -> Vec<Value> {
+        let (client, server) = tokio::io::duplex(4096);
+        let mut server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        let mut client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+
+        relay_to_websocket(response_stream(body), &mut server)
+            .await
+            .unwrap();
+        drop(server);
+
+        let mut frames = Vec::new();
+        while let Some(Ok(message)) = client.next().await {
+            if let WsMessage::Text(text) = message {
+                frames.push(serde_json::from_str(&text).unwrap());
+            }
+        }
+        frames
+    }
+
+    #[tokio::test]
+    // Verify that a response is relayed as delta frames followed by a done frame
+    async fn test_relay_to_websocket() {
+        assert_eq!(
+            relay(RESPONSE).await,
+            vec![
+                json!({"type": "delta", "index": 0, "content": "Hi"}),
+                json!({
+                    "type": "done",
+                    "usage": {"prompt_tokens": 1, "completion_tokens": 1, "total_tokens": 2}
+                }),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    // Verify that a failed response is relayed as an error frame
+    async fn test_relay_to_websocket_error() {
+        let frames = relay("not json").await;
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0]["type"], "error");
+    }
+}
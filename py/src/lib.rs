@@ -0,0 +1,373 @@
+// Copyright 2023 Embyr
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Python bindings for ryst-openai, built as the `ryst` extension module with maturin.
+//!
+//! ```python
+//! import ryst
+//!
+//! messages = [{"role": "user", "content": "Hi"}]
+//! request = ryst.ChatCompletionRequest("gpt-4o", messages)
+//! response = await request.submit()
+//!
+//! async for response in await request.stream():
+//!     print(response["choices"][0]["message"]["content"])
+//!
+//! client = ryst.Client(api_key="sk-...", base_url="https://gateway.internal", timeout=30)
+//! request = ryst.ChatCompletionRequest("gpt-4o", messages, client=client)
+//! ```
+//!
+//! Messages are given as dicts in the API's format, and responses are returned as dicts. Each
+//! method also has a `_blocking` variant for use outside of asyncio, and streams can be iterated
+//! with a plain `for` loop. Requests given a `Client` use its API key, base URL, default headers
+//! and timeout.
+//!
+//! Requests run on a tokio runtime owned by the module, driven from asyncio's default executor.
+//! The runtime's threads never touch the interpreter, so they can't race with it shutting down.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use pyo3::exceptions::{PyRuntimeError, PyStopAsyncIteration, PyStopIteration, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyModule;
+use ryst_openai::reqwest;
+use ryst_openai::{
+    ChatCompletionRequest, ChatCompletionResponse, ChatCompletionResponseStream, CompletionRequest,
+    CompletionResponse, CompletionResponseStream, Message, OpenAIClient, OpenAIError,
+};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::runtime::Runtime;
+
+fn runtime() -> PyResult<&'static Runtime> {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    if let Some(runtime) = RUNTIME.get() {
+        return Ok(runtime);
+    }
+    let runtime = Runtime::new().map_err(|err| PyRuntimeError::new_err(err.to_string()))?;
+    Ok(RUNTIME.get_or_init(|| runtime))
+}
+
+/// Run the future on the runtime to completion, releasing the GIL while it runs.
+fn block_on<F, T>(py: Python<'_>, future: F) -> PyResult<T>
+where
+    F: Future<Output = Result<T, OpenAIError>> + Send,
+    T: Send,
+{
+    let runtime = runtime()?;
+    py.allow_threads(|| runtime.block_on(async { future.await.map_err(to_py_err) }))
+}
+
+/// Returns an awaitable of the result of calling the function in asyncio's default executor.
+fn run_in_executor(function: Bound<'_, PyAny>) -> PyResult<Bound<'_, PyAny>> {
+    let py = function.py();
+    py.import("asyncio")?
+        .call_method0("get_running_loop")?
+        .call_method1("run_in_executor", (py.None(), function))
+}
+
+/// Convert an error to the Python exception for it.
+fn to_py_err(err: OpenAIError) -> PyErr {
+    match err {
+        OpenAIError::InvalidArgument(_) => PyValueError::new_err(err.to_string()),
+        _ => PyRuntimeError::new_err(err.to_string()),
+    }
+}
+
+/// Convert a Python value to a Rust value through its JSON representation.
+fn from_python<T: DeserializeOwned>(value: &Bound<'_, PyAny>) -> PyResult<T> {
+    let json = value
+        .py()
+        .import("json")?
+        .call_method1("dumps", (value,))?
+        .extract::<String>()?;
+    serde_json::from_str(&json).map_err(|err| PyValueError::new_err(err.to_string()))
+}
+
+/// Convert a Rust value to a Python value through its JSON representation.
+fn to_python<T: Serialize>(py: Python<'_>, value: &T) -> PyResult<PyObject> {
+    let json =
+        serde_json::to_string(value).map_err(|err| PyRuntimeError::new_err(err.to_string()))?;
+    py.import("json")?
+        .call_method1("loads", (json,))
+        .map(Bound::unbind)
+}
+
+/// The streams of both request types, read one response at a time.
+trait ResponseStream: Send {
+    type Response: Serialize + Send;
+
+    fn next_response(
+        &mut self,
+    ) -> impl Future<Output = Result<Option<Self::Response>, OpenAIError>>;
+}
+
+impl ResponseStream for ChatCompletionResponseStream {
+    type Response = ChatCompletionResponse;
+
+    fn next_response(
+        &mut self,
+    ) -> impl Future<Output = Result<Option<Self::Response>, OpenAIError>> {
+        self.next()
+    }
+}
+
+impl ResponseStream for CompletionResponseStream {
+    type Response = CompletionResponse;
+
+    fn next_response(
+        &mut self,
+    ) -> impl Future<Output = Result<Option<Self::Response>, OpenAIError>> {
+        self.next()
+    }
+}
+
+/// Read the next response of a stream, or `None` at its end, releasing the GIL while it's read.
+fn next_response<S: ResponseStream>(
+    py: Python<'_>,
+    stream: &Mutex<S>,
+) -> PyResult<Option<PyObject>> {
+    let runtime = runtime()?;
+    let response = py.allow_threads(|| {
+        // A stream poisoned by a panic part way through a response is treated as ended
+        let Ok(mut stream) = stream.lock() else {
+            return Ok(None);
+        };
+        runtime.block_on(stream.next_response()).map_err(to_py_err)
+    })?;
+    response
+        .map(|response| to_python(py, &response))
+        .transpose()
+}
+
+/// The credentials, endpoint and HTTP settings shared by the requests sent through it.
+#[pyclass(name = "Client")]
+struct PyClient {
+    client: OpenAIClient,
+}
+
+#[pymethods]
+impl PyClient {
+    /// The API key is read from the environment when not given, and the timeout is in seconds.
+    #[new]
+    #[pyo3(signature = (api_key=None, base_url=None, headers=None, timeout=None))]
+    fn new(
+        api_key: Option<&str>,
+        base_url: Option<&str>,
+        headers: Option<HashMap<String, String>>,
+        timeout: Option<f64>,
+    ) -> PyResult<Self> {
+        let mut client = api_key.map(OpenAIClient::new).unwrap_or_default();
+        if let Some(base_url) = base_url {
+            client = client.with_base_url(base_url);
+        }
+        for (name, value) in headers.unwrap_or_default() {
+            client = client.with_default_header(&name, &value);
+        }
+        if let Some(timeout) = timeout {
+            let timeout = Duration::try_from_secs_f64(timeout)
+                .map_err(|err| PyValueError::new_err(format!("Invalid timeout: {err}")))?;
+            let http = reqwest::Client::builder()
+                .timeout(timeout)
+                .build()
+                .map_err(|err| PyRuntimeError::new_err(err.to_string()))?;
+            client = client.with_http_client(http);
+        }
+        Ok(Self { client })
+    }
+}
+
+/// A chat completion request, submitted with `submit` or `stream`.
+#[pyclass(name = "ChatCompletionRequest")]
+struct PyChatCompletionRequest {
+    request: ChatCompletionRequest,
+}
+
+#[pymethods]
+impl PyChatCompletionRequest {
+    #[new]
+    #[pyo3(signature = (model, messages, temperature=None, max_tokens=None, client=None))]
+    fn new(
+        model: &str,
+        messages: &Bound<'_, PyAny>,
+        temperature: Option<f32>,
+        max_tokens: Option<i32>,
+        client: Option<PyRef<'_, PyClient>>,
+    ) -> PyResult<Self> {
+        let messages: Vec<Message> = from_python(messages)?;
+        let mut request = ChatCompletionRequest::new(model, &messages);
+        if let Some(temperature) = temperature {
+            request = request.with_temperature(temperature);
+        }
+        if let Some(max_tokens) = max_tokens {
+            request = request.with_max_tokens(max_tokens);
+        }
+        if let Some(client) = client {
+            request = request.with_client(client.client.clone());
+        }
+        Ok(Self { request })
+    }
+
+    /// Submit the request, returning an awaitable of the response.
+    fn submit<'py>(slf: &Bound<'py, Self>) -> PyResult<Bound<'py, PyAny>> {
+        run_in_executor(slf.getattr("submit_blocking")?)
+    }
+
+    /// Submit the request, blocking until the response is returned.
+    fn submit_blocking(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let response = block_on(py, self.request.clone().submit())?;
+        to_python(py, &response)
+    }
+
+    /// Submit the request as a stream, returning an awaitable of an iterator of responses.
+    fn stream<'py>(slf: &Bound<'py, Self>) -> PyResult<Bound<'py, PyAny>> {
+        run_in_executor(slf.getattr("stream_blocking")?)
+    }
+
+    /// Submit the request as a stream, blocking until the stream is returned.
+    fn stream_blocking(&self, py: Python<'_>) -> PyResult<PyChatCompletionStream> {
+        let stream = block_on(py, self.request.clone().stream())?;
+        Ok(PyChatCompletionStream {
+            stream: Mutex::new(stream),
+        })
+    }
+}
+
+/// The responses of a streamed chat completion request, as an async or blocking iterator.
+#[pyclass(name = "ChatCompletionStream")]
+struct PyChatCompletionStream {
+    stream: Mutex<ChatCompletionResponseStream>,
+}
+
+#[pymethods]
+impl PyChatCompletionStream {
+    fn __aiter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __anext__<'py>(slf: &Bound<'py, Self>) -> PyResult<Bound<'py, PyAny>> {
+        run_in_executor(slf.getattr("next_async")?)
+    }
+
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&self, py: Python<'_>) -> PyResult<PyObject> {
+        next_response(py, &self.stream)?.ok_or_else(|| PyStopIteration::new_err(()))
+    }
+
+    /// Read the next response for `__anext__`, from the executor.
+    fn next_async(&self, py: Python<'_>) -> PyResult<PyObject> {
+        next_response(py, &self.stream)?.ok_or_else(|| PyStopAsyncIteration::new_err(()))
+    }
+}
+
+/// A completion request, submitted with `submit` or `stream`.
+#[pyclass(name = "CompletionRequest")]
+struct PyCompletionRequest {
+    request: CompletionRequest,
+}
+
+#[pymethods]
+impl PyCompletionRequest {
+    #[new]
+    #[pyo3(signature = (model, prompt, temperature=None, max_tokens=None, client=None))]
+    fn new(
+        model: &str,
+        prompt: &str,
+        temperature: Option<f32>,
+        max_tokens: Option<i32>,
+        client: Option<PyRef<'_, PyClient>>,
+    ) -> Self {
+        let mut request = CompletionRequest::new(model, prompt);
+        if let Some(temperature) = temperature {
+            request = request.with_temperature(temperature);
+        }
+        if let Some(max_tokens) = max_tokens {
+            request = request.with_max_tokens(max_tokens);
+        }
+        if let Some(client) = client {
+            request = request.with_client(client.client.clone());
+        }
+        Self { request }
+    }
+
+    /// Submit the request, returning an awaitable of the response.
+    fn submit<'py>(slf: &Bound<'py, Self>) -> PyResult<Bound<'py, PyAny>> {
+        run_in_executor(slf.getattr("submit_blocking")?)
+    }
+
+    /// Submit the request, blocking until the response is returned.
+    fn submit_blocking(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let response = block_on(py, self.request.clone().submit())?;
+        to_python(py, &response)
+    }
+
+    /// Submit the request as a stream, returning an awaitable of an iterator of responses.
+    fn stream<'py>(slf: &Bound<'py, Self>) -> PyResult<Bound<'py, PyAny>> {
+        run_in_executor(slf.getattr("stream_blocking")?)
+    }
+
+    /// Submit the request as a stream, blocking until the stream is returned.
+    fn stream_blocking(&self, py: Python<'_>) -> PyResult<PyCompletionStream> {
+        let stream = block_on(py, self.request.clone().stream())?;
+        Ok(PyCompletionStream {
+            stream: Mutex::new(stream),
+        })
+    }
+}
+
+/// The responses of a streamed completion request, as an async or blocking iterator.
+#[pyclass(name = "CompletionStream")]
+struct PyCompletionStream {
+    stream: Mutex<CompletionResponseStream>,
+}
+
+#[pymethods]
+impl PyCompletionStream {
+    fn __aiter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __anext__<'py>(slf: &Bound<'py, Self>) -> PyResult<Bound<'py, PyAny>> {
+        run_in_executor(slf.getattr("next_async")?)
+    }
+
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&self, py: Python<'_>) -> PyResult<PyObject> {
+        next_response(py, &self.stream)?.ok_or_else(|| PyStopIteration::new_err(()))
+    }
+
+    /// Read the next response for `__anext__`, from the executor.
+    fn next_async(&self, py: Python<'_>) -> PyResult<PyObject> {
+        next_response(py, &self.stream)?.ok_or_else(|| PyStopAsyncIteration::new_err(()))
+    }
+}
+
+#[pymodule]
+fn ryst(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyClient>()?;
+    module.add_class::<PyChatCompletionRequest>()?;
+    module.add_class::<PyChatCompletionStream>()?;
+    module.add_class::<PyCompletionRequest>()?;
+    module.add_class::<PyCompletionStream>()?;
+    Ok(())
+}